@@ -0,0 +1,112 @@
+use crate::errors::*;
+use serde::{Deserialize, Serialize};
+
+/// Storage classes restic can't use, objects in them have to be restored before they can be
+/// read and restic reads pack files synchronously
+const ARCHIVE_STORAGE_CLASSES: &[&str] = &["GLACIER", "DEEP_ARCHIVE"];
+
+/// An extended option of a backend, passed to restic as `-o key=value`
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendOption {
+	/// `b2.connections`, concurrent connections to B2, at least 1
+	B2Connections(u32),
+	/// `s3.connections`, concurrent connections to S3, at least 1
+	S3Connections(u32),
+	/// `s3.storage-class` of the objects restic writes, e.g. `STANDARD_IA`. Archive classes
+	/// (`GLACIER`, `DEEP_ARCHIVE`) are rejected, restic has to be able to read every object
+	/// right away.
+	S3StorageClass(String),
+	/// `sftp.command`, the command run instead of `ssh` to reach the server
+	SftpCommand(String),
+	/// `rclone.program`, path of the rclone binary
+	RcloneProgram(String),
+	/// `local.layout`, the directory layout of a local repository, e.g. `default`
+	LocalLayout(String),
+	/// Any other option as key and value, e.g. `("azure.connections", "10")`
+	Custom(String, String),
+}
+
+impl BackendOption {
+	pub fn key(&self) -> &str {
+		match self {
+			BackendOption::B2Connections(_) => "b2.connections",
+			BackendOption::S3Connections(_) => "s3.connections",
+			BackendOption::S3StorageClass(_) => "s3.storage-class",
+			BackendOption::SftpCommand(_) => "sftp.command",
+			BackendOption::RcloneProgram(_) => "rclone.program",
+			BackendOption::LocalLayout(_) => "local.layout",
+			BackendOption::Custom(key, _) => key,
+		}
+	}
+
+	pub fn value(&self) -> String {
+		match self {
+			BackendOption::B2Connections(connections) | BackendOption::S3Connections(connections) => {
+				connections.to_string()
+			}
+			BackendOption::S3StorageClass(value)
+			| BackendOption::SftpCommand(value)
+			| BackendOption::RcloneProgram(value)
+			| BackendOption::LocalLayout(value)
+			| BackendOption::Custom(_, value) => value.clone(),
+		}
+	}
+
+	/// The argument following `-o`, fails with `InvalidConfig` for a zero connection count, an
+	/// archive storage class or a custom key restic wouldn't read as `<backend>.<option>`
+	pub fn as_arg(&self) -> Result<String> {
+		let invalid = |reason: String| -> Result<String> { Err(ErrorKind::InvalidConfig(reason).into()) };
+		match self {
+			BackendOption::B2Connections(0) | BackendOption::S3Connections(0) => {
+				return invalid(format!("{} must be at least 1", self.key()));
+			}
+			BackendOption::S3StorageClass(storage_class)
+				if ARCHIVE_STORAGE_CLASSES.contains(&storage_class.to_ascii_uppercase().as_str()) =>
+			{
+				return invalid(format!(
+					"S3 storage class {} needs objects to be restored before reading, restic can't use it",
+					storage_class
+				));
+			}
+			BackendOption::Custom(key, _) => {
+				let valid = key.split_once('.').is_some_and(|(backend, option)| !backend.is_empty() && !option.is_empty())
+					&& !key.contains(|c: char| c == '=' || c.is_whitespace());
+				if !valid {
+					return invalid(format!("backend option key {:?} isn't of the form <backend>.<option>", key));
+				}
+			}
+			_ => (),
+		}
+		Ok(format!("{}={}", self.key(), self.value()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn renders_key_value_pairs() {
+		assert_eq!(BackendOption::B2Connections(8).as_arg().unwrap(), "b2.connections=8");
+		assert_eq!(
+			BackendOption::SftpCommand("ssh -p 2222 backup@host -s sftp".into()).as_arg().unwrap(),
+			"sftp.command=ssh -p 2222 backup@host -s sftp"
+		);
+		assert_eq!(
+			BackendOption::Custom("azure.connections".into(), "10".into()).as_arg().unwrap(),
+			"azure.connections=10"
+		);
+
+		assert!(BackendOption::S3Connections(0).as_arg().is_err());
+		for storage_class in &["GLACIER", "deep_archive"] {
+			let err = BackendOption::S3StorageClass((*storage_class).to_owned()).as_arg().unwrap_err();
+			assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)), "{}", storage_class);
+		}
+		assert!(BackendOption::S3StorageClass("STANDARD_IA".into()).as_arg().is_ok());
+		for key in &["connections", ".connections", "b2.", "b2.connections=4", "b2. connections"] {
+			let err = BackendOption::Custom((*key).to_owned(), "4".into()).as_arg().unwrap_err();
+			assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)), "{}", key);
+		}
+	}
+}
@@ -0,0 +1,332 @@
+use crate::classify;
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::{BackupErrorJson, BackupJson, BackupStatusJson, MessageTypeJson, VerboseStatusJson};
+use crate::running_command;
+use crate::trace;
+use crate::{BackupTarget, NotIncludedList, OutputTail, ResticConfig, RetryState, RunningCommand};
+use std::process::Output;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DetailedBackupOptions {
+	/// Keep only every nth status line, 0 and 1 keep all. restic prints several per second,
+	/// so long backups should downsample to bound memory.
+	pub status_every: usize,
+	/// Run with `--verbose`, which reports every processed file and directory, see
+	/// `restic_backup_streaming`
+	pub verbose: bool,
+	/// Keep the verbose entries in `DetailedBackupResult::verbose`. There is one per file, so
+	/// for large backups passing them to a callback is the better choice.
+	pub collect_verbose: bool,
+}
+
+/// A status line with the time it was read
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusSample {
+	/// Time since the backup started, taken from a monotonic clock when the line was read
+	pub elapsed: Duration,
+	pub status: BackupStatusJson,
+}
+
+#[derive(Debug, Clone)]
+pub struct DetailedBackupResult {
+	pub summary: BackupJson,
+	pub samples: Vec<StatusSample>,
+	/// Files restic couldn't read. Unlike `restic_backup`, a snapshot missing these is returned
+	/// with them listed here instead of failing with `PartialBackup`.
+	pub errors: Vec<BackupErrorJson>,
+	/// Folders of the target that were skipped because they couldn't be resolved, see
+	/// `PathMode::AsGiven`, and other lines restic printed to stderr
+	pub warnings: Vec<String>,
+	/// Empty unless `collect_verbose` was set
+	pub verbose: Vec<VerboseStatusJson>,
+}
+
+#[derive(Default)]
+struct Collected {
+	summary: Option<BackupJson>,
+	samples: Vec<StatusSample>,
+	errors: Vec<BackupErrorJson>,
+	warnings: Vec<String>,
+	verbose: Vec<VerboseStatusJson>,
+	statuses_seen: usize,
+	/// Whether a verbose entry reached the callback, the backup isn't retried after that
+	verbose_delivered: bool,
+	progress: trace::ProgressEvents,
+}
+
+impl Collected {
+	fn stdout_line(
+		&mut self,
+		line: &str,
+		elapsed: Duration,
+		options: &DetailedBackupOptions,
+		on_verbose: &mut dyn FnMut(&VerboseStatusJson),
+	) -> Result<()> {
+		let message: MessageTypeJson = match serde_json::from_str(line) {
+			Ok(message) => message,
+			Err(_) => {
+				self.warnings.push(line.to_owned());
+				return Ok(());
+			}
+		};
+		match message.message_type.as_str() {
+			"status" => {
+				self.statuses_seen += 1;
+				self.progress.status_line(line);
+				if options.status_every <= 1 || (self.statuses_seen - 1).is_multiple_of(options.status_every) {
+					let status = parse_json("backup", line)?;
+					self.samples.push(StatusSample { elapsed, status });
+				}
+			}
+			"summary" => self.summary = Some(parse_json("backup", line)?),
+			"error" => self.errors.push(parse_json("backup", line)?),
+			"verbose_status" => {
+				let entry = parse_json("backup", line)?;
+				self.verbose_delivered = true;
+				on_verbose(&entry);
+				if options.collect_verbose {
+					self.verbose.push(entry);
+				}
+			}
+			_ => (),
+		}
+		Ok(())
+	}
+
+	fn stderr_line(&mut self, line: &str) {
+		let line = line.trim_end();
+		if line.is_empty() {
+			return;
+		}
+		match serde_json::from_str::<BackupErrorJson>(line) {
+			Ok(error) => self.errors.push(error),
+			Err(_) => self.warnings.push(line.to_owned()),
+		}
+	}
+}
+
+impl ResticConfig {
+	/// Like `restic_backup`, but also returns every status line (or every nth, see
+	/// `DetailedBackupOptions`) with the time it arrived, and what restic reported on stderr
+	pub fn restic_backup_detailed(
+		&self,
+		backup_targets: &BackupTarget,
+		options: &DetailedBackupOptions,
+	) -> Result<DetailedBackupResult> {
+		self.restic_backup_streaming(backup_targets, options, |_| ())
+	}
+
+	/// `restic_backup_detailed` passing the verbose entries to `on_verbose` as restic prints
+	/// them, with `options.verbose` set. Once an entry was passed on a failed backup isn't
+	/// retried, so no entry is seen twice.
+	pub fn restic_backup_streaming<F: FnMut(&VerboseStatusJson)>(
+		&self,
+		backup_targets: &BackupTarget,
+		options: &DetailedBackupOptions,
+		mut on_verbose: F,
+	) -> Result<DetailedBackupResult> {
+		let _span = operation_span!(self, "backup");
+		let _guard = self.repo_guard(true)?;
+		let folder_warnings = self.check_target_folders(backup_targets)?;
+		self.check_free_space(backup_targets)?;
+		let not_included = self.not_included_list(backup_targets)?;
+		let ignore_patterns = backup_targets.ignore_file_excludes();
+		let mut cmd = self.backup_cmd(backup_targets, not_included.as_ref().map(NotIncludedList::as_args), &ignore_patterns)?;
+		if options.verbose || options.collect_verbose {
+			cmd.arg("--verbose");
+		}
+
+		let mut retry = RetryState::default();
+		let collected = loop {
+			let started = Instant::now();
+			let mut collected = Collected {
+				warnings: folder_warnings.clone(),
+				..Collected::default()
+			};
+			let mut tail = OutputTail::default();
+			let streamed = self.stream_stdout("backup", &mut cmd, running_command::lines_in_background, &mut |line| {
+				let line = String::from_utf8_lossy(line);
+				let line = line.trim_end_matches(['\n', '\r']);
+				tail.push(line);
+				collected.stdout_line(line, started.elapsed(), options, &mut on_verbose)
+			});
+			let attempt = streamed.and_then(|(status, stderr)| {
+				for line in String::from_utf8_lossy(&stderr).lines() {
+					collected.stderr_line(line);
+				}
+				classify::check_output(
+					"backup",
+					Output {
+						status,
+						stdout: tail.into_bytes(),
+						stderr,
+					},
+				)
+			});
+			match attempt {
+				Ok(_) => {
+					self.succeeded(&retry);
+					break collected;
+				}
+				Err(err) if matches!(err.kind(), ErrorKind::PartialBackup(_)) && collected.summary.is_some() => {
+					self.succeeded(&retry);
+					break collected;
+				}
+				Err(err) if collected.verbose_delivered => return Err(err),
+				Err(err) => self.recover(err, &mut retry, &mut cmd)?,
+			}
+		};
+
+		let summary = collected.summary.ok_or(ErrorKind::NoOutputFromRestic)?;
+		trace::record_backup(&summary);
+		Ok(DetailedBackupResult {
+			summary,
+			samples: collected.samples,
+			errors: collected.errors,
+			warnings: collected.warnings,
+			verbose: collected.verbose,
+		})
+	}
+
+	/// Start a backup of the target and return right away, for callers that read restic's
+	/// output themselves and may stop it with `RunningCommand::abort`.
+	///
+	/// The `backup --json` lines are on the stdout pipe and restic's messages on the stderr
+	/// pipe, both have to be read. The repository is guarded like for `restic_backup` until
+	/// restic exited. `timeout`, retries and the observer don't apply, and the exit status is
+	/// left to the caller.
+	pub fn restic_backup_spawn(&self, backup_targets: &BackupTarget) -> Result<RunningCommand> {
+		let _span = operation_span!(self, "backup");
+		let guard = self.repo_guard(true)?;
+		self.check_target_folders(backup_targets)?;
+		self.check_free_space(backup_targets)?;
+		let not_included = self.not_included_list(backup_targets)?;
+		let ignore_patterns = backup_targets.ignore_file_excludes();
+		let mut cmd = self.backup_cmd(backup_targets, not_included.as_ref().map(NotIncludedList::as_args), &ignore_patterns)?;
+		self.prepare()?;
+
+		let mut running = RunningCommand::spawn_captured(&mut cmd).map_err(|err| self.spawn_error(err))?;
+		running.hold(guard);
+		running.hold(not_included);
+		Ok(running)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::restic_outputs::VerboseAction;
+
+	#[test]
+	fn downsamples_status_lines() {
+		let status = r#"{"message_type":"status","percent_done":0.5,"total_files":10,"files_done":5,"total_bytes":100,"bytes_done":50}"#;
+		let options = DetailedBackupOptions {
+			status_every: 4,
+			..Default::default()
+		};
+		let mut collected = Collected::default();
+		for second in 0..10 {
+			collected.stdout_line(status, Duration::from_secs(second), &options, &mut |_| ()).unwrap();
+		}
+		collected
+			.stdout_line(
+				r#"{"message_type":"error","error":{"message":"permission denied"},"during":"archival","item":"/root"}"#,
+				Duration::from_secs(10),
+				&options,
+				&mut |_| (),
+			)
+			.unwrap();
+		collected.stderr_line("Warning: at least one source file could not be read");
+
+		let elapsed: Vec<_> = collected.samples.iter().map(|sample| sample.elapsed.as_secs()).collect();
+		assert_eq!(elapsed, vec![0, 4, 8]);
+		assert_eq!(collected.samples[0].status.files_done, 5);
+		assert_eq!(collected.errors[0].item, "/root");
+		assert_eq!(collected.warnings.len(), 1);
+	}
+
+	#[test]
+	fn verbose_entries_stream_and_collect_on_request() {
+		let lines = [
+			r#"{"message_type":"verbose_status","action":"new","item":"/data/summary","duration":0.01,"data_size":1402,"metadata_size":0,"total_files":0}"#,
+			r#"{"message_type":"verbose_status","action":"unchanged","item":"/data/\"summary\"","duration":0,"data_size":512,"metadata_size":0,"total_files":0}"#,
+			r#"{"message_type":"verbose_status","action":"scan_finished","duration":0.2,"data_size":1914,"metadata_size":0,"total_files":2}"#,
+			r#"{"message_type":"summary","files_new":1,"files_changed":0,"files_unmodified":1,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":0,"data_blobs":1,"tree_blobs":1,"data_added":1402,"total_files_processed":2,"total_bytes_processed":1914,"total_duration":0.3,"snapshot_id":"1234abcd"}"#,
+		];
+		for collect in [false, true].iter() {
+			let options = DetailedBackupOptions {
+				verbose: true,
+				collect_verbose: *collect,
+				..Default::default()
+			};
+			let mut collected = Collected::default();
+			let mut items = Vec::new();
+			for line in lines.iter() {
+				collected
+					.stdout_line(line, Duration::from_secs(0), &options, &mut |entry| items.push(entry.item.clone()))
+					.unwrap();
+			}
+			assert_eq!(items, vec!["/data/summary", "/data/\"summary\"", ""]);
+			assert_eq!(collected.verbose.len(), if *collect { 3 } else { 0 });
+			assert!(matches!(collected.summary, Some(BackupJson::Summary { files_new: 1, .. })));
+		}
+
+		let entry: BackupJson = serde_json::from_str(lines[0]).unwrap();
+		assert!(matches!(entry, BackupJson::VerboseStatus(VerboseStatusJson { action: VerboseAction::New, .. })));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn spawned_backup_holds_the_repository_until_aborted() {
+		use std::io::BufRead;
+
+		let dir = crate::test_util::test_dir("backup-spawn");
+		let script = "#!/bin/sh
+echo '{\"message_type\":\"status\",\"percent_done\":0,\"total_files\":1,\"total_bytes\":1}'
+exec sleep 60
+";
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, script));
+		let target = BackupTarget {
+			folders: vec![dir.clone()],
+			..Default::default()
+		};
+
+		let mut running = config.restic_backup_spawn(&target).unwrap();
+		let mut line = String::new();
+		std::io::BufReader::new(running.take_stdout().unwrap()).read_line(&mut line).unwrap();
+		assert!(line.contains("\"status\""), "{}", line);
+		let err = config.try_restic_backup(&target, None).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::OperationInProgress));
+
+		assert!(!running.abort().unwrap().success());
+		assert!(config.repo_guard(false).is_ok());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn streamed_backup_checks_free_space_first() {
+		let dir = crate::test_util::test_dir("backup-detailed-space");
+		let script = format!("#!/bin/sh\ntouch '{}'\n", dir.join("started").display());
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, &script));
+		config.free_space_check = Some(crate::FreeSpaceCheck {
+			margin: 0,
+			expected_size: Some(u64::MAX),
+		});
+		let target = BackupTarget {
+			folders: vec![dir.clone()],
+			..Default::default()
+		};
+
+		let err = config.restic_backup_detailed(&target, &DetailedBackupOptions::default()).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InsufficientSpace { needed: u64::MAX, .. }), "{}", err);
+		assert!(!dir.join("started").exists());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
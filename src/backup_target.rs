@@ -1,35 +1,404 @@
 use serde::{Deserialize, Serialize, Serializer, Deserializer};
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::borrow::Cow;
 use std::ops::Deref;
-use std::path::{Path, PathBuf};
-use serde::de::Visitor;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use serde::de::{MapAccess, Visitor};
+use serde::ser::SerializeStruct;
 use std::fmt;
+use rayon::prelude::*;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use crate::ignore_files::{restic_patterns, IgnoreStack};
+use crate::restic_outputs::SnapshotsJson;
+use crate::tag::{validate_tags, TagError};
 
+/// An exclusion pattern as restic understands it.
+///
+/// Patterns are normalized the way restic matches them: a trailing `/` is removed, and a pattern
+/// that doesn't start with `/` can match at any depth, so it is compiled with a `**/` prefix
+/// unless it already has one.
+/// `target` therefore matches `/home/me/project/target`, while `/target` only matches at the
+/// root. The pattern as given is kept, `Display` and serialization show it instead of the
+/// compiled glob, so a pattern survives a serde round trip unchanged.
+///
+/// Matching follows restic's `--exclude`, the pattern is passed to restic as it is compiled,
+/// with the separators it was written with:
+///
+/// | pattern          | matches                                             | like restic |
+/// |------------------|-----------------------------------------------------|-------------|
+/// | `*`, `?`, `[a-c]`| within one path component, never across `/`         | yes         |
+/// | `**`             | any number of components, including none            | yes         |
+/// | `name`, `a/b`    | at any depth, `a/b` has to be whole components      | yes         |
+/// | `/a/b`           | only starting at the root                           | yes         |
+/// | `dir/`           | the trailing `/` is dropped, so files match as well | yes         |
+/// | `{a,b}`          | alternatives locally, literal braces in restic      | no          |
+/// | `[!a]`, `[^a]`   | negated class; restic only knows `[^a]`             | `[^a]` only |
+///
+/// A match on a directory excludes everything below it, locally and in restic.
+///
+/// Case insensitive patterns are passed to restic with `--iexclude` and serialized as
+/// `{ "pattern": ..., "case_insensitive": true }`, case sensitive ones stay plain strings.
+///
+/// Anchored patterns, written as `./build` or built with `MyGlob::anchored`, only match
+/// directly below each folder of the target instead of at any depth. restic gets one absolute
+/// pattern per folder, e.g. `--exclude /home/me/build`. `MyGlob::anchored` patterns without the
+/// `./` are serialized with `"anchored": true`.
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct MyGlob(Glob);
+pub struct MyGlob {
+	pattern: String,
+	case_insensitive: bool,
+	anchored: bool,
+	/// For anchored patterns the part below the folders, see `compile_for`
+	glob: Glob,
+	/// `glob` with the separators of the pattern, see `restic_patterns`
+	restic: String,
+}
+
+impl MyGlob {
+	/// Compile a pattern, applying the normalization described on the type
+	pub fn new(pattern: &str) -> std::result::Result<MyGlob, globset::Error> {
+		MyGlob::build(pattern, false, false)
+	}
+
+	/// Compile a pattern that matches regardless of case, like restic's `--iexclude`
+	pub fn case_insensitive(pattern: &str) -> std::result::Result<MyGlob, globset::Error> {
+		MyGlob::build(pattern, true, false)
+	}
+
+	/// Compile a pattern that only matches relative to the folders of the target, like
+	/// `./pattern`. Absolute patterns are unaffected.
+	pub fn anchored(pattern: &str) -> std::result::Result<MyGlob, globset::Error> {
+		MyGlob::build(pattern, false, true)
+	}
+
+	fn build(pattern: &str, case_insensitive: bool, anchored: bool) -> std::result::Result<MyGlob, globset::Error> {
+		let normalized = normalize_pattern(pattern, anchored);
+		Ok(MyGlob {
+			pattern: pattern.to_owned(),
+			case_insensitive,
+			anchored: normalized.anchored,
+			glob: compile_glob(&normalized.glob, case_insensitive)?,
+			restic: normalized.restic,
+		})
+	}
+
+	/// The pattern as given by the user
+	pub fn pattern(&self) -> &str {
+		&self.pattern
+	}
+
+	pub fn is_case_insensitive(&self) -> bool {
+		self.case_insensitive
+	}
+
+	/// Whether the pattern only matches relative to the target's folders
+	pub fn is_anchored(&self) -> bool {
+		self.anchored
+	}
+
+	/// The globs this pattern matches with for a target with `folders`: the glob itself, or
+	/// for anchored patterns one per folder
+	pub(crate) fn compile_for(&self, folders: &[PathBuf]) -> std::result::Result<Vec<Glob>, globset::Error> {
+		if !self.anchored {
+			return Ok(vec![self.glob.clone()]);
+		}
+		folders
+			.iter()
+			.map(|folder| compile_glob(&anchor(&escape_glob(&folder_prefix(folder)), self.glob.glob()), self.case_insensitive))
+			.collect()
+	}
+
+	/// The patterns restic gets for a target with `folders`, see `compile_for`. Unlike the
+	/// globs they keep native separators, so `C:\Temp` reaches restic as written on windows.
+	pub(crate) fn restic_patterns(&self, folders: &[PathBuf]) -> Vec<String> {
+		if !self.anchored {
+			return vec![self.restic.clone()];
+		}
+		folders
+			.iter()
+			.map(|folder| {
+				format!("{}{}{}", escape_restic_glob(&restic_folder_prefix(folder)), MAIN_SEPARATOR, self.restic)
+			})
+			.collect()
+	}
+
+	/// Whether serialization needs the map form
+	fn needs_map(&self) -> bool {
+		self.case_insensitive || (self.anchored && !is_anchored_pattern(&self.pattern))
+	}
+
+	/// The restic flag this exclusion is passed with
+	pub(crate) fn restic_flag(&self) -> &'static str {
+		if self.case_insensitive {
+			"--iexclude"
+		} else {
+			"--exclude"
+		}
+	}
+}
+
+fn compile_glob(glob: &str, case_insensitive: bool) -> std::result::Result<Glob, globset::Error> {
+	GlobBuilder::new(glob)
+		.case_insensitive(case_insensitive)
+		// restic matches path components, a single `*` never crosses a separator
+		.literal_separator(true)
+		.build()
+}
+
+struct NormalizedPattern {
+	glob: String,
+	/// `glob` before the separators were made `/`, what restic gets
+	restic: String,
+	/// Relative to the folders, `glob` is the part below them
+	anchored: bool,
+}
+
+/// The only place patterns are normalized, every way of building a `MyGlob` goes through here
+fn normalize_pattern(pattern: &str, anchored: bool) -> NormalizedPattern {
+	let pattern = pattern.trim_end_matches(is_pattern_separator);
+	let (restic, anchored) = if is_absolute_pattern(&glob_separators(pattern)) {
+		(pattern.to_owned(), false)
+	} else if let Some(relative) = strip_dir_prefix(pattern, ".") {
+		(relative.to_owned(), true)
+	} else if anchored {
+		(pattern.to_owned(), true)
+	} else {
+		// Configs written by older versions can contain the prefix several times
+		let mut relative = pattern;
+		while let Some(rest) = strip_dir_prefix(relative, "**") {
+			relative = rest;
+		}
+		("**/".to_owned() + relative, false)
+	};
+	NormalizedPattern {
+		// Matching is done on `/` separated paths, see `glob_path`
+		glob: glob_separators(&restic).into_owned(),
+		restic,
+		anchored,
+	}
+}
+
+/// `/`, and on windows `\` as well
+fn is_pattern_separator(c: char) -> bool {
+	c == '/' || (cfg!(windows) && c == '\\')
+}
+
+/// `pattern` without a leading `dir` component
+fn strip_dir_prefix<'a>(pattern: &'a str, dir: &str) -> Option<&'a str> {
+	pattern.strip_prefix(dir)?.strip_prefix(is_pattern_separator)
+}
+
+#[cfg(not(windows))]
+fn glob_separators(pattern: &str) -> Cow<'_, str> {
+	Cow::Borrowed(pattern)
+}
+
+#[cfg(windows)]
+fn glob_separators(pattern: &str) -> Cow<'_, str> {
+	Cow::Owned(pattern.replace('\\', "/"))
+}
+
+fn is_anchored_pattern(pattern: &str) -> bool {
+	strip_dir_prefix(pattern, ".").is_some()
+}
+
+/// A folder as the start of an anchored pattern, without a trailing `/` so the root works too
+fn folder_prefix(folder: &Path) -> String {
+	glob_path(folder).to_string_lossy().trim_end_matches('/').to_owned()
+}
+
+/// A folder as the start of an anchored restic pattern, with its own separators
+fn restic_folder_prefix(folder: &Path) -> String {
+	#[cfg(windows)]
+	let folder = strip_verbatim(folder);
+	folder.to_string_lossy().trim_end_matches(is_pattern_separator).to_owned()
+}
+
+fn anchor(prefix: &str, relative: &str) -> String {
+	format!("{}/{}", prefix, relative)
+}
+
+/// Make a path match itself literally when used in a glob
+fn escape_glob(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'?' | '*' | '[' | ']' | '{' | '}' => {
+				escaped.push('[');
+				escaped.push(c);
+				escaped.push(']');
+			}
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// `escape_glob` for patterns passed to restic, which matches with Go's `filepath.Match`.
+/// That takes `\` as escape character, except on windows where it is the separator and a class
+/// like `[*]` is used instead.
+pub(crate) fn escape_restic_glob(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			#[cfg(not(windows))]
+			'?' | '*' | '[' | ']' | '{' | '}' | '\\' => {
+				escaped.push('\\');
+				escaped.push(c);
+			}
+			#[cfg(windows)]
+			'?' | '*' | '[' => {
+				escaped.push('[');
+				escaped.push(c);
+				escaped.push(']');
+			}
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+#[cfg(not(windows))]
+fn is_absolute_pattern(pattern: &str) -> bool {
+	pattern.starts_with('/')
+}
+
+/// `/...` or a drive like `C:/...`
+#[cfg(windows)]
+fn is_absolute_pattern(pattern: &str) -> bool {
+	let bytes = pattern.as_bytes();
+	pattern.starts_with('/') || (bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == b":/")
+}
+
+/// The form of a path the globs are matched against. On windows the extended length prefix
+/// `canonicalize` adds is removed and separators become `/`, like in the normalized patterns.
+#[cfg(not(windows))]
+fn glob_path(path: &Path) -> Cow<'_, Path> {
+	Cow::Borrowed(path)
+}
+
+#[cfg(windows)]
+fn glob_path(path: &Path) -> Cow<'_, Path> {
+	let path = strip_verbatim(path);
+	Cow::Owned(PathBuf::from(path.to_string_lossy().replace('\\', "/")))
+}
+
+/// `\\?\C:\dir` to `C:\dir` and `\\?\UNC\server\share` to `\\server\share`
+#[cfg(windows)]
+fn strip_verbatim(path: &Path) -> Cow<'_, Path> {
+	let text = match path.to_str() {
+		Some(text) => text,
+		None => return Cow::Borrowed(path),
+	};
+	if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+		return Cow::Owned(PathBuf::from(format!(r"\\{}", rest)));
+	}
+	match text.strip_prefix(r"\\?\") {
+		Some(rest) if rest.as_bytes().get(1) == Some(&b':') => Cow::Owned(PathBuf::from(rest)),
+		_ => Cow::Borrowed(path),
+	}
+}
+
+/// `Path::canonicalize`, without the extended length prefix on windows. restic accepts those
+/// paths, but users don't expect them in the folders of a target.
+pub(crate) fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+	let path = path.canonicalize()?;
+	#[cfg(windows)]
+	let path = strip_verbatim(&path).into_owned();
+	Ok(path)
+}
 
 impl Deref for MyGlob {
 	type Target = Glob;
 
 	fn deref(&self) -> &Glob {
-		&self.0
+		&self.glob
+	}
+}
+
+impl std::str::FromStr for MyGlob {
+	type Err = globset::Error;
+
+	fn from_str(pattern: &str) -> std::result::Result<MyGlob, globset::Error> {
+		MyGlob::new(pattern)
+	}
+}
+
+impl fmt::Display for MyGlob {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.pattern)
+	}
+}
+
+/// The glob's pattern is taken as the user pattern and normalized like `MyGlob::new`,
+/// otherwise it would match differently after a serde round trip
+impl From<Glob> for MyGlob {
+	fn from(glob: Glob) -> MyGlob {
+		MyGlob::new(glob.glob()).unwrap_or_else(|_| MyGlob {
+			pattern: glob.glob().to_owned(),
+			case_insensitive: false,
+			anchored: false,
+			restic: glob.glob().to_owned(),
+			glob,
+		})
 	}
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BackupFileSelectionType {
-	Irreverent,
+	/// Neither in nor above any folder of the target
+	Irrelevant,
+	/// Not backed up itself, but contains a folder of the target
 	Contains,
 	Included,
 	Excluded,
 }
 
+impl BackupFileSelectionType {
+	#[deprecated(note = "misspelling, use BackupFileSelectionType::Irrelevant")]
+	#[allow(non_upper_case_globals)]
+	pub const Irreverent: BackupFileSelectionType = BackupFileSelectionType::Irrelevant;
+}
+
+/// Why a path was excluded
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExcludedBy {
+	/// The exclusion at this index of `BackupTarget::exclusions`
+	Pattern { index: usize, pattern: String },
+	/// A pattern from one of the target's ignore files
+	IgnoreFile,
+	/// A pattern from this file of `BackupTarget::exclude_files`
+	ExcludeFile { file: PathBuf, pattern: String },
+	/// The target has inclusions and none of them matched
+	NotIncluded,
+}
+
+/// Result of `BackupTarget::check_path_is_in_backup`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PathSelection {
+	pub selection: BackupFileSelectionType,
+	/// The target folder the path is in, or for `Contains` the folder inside the path
+	pub folder: Option<PathBuf>,
+	/// Set for `Excluded`
+	pub excluded_by: Option<ExcludedBy>,
+}
+
 impl Serialize for MyGlob {
 	fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
 		where
 			S: Serializer,
 	{
-		serializer.serialize_str(&self.glob()[3..])
+		if !self.needs_map() {
+			return serializer.serialize_str(&self.pattern);
+		}
+		let mut state = serializer.serialize_struct("MyGlob", 2 + self.anchored as usize)?;
+		state.serialize_field("pattern", &self.pattern)?;
+		state.serialize_field("case_insensitive", &self.case_insensitive)?;
+		if self.anchored {
+			state.serialize_field("anchored", &self.anchored)?;
+		}
+		state.end()
 	}
 }
 
@@ -39,20 +408,36 @@ impl<'de> Visitor<'de> for MyGlobVisitor {
 	type Value = MyGlob;
 
 	fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-		formatter.write_str("A Unix shell Glob")
+		formatter.write_str("A Unix shell Glob, or a map with pattern, case_insensitive and anchored")
 	}
 
 	fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
 		where
 			E: serde::de::Error,
 	{
-		let modded = if value.starts_with("/") {
-			value.trim_end_matches("/").to_owned()
-		} else {
-			"**/".to_owned() + value.trim_end_matches("/")
-		};
+		MyGlob::new(value).map_err(|_| E::custom("String not glob"))
+	}
 
-		Ok(MyGlob(Glob::new(&modded).map_err(|_| E::custom(format!("String not glob")))?))
+	fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+		where
+			A: MapAccess<'de>,
+	{
+		let mut pattern: Option<String> = None;
+		let mut case_insensitive = false;
+		let mut anchored = false;
+		while let Some(key) = map.next_key::<String>()? {
+			match key.as_str() {
+				"pattern" => pattern = Some(map.next_value()?),
+				"case_insensitive" => case_insensitive = map.next_value()?,
+				"anchored" => anchored = map.next_value()?,
+				_ => {
+					map.next_value::<serde::de::IgnoredAny>()?;
+				}
+			}
+		}
+		let pattern = pattern.ok_or_else(|| serde::de::Error::missing_field("pattern"))?;
+		MyGlob::build(&pattern, case_insensitive, anchored)
+			.map_err(|_| serde::de::Error::custom("String not glob"))
 	}
 }
 
@@ -61,140 +446,1970 @@ impl<'de> Deserialize<'de> for MyGlob {
 		where
 			D: Deserializer<'de>,
 	{
-		deserializer.deserialize_str(MyGlobVisitor)
+		deserializer.deserialize_any(MyGlobVisitor)
 	}
 }
 
+/// How the folders of a `BackupTarget` are stored
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub enum PathMode {
+	/// Canonicalized when added, which fails for folders that don't exist
+	#[default]
+	Canonical,
+	/// Kept as given, e.g. relative paths in a config shared between machines or the mount
+	/// point of a drive that isn't plugged in. They are resolved when the target is first used
+	/// and again whenever a backup starts or `BackupTarget::refresh_folders` is called, folders
+	/// that can't be resolved then are skipped with a warning.
+	AsGiven,
+}
+
+/// What a backup does when one of the target's `exclude_files` doesn't exist
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub enum MissingExcludeFile {
+	/// Fail before restic runs, restic itself would fail as well
+	#[default]
+	Fail,
+	/// Leave the file out and report a warning
+	Warn,
+}
+
+/// A folder of an `AsGiven` target that couldn't be resolved when the target was used
+#[derive(Debug)]
+pub struct UnresolvedFolder {
+	pub folder: PathBuf,
+	pub error: BackupTargetError,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Default)]
 pub struct BackupTarget {
+	/// Canonical paths, or for `PathMode::AsGiven` the paths as given, see `resolve_folders`
 	pub folders: Vec<PathBuf>,
+	#[serde(default)]
+	pub path_mode: PathMode,
 	pub exclusions: Vec<MyGlob>,
+	/// When not empty only files matching one of these, or inside a directory matching one, are
+	/// backed up. restic has no include option, so the local walk finds what isn't selected and
+	/// restic gets that as an exclude file. The folders stay the paths of the snapshot.
+	#[serde(default)]
+	pub inclusions: Vec<MyGlob>,
 	pub tags: Vec<String>,
+	/// Skip files larger than this many bytes, restic's `--exclude-larger-than`
+	#[serde(default)]
+	pub exclude_larger_than: Option<u64>,
+	/// Skip directories marked with a `CACHEDIR.TAG`, restic's `--exclude-caches`
+	#[serde(default)]
+	pub exclude_caches: bool,
+	/// Names of `.gitignore` style files, e.g. `.backupignore`, whose patterns apply to the
+	/// directory they are in. restic doesn't read them, their patterns are passed as excludes.
+	#[serde(default)]
+	pub ignore_file_names: Vec<String>,
+	/// Exclude files passed to restic as `--exclude-file`, e.g. a hand-maintained list per
+	/// machine. The local walk and `check_path_is_in_backup` read their patterns too.
+	#[serde(default)]
+	pub exclude_files: Vec<PathBuf>,
+	/// What a backup does when one of `exclude_files` doesn't exist
+	#[serde(default)]
+	pub missing_exclude_file: MissingExcludeFile,
+	#[serde(skip)]
+	pub(crate) exclusion_cache: GlobSetCache,
+	#[serde(skip)]
+	pub(crate) inclusion_cache: GlobSetCache,
+	#[serde(skip)]
+	pub(crate) exclude_file_cache: ExcludeFileCache,
+	#[serde(skip)]
+	pub(crate) folder_cache: FolderCache,
+}
+
+/// Globs compiled into one set, anchored ones once per folder
+pub(crate) struct CompiledGlobs {
+	set: Arc<GlobSet>,
+	/// For each glob of `set`, the index of the `MyGlob` it was compiled from
+	owners: Vec<usize>,
+}
+
+/// Globs and folders together with what was compiled from them
+type CompiledFor = (Vec<MyGlob>, Vec<PathBuf>, Arc<CompiledGlobs>);
+
+/// The compiled exclusions together with the exclusions and folders they were compiled from, so
+/// changes to the public fields are noticed without every mutation having to go through a method
+#[derive(Default)]
+pub(crate) struct GlobSetCache(RwLock<Option<CompiledFor>>);
+
+impl GlobSetCache {
+	/// Compile `exclusions` followed by the globs of `file_patterns`, the owner indices count
+	/// through both. The cache is checked without copying either.
+	fn get_or_build(
+		&self,
+		exclusions: &[MyGlob],
+		file_patterns: &[(PathBuf, MyGlob)],
+		folders: &[PathBuf],
+	) -> std::result::Result<Arc<CompiledGlobs>, globset::Error> {
+		let globs = || exclusions.iter().chain(file_patterns.iter().map(|(_, glob)| glob));
+		if let Some((cached_for, cached_folders, compiled)) = &*self.0.read().unwrap_or_else(|err| err.into_inner()) {
+			if cached_for.iter().eq(globs()) && cached_folders.as_slice() == folders {
+				return Ok(compiled.clone());
+			}
+		}
+
+		let mut builder = GlobSetBuilder::new();
+		let mut owners = Vec::with_capacity(exclusions.len() + file_patterns.len());
+		for (index, exclusion) in globs().enumerate() {
+			for glob in exclusion.compile_for(folders)? {
+				builder.add(glob);
+				owners.push(index);
+			}
+		}
+		let compiled = Arc::new(CompiledGlobs {
+			set: Arc::new(builder.build()?),
+			owners,
+		});
+		*self.0.write().unwrap_or_else(|err| err.into_inner()) =
+			Some((globs().cloned().collect(), folders.to_vec(), compiled.clone()));
+		Ok(compiled)
+	}
+
+	fn invalidate(&self) {
+		*self.0.write().unwrap_or_else(|err| err.into_inner()) = None;
+	}
+}
+
+/// Clones start with an empty cache
+impl Clone for GlobSetCache {
+	fn clone(&self) -> Self {
+		GlobSetCache::default()
+	}
+}
+
+/// The cache is derived data and never makes two targets different
+impl PartialEq for GlobSetCache {
+	fn eq(&self, _: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for GlobSetCache {}
+
+impl fmt::Debug for GlobSetCache {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("GlobSetCache")
+	}
+}
+
+/// The patterns of exclude files, each with the file it is from
+type ExcludePatterns = Arc<Vec<(PathBuf, MyGlob)>>;
+
+/// The patterns of the exclude files with the file they are from, together with the files they
+/// were read from. They are read the first time the local walk or `check_path_is_in_backup` needs
+/// them, not once per path, and again when a backup starts or `exclude_files` changed.
+#[derive(Default)]
+pub(crate) struct ExcludeFileCache(RwLock<Option<(Vec<PathBuf>, ExcludePatterns)>>);
+
+impl ExcludeFileCache {
+	/// Files that can't be read are left out, backups check for them separately
+	fn get_or_read(&self, files: &[PathBuf]) -> ExcludePatterns {
+		if let Some((cached_for, patterns)) = &*self.0.read().unwrap_or_else(|err| err.into_inner()) {
+			if cached_for.as_slice() == files {
+				return patterns.clone();
+			}
+		}
+
+		let mut patterns = Vec::new();
+		for file in files {
+			let lines = match read_exclude_file(file) {
+				Ok(lines) => lines,
+				Err(err) => {
+					log::debug!("Failed to read exclude file {}: {}", file.display(), err);
+					continue;
+				}
+			};
+			for line in lines {
+				match MyGlob::new(&line) {
+					Ok(glob) => patterns.push((file.clone(), glob)),
+					Err(err) => log::warn!("Skipped pattern {:?} of {}: {}", line, file.display(), err),
+				}
+			}
+		}
+		let patterns = Arc::new(patterns);
+		*self.0.write().unwrap_or_else(|err| err.into_inner()) = Some((files.to_vec(), patterns.clone()));
+		patterns
+	}
+
+	pub(crate) fn invalidate(&self) {
+		*self.0.write().unwrap_or_else(|err| err.into_inner()) = None;
+	}
+}
+
+/// Clones start with an empty cache
+impl Clone for ExcludeFileCache {
+	fn clone(&self) -> Self {
+		ExcludeFileCache::default()
+	}
+}
+
+/// The cache is derived data and never makes two targets different
+impl PartialEq for ExcludeFileCache {
+	fn eq(&self, _: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for ExcludeFileCache {}
+
+impl fmt::Debug for ExcludeFileCache {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ExcludeFileCache")
+	}
+}
+
+/// The resolved folders together with the folders and mode they were resolved from, so the
+/// per path lookups don't canonicalize every folder again. Resolved again when a backup starts
+/// or the folders changed.
+#[derive(Default)]
+pub(crate) struct FolderCache(RwLock<Option<(Vec<PathBuf>, PathMode, Arc<Vec<PathBuf>>)>>);
+
+impl FolderCache {
+	fn get_or_resolve(&self, target: &BackupTarget) -> Arc<Vec<PathBuf>> {
+		if let Some((cached_for, mode, resolved)) = &*self.0.read().unwrap_or_else(|err| err.into_inner()) {
+			if cached_for == &target.folders && *mode == target.path_mode {
+				return resolved.clone();
+			}
+		}
+
+		let resolved = Arc::new(target.resolve_folders().0);
+		*self.0.write().unwrap_or_else(|err| err.into_inner()) =
+			Some((target.folders.clone(), target.path_mode, resolved.clone()));
+		resolved
+	}
+
+	pub(crate) fn invalidate(&self) {
+		*self.0.write().unwrap_or_else(|err| err.into_inner()) = None;
+	}
+}
+
+/// Clones start with an empty cache
+impl Clone for FolderCache {
+	fn clone(&self) -> Self {
+		FolderCache::default()
+	}
+}
+
+/// The cache is derived data and never makes two targets different
+impl PartialEq for FolderCache {
+	fn eq(&self, _: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for FolderCache {}
+
+impl fmt::Debug for FolderCache {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("FolderCache")
+	}
+}
+
+/// Why a `BackupTarget` couldn't be built or queried
+#[derive(Debug, Error)]
+pub enum BackupTargetError {
+	#[error("Failed to resolve {}: {source}", .path.display())]
+	Canonicalize { path: PathBuf, source: std::io::Error },
+	#[error("{} is relative and can't be resolved", .0.display())]
+	RelativePath(PathBuf),
+	#[error("Failed to read {}: {source}", .path.display())]
+	Read { path: PathBuf, source: std::io::Error },
+	#[error("Invalid tag: {0}")]
+	InvalidTag(#[from] TagError),
+	#[error("Invalid exclusion pattern: {0}")]
+	Glob(#[from] globset::Error),
+	#[error("Failed to add {} to the file tree: {reason}", .path.display())]
+	Store { path: PathBuf, reason: String },
+}
+
+/// The patterns of an exclude file the way restic reads them: each line trimmed, blank lines and
+/// lines starting with `#` skipped, and environment variables expanded, see `expand_env`
+pub(crate) fn read_exclude_file(path: &Path) -> std::io::Result<Vec<String>> {
+	Ok(std::fs::read_to_string(path)?
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(|line| expand_env(line, |name| std::env::var(name).ok()))
+		.collect())
+}
+
+/// Expand `$NAME` and `${NAME}` like restic does in exclude files, unset variables are empty and
+/// `$$` is a literal `$`. The preview looks them up in this process's environment, restic in the
+/// one it runs with, so variables only set for restic (`ResticConfig::extra_env`) aren't seen here.
+fn expand_env<F: Fn(&str) -> Option<String>>(line: &str, lookup: F) -> String {
+	let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+	let mut expanded = String::with_capacity(line.len());
+	let mut rest = line;
+	while let Some(dollar) = rest.find('$') {
+		expanded.push_str(&rest[..dollar]);
+		let after = &rest[dollar + 1..];
+		let (name, consumed) = if let Some(after_dollar) = after.strip_prefix('$') {
+			expanded.push('$');
+			rest = after_dollar;
+			continue;
+		} else if let Some(braced) = after.strip_prefix('{') {
+			match braced.find('}') {
+				Some(end) => (&braced[..end], end + 2),
+				None => ("", 0),
+			}
+		} else {
+			let end = after.find(|c: char| !is_name_char(c)).unwrap_or(after.len());
+			(&after[..end], end)
+		};
+		if name.is_empty() {
+			expanded.push('$');
+		} else {
+			expanded.push_str(&lookup(name).unwrap_or_default());
+		}
+		rest = &after[consumed..];
+	}
+	expanded.push_str(rest);
+	expanded
+}
+
+/// Canonicalize a folder, with `allow_missing` a folder that doesn't exist (yet) is kept as
+/// given as long as it is absolute
+fn resolve_folder(path: &Path, allow_missing: bool) -> std::result::Result<PathBuf, BackupTargetError> {
+	match canonicalize(path) {
+		Ok(path) => Ok(path),
+		Err(err) if allow_missing && err.kind() == std::io::ErrorKind::NotFound => {
+			if path.is_absolute() {
+				Ok(path.to_owned())
+			} else {
+				Err(BackupTargetError::RelativePath(path.to_owned()))
+			}
+		}
+		Err(source) => Err(BackupTargetError::Canonicalize {
+			path: path.to_owned(),
+			source,
+		}),
+	}
+}
+
+/// Canonicalize the parent and re-append the file name, so a final symlink isn't followed and
+/// a file that doesn't exist can still be placed
+fn resolve_no_follow(path: &Path) -> std::result::Result<PathBuf, BackupTargetError> {
+	match (path.parent(), path.file_name()) {
+		(Some(parent), Some(name)) => {
+			let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+			Ok(resolve_folder(parent, false)?.join(name))
+		}
+		// `/`, or ends in `..`
+		_ => resolve_folder(path, false),
+	}
 }
 
 impl BackupTarget {
-	pub fn new<P: AsRef<Path>>(folders: &[P], exclusions: Vec<Glob>, tags: Vec<String>) -> Self {
-		Self {
+	/// Folders are canonicalized, which fails if they don't exist
+	pub fn new<P: AsRef<Path>>(
+		folders: &[P],
+		exclusions: Vec<Glob>,
+		tags: Vec<String>,
+	) -> std::result::Result<Self, BackupTargetError> {
+		Self::from_parts(folders, exclusions.into_iter().map(MyGlob::from).collect(), tags, PathMode::Canonical)
+	}
+
+	/// Like `new`, with `PathMode::AsGiven` the folders are kept as they are and don't have to
+	/// exist yet
+	pub fn new_with_mode<P: AsRef<Path>>(
+		folders: &[P],
+		exclusions: Vec<Glob>,
+		tags: Vec<String>,
+		path_mode: PathMode,
+	) -> std::result::Result<Self, BackupTargetError> {
+		Self::from_parts(folders, exclusions.into_iter().map(MyGlob::from).collect(), tags, path_mode)
+	}
+
+	fn from_parts<P: AsRef<Path>>(
+		folders: &[P],
+		exclusions: Vec<MyGlob>,
+		tags: Vec<String>,
+		path_mode: PathMode,
+	) -> std::result::Result<Self, BackupTargetError> {
+		validate_tags(&tags)?;
+		let mut target = Self {
 			folders: folders
 				.iter()
-				.map(|c| {
-					c.as_ref()
-						.canonicalize()
-						.expect("Failed to canonicalize path, path does not exist most likely")
+				.map(|c| match path_mode {
+					PathMode::Canonical => resolve_folder(c.as_ref(), false),
+					PathMode::AsGiven => Ok(c.as_ref().to_owned()),
 				})
-				.collect(),
+				.collect::<std::result::Result<Vec<PathBuf>, BackupTargetError>>()?,
+			path_mode,
 			tags,
-			exclusions: exclusions.into_iter().map(|c| MyGlob(c)).collect(),
-		}
+			exclusions,
+			..Default::default()
+		};
+		target.exclusion_cache.get_or_build(&target.exclusions, &[], &target.effective_folders())?;
+		target.normalize();
+		Ok(target)
 	}
 
 	pub fn new_from_string<P: AsRef<Path>>(
 		folders: &[P],
 		exclusions: Vec<String>,
 		tags: Vec<String>,
-	) -> std::result::Result<Self, globset::Error> {
-		Ok(Self::new(
+	) -> std::result::Result<Self, BackupTargetError> {
+		Self::from_parts(
 			folders,
 			exclusions
 				.iter()
-				.map(|c| Glob::new(&format!("**/{}", c)))
-				.collect::<std::result::Result<Vec<Glob>, globset::Error>>()?,
+				.map(|c| MyGlob::new(c))
+				.collect::<std::result::Result<Vec<MyGlob>, globset::Error>>()?,
 			tags,
-		))
+			PathMode::Canonical,
+		)
 	}
 
-	pub fn get_exclusions_as_globset(&self) -> GlobSet {
-		let mut builder = GlobSetBuilder::new();
-		for exclusion in &self.exclusions {
-			builder.add(exclusion.clone().0);
+	/// The folders as they are on disk now: for `PathMode::AsGiven` canonicalized, with the
+	/// folders that can't be resolved (missing, or relative to a directory that doesn't exist)
+	/// returned separately. Canonical targets are returned as they are.
+	pub fn resolve_folders(&self) -> (Vec<PathBuf>, Vec<UnresolvedFolder>) {
+		if self.path_mode == PathMode::Canonical {
+			return (self.folders.clone(), Vec::new());
+		}
+		let mut resolved = Vec::with_capacity(self.folders.len());
+		let mut unresolved = Vec::new();
+		for folder in &self.folders {
+			match resolve_folder(folder, false) {
+				Ok(path) => resolved.push(path),
+				Err(error) => unresolved.push(UnresolvedFolder {
+					folder: folder.clone(),
+					error,
+				}),
+			}
 		}
-		builder.build().unwrap()
+		(resolved, unresolved)
 	}
 
-	pub fn add_folder<P: AsRef<Path>>(&mut self, folder_path: P) {
-		self.folders.push(
-			folder_path
-				.as_ref()
-				.canonicalize()
-				.expect("Failed to canonicalize path, not sure when this happens"),
-		)
+	/// The folders the walk and restic work on, see `resolve_folders` and `FolderCache`
+	pub(crate) fn effective_folders(&self) -> Arc<Vec<PathBuf>> {
+		self.folder_cache.get_or_resolve(self)
 	}
 
-	pub fn check_path_is_in_backup<P: AsRef<Path>>(&self, path: P) -> BackupFileSelectionType {
-		if self
-			.folders
-			.iter()
-			.any(|c| {
-				path.as_ref()
-					.canonicalize()
-					.unwrap()
-					.starts_with(c.as_path())
+	/// Resolve the folders of an `AsGiven` target again when it is next used, e.g. after a
+	/// drive was plugged in. Backups do this themselves.
+	pub fn refresh_folders(&self) {
+		self.folder_cache.invalidate();
+	}
+
+	/// The compiled exclusions, only recompiled when the exclusions or folders changed. Anchored
+	/// exclusions are in the set once per folder.
+	///
+	/// The constructors and `add_exclusion` already fail on exclusions that don't compile
+	/// together, if the public field was edited into such a state no path is excluded and a
+	/// warning is logged.
+	pub fn get_exclusions_as_globset(&self) -> Arc<GlobSet> {
+		self.compiled_exclusions().set.clone()
+	}
+
+	fn compiled_exclusions(&self) -> Arc<CompiledGlobs> {
+		self.exclusion_cache
+			.get_or_build(&self.exclusions, &self.exclude_file_patterns(), &self.effective_folders())
+			.unwrap_or_else(|err| {
+				log::warn!("Failed to compile exclusions, nothing is excluded: {}", err);
+				Arc::new(CompiledGlobs {
+					set: Arc::new(GlobSet::empty()),
+					owners: Vec::new(),
+				})
 			})
-		{
-			let ex_set = self.get_exclusions_as_globset();
+	}
 
-			if path.as_ref().ancestors().any(|c| {
-				ex_set.is_match(c)
-			}) {
-				BackupFileSelectionType::Excluded
-			} else {
-				BackupFileSelectionType::Included
-			}
-		} else if self
-			.folders
+	/// The patterns of `exclude_files` with the file they are from, see `ExcludeFileCache`
+	fn exclude_file_patterns(&self) -> ExcludePatterns {
+		self.exclude_file_cache.get_or_read(&self.exclude_files)
+	}
+
+	/// The patterns restic gets for the exclusions, with their flag
+	pub(crate) fn restic_exclusions(&self) -> Vec<(&'static str, String)> {
+		let folders = self.effective_folders();
+		self.exclusions
 			.iter()
-			.any(|c| {
-				c.canonicalize()
-					.map(|c| c.starts_with(path.as_ref()))
-					.unwrap_or(false)
-			}) {
-			BackupFileSelectionType::Contains
-		} else {
-			BackupFileSelectionType::Irreverent
-		}
+			.flat_map(|exclusion| {
+				let flag = exclusion.restic_flag();
+				exclusion
+					.restic_patterns(&folders)
+					.into_iter()
+					.map(move |pattern| (flag, pattern))
+			})
+			.collect()
+	}
+
+	/// Add a folder, canonicalized like in `new` unless the target keeps paths as given
+	pub fn add_folder<P: AsRef<Path>>(&mut self, folder_path: P) -> std::result::Result<(), BackupTargetError> {
+		self.add_folder_with(folder_path, false)
+	}
+
+	/// Add a folder, with `allow_missing` an absolute path that doesn't exist yet is added as is,
+	/// e.g. a mount point that only appears later
+	pub fn add_folder_with<P: AsRef<Path>>(
+		&mut self,
+		folder_path: P,
+		allow_missing: bool,
+	) -> std::result::Result<(), BackupTargetError> {
+		let folder = match self.path_mode {
+			PathMode::Canonical => resolve_folder(folder_path.as_ref(), allow_missing)?,
+			PathMode::AsGiven => folder_path.as_ref().to_owned(),
+		};
+		self.folders.push(folder);
+		Ok(())
 	}
-	pub fn generate_files(&self) -> filepath_tree::PathStore<()> {
-		let mut store = filepath_tree::PathStore::new(None);
+
+	/// Remove duplicate folders and folders already covered by another folder of the target,
+	/// returns the removed folders. The constructors call this.
+	///
+	/// A folder inside another one is kept if it or a directory between the two is excluded, it
+	/// wouldn't be backed up through the outer folder.
+	pub fn normalize(&mut self) -> Vec<PathBuf> {
 		let ex_set = self.get_exclusions_as_globset();
+		let mut kept: Vec<PathBuf> = Vec::with_capacity(self.folders.len());
+		let mut removed = Vec::new();
 
-		for folder in &self.folders {
-			let mut walk = walkdir::WalkDir::new(&folder)
-				.follow_links(false)
-				.into_iter();
-
-			while let Some(entry) = walk.next() {
-				let entry = match entry {
-					Ok(c) => c,
-					Err(err) => {
-						eprintln!("Error while walking: {}", err);
-						continue;
-					}
-				};
+		let is_covered = |folder: &Path, by: &Path| {
+			folder.starts_with(by)
+				&& !folder
+					.ancestors()
+					.take_while(|ancestor| *ancestor != by)
+					.any(|ancestor| ex_set.is_match(glob_path(ancestor)))
+		};
 
-				if ex_set.is_match(entry.path()) {
-					println!("Excluded path found: {}", entry.path().display());
-					walk.skip_current_dir();
-					continue;
-				}
-				store
-					.add_path(entry.path(), None)
-					.expect("Failed to add to store");
+		for folder in self.folders.drain(..) {
+			if kept.iter().any(|other| is_covered(&folder, other)) {
+				removed.push(folder);
+				continue;
 			}
+			// An earlier folder can be inside this one
+			let (covered, rest): (Vec<PathBuf>, Vec<PathBuf>) =
+				kept.into_iter().partition(|other| is_covered(other, &folder));
+			removed.extend(covered);
+			kept = rest;
+			kept.push(folder);
 		}
 
-		store
+		self.folders = kept;
+		removed
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	use super::*;
+	/// Remove a folder, the path is canonicalized first when possible so `/home/me/../me`
+	/// removes `/home/me`. Returns whether a folder was removed.
+	pub fn remove_folder(&mut self, folder_path: &Path) -> bool {
+		let folder_path = match self.path_mode {
+			PathMode::Canonical => canonicalize(folder_path).unwrap_or_else(|_| folder_path.to_owned()),
+			PathMode::AsGiven => folder_path.to_owned(),
+		};
+		let before = self.folders.len();
+		self.folders.retain(|folder| folder != &folder_path);
+		self.folders.len() != before
+	}
+
+	/// The compiled inclusions, see `get_exclusions_as_globset`
+	pub fn get_inclusions_as_globset(&self) -> Arc<GlobSet> {
+		match self.inclusion_cache.get_or_build(&self.inclusions, &[], &self.effective_folders()) {
+			Ok(compiled) => compiled.set.clone(),
+			Err(err) => {
+				log::warn!("Failed to compile inclusions, nothing is included: {}", err);
+				Arc::new(GlobSet::empty())
+			}
+		}
+	}
+
+	/// Fails and leaves the exclusions unchanged if the new set doesn't compile
+	pub fn add_exclusion(&mut self, exclusion: MyGlob) -> std::result::Result<(), BackupTargetError> {
+		self.exclusions.push(exclusion);
+		if let Err(err) = self.exclusion_cache.get_or_build(&self.exclusions, &[], &self.effective_folders()) {
+			self.exclusions.pop();
+			return Err(err.into());
+		}
+		Ok(())
+	}
+
+	/// Remove all exclusions with this user-facing pattern, returns whether one was removed
+	pub fn remove_exclusion(&mut self, pattern: &str) -> bool {
+		let before = self.exclusions.len();
+		self.exclusions.retain(|exclusion| exclusion.pattern() != pattern);
+		self.exclusion_cache.invalidate();
+		self.exclusions.len() != before
+	}
+
+	/// Fails and leaves the tags unchanged if one of them is invalid
+	pub fn set_tags(&mut self, tags: Vec<String>) -> std::result::Result<(), BackupTargetError> {
+		validate_tags(&tags)?;
+		self.tags = tags;
+		Ok(())
+	}
+
+	/// Whether the snapshot was made from this target: it has all of the target's tags and
+	/// exactly the target's folders as paths, which is how restic groups snapshots by default
+	pub fn matches_snapshot(&self, snapshot: &SnapshotsJson) -> bool {
+		let has_tags = self.tags.iter().all(|tag| snapshot.tags.contains(tag));
+		// Path comparison is per component, so trailing slashes don't matter
+		let folders = self.effective_folders();
+		let has_paths = snapshot.paths.len() == folders.len()
+			&& folders.iter().all(|folder| {
+				snapshot.paths.iter().any(|path| Path::new(path) == folder.as_path())
+			});
+
+		has_tags && has_paths
+	}
+
+	/// Where `path` stands relative to the backup.
+	///
+	/// Like restic, a symlink is judged by where it is and not by what it points to, and a path
+	/// that doesn't exist (anymore) is judged by its location. Fails if not even the parent
+	/// directory of the path can be resolved.
+	///
+	/// The folders of an `AsGiven` target are resolved on first use, see `refresh_folders`,
+	/// `folder` is then the resolved path. Folders that can't be resolved contain nothing.
+	pub fn check_path_is_in_backup<P: AsRef<Path>>(
+		&self,
+		path: P,
+	) -> std::result::Result<PathSelection, BackupTargetError> {
+		let path = resolve_no_follow(path.as_ref())?;
+		let folders = self.effective_folders();
+
+		if let Some(folder) = folders.iter().find(|c| path.starts_with(c.as_path())) {
+			let excluded_by = self.exclusion_reason(folder, &path);
+			return Ok(PathSelection {
+				selection: if excluded_by.is_some() {
+					BackupFileSelectionType::Excluded
+				} else {
+					BackupFileSelectionType::Included
+				},
+				folder: Some(folder.clone()),
+				excluded_by,
+			});
+		}
+
+		let inner = folders.iter().find(|c| c.starts_with(&path));
+		Ok(PathSelection {
+			selection: if inner.is_some() {
+				BackupFileSelectionType::Contains
+			} else {
+				BackupFileSelectionType::Irrelevant
+			},
+			folder: inner.cloned(),
+			excluded_by: None,
+		})
+	}
+
+	/// The outermost exclusion that applies to `path` or one of its parents, that is the one
+	/// restic stops at
+	fn exclusion_reason(&self, folder: &Path, path: &Path) -> Option<ExcludedBy> {
+		let compiled = self.compiled_exclusions();
+		let mut ancestors: Vec<&Path> = path.ancestors().collect();
+		ancestors.reverse();
+		let pattern = ancestors
+			.into_iter()
+			.find_map(|ancestor| compiled.set.matches(glob_path(ancestor)).into_iter().next())
+			.and_then(|glob_index| {
+				let index = compiled.owners[glob_index];
+				match self.exclusions.get(index) {
+					Some(exclusion) => Some(ExcludedBy::Pattern {
+						index,
+						pattern: exclusion.pattern().to_owned(),
+					}),
+					None => self
+						.exclude_file_patterns()
+						.get(index - self.exclusions.len())
+						.map(|(file, glob)| ExcludedBy::ExcludeFile {
+							file: file.clone(),
+							pattern: glob.pattern().to_owned(),
+						}),
+				}
+			});
+		if pattern.is_some() {
+			return pattern;
+		}
+
+		if !self.ignore_file_names.is_empty() && IgnoreStack::new(&self.ignore_file_names).is_path_ignored(folder, path) {
+			return Some(ExcludedBy::IgnoreFile);
+		}
+
+		// Directories are always walked, inclusions only select files
+		let is_dir = path.symlink_metadata().is_ok_and(|metadata| metadata.is_dir());
+		if !self.inclusions.is_empty() && !is_dir {
+			let in_set = self.get_inclusions_as_globset();
+			let included = path
+				.ancestors()
+				.take_while(|ancestor| ancestor.starts_with(folder))
+				.any(|ancestor| in_set.is_match(glob_path(ancestor)));
+			if !included {
+				return Some(ExcludedBy::NotIncluded);
+			}
+		}
+		None
+	}
+
+	/// Write what the inclusions don't select for restic's `--exclude-file` to a new file in
+	/// `dir`: the files that aren't included and the directories without an included file.
+	/// The folders stay the paths of the backup, so restic finds the parent snapshot like for
+	/// any other target. The file is removed when the returned list is dropped.
+	pub(crate) fn write_not_included(&self, dir: &Path) -> std::io::Result<NotIncludedList> {
+		use std::io::Write;
+
+		// `create_new` neither follows a planted symlink nor truncates a file left behind by an
+		// earlier process with the same pid, the next name is tried instead
+		static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+		let (path, file) = loop {
+			let path = dir.join(format!(
+				"restic-interfacer-excludes-{}-{}",
+				std::process::id(),
+				COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+			));
+			match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+				Ok(file) => break (path, file),
+				Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+				Err(err) => return Err(err),
+			}
+		};
+		// Only owns the file once it was created here, dropping it removes the file
+		let mut list = NotIncludedList {
+			path,
+			patterns: Vec::new(),
+			included: 0,
+		};
+		let mut file = std::io::BufWriter::new(file);
+
+		let in_set = self.get_inclusions_as_globset();
+		let folders = self.effective_folders();
+		let mut entries = Vec::new();
+		for entry in self.walk_roots(folders.iter().map(|folder| (folder.clone(), true)).collect(), false).all_files() {
+			match entry {
+				Ok(entry) => entries.push((entry.file_type().is_dir(), entry.into_path())),
+				Err(err) => log::warn!("Error while walking: {}", err),
+			}
+		}
+		entries.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+		// Directories with an included file somewhere below them
+		let mut needed = std::collections::HashSet::new();
+		for (_, path) in entries.iter().filter(|(is_dir, path)| !is_dir && is_included(&in_set, &folders, path)) {
+			list.included += 1;
+			for ancestor in path.ancestors().skip(1) {
+				if !needed.insert(ancestor.to_owned()) {
+					break;
+				}
+			}
+		}
+
+		// Sorted, so everything below an excluded directory follows it
+		let mut excluded_dir: Option<&Path> = None;
+		for (is_dir, path) in &entries {
+			if excluded_dir.is_some_and(|dir| path.starts_with(dir)) {
+				continue;
+			}
+			let excluded = if *is_dir {
+				!needed.contains(path)
+			} else {
+				!is_included(&in_set, &folders, path)
+			};
+			if !excluded {
+				continue;
+			}
+			if *is_dir {
+				excluded_dir = Some(path.as_path());
+			}
+			let pattern = match glob_path(path).to_str() {
+				Some(text) => escape_restic_glob(text),
+				None => {
+					log::warn!("Can't exclude {} from the backup, its name isn't UTF-8", path.display());
+					continue;
+				}
+			};
+			// restic trims the lines of exclude files, skips comments and expands `$VAR`
+			if pattern.contains(['$', '\n', '\r']) || pattern.trim() != pattern {
+				list.patterns.push(pattern);
+			} else {
+				writeln!(file, "{}", pattern)?;
+			}
+		}
+		file.flush()?;
+		Ok(list)
+	}
+	/// Walk the folders depth first, skipping excluded entries.
+	///
+	/// Folders are walked in sorted order and the entries of each directory are sorted by
+	/// name, which is the same order restic lists the nodes of a snapshot in. Symlinks aren't
+	/// followed, as restic doesn't follow them either.
+	pub(crate) fn walk(&self) -> TargetWalk {
+		self.walk_with(false)
+	}
+
+	fn walk_with(&self, follow_links: bool) -> TargetWalk {
+		let mut folders: Vec<PathBuf> = self.effective_folders().to_vec();
+		folders.sort();
+
+		self.walk_roots(folders.into_iter().map(|folder| (folder, true)).collect(), follow_links)
+	}
+
+	/// Walk the given roots, only the root itself for roots that aren't `recursive`
+	fn walk_roots(&self, roots: Vec<(PathBuf, bool)>, follow_links: bool) -> TargetWalk {
+		TargetWalk {
+			roots: roots.into_iter(),
+			current: None,
+			follow_links,
+			ex_set: self.get_exclusions_as_globset(),
+			in_set: if self.inclusions.is_empty() {
+				None
+			} else {
+				Some(self.get_inclusions_as_globset())
+			},
+			exclude_larger_than: self.exclude_larger_than,
+			exclude_caches: self.exclude_caches,
+			folders: self.effective_folders(),
+			ignores: if self.ignore_file_names.is_empty() {
+				None
+			} else {
+				Some(IgnoreStack::new(&self.ignore_file_names))
+			},
+		}
+	}
+
+	/// The patterns of all ignore files in the target as restic excludes, empty without
+	/// `ignore_file_names`. Walks the whole target to find the files.
+	pub fn ignore_file_excludes(&self) -> Vec<String> {
+		if self.ignore_file_names.is_empty() {
+			return Vec::new();
+		}
+
+		self.walk()
+			.filter_map(|entry| entry.ok())
+			.filter(|entry| entry.file_type().is_file())
+			.filter(|entry| {
+				let name = entry.file_name().to_string_lossy();
+				self.ignore_file_names.iter().any(|ignore_name| *ignore_name == name)
+			})
+			.flat_map(|entry| restic_patterns(entry.path()))
+			.collect()
+	}
+
+	/// Count the files and bytes a backup of this target would read, honoring all exclusions
+	pub fn estimate(&self) -> std::result::Result<TargetEstimate, BackupTargetError> {
+		self.estimate_bounded(None)
+	}
+
+	/// Like `estimate`, but stops after `max_entries` entries and sets `truncated`, so huge or
+	/// pathological trees can't keep it busy indefinitely
+	pub fn estimate_bounded(&self, max_entries: Option<u64>) -> std::result::Result<TargetEstimate, BackupTargetError> {
+		let mut estimate = TargetEstimate::default();
+
+		for entry in self.walk() {
+			if max_entries.is_some_and(|max| estimate.files + estimate.dirs >= max) {
+				estimate.truncated = true;
+				break;
+			}
+
+			let entry = match entry {
+				Ok(c) => c,
+				// The folders themselves have to be readable, anything below is best effort
+				Err(err) if err.depth() == 0 => {
+					return Err(BackupTargetError::Read {
+						path: err.path().map(Path::to_owned).unwrap_or_default(),
+						source: err.into(),
+					});
+				}
+				Err(err) => {
+					log::debug!("Error while estimating: {}", err);
+					estimate.unreadable += 1;
+					continue;
+				}
+			};
+
+			if entry.file_type().is_dir() {
+				estimate.dirs += 1;
+			} else {
+				estimate.files += 1;
+				match FileMeta::from_entry(&entry) {
+					Ok(meta) => estimate.bytes += meta.size,
+					Err(_) => estimate.unreadable += 1,
+				}
+			}
+		}
+
+		Ok(estimate)
+	}
+
+	/// Split the walk into independent parts for the parallel walker: every folder on its own,
+	/// and every entry directly inside a folder as a separate subtree
+	fn walk_units(&self) -> Vec<(PathBuf, bool)> {
+		let ex_set = self.get_exclusions_as_globset();
+		let mut units = Vec::new();
+
+		for folder in self.effective_folders().iter() {
+			if ex_set.is_match(glob_path(folder)) || (self.exclude_caches && is_tagged_cache_dir(folder)) {
+				continue;
+			}
+			match std::fs::read_dir(folder) {
+				Ok(children) => {
+					units.push((folder.clone(), false));
+					// Unreadable children show up as errors when their unit is walked
+					units.extend(children.filter_map(|child| child.ok()).map(|child| (child.path(), true)));
+				}
+				// Let the walk report the error
+				Err(_) => units.push((folder.clone(), true)),
+			}
+		}
+
+		units
+	}
+
+	/// Walk with `options.threads` threads, entries are passed to `on_entry` in no particular
+	/// order. One thread, or failing to start the thread pool, walks sequentially in sorted order.
+	pub(crate) fn walk_parallel<F>(&self, options: &WalkOptions, mut on_entry: F)
+	where
+		F: FnMut(walkdir::Result<walkdir::DirEntry>),
+	{
+		let pool = match options.threads {
+			0 | 1 => None,
+			threads => rayon::ThreadPoolBuilder::new()
+				.num_threads(threads)
+				.build()
+				.map_err(|err| log::warn!("Failed to start walker threads, walking sequentially: {}", err))
+				.ok(),
+		};
+		let pool = match pool {
+			Some(pool) => pool,
+			None => return self.walk_with(options.follow_symlinks).for_each(on_entry),
+		};
+
+		let units = self.walk_units();
+		let (sender, receiver) = std::sync::mpsc::sync_channel(1024);
+		std::thread::scope(|scope| {
+			scope.spawn(|| {
+				pool.install(|| {
+					units.into_par_iter().for_each_with(sender, |sender, unit| {
+						for entry in self.walk_roots(vec![unit], options.follow_symlinks) {
+							if sender.send(entry).is_err() {
+								return;
+							}
+						}
+					})
+				})
+			});
+			// All insertions happen here, the store doesn't need to be shared between threads
+			for entry in receiver {
+				on_entry(entry);
+			}
+		});
+	}
+
+	/// Build a tree of all files that would be backed up, walking sequentially
+	pub fn generate_files(&self) -> std::result::Result<WalkReport, BackupTargetError> {
+		self.generate_files_with(
+			&WalkOptions {
+				threads: 1,
+				..Default::default()
+			},
+			|_| {},
+		)
+	}
+
+	/// Build a tree of all files that would be backed up.
+	///
+	/// `progress` is called every `options.progress_interval` entries. Entries that can't be read
+	/// are collected in the report's `errors` and left out of the tree, as are symlinks that lead
+	/// back into one of their own parents when `options.follow_symlinks` is set. Fails if a path
+	/// can't be added to the tree, the walk stops adding entries then.
+	pub fn generate_files_with<F: FnMut(WalkProgress)>(
+		&self,
+		options: &WalkOptions,
+		progress: F,
+	) -> std::result::Result<WalkReport, BackupTargetError> {
+		// Paths whose metadata can't be read are still part of the backup, just without details
+		self.generate_files_with_payload(options, |entry| FileMeta::from_entry(entry).ok(), progress)
+	}
+
+	/// Like `generate_files_with`, but the tree holds what `make_payload` returns for each entry
+	/// instead of its `FileMeta`, e.g. a selection state or the backup status of the path. Paths
+	/// it returns None for are in the tree without a payload.
+	///
+	/// `make_payload` is called on the calling thread, one entry at a time, also when walking
+	/// with several threads.
+	pub fn generate_files_with_payload<T, M, F>(
+		&self,
+		options: &WalkOptions,
+		mut make_payload: M,
+		mut progress: F,
+	) -> std::result::Result<WalkReport<T>, BackupTargetError>
+	where
+		M: FnMut(&walkdir::DirEntry) -> Option<T>,
+		F: FnMut(WalkProgress),
+	{
+		let mut report = WalkReport {
+			store: filepath_tree::PathStore::new(None),
+			errors: Vec::new(),
+		};
+		let interval = options.progress_interval.max(1);
+		let mut entries_seen = 0u64;
+		let mut failed = None;
+
+		self.walk_parallel(options, |entry| {
+			if failed.is_some() {
+				return;
+			}
+			entries_seen += 1;
+			let entry = match entry {
+				Ok(c) => c,
+				Err(err) => {
+					report.errors.push(WalkError::from(err));
+					return;
+				}
+			};
+
+			if entries_seen.is_multiple_of(interval) {
+				progress(WalkProgress {
+					entries_seen,
+					current_path: entry.path().to_owned(),
+				});
+			}
+
+			let payload = make_payload(&entry);
+			if let Err(err) = report.store.add_path(entry.path(), payload) {
+				failed = Some(BackupTargetError::Store {
+					path: entry.path().to_owned(),
+					reason: format!("{:?}", err),
+				});
+			}
+		});
+
+		match failed {
+			Some(err) => Err(err),
+			None => Ok(report),
+		}
+	}
+}
+
+/// How `generate_files_with` walks the target
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WalkOptions {
+	/// Number of walker threads, 1 walks sequentially
+	pub threads: usize,
+	/// Report progress every this many entries
+	pub progress_interval: u64,
+	/// Walk into symlinked directories and describe symlinks by what they point to.
+	///
+	/// Only the local walk is affected: restic never follows symlinks and stores them as links,
+	/// so a tree built this way shows more than a backup of the target would contain. To back up
+	/// what a symlink points to, add the resolved path as a folder of the target.
+	pub follow_symlinks: bool,
+}
+
+impl Default for WalkOptions {
+	/// One thread per core, progress every 1000 entries, symlinks aren't followed
+	fn default() -> Self {
+		WalkOptions {
+			threads: std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1),
+			progress_interval: 1000,
+			follow_symlinks: false,
+		}
+	}
+}
+
+/// Passed to the progress callback of `generate_files_with`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WalkProgress {
+	/// Entries seen so far, including excluded ones that were skipped and failed ones
+	pub entries_seen: u64,
+	pub current_path: PathBuf,
+}
+
+/// An entry that couldn't be read during a walk
+#[derive(Debug)]
+pub struct WalkError {
+	/// Empty if walkdir didn't know the path
+	pub path: PathBuf,
+	/// For a symlink loop, the directory `path` leads back to
+	pub loop_ancestor: Option<PathBuf>,
+	pub error: std::io::Error,
+}
+
+impl From<walkdir::Error> for WalkError {
+	fn from(err: walkdir::Error) -> WalkError {
+		WalkError {
+			path: err.path().map(Path::to_owned).unwrap_or_default(),
+			loop_ancestor: err.loop_ancestor().map(Path::to_owned),
+			error: err.into(),
+		}
+	}
+}
+
+/// Metadata of a walked entry, taken from the walk itself so no path is stat'ed twice
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileMeta {
+	/// 0 for directories
+	pub size: u64,
+	pub mtime: Option<std::time::SystemTime>,
+	pub is_dir: bool,
+}
+
+impl FileMeta {
+	/// Uses a symlink's own metadata, unless the walk follows symlinks
+	pub(crate) fn from_entry(entry: &walkdir::DirEntry) -> std::result::Result<FileMeta, walkdir::Error> {
+		let metadata = entry.metadata()?;
+		Ok(FileMeta {
+			size: if metadata.is_file() { metadata.len() } else { 0 },
+			mtime: metadata.modified().ok(),
+			is_dir: metadata.is_dir(),
+		})
+	}
+
+	/// Modification time in whole seconds since the epoch, the precision restic's ls output has
+	pub fn mtime_secs(&self) -> Option<i64> {
+		self.mtime
+			.and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+			.map(|mtime| mtime.as_secs() as i64)
+	}
+}
+
+/// The tree of a walk, by default with the `FileMeta` of every path
+pub struct WalkReport<T = FileMeta> {
+	pub store: filepath_tree::PathStore<T>,
+	pub errors: Vec<WalkError>,
+}
+
+/// What the inclusions of a target don't select, see `BackupTarget::write_not_included`. The
+/// exclude file is deleted on drop.
+#[derive(Debug)]
+pub(crate) struct NotIncludedList {
+	/// Exclude file for `--exclude-file`
+	pub(crate) path: PathBuf,
+	/// Patterns restic would read differently from an exclude file, passed as `--exclude`
+	pub(crate) patterns: Vec<String>,
+	/// Number of files the inclusions select
+	pub(crate) included: usize,
+}
+
+impl NotIncludedList {
+	pub(crate) fn as_args(&self) -> (&Path, &[String]) {
+		(&self.path, &self.patterns)
+	}
+}
+
+impl Drop for NotIncludedList {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// Whether the inclusions select a file: it or a directory above it, up to its folder, matches
+fn is_included(in_set: &GlobSet, folders: &[PathBuf], path: &Path) -> bool {
+	path.ancestors()
+		.take_while(|ancestor| folders.iter().any(|folder| ancestor.starts_with(folder)))
+		.any(|ancestor| in_set.is_match(glob_path(ancestor)))
+}
+
+/// What a backup of a target would read, from `BackupTarget::estimate`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TargetEstimate {
+	/// Files, symlinks and other non-directory entries
+	pub files: u64,
+	pub dirs: u64,
+	/// Summed size of the regular files
+	pub bytes: u64,
+	/// Entries that couldn't be read, their size is missing from `bytes`
+	pub unreadable: u64,
+	/// The walk stopped at the entry limit, the numbers are a lower bound
+	pub truncated: bool,
+}
+
+/// The contents of `CACHEDIR.TAG` have to start with this, see https://bford.info/cachedir/
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+fn is_tagged_cache_dir(dir: &Path) -> bool {
+	use std::io::Read;
+
+	let mut signature = [0; CACHEDIR_TAG_SIGNATURE.len()];
+	std::fs::File::open(dir.join("CACHEDIR.TAG"))
+		.and_then(|mut file| file.read_exact(&mut signature))
+		.map(|_| signature == CACHEDIR_TAG_SIGNATURE)
+		.unwrap_or(false)
+}
+
+pub(crate) struct TargetWalk {
+	roots: std::vec::IntoIter<(PathBuf, bool)>,
+	current: Option<walkdir::IntoIter>,
+	follow_links: bool,
+	ex_set: Arc<GlobSet>,
+	/// Files have to match this, or be inside a directory that does
+	in_set: Option<Arc<GlobSet>>,
+	exclude_larger_than: Option<u64>,
+	exclude_caches: bool,
+	/// The target's folders, to find the ignore files above a root
+	folders: Arc<Vec<PathBuf>>,
+	ignores: Option<IgnoreStack>,
+}
+
+impl TargetWalk {
+	/// Return the files the inclusions don't select as well, only the exclusions apply
+	fn all_files(mut self) -> TargetWalk {
+		self.in_set = None;
+		self
+	}
+
+	/// Don't descend into the directory returned last
+	pub(crate) fn skip_current_dir(&mut self) {
+		if let Some(walk) = &mut self.current {
+			walk.skip_current_dir();
+		}
+	}
+}
+
+/// Exclusions other than the globs. Not a method, the walk borrows `TargetWalk` mutably
+fn excluded_by_options(entry: &walkdir::DirEntry, exclude_larger_than: Option<u64>, exclude_caches: bool) -> bool {
+	if entry.file_type().is_dir() {
+		return exclude_caches && is_tagged_cache_dir(entry.path());
+	}
+	match exclude_larger_than {
+		Some(limit) if entry.file_type().is_file() => {
+			entry.metadata().map(|metadata| metadata.len() > limit).unwrap_or(false)
+		}
+		_ => false,
+	}
+}
+
+impl Iterator for TargetWalk {
+	type Item = walkdir::Result<walkdir::DirEntry>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some(walk) = &mut self.current {
+				match walk.next() {
+					Some(Ok(entry)) => {
+						let is_dir = entry.file_type().is_dir();
+						let ignored = match &mut self.ignores {
+							Some(ignores) => ignores.is_ignored(entry.path(), is_dir),
+							None => false,
+						};
+						if ignored
+							|| self.ex_set.is_match(glob_path(entry.path()))
+							|| excluded_by_options(&entry, self.exclude_larger_than, self.exclude_caches)
+						{
+							log::trace!("Excluded path found: {}", entry.path().display());
+							if is_dir {
+								walk.skip_current_dir();
+							}
+							continue;
+						}
+						if let (Some(ignores), true) = (&mut self.ignores, is_dir) {
+							ignores.enter_dir(entry.path());
+						}
+						if let (Some(in_set), false) = (&self.in_set, is_dir) {
+							if !is_included(in_set, &self.folders, entry.path()) {
+								continue;
+							}
+						}
+						return Some(Ok(entry));
+					}
+					Some(Err(err)) => return Some(Err(err)),
+					None => self.current = None,
+				}
+			}
+
+			let (root, recursive) = self.roots.next()?;
+			if let Some(ignores) = &mut self.ignores {
+				if let Some(folder) = self.folders.iter().find(|folder| root.starts_with(folder)) {
+					ignores.enter_parents(folder, &root);
+				}
+			}
+			// A folder of the target is walked into even when it's a symlink. Other roots are
+			// entries the parallel walk split off a folder, a symlink among them stays a link
+			// unless links are followed, the same as in a sequential walk.
+			let follow_root = self.follow_links || self.folders.contains(&root);
+			let mut walk = walkdir::WalkDir::new(root).follow_root_links(follow_root);
+			if !recursive {
+				walk = walk.max_depth(0);
+			}
+			self.current = Some(
+				walk.follow_links(self.follow_links)
+					.sort_by(|a, b| a.file_name().cmp(b.file_name()))
+					.into_iter(),
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn check_serialize_deserialize() {
+		let dir = crate::test_util::test_dir("serialize");
+		let folders = [dir.join("d"), dir.join("c").join("Windows")];
+		for folder in &folders {
+			std::fs::create_dir_all(folder).unwrap();
+		}
 
-	#[test]
-	fn check_serialize_deserialize() {
 		let backup_tar =
-			BackupTarget::new_from_string(&vec!["/mnt/d/", "/mnt/c/Windows/"], vec!["system32".to_owned()], vec!["abc".to_owned()]).unwrap();
+			BackupTarget::new_from_string(&folders, vec!["system32".to_owned()], vec!["abc".to_owned()]).unwrap();
 		let out_tar: BackupTarget = serde_json::from_str(&serde_json::to_string(&backup_tar).unwrap()).unwrap();
 		assert_eq!(backup_tar, out_tar);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn my_glob_round_trips() {
+		for pattern in &["/mnt/d/cache", "/tmp/", "node_modules", "*.tmp", "**/.git", "/home/**/target"] {
+			let glob = MyGlob::new(pattern).unwrap();
+			let json = serde_json::to_string(&glob).unwrap();
+			assert_eq!(json, serde_json::to_string(pattern).unwrap());
+			let out: MyGlob = serde_json::from_str(&json).unwrap();
+			assert_eq!(out, glob);
+		}
+
+		let target = BackupTarget::new(&["/"], vec![Glob::new("/var/cache").unwrap()], vec![]).unwrap();
+		let out: BackupTarget = serde_json::from_str(&serde_json::to_string(&target).unwrap()).unwrap();
+		assert_eq!(out.exclusions[0].glob(), "/var/cache");
+	}
+
+	#[test]
+	fn repeated_round_trips_keep_matching() {
+		let paths = ["/mnt/d/.git", "/mnt/d/src/.git/config", "/mnt/d/src/main.rs", "/mnt/d/system32/x"];
+		let target = BackupTarget::new_from_string(&["/"], vec![".git".to_owned(), "system32/".to_owned()], vec![]).unwrap();
+		let matches = |target: &BackupTarget| {
+			let set = target.get_exclusions_as_globset();
+			paths.iter().map(|path| set.is_match(path)).collect::<Vec<_>>()
+		};
+		let expected = matches(&target);
+		assert_eq!(expected, [true, false, false, false]);
+
+		let mut current = target.clone();
+		for _ in 0..5 {
+			current = serde_json::from_str(&serde_json::to_string(&current).unwrap()).unwrap();
+			assert_eq!(current, target);
+			assert_eq!(matches(&current), expected);
+		}
+
+		let legacy: MyGlob = serde_json::from_str(r#""**/**/.git""#).unwrap();
+		assert_eq!(legacy.glob(), "**/.git");
+	}
+
+	#[test]
+	fn exclusions_match_like_restic() {
+		let fixtures = include_str!("../fixtures/restic_exclude_matches.tsv");
+		for line in fixtures.lines().filter(|line| !line.starts_with('#')) {
+			let fields: Vec<&str> = line.split('\t').collect();
+			let (pattern, path, expected) = (fields[0], Path::new(fields[1]), fields[2] == "true");
+
+			let glob = MyGlob::new(pattern).unwrap();
+			let matcher = glob.compile_matcher();
+			let excluded = path.ancestors().any(|ancestor| matcher.is_match(ancestor));
+			assert_eq!(excluded, expected, "pattern {:?} on {:?}", pattern, path);
+
+			let target = BackupTarget {
+				exclusions: vec![glob],
+				..Default::default()
+			};
+			let set = target.get_exclusions_as_globset();
+			assert_eq!(path.ancestors().any(|ancestor| set.is_match(ancestor)), expected);
+		}
+	}
+
+	#[test]
+	fn case_insensitive_globs() {
+		let glob = MyGlob::case_insensitive("*.JPG").unwrap();
+		assert!(glob.compile_matcher().is_match("/photos/holiday.jpg"));
+		assert_eq!(glob.restic_flag(), "--iexclude");
+		assert!(!MyGlob::new("*.JPG").unwrap().compile_matcher().is_match("/photos/holiday.jpg"));
+
+		let json = serde_json::to_string(&glob).unwrap();
+		assert_eq!(json, r#"{"pattern":"*.JPG","case_insensitive":true}"#);
+		assert_eq!(serde_json::from_str::<MyGlob>(&json).unwrap(), glob);
+		let plain: MyGlob = serde_json::from_str(r#"{"pattern":"*.JPG"}"#).unwrap();
+		assert_eq!(plain, MyGlob::new("*.JPG").unwrap());
+	}
+
+	#[test]
+	fn my_glob_normalization() {
+		let glob: MyGlob = "target/".parse().unwrap();
+		assert_eq!(glob.glob(), "**/target");
+		assert_eq!(glob.to_string(), "target/");
+		assert!(glob.compile_matcher().is_match("/home/me/project/target"));
+
+		let glob = MyGlob::new("/tmp").unwrap();
+		assert_eq!(glob.glob(), "/tmp");
+		assert_eq!(glob.to_string(), "/tmp");
+		assert!(!glob.compile_matcher().is_match("/var/tmp"));
+	}
+
+	#[test]
+	fn anchored_exclusions_match_below_each_folder() {
+		let folders = vec![PathBuf::from("/home/me"), PathBuf::from("/srv/data [old]")];
+		let target = BackupTarget {
+			folders: folders.clone(),
+			exclusions: vec![
+				MyGlob::new("./build/").unwrap(),
+				MyGlob::new("*.tmp").unwrap(),
+				MyGlob::anchored("cache").unwrap(),
+			],
+			..Default::default()
+		};
+		assert!(target.exclusions[0].is_anchored());
+		assert!(!target.exclusions[1].is_anchored());
+		assert!(!MyGlob::anchored("/var/cache").unwrap().is_anchored());
+
+		let set = target.get_exclusions_as_globset();
+		assert!(set.is_match("/home/me/build"));
+		assert!(set.is_match("/srv/data [old]/build"));
+		assert!(!set.is_match("/home/me/src/build"));
+		assert!(!set.is_match("/home/build"));
+		assert!(set.is_match("/home/me/src/a.tmp"));
+		assert!(set.is_match("/srv/data [old]/cache"));
+
+		// The brackets of the folder are literal for restic as well
+		let old = if cfg!(windows) { "[[]old]" } else { r"\[old\]" };
+		assert_eq!(
+			target.restic_exclusions(),
+			vec![
+				("--exclude", "/home/me/build".to_owned()),
+				("--exclude", format!("/srv/data {}/build", old)),
+				("--exclude", "**/*.tmp".to_owned()),
+				("--exclude", "/home/me/cache".to_owned()),
+				("--exclude", format!("/srv/data {}/cache", old)),
+			]
+		);
+
+		// The index points at the pattern, not at the glob compiled for one folder
+		assert_eq!(
+			target.exclusion_reason(&folders[1], Path::new("/srv/data [old]/cache/x")),
+			Some(ExcludedBy::Pattern {
+				index: 2,
+				pattern: "cache".to_owned()
+			})
+		);
+
+		let json = serde_json::to_string(&target.exclusions).unwrap();
+		assert_eq!(json, r#"["./build/","*.tmp",{"pattern":"cache","case_insensitive":false,"anchored":true}]"#);
+		assert_eq!(serde_json::from_str::<Vec<MyGlob>>(&json).unwrap(), target.exclusions);
+	}
+
+	#[test]
+	fn mutators() {
+		let mut target = BackupTarget::new_from_string(&["/"], vec!["*.tmp".to_owned()], vec![]).unwrap();
+		target.add_exclusion(MyGlob::new("/var/cache/").unwrap()).unwrap();
+		assert!(target.remove_exclusion("*.tmp"));
+		assert!(!target.remove_exclusion("*.tmp"));
+		assert_eq!(target.exclusions, vec![MyGlob::new("/var/cache/").unwrap()]);
+
+		assert!(target.remove_folder(Path::new("/tmp/..")));
+		assert!(target.folders.is_empty());
+		assert!(!target.remove_folder(Path::new("/does/not/exist")));
+
+		target.set_tags(vec!["laptop".to_owned()]).unwrap();
+		assert_eq!(target.tags, ["laptop"]);
+		assert!(target.set_tags(vec!["a,b".to_owned()]).is_err());
+		assert_eq!(target.tags, ["laptop"]);
+	}
+
+	#[test]
+	fn globset_cache_follows_exclusions() {
+		let mut target = BackupTarget::new_from_string(&["/"], vec!["*.tmp".to_owned()], vec![]).unwrap();
+		let first = target.get_exclusions_as_globset();
+		assert!(Arc::ptr_eq(&first, &target.get_exclusions_as_globset()));
+		assert!(first.is_match("/a.tmp"));
+
+		target.exclusions.push(MyGlob::new("*.log").unwrap());
+		assert!(target.get_exclusions_as_globset().is_match("/a.log"));
+		assert!(target.remove_exclusion("*.log"));
+		assert!(!target.get_exclusions_as_globset().is_match("/a.log"));
+	}
+
+	#[test]
+	fn normalize_merges_nested_folders() {
+		let mut target = BackupTarget {
+			folders: vec![
+				"/home/me/projects".into(),
+				"/home/me".into(),
+				"/home/me".into(),
+				"/home/me/cache/keep".into(),
+				"/srv".into(),
+			],
+			exclusions: vec![MyGlob::new("cache").unwrap()],
+			..Default::default()
+		};
+		let removed = target.normalize();
+		assert_eq!(removed, [PathBuf::from("/home/me/projects"), "/home/me".into()]);
+		assert_eq!(target.folders, [PathBuf::from("/home/me"), "/home/me/cache/keep".into(), "/srv".into()]);
+		assert!(target.normalize().is_empty());
+	}
+
+	#[test]
+	fn estimate_honors_exclusions() {
+		let root = crate::test_util::test_dir("estimate");
+		std::fs::create_dir_all(root.join("data/cache")).unwrap();
+		std::fs::create_dir_all(root.join("data/skip")).unwrap();
+		std::fs::write(root.join("data/a"), vec![0; 100]).unwrap();
+		std::fs::write(root.join("data/big"), vec![0; 5000]).unwrap();
+		std::fs::write(root.join("data/skip/b"), vec![0; 10]).unwrap();
+		std::fs::write(root.join("data/cache/CACHEDIR.TAG"), CACHEDIR_TAG_SIGNATURE).unwrap();
+
+		let mut target = BackupTarget::new_from_string(&[root.join("data")], vec!["skip".to_owned()], vec![]).unwrap();
+		target.exclude_larger_than = Some(1000);
+		target.exclude_caches = true;
+		let estimate = target.estimate().unwrap();
+		assert_eq!((estimate.files, estimate.dirs, estimate.bytes), (1, 1, 100));
+		assert!(!estimate.truncated);
+		assert!(target.estimate_bounded(Some(1)).unwrap().truncated);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn parallel_walk_matches_sequential() {
+		let root = crate::test_util::test_dir("parallel-walk");
+		for top in 0..4 {
+			let mut dir = root.join(format!("top{}", top));
+			for depth in 0..6 {
+				dir = dir.join(format!("level{}", depth));
+				std::fs::create_dir_all(dir.join("skipped")).unwrap();
+				for file in 0..3 {
+					std::fs::write(dir.join(format!("file{}", file)), b"").unwrap();
+				}
+			}
+		}
+
+		let target = BackupTarget::new_from_string(&[&root], vec!["skipped".to_owned()], vec![]).unwrap();
+		let collect = |threads| {
+			let mut paths = Vec::new();
+			let options = WalkOptions {
+				threads,
+				..Default::default()
+			};
+			target.walk_parallel(&options, |entry| paths.push(entry.unwrap().into_path()));
+			paths.sort();
+			paths
+		};
+		let sequential = collect(1);
+		assert_eq!(sequential.len(), 1 + 4 * (1 + 6 * 4));
+		for _ in 0..3 {
+			assert_eq!(collect(4), sequential);
+		}
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn generate_files_reports_progress() {
+		let root = crate::test_util::test_dir("progress");
+		for file in 0..10 {
+			std::fs::write(root.join(format!("file{}", file)), b"").unwrap();
+		}
+
+		let target = BackupTarget::new_from_string(&[&root], vec![], vec![]).unwrap();
+		let mut calls = Vec::new();
+		let options = WalkOptions {
+			threads: 1,
+			progress_interval: 4,
+			follow_symlinks: false,
+		};
+		let report = target.generate_files_with(&options, |progress| calls.push(progress.entries_seen)).unwrap();
+		assert_eq!(calls, [4, 8]);
+		assert!(report.errors.is_empty());
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn generate_files_with_payload_sees_every_entry() {
+		let root = crate::test_util::test_dir("payload");
+		std::fs::create_dir_all(root.join("skipped")).unwrap();
+		for file in &["a", "b", "skipped/c"] {
+			std::fs::write(root.join(file), b"").unwrap();
+		}
+
+		let target = BackupTarget::new_from_string(&[&root], vec!["skipped".to_owned()], vec![]).unwrap();
+		let options = WalkOptions {
+			threads: 2,
+			..Default::default()
+		};
+		let mut seen = Vec::new();
+		let report = target
+			.generate_files_with_payload(
+				&options,
+				|entry| {
+					seen.push(entry.file_name().to_owned());
+					Some(entry.depth())
+				},
+				|_| {},
+			)
+			.unwrap();
+		seen.sort();
+		// The root, named like its directory, sorts last
+		assert_eq!(seen, vec!["a".into(), "b".into(), root.canonicalize().unwrap().file_name().unwrap().to_owned()]);
+		assert!(report.errors.is_empty());
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn follows_symlinks_when_asked() {
+		let root = crate::test_util::test_dir("follow-symlinks");
+		std::fs::create_dir_all(root.join("archive/2020")).unwrap();
+		std::fs::create_dir_all(root.join("library")).unwrap();
+		std::fs::write(root.join("archive/2020/photo.jpg"), b"").unwrap();
+		std::os::unix::fs::symlink(root.join("archive/2020"), root.join("library/2020")).unwrap();
+		std::os::unix::fs::symlink(root.join("library"), root.join("library/loop")).unwrap();
+
+		let target = BackupTarget::new_from_string(&[root.join("library")], vec![], vec![]).unwrap();
+		let collect = |threads, follow_symlinks| {
+			let options = WalkOptions {
+				threads,
+				follow_symlinks,
+				..Default::default()
+			};
+			let mut paths = Vec::new();
+			let mut errors = Vec::new();
+			target.walk_parallel(&options, |entry| match entry {
+				Ok(entry) => paths.push(entry.into_path()),
+				Err(err) => errors.push(WalkError::from(err)),
+			});
+			paths.sort();
+			(paths, errors)
+		};
+
+		let library = root.join("library").canonicalize().unwrap();
+		// The parallel walk starts a walk at each symlink inside the folder, they stay links too
+		for threads in &[1, 4] {
+			let (paths, errors) = collect(*threads, false);
+			assert_eq!(paths, vec![library.clone(), library.join("2020"), library.join("loop")], "{} threads", threads);
+			assert!(errors.is_empty());
+		}
+
+		let (paths, errors) = collect(1, true);
+		assert_eq!(paths, vec![library.clone(), library.join("2020"), library.join("2020/photo.jpg")]);
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].path, library.join("loop"));
+		assert_eq!(errors[0].loop_ancestor.as_deref(), Some(library.as_path()));
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn exclude_files_apply_to_the_local_walk() {
+		let root = crate::test_util::test_dir("exclude-file");
+		std::fs::create_dir_all(root.join("data/cache")).unwrap();
+		for file in &["data/a.log", "data/cache/x", "data/#notes", "data/main.rs"] {
+			std::fs::write(root.join(file), b"").unwrap();
+		}
+		let excludes = root.join("excludes.txt");
+		std::fs::write(&excludes, "# logs\n*.log\n\n   \n  # indented comment\n  cache  \n#notes\n").unwrap();
+		assert_eq!(read_exclude_file(&excludes).unwrap(), ["*.log", "cache"]);
+
+		let mut target = BackupTarget::new_from_string(&[root.join("data")], vec![], vec![]).unwrap();
+		target.exclude_files = vec![excludes.clone(), root.join("missing.txt")];
+		let mut files: Vec<PathBuf> = target
+			.walk()
+			.map(|entry| entry.unwrap().into_path())
+			.filter(|path| path.is_file())
+			.map(|path| path.strip_prefix(&root).unwrap().to_owned())
+			.collect();
+		files.sort();
+		assert_eq!(files, [PathBuf::from("data/#notes"), PathBuf::from("data/main.rs")]);
+
+		let selection = target.check_path_is_in_backup(root.join("data/cache/x")).unwrap();
+		assert_eq!(
+			selection.excluded_by,
+			Some(ExcludedBy::ExcludeFile {
+				file: excludes,
+				pattern: "cache".to_owned()
+			})
+		);
+		assert_eq!(target.restic_exclusions(), []);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn expands_variables_in_exclude_files() {
+		let lookup = |name: &str| match name {
+			"HOME" => Some("/home/me".to_owned()),
+			_ => None,
+		};
+		assert_eq!(expand_env("$HOME/.cache", lookup), "/home/me/.cache");
+		assert_eq!(expand_env("${HOME}_old/*.tmp", lookup), "/home/me_old/*.tmp");
+		assert_eq!(expand_env("$UNSET/x", lookup), "/x");
+		assert_eq!(expand_env("price$$/$", lookup), "price$/$");
+		assert_eq!(expand_env("${HOME", lookup), "${HOME");
+	}
+
+	#[test]
+	fn exclude_files_are_read_once() {
+		let root = crate::test_util::test_dir("exclude-file-once");
+		std::fs::create_dir_all(root.join("data")).unwrap();
+		for file in &["data/a.log", "data/b.tmp"] {
+			std::fs::write(root.join(file), b"").unwrap();
+		}
+		let excludes = root.join("excludes.txt");
+		std::fs::write(&excludes, "*.log\n").unwrap();
+
+		let mut target = BackupTarget::new_from_string(&[root.join("data")], vec![], vec![]).unwrap();
+		target.exclude_files = vec![excludes.clone()];
+		assert!(target.check_path_is_in_backup(root.join("data/a.log")).unwrap().excluded_by.is_some());
+
+		// Rewriting the file doesn't change what the target excludes until the cache is dropped
+		std::fs::write(&excludes, "*.tmp\n").unwrap();
+		assert!(target.check_path_is_in_backup(root.join("data/a.log")).unwrap().excluded_by.is_some());
+		assert!(target.check_path_is_in_backup(root.join("data/b.tmp")).unwrap().excluded_by.is_none());
+		target.exclude_file_cache.invalidate();
+		assert!(target.check_path_is_in_backup(root.join("data/a.log")).unwrap().excluded_by.is_none());
+		assert!(target.check_path_is_in_backup(root.join("data/b.tmp")).unwrap().excluded_by.is_some());
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn ignore_files_apply_below_their_directory() {
+		let root = crate::test_util::test_dir("ignore-file");
+		std::fs::create_dir_all(root.join("project/build")).unwrap();
+		std::fs::create_dir_all(root.join("project/src")).unwrap();
+		std::fs::create_dir_all(root.join("other/build")).unwrap();
+		std::fs::write(root.join("project/.backupignore"), "build/\n*.log\n!keep.log\n/src/generated\n").unwrap();
+		for file in &["project/build/out", "project/a.log", "project/keep.log", "project/src/generated", "project/src/main.rs", "other/build/out"] {
+			std::fs::write(root.join(file), b"").unwrap();
+		}
+
+		let mut target = BackupTarget::new_from_string(&[&root], vec![], vec![]).unwrap();
+		target.ignore_file_names = vec![".backupignore".to_owned()];
+		let relative = |threads| {
+			let mut paths = Vec::new();
+			let options = WalkOptions {
+				threads,
+				..Default::default()
+			};
+			target.walk_parallel(&options, |entry| {
+				let entry = entry.unwrap();
+				if entry.file_type().is_file() {
+					paths.push(entry.path().strip_prefix(&root).unwrap().to_owned());
+				}
+			});
+			paths.sort();
+			paths
+		};
+		let expected: Vec<PathBuf> = ["other/build/out", "project/.backupignore", "project/keep.log", "project/src/main.rs"]
+			.iter()
+			.map(PathBuf::from)
+			.collect();
+		assert_eq!(relative(1), expected);
+		assert_eq!(relative(4), expected);
+
+		let check = |file: &str| target.check_path_is_in_backup(root.join(file)).unwrap().selection;
+		assert_eq!(check("project/build/out"), BackupFileSelectionType::Excluded);
+		assert_eq!(check("project/keep.log"), BackupFileSelectionType::Included);
+		assert_eq!(check("other/build/out"), BackupFileSelectionType::Included);
+
+		let project = root.join("project").to_string_lossy().into_owned();
+		assert_eq!(
+			target.ignore_file_excludes(),
+			[
+				format!("{}/**/build", project),
+				format!("{}/**/*.log", project),
+				format!("!{}/**/keep.log", project),
+				format!("{}/src/generated", project),
+			]
+		);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn ignore_file_patterns_escape_their_directory() {
+		let root = crate::test_util::test_dir("ignore-file-escape");
+		let project = root.join("project [old]");
+		std::fs::create_dir_all(&project).unwrap();
+		std::fs::write(project.join(".backupignore"), "*.log
+").unwrap();
+
+		let mut target = BackupTarget::new_from_string(&[&root], vec![], vec![]).unwrap();
+		target.ignore_file_names = vec![".backupignore".to_owned()];
+		let escaped = escape_restic_glob(&project.to_string_lossy());
+		assert!(escaped.ends_with(if cfg!(windows) { "project [[]old]" } else { r"project \[old\]" }));
+		assert_eq!(target.ignore_file_excludes(), [format!("{}/**/*.log", escaped)]);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn inclusions_select_the_same_files_locally_and_for_restic() {
+		let root = crate::test_util::test_dir("inclusion");
+		std::fs::create_dir_all(root.join("docs/old")).unwrap();
+		std::fs::create_dir_all(root.join("Invoices")).unwrap();
+		std::fs::create_dir_all(root.join("misc")).unwrap();
+		for file in &[
+			"docs/a.docx",
+			"docs/b.pdf",
+			"docs/$HOME.txt",
+			"docs/old/c.xlsx",
+			"docs/old/d.docx",
+			"Invoices/2020.pdf",
+			"misc/notes.txt",
+		] {
+			std::fs::write(root.join(file), b"").unwrap();
+		}
+
+		let mut target = BackupTarget::new_from_string(&[&root], vec!["old".to_owned()], vec![]).unwrap();
+		target.inclusions = vec![
+			MyGlob::new("*.docx").unwrap(),
+			MyGlob::new("*.xlsx").unwrap(),
+			MyGlob::new("Invoices").unwrap(),
+		];
+
+		let mut local: Vec<PathBuf> = target
+			.walk()
+			.map(|entry| entry.unwrap())
+			.filter(|entry| !entry.file_type().is_dir())
+			.map(|entry| entry.into_path())
+			.collect();
+		local.sort();
+		assert_eq!(local, [root.join("Invoices/2020.pdf"), root.join("docs/a.docx")]);
+
+		// restic excludes the rest: single files, whole directories without an included file,
+		// and names restic would expand in an exclude file as patterns of their own
+		let lists = crate::test_util::test_dir("inclusion-lists");
+		let list = target.write_not_included(&lists).unwrap();
+		let prefix = escape_restic_glob(glob_path(&root).to_str().unwrap());
+		assert_eq!(list.included, 2);
+		assert_eq!(
+			std::fs::read_to_string(&list.path).unwrap(),
+			format!("{0}/docs/b.pdf\n{0}/misc\n", prefix)
+		);
+		assert_eq!(list.patterns, [format!("{}/docs/$HOME.txt", prefix)]);
+
+		let selection = target.check_path_is_in_backup(root.join("docs/b.pdf")).unwrap();
+		assert_eq!(selection.excluded_by, Some(ExcludedBy::NotIncluded));
+		let selection = target.check_path_is_in_backup(root.join("docs")).unwrap();
+		assert_eq!(selection.selection, BackupFileSelectionType::Included);
+
+		let list_path = list.path.clone();
+		drop(list);
+		assert!(!list_path.exists());
+
+		target.inclusions = vec![MyGlob::new("*.odt").unwrap()];
+		assert_eq!(target.write_not_included(&lists).unwrap().included, 0);
+		std::fs::remove_dir_all(&root).unwrap();
+		std::fs::remove_dir_all(&lists).unwrap();
+	}
+
+	#[test]
+	fn missing_folders_are_errors() {
+		let missing = Path::new("/does/not/exist");
+		let err = BackupTarget::new(&[missing], vec![], vec![]).unwrap_err();
+		assert!(matches!(err, BackupTargetError::Canonicalize { ref path, .. } if path == missing));
+
+		let mut target = BackupTarget::default();
+		assert!(target.add_folder(missing).is_err());
+		target.add_folder_with(missing, true).unwrap();
+		assert_eq!(target.folders, [missing]);
+		assert!(matches!(
+			target.add_folder_with("not/absolute", true),
+			Err(BackupTargetError::RelativePath(_))
+		));
+	}
+
+	#[test]
+	fn as_given_folders_are_resolved_when_used() {
+		let root = crate::test_util::test_dir("as-given");
+		std::fs::create_dir_all(root.join("present")).unwrap();
+		// The `..` stays until the target is used
+		let present = root.join("present/../present");
+		let drive = root.join("drive");
+
+		assert!(BackupTarget::new(&[&present, &drive], vec![], vec![]).is_err());
+		let target = BackupTarget::new_with_mode(&[&present, &drive], vec![], vec![], PathMode::AsGiven).unwrap();
+		assert_eq!(target.folders, vec![present.clone(), drive.clone()]);
+
+		let (resolved, unresolved) = target.resolve_folders();
+		assert_eq!(resolved, vec![root.join("present").canonicalize().unwrap()]);
+		assert_eq!(unresolved.len(), 1);
+		assert_eq!(unresolved[0].folder, drive);
+
+		let check = |path: PathBuf| target.check_path_is_in_backup(path).unwrap().selection;
+		assert_eq!(check(root.join("present/file")), BackupFileSelectionType::Included);
+		assert_eq!(check(root.join("drive")), BackupFileSelectionType::Irrelevant);
+
+		// Once the drive is there and the folders are refreshed, it is part of the backup
+		std::fs::create_dir_all(&drive).unwrap();
+		assert_eq!(check(drive.join("file")), BackupFileSelectionType::Irrelevant);
+		assert!(Arc::ptr_eq(&target.effective_folders(), &target.effective_folders()));
+		target.refresh_folders();
+		assert_eq!(check(drive.join("file")), BackupFileSelectionType::Included);
+		assert!(target.resolve_folders().1.is_empty());
+
+		// Older configs have no path mode
+		let json = r#"{"folders":["/data"],"exclusions":[],"tags":[]}"#;
+		assert_eq!(serde_json::from_str::<BackupTarget>(json).unwrap().path_mode, PathMode::Canonical);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn check_path_symlinks_and_missing_paths() {
+		let root = crate::test_util::test_dir("check-path");
+		std::fs::create_dir_all(root.join("backup/cache")).unwrap();
+		std::fs::create_dir_all(root.join("outside")).unwrap();
+		std::fs::write(root.join("outside/file"), b"").unwrap();
+		std::os::unix::fs::symlink(root.join("outside/file"), root.join("backup/link")).unwrap();
+
+		let target = BackupTarget::new_from_string(&[root.join("backup")], vec!["cache".to_owned()], vec![]).unwrap();
+		let check = |path: PathBuf| target.check_path_is_in_backup(path).unwrap().selection;
+		assert_eq!(check(root.join("backup/link")), BackupFileSelectionType::Included);
+		assert_eq!(check(root.join("outside/file")), BackupFileSelectionType::Irrelevant);
+		assert_eq!(check(root.join("backup/deleted")), BackupFileSelectionType::Included);
+		assert_eq!(check(root.join("backup/cache/deleted")), BackupFileSelectionType::Excluded);
+		assert_eq!(check(root.clone()), BackupFileSelectionType::Contains);
+		assert!(target.check_path_is_in_backup(root.join("missing/deleted")).is_err());
+
+		let excluded = target.check_path_is_in_backup(root.join("backup/cache/deleted")).unwrap();
+		assert_eq!(excluded.folder, Some(root.join("backup").canonicalize().unwrap()));
+		assert_eq!(
+			excluded.excluded_by,
+			Some(ExcludedBy::Pattern {
+				index: 0,
+				pattern: "cache".to_owned()
+			})
+		);
+		let contains = target.check_path_is_in_backup(&root).unwrap();
+		assert_eq!(contains.folder, excluded.folder);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn matches_snapshot_paths_and_tags() {
+		let target = BackupTarget {
+			folders: vec!["/home/me/docs".into(), "/home/me/photos".into()],
+			tags: vec!["laptop".into()],
+			..Default::default()
+		};
+		let mut snapshot: SnapshotsJson = serde_json::from_str(r#"{
+			"hostname": "laptop", "id": "0d9613ea8d2c", "short_id": "0d9613ea", "username": "me",
+			"time": "2020-01-01T10:00:00.123+01:00", "tree": "ab12cd34",
+			"paths": ["/home/me/photos/", "/home/me/docs"], "tags": ["laptop", "nightly"]
+		}"#).unwrap();
+		assert!(target.matches_snapshot(&snapshot));
+
+		snapshot.paths.pop();
+		assert!(!target.matches_snapshot(&snapshot));
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn windows_paths_match_globs() {
+		assert_eq!(strip_verbatim(Path::new(r"\\?\C:\Users\me")), Path::new(r"C:\Users\me"));
+		assert_eq!(strip_verbatim(Path::new(r"\\?\UNC\nas\share")), Path::new(r"\\nas\share"));
+		assert_eq!(strip_verbatim(Path::new(r"C:\Users\me")), Path::new(r"C:\Users\me"));
+
+		let absolute = MyGlob::new(r"C:\Users\me\AppData").unwrap().compile_matcher();
+		assert!(absolute.is_match(glob_path(Path::new(r"\\?\C:\Users\me\AppData"))));
+		assert!(absolute.is_match(glob_path(Path::new(r"C:\Users\me\AppData"))));
+		assert!(!absolute.is_match(glob_path(Path::new(r"D:\Users\me\AppData"))));
+
+		let relative = MyGlob::new("*.tmp").unwrap().compile_matcher();
+		assert!(relative.is_match(glob_path(Path::new(r"\\?\C:\Users\me\report.tmp"))));
 	}
 }
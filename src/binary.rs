@@ -0,0 +1,149 @@
+use crate::errors::*;
+use crate::priority::find_in_path;
+use crate::restic_version::ResticVersion;
+use crate::{ResticConfig, RESTIC_COMMAND};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The restic binary commands run, from `ResticConfig::locate_binary`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResticBinary {
+	pub path: PathBuf,
+	pub version: ResticVersion,
+}
+
+/// `program` itself when it names a path, or the first match in PATH. On windows `.exe` is
+/// added to names without an extension, like the system does when starting them.
+fn resolve_program(program: &Path) -> Option<PathBuf> {
+	let names: Vec<PathBuf> = if cfg!(windows) && program.extension().is_none() {
+		vec![program.with_extension("exe"), program.to_owned()]
+	} else {
+		vec![program.to_owned()]
+	};
+
+	if program.components().count() > 1 || program.is_absolute() {
+		return names.into_iter().find(|name| name.is_file());
+	}
+	names
+		.iter()
+		.filter_map(|name| name.to_str())
+		.find_map(find_in_path)
+}
+
+impl ResticConfig {
+	/// `restic_binary`, or restic's name to look up in PATH
+	pub(crate) fn restic_program(&self) -> &OsStr {
+		self.restic_binary
+			.as_ref()
+			.map(|path| path.as_os_str())
+			.unwrap_or_else(|| OsStr::new(RESTIC_COMMAND))
+	}
+
+	/// The error for restic failing to start, `ResticBinaryNotFound` when it doesn't exist
+	pub(crate) fn spawn_error(&self, err: std::io::Error) -> Error {
+		match err.kind() {
+			std::io::ErrorKind::NotFound => Error::with_chain(
+				err,
+				ErrorKind::ResticBinaryNotFound {
+					searched: PathBuf::from(self.restic_program()),
+				},
+			),
+			_ => Error::with_chain(err, "Failed to start restic"),
+		}
+	}
+
+	/// Find the restic binary commands would run and ask it for its version, for a preflight
+	/// check. Looks at `restic_binary` when set and searches PATH otherwise, or when
+	/// `restic_binary` is a bare name.
+	///
+	/// Fails with `ResticBinaryNotFound` when there is no such file. Neither the repository
+	/// nor the password are needed.
+	pub fn locate_binary(&self) -> Result<ResticBinary> {
+		let _span = operation_span!(self, "version");
+		let program = Path::new(self.restic_program());
+		let path = resolve_program(program).ok_or_else(|| ErrorKind::ResticBinaryNotFound {
+			searched: program.to_owned(),
+		})?;
+
+		let mut cmd = Command::new(&path);
+		cmd.arg("version").stdin(Stdio::null());
+		#[cfg(windows)]
+		{
+			use std::os::windows::process::CommandExt;
+			cmd.creation_flags(crate::priority::CREATE_NO_WINDOW);
+		}
+		let observation = self.observe_start("version", &cmd);
+		let output = match cmd.output() {
+			Ok(output) => output,
+			Err(err) => {
+				let err = self.spawn_error(err);
+				observation.finish(None, 0, 0, Some(&err));
+				return Err(err);
+			}
+		};
+		observation.finish(
+			output.status.code(),
+			output.stdout.len() as u64,
+			output.stderr.len() as u64,
+			None,
+		);
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let version = ResticVersion::from_version_output(&stdout).ok_or_else(|| {
+			ErrorKind::Msg(format!(
+				"{} doesn't look like restic, its version output was {:?}",
+				path.display(),
+				stdout.trim()
+			))
+		})?;
+		Ok(ResticBinary { path, version })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ResticStorageConfig;
+
+	fn config_with_binary(binary: &Path) -> ResticConfig {
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(binary.to_owned());
+		config
+	}
+
+	#[test]
+	fn missing_binary_has_its_own_error() {
+		let config = config_with_binary(Path::new("/nonexistent/restic"));
+		let err = config.locate_binary().unwrap_err();
+		match err.kind() {
+			ErrorKind::ResticBinaryNotFound { searched } => assert_eq!(searched, Path::new("/nonexistent/restic")),
+			other => panic!("unexpected error {:?}", other),
+		}
+
+		let err = config.run_raw(&[OsStr::new("version")]).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::ResticBinaryNotFound { .. }));
+
+		let config = config_with_binary(Path::new("restic-interfacer-not-installed"));
+		assert!(matches!(
+			config.locate_binary().unwrap_err().kind(),
+			ErrorKind::ResticBinaryNotFound { .. }
+		));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn locates_binary_and_version() {
+		let dir = crate::test_util::test_dir("locate-binary");
+		let script = "#!/bin/sh\necho 'restic 0.16.4 compiled with go1.21.6 on linux/amd64'\n";
+		let restic = crate::test_util::fake_restic(&dir, script);
+
+		// The password isn't needed, so a missing one doesn't matter
+		let mut config = config_with_binary(&restic);
+		config.set_password(crate::PasswordSource::Redacted);
+		let binary = config.locate_binary().unwrap();
+		assert_eq!(binary.path, restic);
+		assert_eq!(binary.version, ResticVersion::new(0, 16, 4));
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,222 @@
+use crate::errors::*;
+use crate::snapshot_id::validate_id;
+use crate::repo_format::{Compression, COMPRESSION_REPO_VERSION};
+use crate::{
+	BackendOption, CommandObserver, FreeSpaceCheck, PasswordSource, ProcessPriority, RepositorySource, ResticConfig,
+	ResticStorageConfig, RetryPolicy,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Fluent construction of a `ResticConfig`, validated in `build`
+#[derive(Debug, Clone)]
+pub struct ResticConfigBuilder {
+	config: ResticConfig,
+}
+
+impl ResticConfigBuilder {
+	pub fn new<P: Into<PasswordSource>, R: Into<RepositorySource>>(repo_password: P, repo_path: R) -> Self {
+		ResticConfigBuilder {
+			config: ResticConfig::new(repo_password, repo_path),
+		}
+	}
+
+	pub fn isolate_env(mut self, isolate_env: bool) -> Self {
+		self.config.isolate_env = isolate_env;
+		self
+	}
+
+	/// Set an additional environment variable on every restic invocation
+	pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, val: V) -> Self {
+		self.config.extra_env.insert(key.into(), val.into());
+		self
+	}
+
+	pub fn max_cpus(mut self, max_cpus: u32) -> Self {
+		self.config.max_cpus = Some(max_cpus);
+		self
+	}
+
+	pub fn temp_dir<P: Into<PathBuf>>(mut self, temp_dir: P) -> Self {
+		self.config.temp_dir = Some(temp_dir.into());
+		self
+	}
+
+	pub fn cache_dir<P: Into<PathBuf>>(mut self, cache_dir: P) -> Self {
+		self.config.cache_dir = Some(cache_dir.into());
+		self
+	}
+
+	pub fn no_cache(mut self, no_cache: bool) -> Self {
+		self.config.no_cache = no_cache;
+		self
+	}
+
+	/// Remove old cache directories as part of every command
+	pub fn cleanup_cache_on_run(mut self, cleanup_cache_on_run: bool) -> Self {
+		self.config.cleanup_cache_on_run = cleanup_cache_on_run;
+		self
+	}
+
+	/// Pass an extended backend option to every command
+	pub fn backend_option(mut self, option: BackendOption) -> Self {
+		self.config.backend_options.push(option);
+		self
+	}
+
+	pub fn priority(mut self, priority: ProcessPriority) -> Self {
+		self.config.priority = Some(priority);
+		self
+	}
+
+	/// Saved with the config, except for a custom `RetryPolicy::classify`
+	pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+		self.config.retry_policy = Some(retry_policy);
+		self
+	}
+
+	pub fn auto_unlock_stale(mut self, auto_unlock_stale: bool) -> Self {
+		self.config.auto_unlock_stale = auto_unlock_stale;
+		self
+	}
+
+	/// Told about every restic invocation, see `ResticConfig::set_observer`
+	pub fn observer(mut self, observer: Arc<dyn CommandObserver>) -> Self {
+		self.config.set_observer(observer);
+		self
+	}
+
+	/// Check that a local repository has room before each backup
+	pub fn free_space_check(mut self, check: FreeSpaceCheck) -> Self {
+		self.config.free_space_check = Some(check);
+		self
+	}
+
+	/// Whether read-only commands run with `--no-lock`, on by default
+	pub fn no_lock(mut self, no_lock: bool) -> Self {
+		self.config.no_lock = no_lock;
+		self
+	}
+
+	/// Passwords to try when `repo_password` is rejected, see `ResticConfig::with_fallback_passwords`
+	pub fn fallback_passwords(mut self, passwords: Vec<PasswordSource>) -> Self {
+		self.config.fallback_passwords = passwords;
+		self
+	}
+
+	pub fn restic_binary<P: Into<PathBuf>>(mut self, restic_binary: P) -> Self {
+		self.config.restic_binary = Some(restic_binary.into());
+		self
+	}
+
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.config.timeout = Some(timeout);
+		self
+	}
+
+	/// Also requires a version 2 repository, see `ResticConfig::min_repo_version`
+	pub fn compression(mut self, compression: Compression) -> Self {
+		self.config.compression = Some(compression);
+		self.require_repo_version(COMPRESSION_REPO_VERSION)
+	}
+
+	/// Pack size in MiB, also requires a version 2 repository
+	pub fn pack_size(mut self, pack_size: u32) -> Self {
+		self.config.pack_size = Some(pack_size);
+		self.require_repo_version(COMPRESSION_REPO_VERSION)
+	}
+
+	/// Raise `min_repo_version` to at least `min`
+	pub fn require_repo_version(mut self, min: u32) -> Self {
+		self.config.min_repo_version = Some(self.config.min_repo_version.map_or(min, |current| current.max(min)));
+		self
+	}
+
+	pub fn key_hint<S: Into<String>>(mut self, key_hint: S) -> Self {
+		self.config.key_hint = Some(key_hint.into());
+		self
+	}
+
+	pub fn build(self) -> Result<ResticConfig> {
+		let config = self.config;
+		let invalid = |reason: &str| -> Result<ResticConfig> { Err(ErrorKind::InvalidConfig(reason.to_owned()).into()) };
+
+		if let RepositorySource::Inline(ResticStorageConfig::Local(path)) = &config.repo_path {
+			if path.as_os_str().is_empty() {
+				return invalid("local repository path is empty");
+			}
+		}
+		if config.max_cpus == Some(0) {
+			return invalid("max_cpus must be at least 1");
+		}
+		if config.no_cache && config.cleanup_cache_on_run {
+			return invalid("cleanup_cache_on_run can't be combined with no_cache");
+		}
+		if config.timeout == Some(Duration::from_secs(0)) {
+			return invalid("timeout must be greater than zero");
+		}
+		if let Some(retry_policy) = &config.retry_policy {
+			if retry_policy.max_attempts == 0 {
+				return invalid("retry policy needs at least one attempt");
+			}
+		}
+		if config.restic_binary.as_ref().map(|path| path.as_os_str().is_empty()).unwrap_or(false) {
+			return invalid("restic binary path is empty");
+		}
+		if let Some(key_hint) = &config.key_hint {
+			if let Err(err) = validate_id(key_hint) {
+				return invalid(&format!("key hint: {}", err));
+			}
+		}
+
+		Ok(config)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn builder_matches_hand_built_config() {
+		let built = ResticConfig::builder("1234", ResticStorageConfig::Local("/srv/repo".into()))
+			.cache_dir("/var/cache/restic")
+			.max_cpus(2)
+			.timeout(Duration::from_secs(3600))
+			.env("RESTIC_PACK_SIZE", "64")
+			.build()
+			.unwrap();
+
+		let mut hand_built = ResticConfig::new("1234", ResticStorageConfig::Local("/srv/repo".into()));
+		hand_built.cache_dir = Some("/var/cache/restic".into());
+		hand_built.max_cpus = Some(2);
+		hand_built.timeout = Some(Duration::from_secs(3600));
+		hand_built.extra_env.insert("RESTIC_PACK_SIZE".into(), "64".into());
+
+		assert_eq!(
+			serde_json::to_string(&built).unwrap(),
+			serde_json::to_string(&hand_built).unwrap()
+		);
+	}
+
+	#[test]
+	fn build_validates() {
+		let local = |path: &str| ResticConfig::builder("1234", ResticStorageConfig::Local(path.into()));
+		assert!(local("").build().is_err());
+		assert!(local("/srv/repo").max_cpus(0).build().is_err());
+		assert!(local("/srv/repo").timeout(Duration::from_secs(0)).build().is_err());
+		assert!(local("/srv/repo").no_cache(true).cleanup_cache_on_run(true).build().is_err());
+		assert!(local("/srv/repo").build().is_ok());
+	}
+
+	#[test]
+	fn format_options_require_repo_version() {
+		let config = ResticConfig::builder("1234", ResticStorageConfig::Local("/srv/repo".into()))
+			.require_repo_version(1)
+			.compression(Compression::Max)
+			.build()
+			.unwrap();
+		assert_eq!(config.min_repo_version, Some(COMPRESSION_REPO_VERSION));
+	}
+}
@@ -0,0 +1,70 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::{SnapshotDetailJson, TreeJson};
+use crate::snapshot_id::validate_full_id;
+use crate::{ResticConfig, SnapshotId};
+use std::process::Command;
+
+impl ResticConfig {
+	fn cat_cmd(&self, object: &str, id: &str) -> Result<Command> {
+		let mut cmd = self.read_only_cmd_setup()?;
+		cmd.arg("cat").arg(object).arg(id);
+		Ok(cmd)
+	}
+
+	/// The stored snapshot object, including the id of its root tree
+	pub fn cat_snapshot(&self, id: &SnapshotId) -> Result<SnapshotDetailJson> {
+		let _span = operation_span!(self, "cat");
+		let mut cmd = self.cat_cmd("snapshot", id.as_str())?;
+		self.output_parsing("cat", &mut cmd, |stdout_data| {
+			parse_json("cat snapshot", &stdout_data)
+		})
+	}
+
+	/// The entries of one directory, read from its tree blob. Directories have the id of their
+	/// own tree in `subtree`, so a snapshot can be browsed one directory at a time starting at
+	/// `cat_snapshot(..).tree`.
+	///
+	/// restic only looks up blobs by their full id, shortened ids are rejected with `InvalidId`.
+	pub fn cat_tree(&self, tree_id: &str) -> Result<TreeJson> {
+		let _span = operation_span!(self, "cat");
+		validate_full_id(tree_id).map_err(ErrorKind::InvalidId)?;
+		let mut cmd = self.cat_cmd("blob", &tree_id.to_ascii_lowercase())?;
+		self.output_parsing("cat", &mut cmd, |stdout_data| {
+			parse_json("cat blob", &stdout_data)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::restic_outputs::NodeType;
+	use crate::ResticStorageConfig;
+
+	#[test]
+	fn parses_snapshot_and_tree() {
+		let snapshot: SnapshotDetailJson = serde_json::from_str(include_str!("../fixtures/restic_cat/snapshot.json")).unwrap();
+		assert_eq!(snapshot.tree.len(), 64);
+		assert_eq!(snapshot.excludes, vec!["*.tmp"]);
+
+		let tree: TreeJson = serde_json::from_str(include_str!("../fixtures/restic_cat/tree.json")).unwrap();
+		let dir = tree.nodes.iter().find(|node| node.node_type == NodeType::Dir).unwrap();
+		assert_eq!(dir.name, "documents");
+		assert!(dir.subtree.is_some());
+		assert!(dir.content.is_empty());
+		let file = tree.nodes.iter().find(|node| node.node_type == NodeType::File).unwrap();
+		assert_eq!(file.content.len(), 2);
+		assert_eq!(file.subtree, None);
+
+		let empty: TreeJson = serde_json::from_str(r#"{"nodes":null}"#).unwrap();
+		assert!(empty.nodes.is_empty());
+	}
+
+	#[test]
+	fn tree_ids_must_be_full() {
+		let config = ResticConfig::new("1234".to_owned(), ResticStorageConfig::Local("./sample_repo".into()));
+		let err = config.cat_tree("0d9613ea").unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InvalidId(_)));
+	}
+}
@@ -0,0 +1,368 @@
+use crate::errors::*;
+use crate::ResticConfig;
+use std::process::Command;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CheckOptions {
+	/// Also read and verify all pack files, which downloads the whole repository
+	pub read_data: bool,
+	/// Only read a subset of the pack files, e.g. `1/5` or `10%`
+	pub read_data_subset: Option<String>,
+	/// Use the repository's cache (`--with-cache`) instead of a fresh temporary one, so the
+	/// index isn't downloaded again on every check. Much faster and cheaper on paid backends,
+	/// but a stale or damaged cache can hide problems in the repository. Set
+	/// `ResticConfig::cache_dir` to share the cache of the backups. Ignored with
+	/// `ResticConfig::no_cache`.
+	pub with_cache: bool,
+}
+
+/// Outcome of `check`, problems found in the repository are reported here instead of as errors
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CheckReport {
+	/// No problems were found
+	pub ok: bool,
+	/// Lines restic printed about problems it found
+	pub errors: Vec<String>,
+	/// The problems classified, see `CheckFinding`
+	pub findings: Vec<CheckFinding>,
+}
+
+/// A problem `check` reported
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CheckFinding {
+	/// Blobs no snapshot references, `prune` removes them
+	UnusedBlobs { count: u64 },
+	/// A damaged or unreadable pack file
+	PackError { pack_id: String, message: String },
+	/// A damaged tree, `path` is the name of the entry restic complained about if it named one
+	TreeError {
+		tree_id: String,
+		path: Option<String>,
+		message: String,
+	},
+	/// An error line none of the patterns matched
+	Other(String),
+}
+
+impl CheckFinding {
+	/// Pack and tree errors, which mean data is damaged or missing and need a human to look at
+	/// them. Unused blobs are harmless.
+	pub fn is_damage(&self) -> bool {
+		matches!(self, CheckFinding::PackError { .. } | CheckFinding::TreeError { .. })
+	}
+}
+
+impl CheckReport {
+	/// Number of unused blobs reported, a reason to schedule a prune
+	pub fn unused_blobs(&self) -> u64 {
+		self.findings
+			.iter()
+			.map(|finding| match finding {
+				CheckFinding::UnusedBlobs { count } => *count,
+				_ => 0,
+			})
+			.sum()
+	}
+
+	pub fn has_damage(&self) -> bool {
+		self.findings.iter().any(CheckFinding::is_damage)
+	}
+}
+
+/// Verifies the whole repository over several check runs, each reading the next of `buckets`
+/// subsets with `--read-data-subset n/m`. Store `state` between runs to continue where the
+/// last one stopped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ReadDataRotation {
+	buckets: u32,
+	/// The subset the next check reads, 1 to `buckets`
+	next: u32,
+}
+
+impl ReadDataRotation {
+	/// Start a rotation over `buckets` subsets at the first one, fails for 0 buckets
+	pub fn new(buckets: u32) -> Result<ReadDataRotation> {
+		if buckets == 0 {
+			return Err(ErrorKind::InvalidConfig("read data rotation needs at least one bucket".to_owned()).into());
+		}
+		Ok(ReadDataRotation { buckets, next: 1 })
+	}
+
+	/// Continue a rotation from a `state` string. A state from a rotation with a different
+	/// number of buckets starts over, its position means something else.
+	pub fn from_state(buckets: u32, state: &str) -> Result<ReadDataRotation> {
+		let mut rotation = ReadDataRotation::new(buckets)?;
+		let parsed = state
+			.split_once('/')
+			.and_then(|(next, buckets)| Some((next.trim().parse::<u32>().ok()?, buckets.trim().parse::<u32>().ok()?)));
+		match parsed {
+			Some((next, state_buckets)) if state_buckets == buckets && (1..=buckets).contains(&next) => {
+				rotation.next = next
+			}
+			Some((_, state_buckets)) if state_buckets != buckets => (),
+			_ => return Err(ErrorKind::InvalidConfig(format!("invalid read data rotation state {:?}", state)).into()),
+		}
+		Ok(rotation)
+	}
+
+	pub fn buckets(&self) -> u32 {
+		self.buckets
+	}
+
+	/// The subset the next check reads, in restic's `n/m` form
+	pub fn next_subset(&self) -> String {
+		format!("{}/{}", self.next, self.buckets)
+	}
+
+	/// Position to store between runs, read back with `from_state`
+	pub fn state(&self) -> String {
+		self.next_subset()
+	}
+
+	fn advance(&mut self) {
+		self.next = self.next % self.buckets + 1;
+	}
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum FindingPattern {
+	/// `unused blob <id>`, one line per blob
+	UnusedBlob,
+	/// `pack <id>: <message>`
+	Pack,
+	/// `tree <id>: <message>`, the message usually names the entry in quotes
+	Tree,
+	/// Lines that only introduce the following ones, e.g. `error for tree <id>:`
+	Skip,
+}
+
+/// How restic words what check finds, matched against the start of each trimmed output line
+/// in order. Extend this when restic's wording changes.
+const FINDING_PATTERNS: &[(&str, FindingPattern)] = &[
+	("error for tree ", FindingPattern::Skip),
+	("unused blob ", FindingPattern::UnusedBlob),
+	("pack ", FindingPattern::Pack),
+	("tree ", FindingPattern::Tree),
+];
+
+/// Restic's short ids are 8 hex characters, full ones 64
+fn is_object_id(id: &str) -> bool {
+	id.len() >= 8 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Classify one output line, None for lines that aren't about a problem
+fn classify_line(line: &str) -> Option<CheckFinding> {
+	let line = line.trim();
+	for (prefix, pattern) in FINDING_PATTERNS {
+		let rest = match line.strip_prefix(prefix) {
+			Some(rest) => rest,
+			None => continue,
+		};
+		match pattern {
+			FindingPattern::Skip => return None,
+			FindingPattern::UnusedBlob if is_object_id(rest) => return Some(CheckFinding::UnusedBlobs { count: 1 }),
+			FindingPattern::Pack | FindingPattern::Tree => {
+				let (id, message) = match rest.split_once(':') {
+					Some((id, message)) if is_object_id(id) => (id.to_owned(), message.trim().to_owned()),
+					_ => continue,
+				};
+				return Some(if *pattern == FindingPattern::Pack {
+					CheckFinding::PackError { pack_id: id, message }
+				} else {
+					let path = message.split('"').nth(1).map(str::to_owned);
+					CheckFinding::TreeError {
+						tree_id: id,
+						path,
+						message,
+					}
+				});
+			}
+			_ => continue,
+		}
+	}
+	if line.to_lowercase().contains("error") {
+		return Some(CheckFinding::Other(line.to_owned()));
+	}
+	None
+}
+
+/// Classify the output of check, unused blob lines are summed up into one finding
+fn classify_findings<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<CheckFinding> {
+	let mut unused = 0;
+	let mut findings = Vec::new();
+	for finding in lines.filter_map(classify_line) {
+		match finding {
+			CheckFinding::UnusedBlobs { count } => unused += count,
+			finding => findings.push(finding),
+		}
+	}
+	if unused > 0 {
+		findings.insert(0, CheckFinding::UnusedBlobs { count: unused });
+	}
+	findings
+}
+
+impl ResticConfig {
+	pub(crate) fn check_cmd_with(&self, options: &CheckOptions) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("check");
+		if let Some(subset) = &options.read_data_subset {
+			cmd.arg(format!("--read-data-subset={}", subset));
+		} else if options.read_data {
+			cmd.arg("--read-data");
+		}
+		if options.with_cache && !self.no_cache {
+			cmd.arg("--with-cache");
+		}
+		Ok(cmd)
+	}
+
+	/// Check the repository for errors.
+	///
+	/// Fails only when the check couldn't run (wrong password, locked, unreachable...),
+	/// damage found in the repository is reported in the `CheckReport`.
+	pub fn check(&self, options: &CheckOptions) -> Result<CheckReport> {
+		let _span = operation_span!(self, "check");
+		let output = self.run_captured("check", &mut self.check_cmd_with(options)?)?;
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		// Unused blobs are reported by a successful check as well
+		let findings = classify_findings(stdout.lines().chain(stderr.lines()));
+		if output.status.success() {
+			return Ok(CheckReport {
+				ok: true,
+				errors: Vec::new(),
+				findings,
+			});
+		}
+
+		let err = crate::classify::classify_failure("check", &output);
+		if !matches!(err.kind(), ErrorKind::ResticCommandFailed(_)) {
+			return Err(err);
+		}
+
+		Ok(CheckReport {
+			ok: false,
+			errors: stdout
+				.lines()
+				.chain(stderr.lines())
+				.filter(|line| line.to_lowercase().contains("error"))
+				.map(|line| line.trim().to_owned())
+				.collect(),
+			findings,
+		})
+	}
+
+	/// Check reading the next subset of `rotation`, which is advanced once the check ran, also
+	/// when it found problems. Returns the report and the state to store for the next run.
+	pub fn check_rotating(&self, rotation: &mut ReadDataRotation) -> Result<(CheckReport, String)> {
+		let _span = operation_span!(self, "check");
+		let options = CheckOptions {
+			read_data_subset: Some(rotation.next_subset()),
+			..CheckOptions::default()
+		};
+		let report = self.check(&options)?;
+		rotation.advance();
+		Ok((report, rotation.state()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn classifies_check_output() {
+		let output = r#"using temporary cache in /tmp/restic-check-cache-123
+load indexes
+check all packs
+pack 8ac1fbf6: not referenced in any index
+check snapshots, trees and blobs
+error for tree 5d6e7f8a:
+  tree 5d6e7f8a: file "report.pdf" blob 0 size could not be found
+  tree 5d6e7f8a: file "notes.txt" blob 1a2b3c4d not found in index
+unused blob 0f1e2d3c
+unused blob 4b5a6978
+Fatal: repository contains errors"#;
+		let findings = classify_findings(output.lines());
+		assert_eq!(
+			findings,
+			vec![
+				CheckFinding::UnusedBlobs { count: 2 },
+				CheckFinding::PackError {
+					pack_id: "8ac1fbf6".to_owned(),
+					message: "not referenced in any index".to_owned(),
+				},
+				CheckFinding::TreeError {
+					tree_id: "5d6e7f8a".to_owned(),
+					path: Some("report.pdf".to_owned()),
+					message: r#"file "report.pdf" blob 0 size could not be found"#.to_owned(),
+				},
+				CheckFinding::TreeError {
+					tree_id: "5d6e7f8a".to_owned(),
+					path: Some("notes.txt".to_owned()),
+					message: r#"file "notes.txt" blob 1a2b3c4d not found in index"#.to_owned(),
+				},
+				CheckFinding::Other("Fatal: repository contains errors".to_owned()),
+			]
+		);
+
+		let report = CheckReport {
+			findings,
+			..Default::default()
+		};
+		assert_eq!(report.unused_blobs(), 2);
+		assert!(report.has_damage());
+		assert!(!CheckFinding::UnusedBlobs { count: 2 }.is_damage());
+	}
+
+	#[test]
+	fn checks_with_the_repository_cache() {
+		let args = |config: &ResticConfig| -> Vec<String> {
+			let options = CheckOptions {
+				with_cache: true,
+				..CheckOptions::default()
+			};
+			let cmd = config.check_cmd_with(&options).unwrap();
+			cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+		};
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local("./sample_repo".into()));
+		config.cache_dir = Some(std::env::temp_dir().join("restic-interfacer-check-cache-test"));
+		let with_cache = args(&config);
+		assert!(with_cache.contains(&"--with-cache".to_owned()));
+		let cache_dir = with_cache.iter().position(|arg| arg == "--cache-dir").unwrap();
+		assert!(with_cache[cache_dir + 1].ends_with("restic-interfacer-check-cache-test"));
+
+		config.no_cache = true;
+		assert!(!args(&config).contains(&"--with-cache".to_owned()));
+	}
+
+	#[test]
+	fn rotation_cycles_and_resumes() {
+		let mut rotation = ReadDataRotation::new(3).unwrap();
+		let mut subsets = Vec::new();
+		for _ in 0..4 {
+			subsets.push(rotation.next_subset());
+			rotation.advance();
+		}
+		assert_eq!(subsets, ["1/3", "2/3", "3/3", "1/3"]);
+
+		let resumed = ReadDataRotation::from_state(3, &rotation.state()).unwrap();
+		assert_eq!(resumed.next_subset(), "2/3");
+		// Another bucket count starts over
+		assert_eq!(ReadDataRotation::from_state(30, "2/3").unwrap().next_subset(), "1/30");
+		assert!(ReadDataRotation::from_state(3, "4/3").is_err());
+		assert!(ReadDataRotation::from_state(3, "garbage").is_err());
+		assert!(ReadDataRotation::new(0).is_err());
+	}
+
+	#[test]
+	fn unmatched_wording_falls_back() {
+		// Starts like a pack error, but without an id it's only an error line
+		assert_eq!(
+			classify_line("pack file cannot be listed: error reading"),
+			Some(CheckFinding::Other("pack file cannot be listed: error reading".to_owned()))
+		);
+		assert_eq!(classify_line("check snapshots, trees and blobs"), None);
+	}
+}
@@ -0,0 +1,185 @@
+use crate::errors::*;
+use crate::lock::LockInfo;
+use std::process::Output;
+
+/// Exit codes documented by restic, older versions exit with 1 for everything
+const EXIT_PARTIAL_BACKUP: i32 = 3;
+const EXIT_REPO_NOT_FOUND: i32 = 10;
+const EXIT_REPO_LOCKED: i32 = 11;
+const EXIT_WRONG_PASSWORD: i32 = 12;
+
+/// stderr fragments of backends that couldn't be reached at all, checked before
+/// `REPO_NOT_FOUND_PATTERNS` since restic reports those as failing to open the repository too.
+/// Certificate errors (`x509:`) aren't among them, they won't go away by retrying.
+const UNREACHABLE_PATTERNS: &[&str] = &[
+	"connection refused",
+	"no such host",
+	"network is unreachable",
+	"no route to host",
+	"tls handshake timeout",
+	"i/o timeout",
+	"dial tcp",
+];
+
+/// restic's wording for a missing config file. Not "unable to open repository", restic prints
+/// that for every failure to open one, e.g. an untrusted certificate.
+const REPO_NOT_FOUND_PATTERNS: &[&str] = &[
+	"unable to open config file",
+	"repository does not exist",
+	"is there a repository at the following location",
+];
+
+/// Subcommands `ResticConfig::no_lock` runs with `--no-lock`
+const READ_ONLY_SUBCOMMANDS: &[&str] = &["snapshots", "ls", "find", "stats", "cat"];
+
+/// Amount of stdout kept in a `CommandFailure`, taken from the end where restic's summary is
+const STDOUT_EXCERPT_LEN: usize = 2048;
+
+/// Turn a finished restic process into its output, or the error describing why it failed
+pub(crate) fn check_output(subcommand: &str, output: Output) -> Result<Output> {
+	if output.status.success() {
+		Ok(output)
+	} else {
+		Err(classify_failure(subcommand, &output))
+	}
+}
+
+pub(crate) fn classify_failure(subcommand: &str, output: &Output) -> Error {
+	let failure = command_failure(subcommand, output);
+
+	let kind = match classify_exit_code(&failure).or_else(|| classify_stderr(&failure)) {
+		Some(ErrorKind::ResticRepoLocked(mut lock)) => {
+			lock.read_only_command = READ_ONLY_SUBCOMMANDS.contains(&subcommand);
+			ErrorKind::ResticRepoLocked(lock)
+		}
+		Some(kind) => kind,
+		None => return ErrorKind::ResticCommandFailed(failure).into(),
+	};
+
+	Error::with_chain(Error::from(ErrorKind::ResticCommandFailed(failure)), kind)
+}
+
+fn classify_exit_code(failure: &CommandFailure) -> Option<ErrorKind> {
+	match failure.exit_code? {
+		EXIT_PARTIAL_BACKUP => Some(ErrorKind::PartialBackup(None)),
+		EXIT_REPO_NOT_FOUND => Some(ErrorKind::ResticRepoNotFound),
+		EXIT_REPO_LOCKED => Some(ErrorKind::ResticRepoLocked(
+			LockInfo::from_stderr(&failure.stderr).unwrap_or_default(),
+		)),
+		EXIT_WRONG_PASSWORD => Some(ErrorKind::ResticRepoInvalidPassword),
+		_ => None,
+	}
+}
+
+/// Fallback for restic versions that exit with 1 on every error
+fn classify_stderr(failure: &CommandFailure) -> Option<ErrorKind> {
+	let stderr = failure.stderr.to_lowercase();
+	let matches_any = |patterns: &[&str]| patterns.iter().any(|pattern| stderr.contains(pattern));
+
+	if let Some(lock) = LockInfo::from_stderr(&failure.stderr) {
+		Some(ErrorKind::ResticRepoLocked(lock))
+	} else if stderr.contains("wrong password") {
+		Some(ErrorKind::ResticRepoInvalidPassword)
+	} else if matches_any(UNREACHABLE_PATTERNS) {
+		Some(ErrorKind::BackendUnreachable)
+	} else if matches_any(REPO_NOT_FOUND_PATTERNS) {
+		Some(ErrorKind::ResticRepoNotFound)
+	} else {
+		None
+	}
+}
+
+fn command_failure(subcommand: &str, output: &Output) -> CommandFailure {
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let mut excerpt_start = stdout.len().saturating_sub(STDOUT_EXCERPT_LEN);
+	while !stdout.is_char_boundary(excerpt_start) {
+		excerpt_start += 1;
+	}
+
+	CommandFailure {
+		subcommand: subcommand.to_owned(),
+		exit_code: output.status.code(),
+		stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+		stdout_excerpt: stdout[excerpt_start..].to_owned(),
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use std::os::unix::process::ExitStatusExt;
+	use std::process::ExitStatus;
+
+	fn output(code: i32, stderr: &str) -> Output {
+		Output {
+			status: ExitStatus::from_raw(code << 8),
+			stdout: b"{\"message_type\":\"summary\"}".to_vec(),
+			stderr: stderr.as_bytes().to_vec(),
+		}
+	}
+
+	fn kind_for(code: i32, stderr: &str) -> Error {
+		classify_failure("backup", &output(code, stderr))
+	}
+
+	#[test]
+	fn classifies_by_exit_code() {
+		assert!(matches!(kind_for(3, "error: read /root: permission denied").kind(), ErrorKind::PartialBackup(None)));
+		assert!(matches!(kind_for(10, "Fatal: repository does not exist").kind(), ErrorKind::ResticRepoNotFound));
+		assert!(matches!(kind_for(11, "unable to create lock in backend").kind(), ErrorKind::ResticRepoLocked(_)));
+		assert!(matches!(kind_for(12, "Fatal: wrong password or no key found").kind(), ErrorKind::ResticRepoInvalidPassword));
+	}
+
+	#[test]
+	fn locked_read_only_commands_mention_no_lock() {
+		let locked = "unable to create lock in backend: repository is already locked by PID 1 on host by user";
+		let err = classify_failure("snapshots", &output(11, locked));
+		assert!(matches!(err.kind(), ErrorKind::ResticRepoLocked(LockInfo { read_only_command: true, .. })));
+		assert!(err.to_string().contains("no_lock"));
+
+		let err = kind_for(11, locked);
+		assert!(matches!(err.kind(), ErrorKind::ResticRepoLocked(LockInfo { read_only_command: false, .. })));
+		assert!(!err.to_string().contains("no_lock"));
+	}
+
+	#[test]
+	fn falls_back_to_stderr() {
+		assert!(matches!(kind_for(1, "Fatal: wrong password or no key found").kind(), ErrorKind::ResticRepoInvalidPassword));
+		assert!(matches!(
+			kind_for(1, "unable to create lock in backend: repository is already locked by PID 1 on host by user").kind(),
+			ErrorKind::ResticRepoLocked(_)
+		));
+		assert!(matches!(kind_for(1, "Fatal: something else").kind(), ErrorKind::ResticCommandFailed(_)));
+	}
+
+	#[test]
+	fn distinguishes_missing_repo_from_unreachable_backend() {
+		let missing = "Fatal: unable to open config file: stat /mnt/backup/config: no such file or directory
+Is there a repository at the following location?
+/mnt/backup";
+		assert!(matches!(kind_for(1, missing).kind(), ErrorKind::ResticRepoNotFound));
+
+		let dns = "Fatal: unable to open repository at s3:s3.example.com/bucket: Get \"https://s3.example.com/bucket\": dial tcp: lookup s3.example.com: no such host";
+		assert!(matches!(kind_for(1, dns).kind(), ErrorKind::BackendUnreachable));
+
+		let refused = "Fatal: unable to open repository at rest:http://nas:8000/: dial tcp 10.0.0.2:8000: connect: connection refused";
+		assert!(matches!(kind_for(1, refused).kind(), ErrorKind::BackendUnreachable));
+
+		let handshake = "Fatal: unable to open repository at rest:https://nas:8000/: Get \"https://nas:8000/config\": net/http: TLS handshake timeout";
+		assert!(matches!(kind_for(1, handshake).kind(), ErrorKind::BackendUnreachable));
+
+		let certificate = "Fatal: unable to open repository at rest:https://nas:8000/: Get \"https://nas:8000/config\": x509: certificate signed by unknown authority";
+		let err = kind_for(1, certificate);
+		assert!(matches!(err.kind(), ErrorKind::ResticCommandFailed(_)));
+		assert!(!crate::retry::is_transient_error(&err));
+	}
+
+	#[test]
+	fn keeps_command_failure() {
+		let err = classify_failure("backup", &output(12, "Fatal: wrong password or no key found"));
+		let failure = err.command_failure().unwrap();
+		assert_eq!(failure.subcommand, "backup");
+		assert_eq!(failure.exit_code, Some(12));
+		assert!(failure.stdout_excerpt.contains("summary"));
+	}
+}
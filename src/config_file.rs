@@ -0,0 +1,238 @@
+use crate::{BackupTarget, ResticConfig};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Version written to config files, files with a newer version are rejected
+pub const CONFIG_SCHEMA_VERSION: u32 = 1;
+
+/// Why a config file couldn't be loaded or saved
+#[derive(Debug, Error)]
+pub enum ConfigFileError {
+	#[error("Failed to access {}: {source}", .path.display())]
+	Io { path: PathBuf, source: std::io::Error },
+	#[error("{}{}: {message}", .path.display(), .line.map(|line| format!(":{}", line)).unwrap_or_default())]
+	Parse {
+		path: PathBuf,
+		/// 1 based line of the error, when the parser reports it
+		line: Option<usize>,
+		message: String,
+	},
+	#[error("{} has schema version {found}, only up to {} is supported", .path.display(), CONFIG_SCHEMA_VERSION)]
+	UnsupportedVersion { path: PathBuf, found: u32 },
+	#[error("Can't tell the format of {} from its extension, or support for it is not enabled", .0.display())]
+	UnknownFormat(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+	Json,
+	#[cfg(feature = "toml")]
+	Toml,
+	#[cfg(feature = "yaml")]
+	Yaml,
+}
+
+impl Format {
+	/// `.json` always works, `.toml` and `.yaml`/`.yml` need the `toml` and `yaml` features
+	fn for_path(path: &Path) -> Result<Format, ConfigFileError> {
+		let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+		match extension.to_ascii_lowercase().as_str() {
+			"json" => Ok(Format::Json),
+			#[cfg(feature = "toml")]
+			"toml" => Ok(Format::Toml),
+			#[cfg(feature = "yaml")]
+			"yaml" | "yml" => Ok(Format::Yaml),
+			_ => Err(ConfigFileError::UnknownFormat(path.to_owned())),
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct VersionedRef<'a, T> {
+	schema_version: u32,
+	#[serde(flatten)]
+	config: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Versioned<T> {
+	/// Files written before versioning have no version
+	#[serde(default = "unversioned")]
+	schema_version: u32,
+	#[serde(flatten)]
+	config: T,
+}
+
+fn unversioned() -> u32 {
+	1
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigFileError> {
+	let format = Format::for_path(path)?;
+	let contents = std::fs::read_to_string(path).map_err(|source| ConfigFileError::Io {
+		path: path.to_owned(),
+		source,
+	})?;
+	let parse_error = |line: Option<usize>, message: String| ConfigFileError::Parse {
+		path: path.to_owned(),
+		line,
+		message,
+	};
+
+	let versioned: Versioned<T> = match format {
+		Format::Json => serde_json::from_str(&contents).map_err(|err| parse_error(Some(err.line()), err.to_string()))?,
+		#[cfg(feature = "toml")]
+		Format::Toml => toml::from_str(&contents).map_err(|err| {
+			let line = err.span().map(|span| contents[..span.start].matches('\n').count() + 1);
+			parse_error(line, err.message().to_owned())
+		})?,
+		#[cfg(feature = "yaml")]
+		Format::Yaml => serde_yaml::from_str(&contents)
+			.map_err(|err| parse_error(err.location().map(|location| location.line()), err.to_string()))?,
+	};
+
+	if versioned.schema_version > CONFIG_SCHEMA_VERSION {
+		return Err(ConfigFileError::UnsupportedVersion {
+			path: path.to_owned(),
+			found: versioned.schema_version,
+		});
+	}
+	Ok(versioned.config)
+}
+
+fn save<T: Serialize>(path: &Path, config: &T) -> Result<(), ConfigFileError> {
+	let versioned = VersionedRef {
+		schema_version: CONFIG_SCHEMA_VERSION,
+		config,
+	};
+	let serialize_error = |message: String| ConfigFileError::Parse {
+		path: path.to_owned(),
+		line: None,
+		message,
+	};
+
+	let contents = match Format::for_path(path)? {
+		Format::Json => serde_json::to_string_pretty(&versioned).map_err(|err| serialize_error(err.to_string()))?,
+		#[cfg(feature = "toml")]
+		Format::Toml => toml::to_string_pretty(&versioned).map_err(|err| serialize_error(err.to_string()))?,
+		#[cfg(feature = "yaml")]
+		Format::Yaml => serde_yaml::to_string(&versioned).map_err(|err| serialize_error(err.to_string()))?,
+	};
+
+	std::fs::write(path, contents).map_err(|source| ConfigFileError::Io {
+		path: path.to_owned(),
+		source,
+	})
+}
+
+impl BackupTarget {
+	/// Load a target from a `.json`, `.toml` or `.yaml` file, see `save_to_path`
+	pub fn from_path<P: AsRef<Path>>(path: P) -> Result<BackupTarget, ConfigFileError> {
+		load(path.as_ref())
+	}
+
+	/// Save the target with a `schema_version`, the format follows the file extension
+	pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigFileError> {
+		save(path.as_ref(), self)
+	}
+}
+
+impl ResticConfig {
+	/// Load a config from a `.json`, `.toml` or `.yaml` file. Keep the password out of the file
+	/// with `"${ENV_VAR}"` or `{ file = "..." }`, see `PasswordSource`.
+	pub fn from_path<P: AsRef<Path>>(path: P) -> Result<ResticConfig, ConfigFileError> {
+		load(path.as_ref())
+	}
+
+	/// Save the config with a `schema_version`, the format follows the file extension. A plain
+	/// password is written as `REDACTED_PASSWORD`, see `save_to_path_with_secrets`.
+	pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigFileError> {
+		save(path.as_ref(), self)
+	}
+
+	/// Like `save_to_path`, but a plain password is written to the file as it is
+	pub fn save_to_path_with_secrets<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigFileError> {
+		crate::password::with_secrets(|| save(path.as_ref(), self))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{PasswordSource, ResticStorageConfig};
+
+	fn round_trip(extension: &str) {
+		let path = crate::test_util::test_dir("config").join(format!("config.{}", extension));
+		let mut config = ResticConfig::new(String::new(), ResticStorageConfig::Local("/srv/repo".into()));
+		config.repo_password = PasswordSource::Env("REPO_PW".to_owned());
+		config.retry_policy = Some(crate::RetryPolicy::new(5, std::time::Duration::from_secs(2)));
+		config.save_to_path(&path).unwrap();
+
+		let contents = std::fs::read_to_string(&path).unwrap();
+		assert!(contents.contains("schema_version"));
+		assert!(contents.contains("${REPO_PW}"));
+		let loaded = ResticConfig::from_path(&path).unwrap();
+		assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&config).unwrap());
+		assert_eq!(loaded.retry_policy.unwrap().max_attempts, 5);
+		std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+
+	#[test]
+	fn json_round_trip() {
+		round_trip("json");
+	}
+
+	#[test]
+	fn plain_passwords_are_only_saved_on_request() {
+		let path = crate::test_util::test_dir("config-secrets").join("config.json");
+		let mut config = ResticConfig::new("hunter2", ResticStorageConfig::Local("/srv/repo".into()));
+
+		config.save_to_path(&path).unwrap();
+		assert!(!std::fs::read_to_string(&path).unwrap().contains("hunter2"));
+		config = ResticConfig::from_path(&path).unwrap();
+		assert_eq!(config.repo_password, PasswordSource::Redacted);
+
+		config.set_password("hunter2");
+		config.save_to_path_with_secrets(&path).unwrap();
+		assert_eq!(ResticConfig::from_path(&path).unwrap().repo_password, PasswordSource::from("hunter2"));
+		std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+
+	#[cfg(feature = "toml")]
+	#[test]
+	fn toml_round_trip_and_errors() {
+		round_trip("toml");
+
+		let path = crate::test_util::test_dir("config-error").join("target.toml");
+		std::fs::write(&path, "folders = []\ntags = [\n\"a\",\nexclusions = 3\n").unwrap();
+		match BackupTarget::from_path(&path) {
+			Err(ConfigFileError::Parse { line: Some(line), .. }) => assert!(line > 1),
+			other => panic!("unexpected result {:?}", other.map(|_| ())),
+		}
+		std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+
+	#[cfg(feature = "yaml")]
+	#[test]
+	fn yaml_round_trip() {
+		round_trip("yaml");
+	}
+
+	#[test]
+	fn rejects_newer_schema_and_unknown_formats() {
+		let path = crate::test_util::test_dir("config-version").join("target.json");
+		std::fs::write(&path, r#"{"schema_version": 99, "folders": [], "exclusions": [], "tags": []}"#).unwrap();
+		assert!(matches!(
+			BackupTarget::from_path(&path),
+			Err(ConfigFileError::UnsupportedVersion { found: 99, .. })
+		));
+		std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+		assert!(matches!(
+			BackupTarget::from_path("target.ini"),
+			Err(ConfigFileError::UnknownFormat(_))
+		));
+	}
+}
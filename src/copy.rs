@@ -0,0 +1,248 @@
+use crate::errors::*;
+use crate::restic_version::ResticVersion;
+use crate::snapshot_query::SnapshotFilter;
+use crate::{CommandPreview, RepositorySource, ResticConfig, StderrKind, StderrLine, TransferStats};
+use std::ffi::{OsStr, OsString};
+use std::process::Command;
+
+/// `--from-repo` and the `RESTIC_FROM_*` variables replaced `--repo2` in restic 0.14.0
+const FROM_REPO_VERSION: ResticVersion = ResticVersion::new(0, 14, 0);
+
+const FROM_REPOSITORY_ENV: &str = "RESTIC_FROM_REPOSITORY";
+const FROM_REPOSITORY_FILE_ENV: &str = "RESTIC_FROM_REPOSITORY_FILE";
+const FROM_KEY_HINT_ENV: &str = "RESTIC_FROM_KEY_HINT";
+/// The source's password variables and the names restic reads them from for `--from-repo`
+const FROM_RENAMED_ENVS: &[(&str, &str)] = &[
+	("RESTIC_PASSWORD", "RESTIC_FROM_PASSWORD"),
+	("RESTIC_PASSWORD_FILE", "RESTIC_FROM_PASSWORD_FILE"),
+];
+
+/// Set the variables that make `source` restic's `--from-repo` on `cmd`, which is a command
+/// for the destination repository.
+///
+/// Passwords have their own `RESTIC_FROM_*` names, but backend credentials (B2, S3...) are
+/// read from the same variables for both repositories. Those are passed on when the
+/// destination doesn't use the variable or uses the same value, and rejected with
+/// `InvalidConfig` when both repositories need different values. `inherited_env` looks up
+/// the variables the destination inherits from our environment, see `ResticConfig::inherited_env`.
+pub(crate) fn apply_source_env(
+	source: &ResticConfig,
+	cmd: &mut Command,
+	inherited_env: impl Fn(&OsStr) -> Option<OsString>,
+) -> Result<()> {
+	let mut source_cmd = Command::new("restic");
+	source.active_password().apply(&mut source_cmd)?;
+	source.repo_path.add_env_vars(&mut source_cmd);
+	source_cmd.envs(&source.extra_env);
+
+	let dest_env = |key: &OsStr| -> Option<OsString> {
+		match cmd.get_envs().find(|(dest_key, _)| *dest_key == key) {
+			Some((_, val)) => val.map(OsStr::to_owned),
+			None => inherited_env(key),
+		}
+	};
+
+	let mut env = Vec::new();
+	for (key, val) in source_cmd.get_envs() {
+		let val = match val {
+			Some(val) => val.to_owned(),
+			None => continue,
+		};
+		if let Some((_, renamed)) = FROM_RENAMED_ENVS.iter().find(|(name, _)| OsStr::new(name) == key) {
+			env.push((OsString::from(renamed), val));
+			continue;
+		}
+		match dest_env(key) {
+			Some(dest_val) if dest_val != val => {
+				return Err(ErrorKind::InvalidConfig(format!(
+					"source and destination repository need different values for {}, restic reads it for both",
+					key.to_string_lossy()
+				))
+				.into())
+			}
+			_ => env.push((key.to_owned(), val)),
+		}
+	}
+
+	cmd.envs(env);
+	match &*source.repo_source() {
+		RepositorySource::File(path) => cmd.env(FROM_REPOSITORY_FILE_ENV, path),
+		inline => cmd.env(FROM_REPOSITORY_ENV, inline.label()),
+	};
+	if let Some(key_hint) = &source.key_hint {
+		cmd.env(FROM_KEY_HINT_ENV, key_hint);
+	}
+	Ok(())
+}
+
+/// What `replicate` copied
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CopyReport {
+	/// Ids of the new snapshots in the destination, snapshots that were already there are
+	/// left out
+	pub copied: Vec<String>,
+	/// The last state of the `packs copied` counter, None when restic didn't print progress
+	pub transfer: Option<TransferStats>,
+	/// Warnings restic printed to stderr
+	pub warnings: Vec<String>,
+}
+
+impl CopyReport {
+	pub(crate) fn from_output(output: &str, stderr: &[StderrLine]) -> CopyReport {
+		let copied = output
+			.lines()
+			.filter_map(|line| line.trim().strip_prefix("snapshot ")?.strip_suffix(" saved"))
+			.map(str::to_owned)
+			.collect();
+		let lines = output.lines().chain(stderr.iter().map(|line| line.text.as_str()));
+		CopyReport {
+			copied,
+			transfer: TransferStats::last_in(lines, "copied"),
+			warnings: stderr
+				.iter()
+				.filter(|line| line.kind == StderrKind::Warning)
+				.map(|line| line.text.clone())
+				.collect(),
+		}
+	}
+}
+
+impl ResticConfig {
+	/// A variable restic gets from our environment when it isn't set on its command
+	fn inherited_env(&self, key: &OsStr) -> Option<OsString> {
+		if self.isolate_env {
+			None
+		} else {
+			std::env::var_os(key)
+		}
+	}
+
+	fn init_copy_destination_cmd(&self, source: &ResticConfig) -> Result<Command> {
+		let mut cmd = self.init_cmd()?;
+		cmd.arg("--copy-chunker-params");
+		apply_source_env(source, &mut cmd, |key| self.inherited_env(key))?;
+		Ok(cmd)
+	}
+
+	pub(crate) fn copy_cmd(&self, source: &ResticConfig, filter: &SnapshotFilter) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("copy");
+		filter.apply(&mut cmd)?;
+		apply_source_env(source, &mut cmd, |key| self.inherited_env(key))?;
+		Ok(cmd)
+	}
+
+	/// Create this repository with the chunker parameters of `source`, so snapshots copied
+	/// from there deduplicate as well as in the source
+	pub fn init_copy_destination(&self, source: &ResticConfig) -> Result<()> {
+		let _span = operation_span!(self, "init");
+		self.restic_version()?.require("--from-repo", FROM_REPO_VERSION)?;
+		let _guard = self.repo_guard(true)?;
+		let mut cmd = self.init_copy_destination_cmd(source)?;
+		let mut unchecked = self.clone();
+		unchecked.min_repo_version = None;
+		unchecked.output_parsing("init", &mut cmd, |_| Ok(()))
+	}
+
+	/// Copy the snapshots of this repository matching `filter` to `dest`, snapshots already
+	/// there are skipped by restic. `dest` is created with `init_copy_destination` first when
+	/// it doesn't exist.
+	pub fn replicate(&self, dest: &ResticConfig, filter: &SnapshotFilter) -> Result<CopyReport> {
+		let _span = operation_span!(self, "copy");
+		match dest.repo_version() {
+			Ok(_) => (),
+			Err(err) if matches!(err.kind(), ErrorKind::ResticRepoNotFound) => dest.init_copy_destination(self)?,
+			Err(err) => return Err(err),
+		}
+
+		let _guard = dest.repo_guard(true)?;
+		let mut cmd = dest.copy_cmd(self, filter)?;
+		dest.output_parsing_with_stderr("copy", &mut cmd, |stdout_data, stderr| {
+			Ok(CopyReport::from_output(&stdout_data, stderr))
+		})
+	}
+
+	/// The command `replicate` runs to copy to `dest`, secrets redacted
+	pub fn preview_replicate(&self, dest: &ResticConfig, filter: &SnapshotFilter) -> Result<CommandPreview> {
+		dest.preview(dest.copy_cmd(self, filter))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{B2Config, ResticStorageConfig};
+
+	fn env_of(cmd: &Command, key: &str) -> Option<String> {
+		cmd.get_envs()
+			.find(|(name, _)| *name == OsStr::new(key))
+			.and_then(|(_, val)| val)
+			.map(|val| val.to_string_lossy().into_owned())
+	}
+
+	fn b2(account_id: &str) -> ResticStorageConfig {
+		ResticStorageConfig::B2(B2Config {
+			bucket_name: "bucket".to_owned(),
+			repo_path: "repo".to_owned(),
+			account_key: "key".to_owned(),
+			account_id: account_id.to_owned(),
+			connections: None,
+		})
+	}
+
+	#[test]
+	fn source_password_is_renamed() {
+		let source = ResticConfig::new("source-pw".to_owned(), ResticStorageConfig::Local("/srv/source".into()));
+		let dest = ResticConfig::new("dest-pw".to_owned(), ResticStorageConfig::Local("/srv/dest".into()));
+		let cmd = dest.copy_cmd(&source, &SnapshotFilter::default()).unwrap();
+
+		assert_eq!(env_of(&cmd, "RESTIC_PASSWORD").as_deref(), Some("dest-pw"));
+		assert_eq!(env_of(&cmd, "RESTIC_FROM_PASSWORD").as_deref(), Some("source-pw"));
+		assert_eq!(env_of(&cmd, FROM_REPOSITORY_ENV).as_deref(), Some("/srv/source"));
+	}
+
+	#[test]
+	fn shared_backend_credentials() {
+		// Same account: the variables agree and are simply set
+		let source = ResticConfig::new("pw".to_owned(), b2("account"));
+		let dest = ResticConfig::new("pw".to_owned(), b2("account"));
+		let cmd = dest.copy_cmd(&source, &SnapshotFilter::default()).unwrap();
+		assert_eq!(env_of(&cmd, "B2_ACCOUNT_ID").as_deref(), Some("account"));
+
+		// Local destination: the source's credentials can be set without conflict
+		let dest = ResticConfig::new("pw".to_owned(), ResticStorageConfig::Local("/srv/dest".into()));
+		let cmd = dest.copy_cmd(&source, &SnapshotFilter::default()).unwrap();
+		assert_eq!(env_of(&cmd, "B2_ACCOUNT_ID").as_deref(), Some("account"));
+	}
+
+	#[test]
+	fn conflicting_backend_credentials_are_rejected() {
+		let source = ResticConfig::new("pw".to_owned(), b2("source-account"));
+		let dest = ResticConfig::new("pw".to_owned(), b2("dest-account"));
+		let err = dest.copy_cmd(&source, &SnapshotFilter::default()).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)));
+	}
+
+	/// The stderr fixture is hand-written in restic's format, it isn't captured
+	#[test]
+	fn reports_copied_snapshots_and_transfer() {
+		let stderr = crate::stderr::classify_stderr(include_bytes!("../fixtures/synthetic_copy_stderr.txt"));
+		let report = CopyReport::from_output(include_str!("../fixtures/restic_copy.txt"), &stderr);
+		assert_eq!(report.copied, ["9b0f3c2a"]);
+		let transfer = report.transfer.unwrap();
+		assert_eq!((transfer.done, transfer.total, transfer.unit.as_str()), (14, 14, "packs copied"));
+		assert_eq!(report.warnings, ["Warning: failed to lock the source repository, retrying"]);
+	}
+
+	#[test]
+	fn inherited_variables_count_for_the_destination() {
+		let mut source = ResticConfig::new("pw".to_owned(), ResticStorageConfig::Local("/srv/source".into()));
+		source.extra_env.insert("RESTIC_INTERFACER_COPY_TEST".into(), "source".into());
+
+		let inherited = |key: &OsStr| Some(OsString::from("inherited")).filter(|_| key == "RESTIC_INTERFACER_COPY_TEST");
+		let mut cmd = Command::new("restic");
+		assert!(apply_source_env(&source, &mut cmd, inherited).is_err());
+		let mut cmd = Command::new("restic");
+		assert!(apply_source_env(&source, &mut cmd, |_| None).is_ok());
+	}
+}
@@ -0,0 +1,205 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::{DiffLine, DiffModifier};
+use crate::{BackupTarget, CommandPreview, ResticConfig, SnapshotId};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A path that differs between two snapshots
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiffEntry {
+	pub path: PathBuf,
+	pub modifier: DiffModifier,
+}
+
+/// The totals restic prints at the end of a diff
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DiffStats {
+	pub files_added: u64,
+	pub files_removed: u64,
+	/// Files whose contents changed
+	pub files_changed: u64,
+	pub dirs_added: u64,
+	pub dirs_removed: u64,
+	pub bytes_added: u64,
+	pub bytes_removed: u64,
+	/// Unknown fields of restic's statistics line
+	pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DiffResult {
+	pub entries: Vec<DiffEntry>,
+	pub stats: DiffStats,
+}
+
+impl ResticConfig {
+	fn diff_cmd(&self, from: &SnapshotId, to: &SnapshotId) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("--json");
+		cmd.arg("diff").arg(from).arg(to);
+		Ok(cmd)
+	}
+
+	/// Run diff, passing every changed path to `on_entry`, and return the statistics
+	fn run_diff<F: FnMut(DiffEntry)>(&self, from: &SnapshotId, to: &SnapshotId, mut on_entry: F) -> Result<DiffStats> {
+		let mut stats = None;
+		self.stream_lines("diff", &mut self.diff_cmd(from, to)?, |line| {
+			let line: DiffLine = parse_json("diff", line)?;
+			match line {
+				DiffLine::Change { path, modifier } => on_entry(DiffEntry {
+					path: path.into(),
+					modifier,
+				}),
+				DiffLine::Statistics {
+					changed_files,
+					added,
+					removed,
+					extra,
+				} => {
+					stats = Some(DiffStats {
+						files_added: added.files,
+						files_removed: removed.files,
+						files_changed: changed_files,
+						dirs_added: added.dirs,
+						dirs_removed: removed.dirs,
+						bytes_added: added.bytes,
+						bytes_removed: removed.bytes,
+						extra,
+					})
+				}
+			}
+			Ok(())
+		})?;
+		stats.ok_or_else(|| ErrorKind::NoOutputFromRestic.into())
+	}
+
+	/// Paths that changed between two snapshots, with restic's totals
+	pub fn diff(&self, from: &SnapshotId, to: &SnapshotId) -> Result<DiffResult> {
+		let _span = operation_span!(self, "diff");
+		let mut entries = Vec::new();
+		let stats = self.run_diff(from, to, |entry| entries.push(entry))?;
+		Ok(DiffResult { entries, stats })
+	}
+
+	/// Only the totals of `diff`, the changed paths are discarded as they arrive
+	pub fn diff_stats(&self, from: &SnapshotId, to: &SnapshotId) -> Result<DiffStats> {
+		let _span = operation_span!(self, "diff");
+		self.run_diff(from, to, |_| ())
+	}
+
+	/// What the newest backup of `target` changed compared to the one before, None when the
+	/// target has fewer than two snapshots. The snapshots are found like in
+	/// `latest_snapshot_for_target`.
+	pub fn changes_since_last_backup(&self, target: &BackupTarget) -> Result<Option<DiffResult>> {
+		let _span = operation_span!(self, "diff");
+		let snapshots = self.latest_snapshots_for_target(target, 2)?;
+		let (newest, previous) = match snapshots.as_slice() {
+			[newest, previous] => (SnapshotId::new(&newest.id)?, SnapshotId::new(&previous.id)?),
+			_ => return Ok(None),
+		};
+		let mut entries = Vec::new();
+		let stats = self.run_diff(&previous, &newest, |entry| entries.push(entry))?;
+		Ok(Some(DiffResult { entries, stats }))
+	}
+
+	pub fn preview_diff(&self, from: &SnapshotId, to: &SnapshotId) -> Result<CommandPreview> {
+		self.preview(self.diff_cmd(from, to))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_diff_lines() {
+		let change: DiffLine = serde_json::from_str(r#"{"message_type":"change","path":"/data/a","modifier":"TM"}"#).unwrap();
+		assert!(matches!(
+			change,
+			DiffLine::Change {
+				modifier: DiffModifier::TypeChanged,
+				..
+			}
+		));
+
+		let stats: DiffLine = serde_json::from_str(
+			r#"{"message_type":"statistics","source_snapshot":"1234abcd","target_snapshot":"5678abcd","changed_files":2,
+			"added":{"files":3,"dirs":1,"others":0,"data_blobs":4,"tree_blobs":2,"bytes":4096},
+			"removed":{"files":1,"dirs":0,"others":0,"data_blobs":1,"tree_blobs":1,"bytes":512}}"#,
+		)
+		.unwrap();
+		match stats {
+			DiffLine::Statistics {
+				changed_files,
+				added,
+				removed,
+				..
+			} => {
+				assert_eq!(changed_files, 2);
+				assert_eq!((added.files, added.bytes), (3, 4096));
+				assert_eq!((removed.files, removed.bytes), (1, 512));
+			}
+			other => panic!("parsed as {:?}", other),
+		}
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn diffs_the_two_newest_snapshots_of_a_target() {
+		use crate::ResticStorageConfig;
+
+		let dir = crate::test_util::test_dir("changes-since");
+		let data = dir.join("data");
+		std::fs::create_dir_all(&data).unwrap();
+		let snapshot = |id: &str, time: &str, path: &std::path::Path| {
+			format!(
+				r#"{{"id":"{}","short_id":"{}","time":"{}","tree":"ab12cd34","paths":["{}"],"hostname":"host","username":"me"}}"#,
+				id,
+				id,
+				time,
+				path.display()
+			)
+		};
+		let snapshots = [
+			snapshot("11111111", "2024-05-01T01:00:00Z", &data),
+			snapshot("33333333", "2024-05-03T01:00:00Z", &data),
+			snapshot("44444444", "2024-05-04T01:00:00Z", &dir),
+			snapshot("22222222", "2024-05-02T01:00:00Z", &data),
+		];
+		let script = format!(
+			"#!/bin/sh
+echo \"$*\" >> '{}'
+case \"$*\" in
+	*snapshots*) echo '[{}]' ;;
+	*diff*)
+		echo '{{\"message_type\":\"change\",\"path\":\"/data/a\",\"modifier\":\"+\"}}'
+		echo '{{\"message_type\":\"statistics\",\"changed_files\":0,\"added\":{{\"files\":1,\"dirs\":0,\"bytes\":5}},\"removed\":{{\"files\":0,\"dirs\":0,\"bytes\":0}}}}' ;;
+esac
+",
+			dir.join("args").display(),
+			snapshots.join(",")
+		);
+		let restic = crate::test_util::fake_restic(&dir, &script);
+
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(restic);
+		let target = BackupTarget {
+			folders: vec![data.clone()],
+			..Default::default()
+		};
+		let changes = config.changes_since_last_backup(&target).unwrap().unwrap();
+		assert_eq!(changes.entries.len(), 1);
+		assert_eq!(changes.stats.files_added, 1);
+		let args = std::fs::read_to_string(dir.join("args")).unwrap();
+		assert!(args.lines().last().unwrap().ends_with("diff 22222222 33333333"));
+
+		let single = BackupTarget {
+			folders: vec![dir.clone()],
+			..Default::default()
+		};
+		assert_eq!(config.changes_since_last_backup(&single).unwrap(), None);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
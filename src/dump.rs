@@ -0,0 +1,106 @@
+use crate::errors::*;
+use crate::restic_version::ResticVersion;
+use crate::{CommandPreview, ResticConfig, SnapshotId};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+/// `dump --archive` was added in restic 0.12.0, before that directories were always dumped as tar
+const ARCHIVE_VERSION: ResticVersion = ResticVersion::new(0, 12, 0);
+
+/// Archive format restic packs a dumped directory into
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+	Tar,
+	Zip,
+}
+
+impl ArchiveFormat {
+	pub fn as_arg(self) -> &'static str {
+		match self {
+			ArchiveFormat::Tar => "tar",
+			ArchiveFormat::Zip => "zip",
+		}
+	}
+}
+
+impl ResticConfig {
+	fn dump_cmd(&self, id: &SnapshotId, path: &Path, archive: Option<ArchiveFormat>) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("dump");
+		if let Some(archive) = archive {
+			cmd.arg("--archive").arg(archive.as_arg());
+		}
+		cmd.arg(id).arg(path);
+		Ok(cmd)
+	}
+
+	/// Write the contents of the file at `path` in the snapshot to `sink`.
+	///
+	/// The bytes are passed on as restic prints them, nothing is buffered or converted.
+	/// Directories come out as tar, use `dump_archive` to choose the format. Returns the
+	/// number of bytes written.
+	pub fn dump<W: Write>(&self, id: &SnapshotId, path: &Path, sink: &mut W) -> Result<u64> {
+		let _span = operation_span!(self, "dump");
+		self.stream_to_writer("dump", &mut self.dump_cmd(id, path, None)?, sink)
+	}
+
+	/// Write an archive of the directory at `path` in the snapshot to `sink`, `/` for the whole
+	/// snapshot. Returns the number of bytes written.
+	pub fn dump_archive<W: Write>(
+		&self,
+		id: &SnapshotId,
+		path: &Path,
+		format: ArchiveFormat,
+		sink: &mut W,
+	) -> Result<u64> {
+		let _span = operation_span!(self, "dump");
+		self.restic_version()?.require("dump --archive", ARCHIVE_VERSION)?;
+		self.stream_to_writer("dump", &mut self.dump_cmd(id, path, Some(format))?, sink)
+	}
+
+	pub fn preview_dump_archive(&self, id: &SnapshotId, path: &Path, format: ArchiveFormat) -> Result<CommandPreview> {
+		self.preview(self.dump_cmd(id, path, Some(format)))
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use crate::ResticStorageConfig;
+
+	#[test]
+	fn streams_the_bytes_unchanged() {
+		let dir = crate::test_util::test_dir("dump");
+		let script = "#!/bin/sh
+case \"$*\" in
+	*version*) echo 'restic 0.16.4 compiled with go1.21.6 on linux/amd64' ;;
+	*'--archive zip'*) printf 'PK\\003\\004'; head -c 300000 /dev/zero ;;
+	*missing*) echo 'Fatal: cannot dump file: path \"/missing\" not found in snapshot' >&2; exit 1 ;;
+	*dump*) printf 'line\\r\\n\\000\\377' ;;
+esac
+";
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, script));
+		let id = SnapshotId::latest();
+
+		let mut file = Vec::new();
+		assert_eq!(config.dump(&id, Path::new("/notes.txt"), &mut file).unwrap(), 8);
+		assert_eq!(file, b"line\r\n\0\xff");
+
+		// Several chunks of output end up in the sink in order
+		let mut archive = Vec::new();
+		let written = config.dump_archive(&id, Path::new("/"), ArchiveFormat::Zip, &mut archive).unwrap();
+		assert_eq!(written, 300_004);
+		assert_eq!(archive.len(), 300_004);
+		assert_eq!(&archive[..4], b"PK\x03\x04");
+		assert!(archive[4..].iter().all(|byte| *byte == 0));
+
+		let mut sink = Vec::new();
+		assert!(config.dump(&id, Path::new("/missing"), &mut sink).is_err());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
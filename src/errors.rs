@@ -1,28 +1,223 @@
-use error_chain::error_chain;
-//use quick_error::quick_error;
+use crate::lock::LockInfo;
+use crate::restic_outputs::BackupJson;
+use crate::restic_version::ResticVersion;
+use crate::snapshot_id::{IdError, SnapshotId};
+use crate::tag::TagError;
+use std::fmt;
+use thiserror::Error;
 
-error_chain! {
+pub type Result<T> = std::result::Result<T, Error>;
 
-    errors {
-        ResticRepoNotFound {
-            description("Restic repository not found at given path")
-            display("Restic repository not found at given path")
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Everything known about a restic invocation that exited unsuccessfully
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandFailure {
+    /// The restic subcommand that was run, e.g. `backup`
+    pub subcommand: String,
+    /// None when restic was killed by a signal
+    pub exit_code: Option<i32>,
+    pub stderr: String,
+    /// The tail end of stdout
+    pub stdout_excerpt: String,
+}
+
+impl fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.exit_code {
+            Some(code) => write!(f, "restic {} failed with exit code {}: {}", self.subcommand, code, self.stderr.trim()),
+            None => write!(f, "restic {} was terminated by a signal: {}", self.subcommand, self.stderr.trim()),
         }
-        ResticRepoInvalidPassword {
-            description("Restic repository is not decrypted with this password")
-            display("Restic repository is not decrypted with this password")
+    }
+}
+
+/// Where restic's JSON output didn't match the type it was parsed as
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JsonMismatch {
+    /// The output that was parsed, e.g. `snapshots`
+    pub output: String,
+    /// Path of the failing field, e.g. `[0].summary.backup_start`. Empty when the output
+    /// isn't JSON at all or the top level has the wrong type.
+    pub path: String,
+    /// What restic printed at `path`, cut after 200 characters
+    pub raw_value: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for JsonMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unexpected restic {} JSON", self.output)?;
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.path)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(raw_value) = &self.raw_value {
+            write!(f, ", found {}", raw_value)?;
+        }
+        Ok(())
+    }
+}
+
+struct DisplayIds<'a>(&'a [SnapshotId]);
+
+impl fmt::Display for DisplayIds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, id) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(id.as_str())?;
         }
-        InvalidId {
-            description("The input id does not contain all hex characters")
-            display("The input id does not contain all hex characters")
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ErrorKind {
+    #[error("Restic repository not found at given path")]
+    ResticRepoNotFound,
+    #[error("restic was not found at {}, install it or set restic_binary", .searched.display())]
+    ResticBinaryNotFound { searched: std::path::PathBuf },
+    #[error("Restic repository is not decrypted with this password")]
+    ResticRepoInvalidPassword,
+    #[error("Invalid id: {0}")]
+    InvalidId(IdError),
+    #[error("Invalid tag: {0}")]
+    InvalidTag(TagError),
+    #[error("No snapshot found for {}", DisplayIds(.0))]
+    SnapshotsNotFound(Vec<SnapshotId>),
+    #[error("Restic output does not contain any output?")]
+    NoOutputFromRestic,
+    #[error("{0}")]
+    ResticCommandFailed(CommandFailure),
+    #[error("Restic repository is locked by PID {:?} on {:?}{}", .0.pid, .0.host, .0.no_lock_hint())]
+    ResticRepoLocked(LockInfo),
+    #[error("Restic repository backend could not be reached, check the network connection")]
+    BackendUnreachable,
+    #[error("Restic did not finish within {0:?}")]
+    Timeout(std::time::Duration),
+    /// restic made a snapshot without the files it couldn't read. Holds its summary, None
+    /// when restic didn't print one.
+    #[error("Backup finished, but some source files could not be read")]
+    PartialBackup(Option<Box<BackupJson>>),
+    #[error("Another operation is already running on this repository")]
+    OperationInProgress,
+    #[error("{feature} needs restic {required} or newer, found {found}")]
+    UnsupportedByResticVersion {
+        feature: String,
+        required: ResticVersion,
+        found: ResticVersion,
+    },
+    #[error("Repository format version {found} is too old, version {needs} is needed")]
+    RepoVersionTooOld { found: u32, needs: u32 },
+    #[error("Not enough free space for the backup, {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("{0}")]
+    UnexpectedJson(JsonMismatch),
+    #[error("Invalid restic configuration: {0}")]
+    InvalidConfig(String),
+    #[error("{0}")]
+    Msg(String),
+}
+
+impl From<&str> for ErrorKind {
+    fn from(msg: &str) -> Self {
+        ErrorKind::Msg(msg.to_owned())
+    }
+}
+
+impl From<String> for ErrorKind {
+    fn from(msg: String) -> Self {
+        ErrorKind::Msg(msg)
+    }
+}
+
+/// The crate's error, an `ErrorKind` optionally caused by another error
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    source: Option<BoxError>,
+}
+
+impl Error {
+    /// Wrap `source` as the cause of an error of `kind`
+    pub fn with_chain<E: Into<BoxError>, K: Into<ErrorKind>>(source: E, kind: K) -> Error {
+        Error {
+            kind: kind.into(),
+            source: Some(source.into()),
         }
-        NoOutputFromRestic {
-            description("Restic output does not contain any output?")
-            display("Restic output does not contain any output?")
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The details of the failed restic invocation behind this error, if any
+    pub fn command_failure(&self) -> Option<&CommandFailure> {
+        let mut current: Option<&(dyn std::error::Error + 'static)> = Some(self);
+        while let Some(err) = current {
+            if let Some(ErrorKind::ResticCommandFailed(failure)) = err.downcast_ref::<Error>().map(Error::kind) {
+                return Some(failure);
+            }
+            current = err.source();
         }
+        None
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|source| &**source as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error { kind, source: None }
+    }
+}
+
+/// Attach context to foreign errors, keeping them as the source
+pub trait ResultExt<T> {
+    fn chain_err<F, K>(self, callback: F) -> Result<T>
+    where
+        F: FnOnce() -> K,
+        K: Into<ErrorKind>;
+}
+
+impl<T, E: std::error::Error + Send + Sync + 'static> ResultExt<T> for std::result::Result<T, E> {
+    fn chain_err<F, K>(self, callback: F) -> Result<T>
+    where
+        F: FnOnce() -> K,
+        K: Into<ErrorKind>,
+    {
+        self.map_err(|err| Error::with_chain(err, callback()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    #[test]
+    fn error_is_send_sync() {
+        assert_send_sync::<Error>();
+    }
+
+    #[test]
+    fn chain_err_keeps_source() {
+        let io_err: std::result::Result<(), std::io::Error> =
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "missing"));
+        let err = io_err.chain_err(|| "Failed to start restic").unwrap_err();
+        assert_eq!(err.to_string(), "Failed to start restic");
+        assert!(std::error::Error::source(&err).unwrap().is::<std::io::Error>());
     }
 }
-//
-//quick_error! {
-//
-//}
@@ -0,0 +1,237 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::{FindJson, FindObjectJson, NodeType, SnapshotsJson};
+use crate::snapshot_id::validate_id;
+use crate::snapshot_query::order_snapshots;
+use crate::{CommandPreview, ResticConfig, SnapshotId, SnapshotSort};
+use chrono::{DateTime, Local, TimeZone};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// One of the date formats restic accepts for `--oldest` and `--newest`, in local time
+const RESTIC_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Which snapshots `find` searches
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FindOptions {
+	/// Only these snapshots, all when empty
+	pub snapshots: Vec<SnapshotId>,
+	/// Only files modified at or after this time
+	pub oldest: Option<DateTime<Local>>,
+	/// Only files modified at or before this time
+	pub newest: Option<DateTime<Local>>,
+	/// Match path patterns case insensitively, has no effect on object ids
+	pub ignore_case: bool,
+}
+
+impl FindOptions {
+	pub fn oldest<Tz: TimeZone>(mut self, oldest: DateTime<Tz>) -> FindOptions {
+		self.oldest = Some(oldest.with_timezone(&Local));
+		self
+	}
+
+	pub fn newest<Tz: TimeZone>(mut self, newest: DateTime<Tz>) -> FindOptions {
+		self.newest = Some(newest.with_timezone(&Local));
+		self
+	}
+}
+
+/// A path matching a `find` pattern
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FindMatch {
+	/// Id of the snapshot containing the path
+	pub snapshot: String,
+	pub path: PathBuf,
+	pub node_type: NodeType,
+	pub size: Option<u64>,
+	pub mtime: Option<String>,
+}
+
+/// The kind of object `find_objects` looks for
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ObjectKind {
+	Blob,
+	Tree,
+	/// Pack files are resolved to the blobs they contain, which are reported
+	Pack,
+}
+
+impl ObjectKind {
+	fn flag(self) -> &'static str {
+		match self {
+			ObjectKind::Blob => "--blob",
+			ObjectKind::Tree => "--tree",
+			ObjectKind::Pack => "--pack",
+		}
+	}
+}
+
+/// A repository object by id, e.g. one `check` reported as damaged
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ObjectRef {
+	Blob(String),
+	Tree(String),
+	Pack(String),
+}
+
+impl ObjectRef {
+	pub fn kind(&self) -> ObjectKind {
+		match self {
+			ObjectRef::Blob(_) => ObjectKind::Blob,
+			ObjectRef::Tree(_) => ObjectKind::Tree,
+			ObjectRef::Pack(_) => ObjectKind::Pack,
+		}
+	}
+
+	pub fn id(&self) -> &str {
+		match self {
+			ObjectRef::Blob(id) | ObjectRef::Tree(id) | ObjectRef::Pack(id) => id,
+		}
+	}
+}
+
+impl ResticConfig {
+	fn find_cmd<S: AsRef<std::ffi::OsStr>>(&self, mode: Option<ObjectKind>, patterns: &[S], options: &FindOptions) -> Result<Command> {
+		let mut cmd = self.read_only_cmd_setup()?;
+		cmd.arg("--json");
+		cmd.arg("find");
+		if let Some(mode) = mode {
+			cmd.arg(mode.flag());
+		}
+		for snapshot in &options.snapshots {
+			cmd.arg("--snapshot").arg(snapshot);
+		}
+		if let Some(oldest) = &options.oldest {
+			cmd.arg("--oldest").arg(oldest.format(RESTIC_DATE_FORMAT).to_string());
+		}
+		if let Some(newest) = &options.newest {
+			cmd.arg("--newest").arg(newest.format(RESTIC_DATE_FORMAT).to_string());
+		}
+		if options.ignore_case {
+			cmd.arg("--ignore-case");
+		}
+		cmd.args(patterns);
+		Ok(cmd)
+	}
+
+	/// Paths matching any of `patterns` (restic's glob syntax) in the selected snapshots
+	pub fn find(&self, patterns: &[&str], options: &FindOptions) -> Result<Vec<FindMatch>> {
+		let _span = operation_span!(self, "find");
+		let mut cmd = self.find_cmd(None, patterns, options)?;
+		let found: Vec<FindJson> = self.output_parsing("find", &mut cmd, |stdout_data| {
+			parse_json("find", &stdout_data)
+		})?;
+
+		Ok(found
+			.into_iter()
+			.flat_map(|snapshot| {
+				let id = snapshot.snapshot;
+				snapshot.matches.into_iter().map(move |node| FindMatch {
+					snapshot: id.clone(),
+					path: node.path.into(),
+					node_type: node.node_type,
+					size: node.size,
+					mtime: node.mtime,
+				})
+			})
+			.collect())
+	}
+
+	/// Where the blobs, trees or packs with these ids are used, e.g. to see which snapshots
+	/// are affected by a pack `check` reported as damaged
+	pub fn find_objects(&self, kind: ObjectKind, ids: &[&str], options: &FindOptions) -> Result<Vec<FindObjectJson>> {
+		let _span = operation_span!(self, "find");
+		for id in ids {
+			validate_id(id).map_err(ErrorKind::InvalidId)?;
+		}
+		let mut cmd = self.find_cmd(Some(kind), ids, options)?;
+		self.output_parsing("find", &mut cmd, |stdout_data| {
+			parse_json("find", &stdout_data)
+		})
+	}
+
+	/// The snapshots using `object`, newest first, e.g. to list the snapshots a damaged pack
+	/// made unrestorable before forgetting them and running `repair`.
+	///
+	/// A pack is resolved to the blobs it contains through the index, so a pack the index no
+	/// longer lists is used by no snapshot. Finding no use is not an error.
+	pub fn snapshots_referencing(&self, object: &ObjectRef) -> Result<Vec<SnapshotsJson>> {
+		let _span = operation_span!(self, "find");
+		let mut ids: Vec<SnapshotId> = Vec::new();
+		for found in self.find_objects(object.kind(), &[object.id()], &FindOptions::default())? {
+			let id = SnapshotId::new(&found.snapshot)?;
+			if !ids.contains(&id) {
+				ids.push(id);
+			}
+		}
+		let mut snapshots = self.get_snapshots_by_id(&ids)?;
+		order_snapshots(&mut snapshots, None, SnapshotSort::NewestFirst);
+		Ok(snapshots)
+	}
+
+	pub fn preview_find(&self, patterns: &[&str], options: &FindOptions) -> Result<CommandPreview> {
+		self.preview(self.find_cmd(None, patterns, options))
+	}
+
+	pub fn preview_find_objects(&self, kind: ObjectKind, ids: &[&str], options: &FindOptions) -> Result<CommandPreview> {
+		self.preview(self.find_cmd(Some(kind), ids, options))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_object_matches() {
+		let found: Vec<FindObjectJson> = serde_json::from_str(
+			r#"[{"object_type":"blob","id":"5e2b7a8f","path":"/data/disk.img","parent_tree":"0a1b2c3d","snapshot":"1234abcd","time":"2021-01-01T10:00:00Z"}]"#,
+		)
+		.unwrap();
+		assert_eq!(found[0].path, "/data/disk.img");
+		assert_eq!(found[0].snapshot, "1234abcd");
+	}
+
+	#[test]
+	fn rejects_invalid_object_ids() {
+		let config = ResticConfig::new("1234", crate::ResticStorageConfig::Local("./sample_repo".into()));
+		let err = config.snapshots_referencing(&ObjectRef::Pack("not-hex".to_owned())).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InvalidId(_)));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn finds_snapshots_using_a_pack() {
+		let dir = crate::test_util::test_dir("referencing");
+		let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+		let script = format!(
+			"#!/bin/sh
+echo \"$*\" >> '{}'
+case \"$*\" in
+	*'--pack 0123abcd'*) cat '{}' ;;
+	*' find '*) echo '[]' ;;
+	*' snapshots '*) cat '{}' ;;
+esac
+",
+			dir.join("args").display(),
+			fixtures.join("restic_find_pack.json").display(),
+			fixtures.join("restic_snapshots/0.12.1.json").display()
+		);
+		let restic = crate::test_util::fake_restic(&dir, &script);
+
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(restic);
+		let snapshots = config.snapshots_referencing(&ObjectRef::Pack("0123abcd".to_owned())).unwrap();
+		let ids: Vec<_> = snapshots.iter().map(|snapshot| snapshot.short_id.as_str()).collect();
+		assert_eq!(ids, ["9e0b5d66", "4a1d4b0f"]);
+		let args = std::fs::read_to_string(dir.join("args")).unwrap();
+		assert!(args.lines().last().unwrap().ends_with("snapshots 9e0b5d66 4a1d4b0f"));
+
+		// An unused blob doesn't list every snapshot
+		assert!(config.snapshots_referencing(&ObjectRef::Blob("ffffffff".to_owned())).unwrap().is_empty());
+		let args = std::fs::read_to_string(dir.join("args")).unwrap();
+		assert!(args.lines().last().unwrap().contains("find --blob ffffffff"));
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
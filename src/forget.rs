@@ -0,0 +1,256 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::{ForgetGroupJson, KeepReasonJson, SnapshotsJson};
+use crate::{ForgetRate, ResticConfig};
+use std::collections::BTreeMap;
+
+/// A rule of a retention policy that kept a snapshot
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RetentionRule {
+	Last,
+	Hourly,
+	Daily,
+	Weekly,
+	Monthly,
+	Yearly,
+	/// `keep_tags`, the tags as given there, e.g. `a,b` for snapshots with both
+	Tag(String),
+	Within,
+	/// A description this crate doesn't know, e.g. from a newer restic
+	Other(String),
+}
+
+impl RetentionRule {
+	/// The rule behind one of restic's descriptions, e.g. `daily snapshot`, `oldest daily
+	/// snapshot`, `has tags [a, b]` or `within 7d`
+	pub fn from_match(description: &str) -> RetentionRule {
+		let rule = description.strip_prefix("oldest ").unwrap_or(description);
+		match rule {
+			"last snapshot" => return RetentionRule::Last,
+			"hourly snapshot" => return RetentionRule::Hourly,
+			"daily snapshot" => return RetentionRule::Daily,
+			"weekly snapshot" => return RetentionRule::Weekly,
+			"monthly snapshot" => return RetentionRule::Monthly,
+			"yearly snapshot" => return RetentionRule::Yearly,
+			_ => (),
+		}
+		if let Some(tags) = rule.strip_prefix("has tags [").and_then(|tags| tags.strip_suffix(']')) {
+			return RetentionRule::Tag(tags.split(", ").collect::<Vec<_>>().join(","));
+		}
+		if rule.starts_with("within ") {
+			return RetentionRule::Within;
+		}
+		RetentionRule::Other(description.to_owned())
+	}
+}
+
+/// Why a snapshot was kept
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct KeepReason {
+	/// Descriptions of the matching rules, e.g. `daily snapshot` or `within 7d`
+	pub matches: Vec<String>,
+	/// The rules of `matches`, in the same order
+	pub rules: Vec<RetentionRule>,
+	/// Per rule (`last`, `daily`...), how many more snapshots it could keep after this one,
+	/// -1 for unlimited rules. With a `keep_daily` of 7 and a counter of 4, this is the 3rd of 7.
+	pub counters: BTreeMap<String, i64>,
+}
+
+impl KeepReason {
+	pub(crate) fn new(matches: Vec<String>, counters: BTreeMap<String, i64>) -> KeepReason {
+		KeepReason {
+			rules: matches.iter().map(|description| RetentionRule::from_match(description)).collect(),
+			matches,
+			counters,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct KeptSnapshot {
+	pub snapshot: SnapshotsJson,
+	/// None when restic didn't report reasons, which versions before 0.10 don't
+	pub reason: Option<KeepReason>,
+}
+
+/// The snapshots of one `--group-by` group
+#[derive(Debug, Clone, Default)]
+pub struct ForgetGroup {
+	pub host: String,
+	pub tags: Vec<String>,
+	pub paths: Vec<String>,
+	pub kept: Vec<KeptSnapshot>,
+	pub removed: Vec<SnapshotsJson>,
+}
+
+impl From<ForgetGroupJson> for ForgetGroup {
+	fn from(group: ForgetGroupJson) -> ForgetGroup {
+		let mut reasons: Vec<KeepReasonJson> = group.reasons.unwrap_or_default();
+		let kept = group
+			.keep
+			.into_iter()
+			.map(|snapshot| {
+				let reason = reasons
+					.iter()
+					.position(|reason| reason.snapshot.id == snapshot.id)
+					.map(|pos| reasons.swap_remove(pos))
+					.map(|reason| KeepReason::new(reason.matches, reason.counters));
+				KeptSnapshot { snapshot, reason }
+			})
+			.collect();
+		ForgetGroup {
+			host: group.host,
+			tags: group.tags.unwrap_or_default(),
+			paths: group.paths.unwrap_or_default(),
+			kept,
+			removed: group.remove.unwrap_or_default(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ForgetResult {
+	pub groups: Vec<ForgetGroup>,
+	/// What restic printed besides the JSON document, e.g. the removal progress or, with
+	/// `--prune`, the prune output
+	pub messages: Vec<String>,
+}
+
+impl ForgetResult {
+	pub fn snapshots_removed(&self) -> usize {
+		self.groups.iter().map(|group| group.removed.len()).sum()
+	}
+}
+
+/// Split the output of `forget --json` into the JSON document and the other lines. restic
+/// prints the document on a line of its own, with plain text around it from `--prune` and
+/// older versions.
+pub(crate) fn parse_forget_output(stdout: &str) -> Result<(Vec<ForgetGroupJson>, Vec<String>)> {
+	let mut groups = None;
+	let mut messages = Vec::new();
+	for line in stdout.lines() {
+		// The removal progress starts with `[` as well
+		if groups.is_none() && (line.starts_with("[{") || line.trim() == "[]") {
+			groups = Some(parse_json::<Vec<ForgetGroupJson>>("forget", line)?);
+		} else if !line.trim().is_empty() {
+			messages.push(line.to_owned());
+		}
+	}
+	Ok((groups.ok_or(ErrorKind::NoOutputFromRestic)?, messages))
+}
+
+impl ResticConfig {
+	/// Like `forget`, but returns the kept snapshots with the rules that kept them and the
+	/// removed snapshots, per group
+	pub fn forget_detailed(&self, forget_rate: &ForgetRate, tags: &[Vec<String>]) -> Result<ForgetResult> {
+		let _span = operation_span!(self, "forget");
+		let _guard = self.repo_guard(true)?;
+		self.require_forget_rate_supported(forget_rate)?;
+		let mut cmd = self.forget_cmd(forget_rate, tags)?;
+		cmd.arg("--json");
+		let (groups, messages) = self.output_parsing("forget", &mut cmd, |stdout_data| parse_forget_output(&stdout_data))?;
+		Ok(ForgetResult {
+			groups: groups.into_iter().map(ForgetGroup::from).collect(),
+			messages,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The fixture is hand-written in restic's format, it isn't captured
+	#[test]
+	fn pairs_reasons_with_kept_snapshots() {
+		let stdout = include_str!("../fixtures/synthetic_forget.txt");
+		let (groups, messages) = parse_forget_output(stdout).unwrap();
+		let result = ForgetResult {
+			groups: groups.into_iter().map(ForgetGroup::from).collect(),
+			messages,
+		};
+
+		assert_eq!(result.groups.len(), 2);
+		assert_eq!(result.snapshots_removed(), 2);
+		assert_eq!(result.messages, vec!["remove 2 snapshots:", "[0:00] 100.00%  2 / 2 files deleted"]);
+
+		let desktop = &result.groups[0];
+		assert_eq!(desktop.host, "desktop");
+		assert_eq!(desktop.paths, vec!["/home/user"]);
+		assert_eq!(desktop.kept.len(), 2);
+		let newest = desktop.kept[0].reason.as_ref().unwrap();
+		assert_eq!(newest.matches, vec!["last snapshot", "daily snapshot"]);
+		assert_eq!(newest.rules, vec![RetentionRule::Last, RetentionRule::Daily]);
+		assert_eq!(newest.counters.get("daily"), Some(&6));
+		let older = desktop.kept[1].reason.as_ref().unwrap();
+		assert_eq!(older.matches, vec!["daily snapshot"]);
+		assert_eq!(older.counters.get("daily"), Some(&5));
+		assert_eq!(desktop.removed[0].short_id, "c2d8a1f0");
+
+		// A group without reasons, as versions before 0.10 print it
+		let laptop = &result.groups[1];
+		assert!(laptop.tags.is_empty());
+		assert!(laptop.kept[0].reason.is_none());
+	}
+
+	/// Runs restic itself, `cargo test -- --ignored` with restic in PATH
+	#[cfg(unix)]
+	#[test]
+	#[ignore]
+	fn parses_what_restic_forget_prints() {
+		use crate::{BackupTarget, KeepCount, ResticStorageConfig, ResticVersion};
+
+		let dir = crate::test_util::test_dir("forget-detailed");
+		let data = dir.join("data");
+		std::fs::create_dir_all(&data).unwrap();
+
+		let config = ResticConfig::new("1234".to_owned(), ResticStorageConfig::Local(dir.join("repo")));
+		config.create_restic_repo().unwrap();
+		let version = config.restic_version().unwrap();
+		let target = BackupTarget::new(&[&data], Vec::new(), Vec::new()).unwrap();
+		for round in 0..3 {
+			std::fs::write(data.join("file.txt"), format!("round {}", round)).unwrap();
+			config.restic_backup(&target, None).unwrap();
+		}
+
+		let forget_rate = ForgetRate {
+			keep_last: Some(KeepCount::Limited(1)),
+			..Default::default()
+		};
+		let result = config.forget_detailed(&forget_rate, &[]).unwrap();
+		assert_eq!(result.groups.len(), 1);
+		assert_eq!(result.snapshots_removed(), 2);
+		let group = &result.groups[0];
+		assert_eq!(group.kept.len(), 1);
+		if version >= ResticVersion::new(0, 10, 0) {
+			let reason = group.kept[0].reason.as_ref().unwrap();
+			assert_eq!(reason.rules, vec![RetentionRule::Last]);
+		}
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn maps_descriptions_to_rules() {
+		let rules: Vec<RetentionRule> = [
+			"oldest weekly snapshot",
+			"has tags [important]",
+			"has tags [a, b]",
+			"within 1y2m",
+			"policy is empty",
+		]
+		.iter()
+		.map(|description| RetentionRule::from_match(description))
+		.collect();
+		assert_eq!(
+			rules,
+			vec![
+				RetentionRule::Weekly,
+				RetentionRule::Tag("important".to_owned()),
+				RetentionRule::Tag("a,b".to_owned()),
+				RetentionRule::Within,
+				RetentionRule::Other("policy is empty".to_owned()),
+			]
+		);
+	}
+}
@@ -0,0 +1,213 @@
+use crate::errors::*;
+use crate::{BackupTarget, RepositorySource, ResticConfig, ResticStorageConfig};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Free space a backup to a local repository has to leave by default, 1 GiB
+pub const DEFAULT_FREE_SPACE_MARGIN: u64 = 1024 * 1024 * 1024;
+
+/// Check before a backup to a local repository that its filesystem has room for it, see
+/// `ResticConfig::free_space_check`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FreeSpaceCheck {
+	/// Bytes that have to stay free on top of the backup
+	#[serde(default = "default_margin")]
+	pub margin: u64,
+	/// Bytes the backup will add. By default what the target's latest snapshot added
+	/// (`SnapshotSummary::data_added`), since restic only stores what changed. A target that
+	/// was never backed up needs its whole size from `BackupTarget::estimate`. Snapshots of
+	/// restic before 0.17 have no summary, the check is then skipped unless this is set.
+	#[serde(default)]
+	pub expected_size: Option<u64>,
+}
+
+fn default_margin() -> u64 {
+	DEFAULT_FREE_SPACE_MARGIN
+}
+
+impl Default for FreeSpaceCheck {
+	fn default() -> Self {
+		FreeSpaceCheck {
+			margin: DEFAULT_FREE_SPACE_MARGIN,
+			expected_size: None,
+		}
+	}
+}
+
+impl ResticConfig {
+	/// Fail with `InsufficientSpace` when a local repository can't hold the backup of
+	/// `target`. Does nothing without `free_space_check` and for other backends. When the
+	/// free space or the expected size can't be read the backup goes ahead.
+	pub(crate) fn check_free_space(&self, target: &BackupTarget) -> Result<()> {
+		let check = match &self.free_space_check {
+			Some(check) => check,
+			None => return Ok(()),
+		};
+		let repo = match &*self.repo_source() {
+			RepositorySource::Inline(ResticStorageConfig::Local(path)) => path.clone(),
+			_ => return Ok(()),
+		};
+
+		let available = match available_space(&repo) {
+			Ok(available) => available,
+			Err(err) => {
+				log::warn!("Skipped the free space check, can't read the free space of {}: {}", repo.display(), err);
+				return Ok(());
+			}
+		};
+		let expected = match check.expected_size {
+			Some(expected) => expected,
+			None => match self.expected_backup_size(target) {
+				Ok(Some(expected)) => expected,
+				Ok(None) => {
+					log::warn!("Skipped the free space check, the latest snapshot of the target has no summary");
+					return Ok(());
+				}
+				Err(err) => {
+					log::warn!("Skipped the free space check, can't estimate the backup: {}", err);
+					return Ok(());
+				}
+			},
+		};
+		let needed = expected.saturating_add(check.margin);
+		if available < needed {
+			return Err(ErrorKind::InsufficientSpace { needed, available }.into());
+		}
+		Ok(())
+	}
+
+	/// What the latest backup of `target` added, the whole target when there is none yet
+	fn expected_backup_size(&self, target: &BackupTarget) -> Result<Option<u64>> {
+		match self.latest_snapshot_for_target(target)? {
+			Some(snapshot) => Ok(snapshot.summary.map(|summary| summary.data_added)),
+			None => Ok(Some(target.estimate().chain_err(|| "Failed to estimate the target")?.bytes)),
+		}
+	}
+}
+
+/// Bytes available to this process on the filesystem of `path`, or of its nearest existing
+/// ancestor for a repository that doesn't exist yet
+fn available_space(path: &Path) -> std::io::Result<u64> {
+	let existing = path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or_else(|| Path::new("."));
+	filesystem_available(existing)
+}
+
+#[cfg(unix)]
+fn filesystem_available(path: &Path) -> std::io::Result<u64> {
+	use std::os::unix::ffi::OsStrExt;
+
+	let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+		.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(windows)]
+fn filesystem_available(path: &Path) -> std::io::Result<u64> {
+	use std::os::windows::ffi::OsStrExt;
+
+	extern "system" {
+		fn GetDiskFreeSpaceExW(dir: *const u16, available: *mut u64, total: *mut u64, free: *mut u64) -> i32;
+	}
+
+	let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+	let mut available = 0;
+	let ok = unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) };
+	if ok == 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn filesystem_available(_path: &Path) -> std::io::Result<u64> {
+	Err(std::io::Error::new(std::io::ErrorKind::Other, "not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::B2Config;
+
+	#[test]
+	fn fails_early_for_local_repositories_only() {
+		let dir = crate::test_util::test_dir("free-space");
+		std::fs::create_dir_all(dir.join("data")).unwrap();
+		assert!(available_space(&dir.join("repo/not/created")).unwrap() > 0);
+
+		let target = BackupTarget {
+			folders: vec![dir.join("data")],
+			..Default::default()
+		};
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(dir.join("missing-restic"));
+		assert!(config.check_free_space(&target).is_ok());
+
+		config.free_space_check = Some(FreeSpaceCheck {
+			margin: 0,
+			expected_size: Some(u64::MAX),
+		});
+		let err = config.restic_backup(&target, None).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InsufficientSpace { needed: u64::MAX, .. }), "{}", err);
+
+		// The estimate of the empty target fits
+		config.free_space_check = Some(FreeSpaceCheck {
+			margin: 0,
+			expected_size: None,
+		});
+		assert!(config.check_free_space(&target).is_ok());
+
+		config.free_space_check = Some(FreeSpaceCheck {
+			margin: 0,
+			expected_size: Some(u64::MAX),
+		});
+		config.repo_path = ResticStorageConfig::B2(B2Config {
+			bucket_name: "bucket".into(),
+			repo_path: "repo".into(),
+			account_key: "key".into(),
+			account_id: "account".into(),
+			connections: None,
+		})
+		.into();
+		assert!(config.check_free_space(&target).is_ok());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn expects_what_the_latest_snapshot_added() {
+		let dir = crate::test_util::test_dir("free-space-incremental");
+		std::fs::create_dir_all(dir.join("data")).unwrap();
+		let snapshot = format!(
+			r#"[{{"id":"1234abcd","short_id":"1234abcd","time":"2024-05-01T00:00:00Z","tree":"","paths":["{}"],"hostname":"host","username":"user","summary":{{"backup_start":"2024-05-01T00:00:00Z","backup_end":"2024-05-01T00:01:00Z","data_added":{}}}}}]"#,
+			dir.join("data").display(),
+			u64::MAX
+		);
+		let script = format!("#!/bin/sh\ncat '{}'\n", dir.join("snapshots.json").display());
+
+		let target = BackupTarget {
+			folders: vec![dir.join("data")],
+			..Default::default()
+		};
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, &script));
+		config.free_space_check = Some(FreeSpaceCheck {
+			margin: 0,
+			expected_size: None,
+		});
+
+		// Never backed up, the empty target fits
+		std::fs::write(dir.join("snapshots.json"), "[]").unwrap();
+		assert!(config.check_free_space(&target).is_ok());
+
+		std::fs::write(dir.join("snapshots.json"), snapshot).unwrap();
+		let err = config.check_free_space(&target).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InsufficientSpace { needed: u64::MAX, .. }), "{}", err);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
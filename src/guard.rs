@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+
+/// Repositories with a mutating operation running in this process, keyed by repository path
+static REPO_LOCKS: OnceLock<Mutex<HashMap<String, Weak<RepoLock>>>> = OnceLock::new();
+
+#[derive(Default)]
+struct RepoLock {
+	busy: Mutex<bool>,
+	released: Condvar,
+}
+
+/// Held while a mutating operation runs on a repository, releases it on drop
+pub(crate) struct RepoGuard {
+	lock: Arc<RepoLock>,
+}
+
+impl Drop for RepoGuard {
+	fn drop(&mut self) {
+		*self.lock.busy.lock().unwrap_or_else(|err| err.into_inner()) = false;
+		self.lock.released.notify_one();
+	}
+}
+
+fn lock_for(key: &str) -> Arc<RepoLock> {
+	let mut locks = REPO_LOCKS
+		.get_or_init(Default::default)
+		.lock()
+		.unwrap_or_else(|err| err.into_inner());
+
+	if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+		return lock;
+	}
+
+	locks.retain(|_, lock| lock.strong_count() > 0);
+	let lock = Arc::new(RepoLock::default());
+	locks.insert(key.to_owned(), Arc::downgrade(&lock));
+	lock
+}
+
+/// Acquire the guard for a repository, None if `blocking` is false and another operation holds it
+pub(crate) fn acquire(key: &str, blocking: bool) -> Option<RepoGuard> {
+	let lock = lock_for(key);
+	{
+		let mut busy = lock.busy.lock().unwrap_or_else(|err| err.into_inner());
+		while *busy {
+			if !blocking {
+				return None;
+			}
+			busy = lock.released.wait(busy).unwrap_or_else(|err| err.into_inner());
+		}
+		*busy = true;
+	}
+
+	Some(RepoGuard { lock })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn guard_is_exclusive_per_repo() {
+		let guard = acquire("/tmp/guard-test-repo", false).unwrap();
+		assert!(acquire("/tmp/guard-test-repo", false).is_none());
+		assert!(acquire("/tmp/other-guard-test-repo", false).is_some());
+
+		drop(guard);
+		assert!(acquire("/tmp/guard-test-repo", false).is_some());
+	}
+}
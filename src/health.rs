@@ -0,0 +1,196 @@
+use crate::check::CheckOptions;
+use crate::errors::*;
+use crate::ResticConfig;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What `health_check` verifies
+#[derive(Debug, Clone, Default)]
+pub struct HealthCheckOptions {
+	/// Fail the snapshot check when the newest snapshot is older than this, unset only
+	/// requires any snapshot to exist
+	pub max_snapshot_age: Option<Duration>,
+	/// Also run `check --with-cache`, which only verifies the repository structure
+	pub run_check: bool,
+	/// Timeout for each restic call, overrides the config's `timeout`
+	pub timeout: Option<Duration>,
+}
+
+/// Result of one sub-check of `health_check`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HealthCheck {
+	pub name: &'static str,
+	pub passed: bool,
+	pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthReport {
+	pub checks: Vec<HealthCheck>,
+	/// Size of the local cache in bytes, None when running without cache or it couldn't be found
+	pub cache_size: Option<u64>,
+}
+
+impl HealthReport {
+	/// Whether all sub-checks passed
+	pub fn healthy(&self) -> bool {
+		self.checks.iter().all(|check| check.passed)
+	}
+
+	fn record(&mut self, name: &'static str, result: std::result::Result<String, String>) {
+		let (passed, message) = match result {
+			Ok(message) => (true, message),
+			Err(message) => (false, message),
+		};
+		self.checks.push(HealthCheck { name, passed, message });
+	}
+}
+
+impl ResticConfig {
+	/// Check the repository for monitoring probes.
+	///
+	/// Runs the sub-checks `repository` (reachable, password works), `snapshots` (newest
+	/// snapshot is recent enough) and optionally `check`, each reported separately. Every restic
+	/// call is bounded by the timeout, so a dead backend fails the probe instead of hanging it.
+	/// The config's retries and stale lock removal are skipped, they would stretch a call past
+	/// the timeout. Only fails when the config itself is unusable.
+	pub fn health_check(&self, options: &HealthCheckOptions) -> Result<HealthReport> {
+		let _span = operation_span!(self, "health_check");
+		let mut config = self.clone();
+		config.timeout = options.timeout.or(self.timeout);
+		config.retry_policy = None;
+		config.auto_unlock_stale = false;
+		config.cmd_setup()?;
+
+		let mut report = HealthReport {
+			checks: Vec::new(),
+			cache_size: config.cache_location().and_then(|dir| dir_size(&dir)),
+		};
+
+		let reachable = config.cat_config().map(|repo| format!("repository {} reachable", repo.id));
+		let reachable_ok = reachable.is_ok();
+		report.record("repository", reachable.map_err(|err| err.to_string()));
+		if !reachable_ok {
+			// Everything else would fail or hit the timeout again the same way
+			report.record("snapshots", Err("skipped, repository not reachable".to_owned()));
+			if options.run_check {
+				report.record("check", Err("skipped, repository not reachable".to_owned()));
+			}
+			return Ok(report);
+		}
+
+		let snapshots = config.get_restic_snapshots().map_err(|err| err.to_string()).and_then(|snapshots| {
+			let newest = snapshots
+				.iter()
+				.filter_map(|snapshot| snapshot.timestamp())
+				.max()
+				.ok_or_else(|| "repository has no snapshots".to_owned())?;
+			let age = chrono::Utc::now().signed_duration_since(newest).to_std().unwrap_or_default();
+			match options.max_snapshot_age {
+				Some(max_age) if age > max_age => Err(format!(
+					"newest snapshot is {}s old, more than the allowed {}s",
+					age.as_secs(),
+					max_age.as_secs()
+				)),
+				_ => Ok(format!("newest snapshot is {}s old", age.as_secs())),
+			}
+		});
+		report.record("snapshots", snapshots);
+
+		if options.run_check {
+			let check_options = CheckOptions {
+				with_cache: true,
+				..Default::default()
+			};
+			let check = match config.check(&check_options) {
+				Ok(check) if check.ok => Ok("no errors found".to_owned()),
+				Ok(check) => Err(check.errors.join("\n")),
+				Err(err) => Err(err.to_string()),
+			};
+			report.record("check", check);
+		}
+
+		Ok(report)
+	}
+
+	/// Directory restic uses as cache, following restic's own lookup
+	fn cache_location(&self) -> Option<PathBuf> {
+		if self.no_cache {
+			return None;
+		}
+		if let Some(cache_dir) = &self.cache_dir {
+			return Some(cache_dir.clone());
+		}
+		if let Some(cache_dir) = self.extra_env.get("RESTIC_CACHE_DIR") {
+			return Some(cache_dir.into());
+		}
+		if let Some(cache_dir) = std::env::var_os("RESTIC_CACHE_DIR").filter(|_| !self.isolate_env) {
+			return Some(cache_dir.into());
+		}
+		if cfg!(windows) {
+			std::env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("restic"))
+		} else if cfg!(target_os = "macos") {
+			std::env::var_os("HOME").map(|dir| PathBuf::from(dir).join("Library/Caches/restic"))
+		} else {
+			std::env::var_os("XDG_CACHE_HOME")
+				.map(PathBuf::from)
+				.or_else(|| std::env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".cache")))
+				.map(|dir| dir.join("restic"))
+		}
+	}
+}
+
+fn dir_size(dir: &std::path::Path) -> Option<u64> {
+	if !dir.is_dir() {
+		return None;
+	}
+	Some(
+		walkdir::WalkDir::new(dir)
+			.into_iter()
+			.filter_map(|entry| entry.ok())
+			.filter_map(|entry| entry.metadata().ok())
+			.filter(|metadata| metadata.is_file())
+			.map(|metadata| metadata.len())
+			.sum(),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn report_is_healthy_only_if_all_checks_pass() {
+		let mut report = HealthReport::default();
+		report.record("repository", Ok("reachable".to_owned()));
+		assert!(report.healthy());
+		report.record("snapshots", Err("too old".to_owned()));
+		assert!(!report.healthy());
+		assert_eq!(report.checks[1].message, "too old");
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn runs_each_call_once_despite_retries() {
+		let dir = crate::test_util::test_dir("health-retry");
+		let script = format!(
+			"#!/bin/sh
+echo \"$*\" >> '{}'
+echo 'Fatal: unable to open repository: dial tcp: connection refused' >&2
+exit 1
+",
+			dir.join("args").display()
+		);
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, &script));
+		config.retry_policy = Some(crate::RetryPolicy::new(3, Duration::from_millis(1)));
+		config.auto_unlock_stale = true;
+
+		let report = config.health_check(&HealthCheckOptions::default()).unwrap();
+		assert!(!report.healthy());
+		let args = std::fs::read_to_string(dir.join("args")).unwrap();
+		assert_eq!(args.lines().count(), 1, "{}", args);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
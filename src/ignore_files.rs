@@ -0,0 +1,129 @@
+use crate::backup_target::escape_restic_glob;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::path::{Path, PathBuf};
+
+/// The `.gitignore` style ignore files that apply at the current position of a depth first walk
+pub(crate) struct IgnoreStack {
+	file_names: Vec<String>,
+	/// Directories with ignore files above the current entry, outermost first
+	stack: Vec<(PathBuf, Gitignore)>,
+}
+
+impl IgnoreStack {
+	pub(crate) fn new(file_names: &[String]) -> IgnoreStack {
+		IgnoreStack {
+			file_names: file_names.to_vec(),
+			stack: Vec::new(),
+		}
+	}
+
+	/// Forget the ignore files of directories that don't contain `path`
+	fn leave_unrelated(&mut self, path: &Path) {
+		while self.stack.last().is_some_and(|(dir, _)| !path.starts_with(dir)) {
+			self.stack.pop();
+		}
+	}
+
+	/// Load the ignore files of a directory the walk descends into
+	pub(crate) fn enter_dir(&mut self, dir: &Path) {
+		self.leave_unrelated(dir);
+
+		let mut builder = GitignoreBuilder::new(dir);
+		let mut found = false;
+		for name in &self.file_names {
+			let file = dir.join(name);
+			if !file.is_file() {
+				continue;
+			}
+			found = true;
+			if let Some(err) = builder.add(&file) {
+				log::warn!("Error in ignore file {}: {}", file.display(), err);
+			}
+		}
+		if !found {
+			return;
+		}
+
+		match builder.build() {
+			Ok(gitignore) => self.stack.push((dir.to_owned(), gitignore)),
+			Err(err) => log::warn!("Failed to load ignore files in {}: {}", dir.display(), err),
+		}
+	}
+
+	/// Load the ignore files of all directories from `folder` down to the parent of `root`, for
+	/// walks that start below a backup folder
+	pub(crate) fn enter_parents(&mut self, folder: &Path, root: &Path) {
+		let mut parents: Vec<&Path> = root.ancestors().skip(1).take_while(|dir| dir.starts_with(folder)).collect();
+		parents.reverse();
+		for dir in parents {
+			self.enter_dir(dir);
+		}
+	}
+
+	/// Whether the ignore files above `path` exclude it, deeper files take precedence
+	pub(crate) fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+		self.leave_unrelated(path);
+		for (_, gitignore) in self.stack.iter().rev() {
+			match gitignore.matched(path, is_dir) {
+				Match::Ignore(_) => return true,
+				Match::Whitelist(_) => return false,
+				Match::None => {}
+			}
+		}
+		false
+	}
+
+	/// Whether `path` inside `folder` is ignored, either itself or through one of its parents
+	pub(crate) fn is_path_ignored(&mut self, folder: &Path, path: &Path) -> bool {
+		let mut dirs: Vec<&Path> = path.ancestors().take_while(|dir| dir.starts_with(folder)).collect();
+		dirs.reverse();
+		for dir in dirs {
+			let is_dir = dir != path || path.symlink_metadata().is_ok_and(|metadata| metadata.is_dir());
+			if self.is_ignored(dir, is_dir) {
+				return true;
+			}
+			if dir != path {
+				self.enter_dir(dir);
+			}
+		}
+		false
+	}
+}
+
+/// Translate an ignore file into restic exclude patterns with the same meaning.
+///
+/// Patterns without a slash match at any depth below the file's directory, others are anchored
+/// to it. restic can't restrict a pattern to directories, so a trailing `/` is dropped. The
+/// directory is escaped, only the file's patterns are globs.
+pub(crate) fn restic_patterns(ignore_file: &Path) -> Vec<String> {
+	let dir = match ignore_file.parent() {
+		Some(dir) => escape_restic_glob(dir.to_string_lossy().trim_end_matches('/')),
+		None => return Vec::new(),
+	};
+	let contents = match std::fs::read_to_string(ignore_file) {
+		Ok(contents) => contents,
+		Err(err) => {
+			log::warn!("Failed to read ignore file {}: {}", ignore_file.display(), err);
+			return Vec::new();
+		}
+	};
+
+	contents
+		.lines()
+		.map(str::trim_end)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(|line| {
+			let (negated, pattern) = match line.strip_prefix('!') {
+				Some(pattern) => ("!", pattern),
+				None => ("", line),
+			};
+			let pattern = pattern.trim_end_matches('/');
+			if pattern.contains('/') {
+				format!("{}{}/{}", negated, dir, pattern.trim_start_matches('/'))
+			} else {
+				format!("{}{}/**/{}", negated, dir, pattern)
+			}
+		})
+		.collect()
+}
@@ -0,0 +1,331 @@
+use crate::errors::*;
+use crate::{BackupJson, BackupTarget, ResticConfig};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How a journaled backup ended
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupOutcome {
+	Success,
+	/// restic made a snapshot but couldn't read some files, see `ErrorKind::PartialBackup`
+	Partial,
+	Failed,
+}
+
+/// The numbers of a backup summary
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct BackupCounts {
+	pub files_new: u64,
+	pub files_changed: u64,
+	pub files_unmodified: u64,
+	pub dirs_new: u64,
+	pub dirs_changed: u64,
+	pub dirs_unmodified: u64,
+	pub data_added: u64,
+	pub total_files_processed: u64,
+	pub total_bytes_processed: u64,
+}
+
+/// One backup in a `BackupJournal`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BackupRecord {
+	/// `BackupTarget::identity` of the target
+	pub target_id: String,
+	pub folders: Vec<PathBuf>,
+	pub tags: Vec<String>,
+	pub started_at: DateTime<Utc>,
+	pub duration: Duration,
+	pub outcome: BackupOutcome,
+	/// Empty when no snapshot was made
+	#[serde(default)]
+	pub snapshot_id: String,
+	/// None when the backup failed, or restic didn't print a summary
+	#[serde(default)]
+	pub counts: Option<BackupCounts>,
+	#[serde(default)]
+	pub warnings: usize,
+	/// The error of a failed backup
+	#[serde(default)]
+	pub error: Option<String>,
+}
+
+impl BackupRecord {
+	/// The record of a backup of `target` that returned `result`, for backups not run through
+	/// `restic_backup` with a journal
+	pub fn from_result(
+		target: &BackupTarget,
+		started_at: DateTime<Utc>,
+		duration: Duration,
+		result: &Result<BackupJson>,
+		warnings: usize,
+	) -> BackupRecord {
+		let mut record = BackupRecord {
+			target_id: target.identity(),
+			folders: target.folders.clone(),
+			tags: target.tags.clone(),
+			started_at,
+			duration,
+			outcome: BackupOutcome::Failed,
+			snapshot_id: String::new(),
+			counts: None,
+			warnings,
+			error: None,
+		};
+		match result {
+			Ok(summary) => {
+				if record.fill_from(summary) {
+					record.outcome = BackupOutcome::Success;
+				} else {
+					record.error = Some("restic printed no summary".to_owned());
+				}
+			}
+			Err(err) => {
+				if let ErrorKind::PartialBackup(summary) = err.kind() {
+					record.outcome = BackupOutcome::Partial;
+					if let Some(summary) = summary {
+						record.fill_from(summary);
+					}
+				}
+				record.error = Some(err.to_string());
+			}
+		}
+		record
+	}
+
+	/// Take the snapshot id and counts of `summary`, false when it isn't a summary
+	fn fill_from(&mut self, summary: &BackupJson) -> bool {
+		if let BackupJson::Summary {
+			files_new,
+			files_changed,
+			files_unmodified,
+			dirs_new,
+			dirs_changed,
+			dirs_unmodified,
+			data_added,
+			total_files_processed,
+			total_bytes_processed,
+			snapshot_id,
+			..
+		} = summary
+		{
+			self.snapshot_id = snapshot_id.clone();
+			self.counts = Some(BackupCounts {
+				files_new: *files_new,
+				files_changed: *files_changed,
+				files_unmodified: *files_unmodified,
+				dirs_new: *dirs_new,
+				dirs_changed: *dirs_changed,
+				dirs_unmodified: *dirs_unmodified,
+				data_added: *data_added,
+				total_files_processed: *total_files_processed,
+				total_bytes_processed: *total_bytes_processed,
+			});
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Backup history in a JSON lines file, one `BackupRecord` per line, e.g. to chart the growth
+/// of a repository.
+///
+/// Writers take an exclusive and readers a shared advisory lock on the file, so several
+/// processes can share a journal. Lines that can't be parsed are skipped when reading.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BackupJournal {
+	path: PathBuf,
+}
+
+impl BackupJournal {
+	/// The journal at `path`, the file is created by the first `record`
+	pub fn new<P: Into<PathBuf>>(path: P) -> BackupJournal {
+		BackupJournal { path: path.into() }
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Append a record
+	pub fn record(&self, record: &BackupRecord) -> Result<()> {
+		let mut line = serde_json::to_vec(record).chain_err(|| "Failed to serialize backup record")?;
+		line.push(b'\n');
+
+		let mut file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)
+			.chain_err(|| format!("Failed to open journal {}", self.path.display()))?;
+		file.lock().chain_err(|| "Failed to lock the journal")?;
+		// One write, so a reader that doesn't lock never sees half a line from us
+		file.write_all(&line).chain_err(|| "Failed to write the journal")?;
+		file.flush().chain_err(|| "Failed to write the journal")?;
+		Ok(())
+	}
+
+	/// The records started within `range`, of `target` only when given, oldest first. A journal
+	/// that doesn't exist yet is empty.
+	pub fn query<R: RangeBounds<DateTime<Utc>>>(&self, range: R, target: Option<&BackupTarget>) -> Result<Vec<BackupRecord>> {
+		let file = match File::open(&self.path) {
+			Ok(file) => file,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(err) => return Err(Error::with_chain(err, format!("Failed to open journal {}", self.path.display()))),
+		};
+		file.lock_shared().chain_err(|| "Failed to lock the journal")?;
+		let target_id = target.map(BackupTarget::identity);
+
+		let mut records = Vec::new();
+		for line in BufReader::new(&file).lines() {
+			let line = line.chain_err(|| "Failed to read the journal")?;
+			if line.trim().is_empty() {
+				continue;
+			}
+			let record: BackupRecord = match serde_json::from_str(&line) {
+				Ok(record) => record,
+				Err(err) => {
+					log::warn!("Skipped unreadable line of journal {}: {}", self.path.display(), err);
+					continue;
+				}
+			};
+			if range.contains(&record.started_at) && target_id.as_ref().is_none_or(|id| *id == record.target_id) {
+				records.push(record);
+			}
+		}
+		records.sort_by_key(|record| record.started_at);
+		Ok(records)
+	}
+}
+
+impl BackupTarget {
+	/// A stable id of the folders and tags, independent of their order, that a `BackupJournal`
+	/// uses to tell targets apart
+	pub fn identity(&self) -> String {
+		let mut folders: Vec<_> = self.folders.iter().map(|folder| folder.to_string_lossy()).collect();
+		folders.sort();
+		let mut tags: Vec<_> = self.tags.iter().map(String::as_str).collect();
+		tags.sort_unstable();
+
+		// FNV-1a, std's hasher isn't guaranteed to be the same across Rust versions
+		let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+		let folders = folders.iter().map(|folder| folder.as_bytes());
+		let tags = tags.iter().map(|tag| tag.as_bytes());
+		// The separator keeps a folder from hashing the same as a tag
+		for part in folders.chain(std::iter::once(&b"\0"[..])).chain(tags) {
+			for byte in part.iter().chain(&[0]) {
+				hash ^= u64::from(*byte);
+				hash = hash.wrapping_mul(0x0100_0000_01b3);
+			}
+		}
+		format!("{:016x}", hash)
+	}
+}
+
+impl ResticConfig {
+	/// `run_backup`, recording the backup in `journal` when given, see `restic_backup`
+	pub(crate) fn run_backup_journaled(
+		&self,
+		backup_targets: &BackupTarget,
+		warnings: &mut Vec<String>,
+		journal: Option<&BackupJournal>,
+	) -> Result<BackupJson> {
+		let started_at = Utc::now();
+		let started = Instant::now();
+		let result = self.run_backup(backup_targets, warnings);
+		if let Some(journal) = journal {
+			let record = BackupRecord::from_result(backup_targets, started_at, started.elapsed(), &result, warnings.len());
+			if let Err(err) = journal.record(&record) {
+				log::warn!("Failed to record the backup in {}: {}", journal.path().display(), err);
+			}
+		}
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn record(target: &BackupTarget, hour: u32, result: &Result<BackupJson>) -> BackupRecord {
+		let started_at = Utc.with_ymd_and_hms(2024, 5, 1, hour, 0, 0).unwrap();
+		BackupRecord::from_result(target, started_at, Duration::from_secs(90), result, 1)
+	}
+
+	#[test]
+	fn records_and_queries() {
+		let dir = crate::test_util::test_dir("journal");
+		let journal = BackupJournal::new(dir.join("journal.jsonl"));
+		assert!(journal.query(.., None).unwrap().is_empty());
+
+		let docs = BackupTarget {
+			folders: vec!["/data/docs".into(), "/data/notes".into()],
+			tags: vec!["nightly".into(), "docs".into()],
+			..Default::default()
+		};
+		let photos = BackupTarget {
+			folders: vec!["/data/photos".into()],
+			..Default::default()
+		};
+		let summary: BackupJson = serde_json::from_str(
+			r#"{"message_type":"summary","files_new":3,"files_changed":1,"files_unmodified":10,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":2,"data_blobs":4,"tree_blobs":2,"data_added":2048,"total_files_processed":14,"total_bytes_processed":4096,"total_duration":1.5,"snapshot_id":"1234abcd"}"#,
+		)
+		.unwrap();
+
+		let threads: Vec<_> = (0..4)
+			.map(|hour| {
+				let journal = journal.clone();
+				let (docs, photos, summary) = (docs.clone(), photos.clone(), summary.clone());
+				std::thread::spawn(move || {
+					let target = if hour % 2 == 0 { &docs } else { &photos };
+					let result = match hour {
+						2 => Err(ErrorKind::PartialBackup(None).into()),
+						3 => Err(ErrorKind::PartialBackup(Some(Box::new(summary))).into()),
+						_ => Ok(summary),
+					};
+					journal.record(&record(target, hour, &result)).unwrap();
+				})
+			})
+			.collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		std::fs::OpenOptions::new()
+			.append(true)
+			.open(journal.path())
+			.unwrap()
+			.write_all(b"{\"truncated\n")
+			.unwrap();
+
+		let all = journal.query(.., None).unwrap();
+		let hours: Vec<_> = all.iter().map(|record| record.started_at.format("%H").to_string()).collect();
+		assert_eq!(hours, ["00", "01", "02", "03"]);
+		assert_eq!(all[0].counts.unwrap().data_added, 2048);
+		assert_eq!(all[0].snapshot_id, "1234abcd");
+		assert_eq!(all[2].outcome, BackupOutcome::Partial);
+		assert_eq!(all[2].counts, None);
+		assert_eq!(all[3].outcome, BackupOutcome::Partial);
+		assert_eq!(all[3].snapshot_id, "1234abcd");
+		assert_eq!(all[3].counts.unwrap().files_new, 3);
+
+		let mut reordered = docs.clone();
+		reordered.folders.reverse();
+		reordered.tags.reverse();
+		assert_eq!(reordered.identity(), docs.identity());
+		assert_ne!(photos.identity(), docs.identity());
+		let docs_only = journal.query(.., Some(&docs)).unwrap();
+		assert_eq!(docs_only.len(), 2);
+		assert!(docs_only.iter().all(|record| record.folders.len() == 2));
+		let from = Utc.with_ymd_and_hms(2024, 5, 1, 1, 0, 0).unwrap();
+		let to = Utc.with_ymd_and_hms(2024, 5, 1, 3, 0, 0).unwrap();
+		assert_eq!(journal.query(from..to, Some(&photos)).unwrap().len(), 1);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,161 @@
+use crate::errors::*;
+use crate::WithRaw;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Raw values in `JsonMismatch` are cut to this many characters
+const RAW_VALUE_MAX_CHARS: usize = 200;
+
+/// Parse restic's JSON output as `T`, `output` names it in errors, e.g. `snapshots`.
+///
+/// The text is parsed into a `Value` first and `T` is read from that, so an error
+/// (`ErrorKind::UnexpectedJson`) can name the field that failed and show what restic printed
+/// there.
+pub(crate) fn parse_json<T: DeserializeOwned>(output: &str, json: &str) -> Result<T> {
+	from_value(output, &parse_value(output, json)?)
+}
+
+/// Parse restic's JSON output once into a `Value` and keep it next to the `T` read from it
+pub(crate) fn parse_json_with_raw<T: DeserializeOwned>(output: &str, json: &str) -> Result<WithRaw<T>> {
+	let raw = parse_value(output, json)?;
+	Ok(WithRaw {
+		value: from_value(output, &raw)?,
+		raw,
+	})
+}
+
+/// Like `parse_json_with_raw` for a JSON array, each element keeps its own raw value
+pub(crate) fn parse_json_list_with_raw<T: DeserializeOwned>(output: &str, json: &str) -> Result<Vec<WithRaw<T>>> {
+	let raw = parse_value(output, json)?;
+	let values: Vec<T> = from_value(output, &raw)?;
+	let items = match raw {
+		Value::Array(items) => items,
+		_ => unreachable!("only an array deserializes as a Vec"),
+	};
+	Ok(values.into_iter().zip(items).map(|(value, raw)| WithRaw { value, raw }).collect())
+}
+
+fn parse_value(output: &str, json: &str) -> Result<Value> {
+	serde_json::from_str(json).map_err(|err| {
+		let mismatch = JsonMismatch {
+			output: output.to_owned(),
+			path: String::new(),
+			raw_value: Some(shortened(json.trim())),
+			message: err.to_string(),
+		};
+		Error::with_chain(err, ErrorKind::UnexpectedJson(mismatch))
+	})
+}
+
+fn from_value<T: DeserializeOwned>(output: &str, value: &Value) -> Result<T> {
+	serde_path_to_error::deserialize(value).map_err(|err| {
+		let (path, raw_value) = locate(value, err.path());
+		let err = err.into_inner();
+		let mismatch = JsonMismatch {
+			output: output.to_owned(),
+			path,
+			raw_value: raw_value.map(|raw| shortened(&raw.to_string())),
+			message: err.to_string(),
+		};
+		Error::with_chain(err, ErrorKind::UnexpectedJson(mismatch))
+	})
+}
+
+/// The path as `[0].summary.backup_start` and the value at it. For a missing field the path
+/// ends at the object that lacks it.
+fn locate<'a>(value: &'a Value, path: &serde_path_to_error::Path) -> (String, Option<&'a Value>) {
+	use serde_path_to_error::Segment;
+
+	let mut display = String::new();
+	let mut current = Some(value);
+	for segment in path.iter() {
+		match segment {
+			Segment::Seq { index } => {
+				display.push_str(&format!("[{}]", index));
+				current = current.and_then(|value| value.get(index));
+			}
+			Segment::Map { key } => {
+				if !display.is_empty() {
+					display.push('.');
+				}
+				display.push_str(key);
+				current = current.and_then(|value| value.get(key));
+			}
+			// Internally tagged enums keep their fields in the same object
+			Segment::Enum { .. } => (),
+			_ => {
+				display.push_str(".?");
+				current = None;
+			}
+		}
+	}
+	(display, current)
+}
+
+fn shortened(raw: &str) -> String {
+	match raw.char_indices().nth(RAW_VALUE_MAX_CHARS) {
+		Some((end, _)) => format!("{}...", &raw[..end]),
+		None => raw.to_owned(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::restic_outputs::DiffLine;
+	use crate::{BackupJson, FindObjectJson, SnapshotsJson};
+
+	fn mismatch_of(err: Error) -> JsonMismatch {
+		match err.kind() {
+			ErrorKind::UnexpectedJson(mismatch) => mismatch.clone(),
+			other => panic!("unexpected error {}", other),
+		}
+	}
+
+	#[test]
+	fn names_the_field_and_its_value() {
+		let json = include_str!("../fixtures/restic_snapshots/0.17.0.json").replacen(
+			r#""time":"2024-08-03T11:02:51.371524812+02:00""#,
+			r#""time":1722675771"#,
+			1,
+		);
+		let err = parse_json::<Vec<SnapshotsJson>>("snapshots", &json).unwrap_err();
+		let mismatch = mismatch_of(err);
+		assert_eq!(mismatch.output, "snapshots");
+		assert_eq!(mismatch.path, "[0].time");
+		assert_eq!(mismatch.raw_value.as_deref(), Some("1722675771"));
+		assert!(mismatch.message.contains("expected a string"), "{}", mismatch.message);
+
+		let err = parse_json::<Vec<FindObjectJson>>("find", r#"[{"object_type":"blob","id":"ab"}]"#).unwrap_err();
+		let mismatch = mismatch_of(err);
+		assert_eq!(mismatch.path, "[0]");
+		assert!(mismatch.message.contains("missing field `path`"), "{}", mismatch.message);
+
+		let err = parse_json::<Vec<SnapshotsJson>>("snapshots", "[{").unwrap_err();
+		assert_eq!(mismatch_of(err).raw_value.as_deref(), Some("[{"));
+	}
+
+	#[test]
+	fn keeps_unknown_fields() {
+		let snapshots: Vec<SnapshotsJson> =
+			parse_json("snapshots", include_str!("../fixtures/restic_snapshots/0.17.0.json")).unwrap();
+		assert_eq!(snapshots[0].extra.keys().collect::<Vec<_>>(), ["gid", "uid"]);
+
+		let json = r#"{"object_type":"blob","id":"ab","path":"/a","snapshot":"cd","pack_hint":"ef"}"#;
+		let found: FindObjectJson = parse_json("find", json).unwrap();
+		assert_eq!(found.extra.len(), 1);
+		assert_eq!(found.extra["pack_hint"], "ef");
+
+		let json = r#"{"message_type":"summary","files_new":1,"files_changed":0,"files_unmodified":0,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":0,"data_blobs":1,"tree_blobs":1,"data_added":10,"total_files_processed":1,"total_bytes_processed":10,"total_duration":0.1,"snapshot_id":"ab","files_skipped":2}"#;
+		match parse_json("backup", json).unwrap() {
+			BackupJson::Summary { extra, .. } => assert_eq!(extra.keys().collect::<Vec<_>>(), ["files_skipped"]),
+			other => panic!("parsed as {:?}", other),
+		}
+
+		let json = r#"{"message_type":"statistics","changed_files":1,"added":{"files":1},"removed":{},"source_snapshot":"ab"}"#;
+		match parse_json("diff", json).unwrap() {
+			DiffLine::Statistics { extra, .. } => assert_eq!(extra.keys().collect::<Vec<_>>(), ["source_snapshot"]),
+			other => panic!("parsed as {:?}", other),
+		}
+	}
+}
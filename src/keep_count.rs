@@ -0,0 +1,211 @@
+use crate::errors::*;
+use crate::restic_version::ResticVersion;
+use crate::{ForgetRate, ResticConfig};
+use serde::de::{self, Visitor};
+use serde::{Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// First version accepting `unlimited` for the `--keep-*` counts
+const UNLIMITED_VERSION: ResticVersion = ResticVersion::new(0, 15, 0);
+
+/// How many snapshots a `--keep-*` rule keeps
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeepCount {
+	/// At most this many, has to be at least 1
+	Limited(u32),
+	/// All snapshots the rule matches, needs restic 0.15 or newer
+	Unlimited,
+}
+
+impl KeepCount {
+	pub(crate) fn as_arg(self) -> Result<String> {
+		match self {
+			KeepCount::Limited(0) => Err(ErrorKind::InvalidConfig(
+				"keep counts must be at least 1, leave the rule out instead".to_owned(),
+			)
+			.into()),
+			KeepCount::Limited(count) => Ok(count.to_string()),
+			KeepCount::Unlimited => Ok("unlimited".to_owned()),
+		}
+	}
+}
+
+/// Serialized as the count, or as `"unlimited"`
+impl Serialize for KeepCount {
+	fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+		where
+			S: Serializer,
+	{
+		match self {
+			KeepCount::Limited(count) => serializer.serialize_u32(*count),
+			KeepCount::Unlimited => serializer.serialize_str("unlimited"),
+		}
+	}
+}
+
+struct KeepCountVisitor;
+
+impl<'de> Visitor<'de> for KeepCountVisitor {
+	type Value = Option<KeepCount>;
+
+	fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str("a count, -1 or \"unlimited\"")
+	}
+
+	fn visit_i64<E>(self, value: i64) -> std::result::Result<Self::Value, E>
+		where
+			E: de::Error,
+	{
+		match value {
+			-1 => Ok(Some(KeepCount::Unlimited)),
+			// 0 is how configs before KeepCount left a rule out
+			0 => Ok(None),
+			_ => u32::try_from(value)
+				.map(|count| Some(KeepCount::Limited(count)))
+				.map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self)),
+		}
+	}
+
+	fn visit_u64<E>(self, value: u64) -> std::result::Result<Self::Value, E>
+		where
+			E: de::Error,
+	{
+		match i64::try_from(value) {
+			Ok(value) => self.visit_i64(value),
+			Err(_) => Err(E::invalid_value(de::Unexpected::Unsigned(value), &self)),
+		}
+	}
+
+	fn visit_str<E>(self, value: &str) -> std::result::Result<Self::Value, E>
+		where
+			E: de::Error,
+	{
+		match value {
+			"unlimited" => Ok(Some(KeepCount::Unlimited)),
+			_ => Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+		}
+	}
+
+	fn visit_none<E>(self) -> std::result::Result<Self::Value, E>
+		where
+			E: de::Error,
+	{
+		Ok(None)
+	}
+
+	fn visit_unit<E>(self) -> std::result::Result<Self::Value, E>
+		where
+			E: de::Error,
+	{
+		Ok(None)
+	}
+
+	fn visit_some<D>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error>
+		where
+			D: Deserializer<'de>,
+	{
+		deserializer.deserialize_any(KeepCountVisitor)
+	}
+}
+
+/// For the `ForgetRate` counts: also reads the plain integers they used to be, where 0 left the
+/// rule out
+pub(crate) fn deserialize_keep_count<'de, D>(deserializer: D) -> std::result::Result<Option<KeepCount>, D::Error>
+	where
+		D: Deserializer<'de>,
+{
+	deserializer.deserialize_option(KeepCountVisitor)
+}
+
+impl ForgetRate {
+	fn counts(&self) -> [(&'static str, Option<KeepCount>); 6] {
+		[
+			("--keep-last", self.keep_last),
+			("--keep-hourly", self.keep_hourly),
+			("--keep-daily", self.keep_daily),
+			("--keep-weekly", self.keep_weekly),
+			("--keep-monthly", self.keep_monthly),
+			("--keep-yearly", self.keep_yearly),
+		]
+	}
+
+	/// The `--keep-*` count flags of the rules that are set
+	pub(crate) fn count_args(&self) -> Result<Vec<String>> {
+		let mut args = Vec::new();
+		for (flag, count) in self.counts().iter() {
+			if let Some(count) = count {
+				args.push(flag.to_string());
+				args.push(count.as_arg()?);
+			}
+		}
+		Ok(args)
+	}
+
+	/// Fail with `UnsupportedByResticVersion` for unlimited counts `version` doesn't accept
+	fn require_version(&self, version: ResticVersion) -> Result<()> {
+		match self.counts().iter().find(|(_, count)| *count == Some(KeepCount::Unlimited)) {
+			Some((flag, _)) => version.require(&format!("{} unlimited", flag), UNLIMITED_VERSION),
+			None => Ok(()),
+		}
+	}
+}
+
+impl ResticConfig {
+	/// Check the restic version before forgetting with unlimited counts. Only asks restic when
+	/// the rate has one.
+	pub(crate) fn require_forget_rate_supported(&self, forget_rate: &ForgetRate) -> Result<()> {
+		if forget_rate.counts().iter().any(|(_, count)| *count == Some(KeepCount::Unlimited)) {
+			forget_rate.require_version(self.restic_version()?)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_old_and_new_counts() {
+		let rate: ForgetRate = serde_json::from_str(
+			r#"{"keep_last": 3, "keep_hourly": 0, "keep_daily": -1, "keep_weekly": "unlimited",
+			"keep_monthly": null, "keep_yearly": 10, "keep_tags": [], "keep_within": null}"#,
+		)
+		.unwrap();
+		assert_eq!(rate.keep_last, Some(KeepCount::Limited(3)));
+		assert_eq!(rate.keep_hourly, None);
+		assert_eq!(rate.keep_daily, Some(KeepCount::Unlimited));
+		assert_eq!(rate.keep_weekly, Some(KeepCount::Unlimited));
+		assert_eq!(rate.keep_monthly, None);
+		assert_eq!(rate.keep_yearly, Some(KeepCount::Limited(10)));
+
+		let json = serde_json::to_value(&rate).unwrap();
+		assert_eq!(json["keep_last"], 3);
+		assert_eq!(json["keep_daily"], "unlimited");
+		let out: ForgetRate = serde_json::from_value(json).unwrap();
+		assert_eq!(out.counts(), rate.counts());
+
+		assert!(serde_json::from_str::<ForgetRate>(r#"{"keep_last": -2, "keep_tags": []}"#).is_err());
+		assert!(serde_json::from_str::<ForgetRate>(r#"{"keep_last": "all", "keep_tags": []}"#).is_err());
+	}
+
+	#[test]
+	fn unlimited_needs_restic_0_15() {
+		let rate = ForgetRate {
+			keep_monthly: Some(KeepCount::Unlimited),
+			..Default::default()
+		};
+		assert_eq!(rate.count_args().unwrap(), ["--keep-monthly", "unlimited"]);
+		assert!(rate.require_version(ResticVersion::new(0, 15, 0)).is_ok());
+		let err = rate.require_version(ResticVersion::new(0, 14, 0)).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::UnsupportedByResticVersion { .. }));
+
+		let zero = ForgetRate {
+			keep_last: Some(KeepCount::Limited(0)),
+			..Default::default()
+		};
+		assert!(zero.count_args().is_err());
+		assert!(zero.require_version(ResticVersion::new(0, 9, 6)).is_ok());
+	}
+}
@@ -0,0 +1,32 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::KeyJson;
+use crate::ResticConfig;
+use std::process::Command;
+
+impl ResticConfig {
+	fn key_list_cmd(&self) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("--json");
+		cmd.arg("key").arg("list");
+		Ok(cmd)
+	}
+
+	/// The keys of the repository, `current` marks the one the password opened
+	pub fn keys(&self) -> Result<Vec<KeyJson>> {
+		let _span = operation_span!(self, "key");
+		self.output_parsing("key", &mut self.key_list_cmd()?, |stdout_data| {
+			parse_json("key", &stdout_data)
+		})
+	}
+
+	/// Id of the key the password belongs to, worth persisting as `key_hint`
+	pub fn current_key_id(&self) -> Result<String> {
+		let _span = operation_span!(self, "key");
+		self.keys()?
+			.into_iter()
+			.find(|key| key.current)
+			.map(|key| key.id)
+			.ok_or_else(|| ErrorKind::NoOutputFromRestic.into())
+	}
+}
@@ -0,0 +1,167 @@
+use crate::errors::*;
+use crate::restic_outputs::{ListJson, LsLine, NodeType};
+use crate::{BackupTarget, FileMeta, ResticConfig, SnapshotId};
+use std::path::PathBuf;
+
+/// Approximate difference between the folders on disk and the latest snapshot of a target
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LocalDiff {
+	/// On disk, but not in the snapshot
+	pub added: Vec<PathBuf>,
+	/// In the snapshot, but no longer on disk
+	pub removed: Vec<PathBuf>,
+	/// Files whose size or modification time changed
+	pub modified: Vec<PathBuf>,
+	/// Id of the snapshot compared against, None if the target has no snapshot yet
+	pub snapshot_id: Option<String>,
+}
+
+impl ResticConfig {
+	/// Compare the target's folders on disk against its latest snapshot.
+	///
+	/// restic lists a snapshot in the same order the local walk visits the disk, so both
+	/// sides are merged as they stream in and memory use doesn't grow with the snapshot size.
+	/// Modifications are detected by size and mtime only, like restic's own change detection.
+	pub fn diff_local(&self, target: &BackupTarget) -> Result<LocalDiff> {
+		let _span = operation_span!(self, "diff_local");
+		let mut diff = LocalDiff::default();
+		let mut local = target
+			.walk()
+			.filter_map(|entry| match entry {
+				Ok(entry) => Some(entry),
+				Err(err) => {
+					log::warn!("Error while walking: {}", err);
+					None
+				}
+			})
+			.peekable();
+
+		if let Some(snapshot) = self.latest_snapshot_for_target(target)? {
+			let id = SnapshotId::new(&snapshot.id)?;
+			diff.snapshot_id = Some(snapshot.id);
+
+			let folders = target.effective_folders();
+			let mut cmd = self.ls_cmd(&id)?;
+			self.stream_lines("ls", &mut cmd, |line| {
+				let node = match LsLine::from_line(line)? {
+					LsLine::Node(node) => node,
+					LsLine::Snapshot(_) => return Ok(()),
+				};
+
+				// ls also lists the parent directories of the backed up folders
+				let remote_path = PathBuf::from(&node.path);
+				if !folders.iter().any(|folder| remote_path.starts_with(folder)) {
+					return Ok(());
+				}
+
+				while let Some(entry) = local.next_if(|entry| entry.path() < remote_path.as_path()) {
+					diff.added.push(entry.into_path());
+				}
+
+				match local.next_if(|entry| entry.path() == remote_path.as_path()) {
+					Some(entry) => {
+						if entry_modified(&entry, &node) {
+							diff.modified.push(remote_path);
+						}
+					}
+					None => diff.removed.push(remote_path),
+				}
+				Ok(())
+			})?;
+		}
+
+		diff.added.extend(local.map(walkdir::DirEntry::into_path));
+		Ok(diff)
+	}
+}
+
+fn entry_modified(entry: &walkdir::DirEntry, node: &ListJson) -> bool {
+	if node.node_type != NodeType::File {
+		return false;
+	}
+
+	let meta = match FileMeta::from_entry(entry) {
+		Ok(meta) => meta,
+		Err(_) => return true,
+	};
+	if meta.is_dir || Some(meta.size) != node.size {
+		return true;
+	}
+
+	let remote_mtime = node.mtime_timestamp().map(|mtime| mtime.timestamp());
+	meta.mtime_secs() != remote_mtime
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use crate::ResticStorageConfig;
+	use chrono::{DateTime, Utc};
+	use std::path::Path;
+
+	fn node(path: &Path, node_type: &str, size: Option<u64>, mtime: &str) -> String {
+		serde_json::json!({
+			"struct_type": "node", "name": path.file_name().unwrap().to_string_lossy(),
+			"path": path, "type": node_type, "size": size, "mode": 420, "uid": 1000, "gid": 1000,
+			"mtime": mtime, "atime": mtime, "ctime": mtime,
+		})
+		.to_string()
+	}
+
+	#[test]
+	fn merges_the_listing_with_the_disk() {
+		let dir = crate::test_util::test_dir("local-diff").canonicalize().unwrap();
+		let data = dir.join("data");
+		std::fs::create_dir_all(data.join("sub")).unwrap();
+		for &(name, content) in &[("a.txt", "same"), ("b.txt", "grown"), ("new.txt", "new"), ("sub/d.txt", "same")] {
+			std::fs::write(data.join(name), content).unwrap();
+		}
+		let mtime = |name: &str| {
+			let modified = std::fs::metadata(data.join(name)).unwrap().modified().unwrap();
+			DateTime::<Utc>::from(modified).to_rfc3339()
+		};
+
+		let id = "4bba301df2d1e5c3e8e3b1a4c7f5d0b0a6c9e2f1d3b5a7c9e1f3a5b7c9d1e3f5";
+		let snapshot = serde_json::json!({
+			"time": "2024-05-01T10:00:00+02:00", "tree": "ab12cd34", "paths": [&data],
+			"hostname": "laptop", "username": "me", "id": id, "short_id": &id[..8],
+		});
+		std::fs::write(dir.join("snapshots.json"), serde_json::json!([&snapshot]).to_string()).unwrap();
+		let mut header = snapshot;
+		header["struct_type"] = "snapshot".into();
+		// Sorted like restic lists it, with the parents of the folder first
+		let listing = [
+			header.to_string(),
+			node(&dir, "dir", None, &mtime(".")),
+			node(&data, "dir", None, &mtime(".")),
+			node(&data.join("a.txt"), "file", Some(4), &mtime("a.txt")),
+			node(&data.join("b.txt"), "file", Some(3), &mtime("b.txt")),
+			node(&data.join("gone.txt"), "file", Some(7), &mtime("a.txt")),
+			node(&data.join("sub"), "dir", None, &mtime("sub")),
+			node(&data.join("sub/d.txt"), "file", Some(4), &mtime("sub/d.txt")),
+		];
+		std::fs::write(dir.join("ls.jsonl"), listing.join("\n") + "\n").unwrap();
+
+		let script = format!(
+			"#!/bin/sh
+case \"$*\" in
+	*snapshots*) cat '{dir}/snapshots.json' ;;
+	*' ls '*) cat '{dir}/ls.jsonl' ;;
+	*) exit 1 ;;
+esac
+",
+			dir = dir.display()
+		);
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, &script));
+		let target = BackupTarget::new_from_string(&[&data], Vec::new(), Vec::new()).unwrap();
+
+		let diff = config.diff_local(&target).unwrap();
+		assert_eq!(diff.snapshot_id.as_deref(), Some(id));
+		assert_eq!(diff.added, [data.join("new.txt")]);
+		assert_eq!(diff.removed, [data.join("gone.txt")]);
+		assert_eq!(diff.modified, [data.join("b.txt")]);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
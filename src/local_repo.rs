@@ -0,0 +1,174 @@
+use crate::errors::*;
+use crate::{RepositorySource, ResticConfig, ResticStorageConfig};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// What is at the path of a local repository
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LocalRepoState {
+	Missing,
+	/// A directory without a restic repository, `init` can create one in it
+	NotARepository,
+	Repository,
+}
+
+impl ResticStorageConfig {
+	/// A relative local path joined to `base`, other locations as they are
+	pub fn normalized(&self, base: Option<&Path>) -> Cow<'_, ResticStorageConfig> {
+		match (self, base) {
+			(ResticStorageConfig::Local(path), Some(base)) if path.is_relative() => {
+				Cow::Owned(ResticStorageConfig::Local(base.join(path)))
+			}
+			_ => Cow::Borrowed(self),
+		}
+	}
+
+	/// Check that a local repository can be used before restic runs, nothing is checked for
+	/// other backends. See `validate_with`.
+	pub fn validate(&self) -> Result<()> {
+		self.validate_with(false)
+	}
+
+	/// Like `validate`, `create_dirs` creates missing parents of a local repository instead of
+	/// failing.
+	///
+	/// Fails with `InvalidConfig` when the path is empty or not a directory, or when the
+	/// repository doesn't exist and its parent is missing or not writable. A directory that
+	/// isn't a repository is accepted, `local_state` tells it apart from a repository.
+	pub fn validate_with(&self, create_dirs: bool) -> Result<()> {
+		let path = match self {
+			ResticStorageConfig::Local(path) => path,
+			_ => return Ok(()),
+		};
+		let invalid = |reason: String| -> Result<()> { Err(ErrorKind::InvalidConfig(reason).into()) };
+		if path.as_os_str().is_empty() {
+			return invalid("local repository path is empty".to_owned());
+		}
+		if path.exists() {
+			if !path.is_dir() {
+				return invalid(format!("local repository {} is not a directory", path.display()));
+			}
+			return Ok(());
+		}
+
+		// A relative path without a directory part is in the current directory
+		let parent = match path.parent() {
+			Some(parent) if !parent.as_os_str().is_empty() => parent,
+			_ => Path::new("."),
+		};
+		if !parent.exists() && create_dirs {
+			std::fs::create_dir_all(parent)
+				.chain_err(|| format!("Failed to create the parent directories of {}", path.display()))?;
+		}
+		if !parent.is_dir() {
+			return invalid(format!(
+				"local repository {} doesn't exist, and neither does its parent directory",
+				path.display()
+			));
+		}
+		if !is_writable(parent) {
+			return invalid(format!(
+				"local repository {} doesn't exist, and its parent directory isn't writable",
+				path.display()
+			));
+		}
+		Ok(())
+	}
+
+	/// Whether a local repository exists, None for other backends. A directory counts as a
+	/// repository when it has restic's `config` file and `keys` directory.
+	pub fn local_state(&self) -> Option<LocalRepoState> {
+		let path = match self {
+			ResticStorageConfig::Local(path) => path,
+			_ => return None,
+		};
+		Some(if !path.is_dir() {
+			LocalRepoState::Missing
+		} else if path.join("config").is_file() && path.join("keys").is_dir() {
+			LocalRepoState::Repository
+		} else {
+			LocalRepoState::NotARepository
+		})
+	}
+}
+
+impl ResticConfig {
+	/// The repository with a relative local path resolved against `repo_base_dir`
+	pub(crate) fn repo_source(&self) -> Cow<'_, RepositorySource> {
+		match (&self.repo_path, &self.repo_base_dir) {
+			(RepositorySource::Inline(storage), Some(base)) => match storage.normalized(Some(base)) {
+				Cow::Owned(storage) => Cow::Owned(RepositorySource::Inline(storage)),
+				Cow::Borrowed(_) => Cow::Borrowed(&self.repo_path),
+			},
+			_ => Cow::Borrowed(&self.repo_path),
+		}
+	}
+
+	/// `ResticStorageConfig::validate_with` for the repository, relative to `repo_base_dir` and
+	/// with `create_repo_dirs`. Nothing is checked for a `RepositorySource::File`.
+	pub fn validate_repo_location(&self) -> Result<()> {
+		match &*self.repo_source() {
+			RepositorySource::Inline(storage) => storage.validate_with(self.create_repo_dirs),
+			RepositorySource::File(_) => Ok(()),
+		}
+	}
+}
+
+#[cfg(unix)]
+fn is_writable(dir: &Path) -> bool {
+	use std::os::unix::ffi::OsStrExt;
+
+	let path = match std::ffi::CString::new(dir.as_os_str().as_bytes()) {
+		Ok(path) => path,
+		Err(_) => return false,
+	};
+	// Asks for the permissions of this process, the mode bits alone don't tell
+	unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_writable(dir: &Path) -> bool {
+	std::fs::metadata(dir).map_or(false, |meta| !meta.permissions().readonly())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tells_missing_locations_from_non_repositories() {
+		let dir = crate::test_util::test_dir("local-repo");
+		std::fs::create_dir_all(dir.join("repo/keys")).unwrap();
+		std::fs::write(dir.join("repo/config"), "").unwrap();
+		std::fs::create_dir_all(dir.join("other")).unwrap();
+		std::fs::write(dir.join("file"), "").unwrap();
+
+		let local = |path: &str| ResticStorageConfig::Local(dir.join(path));
+		assert_eq!(local("repo").local_state(), Some(LocalRepoState::Repository));
+		assert_eq!(local("other").local_state(), Some(LocalRepoState::NotARepository));
+		assert_eq!(local("new").local_state(), Some(LocalRepoState::Missing));
+
+		assert!(local("repo").validate().is_ok());
+		assert!(local("new").validate().is_ok());
+		for invalid in &["file", "missing/new"] {
+			let err = local(invalid).validate().unwrap_err();
+			assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)), "{}", err);
+		}
+		assert!(local("missing/new").validate_with(true).is_ok());
+		assert!(dir.join("missing").is_dir());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn relative_paths_follow_the_base_dir() {
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local("repos/main".into()));
+		config.repo_base_dir = Some("/srv/backup".into());
+		let preview = config.preview_snapshots().unwrap();
+		assert_eq!(preview.args[..2], ["-r", "/srv/backup/repos/main"]);
+
+		config.repo_path = ResticStorageConfig::Local("/elsewhere".into()).into();
+		assert!(matches!(config.repo_source(), Cow::Borrowed(_)));
+	}
+}
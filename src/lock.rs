@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+/// restic considers locks that were not refreshed for this long stale
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// Details restic prints about a lock that blocked a command
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LockInfo {
+	pub pid: Option<u32>,
+	pub host: Option<String>,
+	/// How long ago the lock was created
+	pub age: Option<Duration>,
+	/// restic itself reported the lock as stale
+	pub marked_stale: bool,
+	/// The blocked command only reads the repository, it took the lock because
+	/// `ResticConfig::no_lock` was turned off
+	pub read_only_command: bool,
+}
+
+impl LockInfo {
+	/// Parse the lock description from restic's stderr, None when the error is not a lock error
+	pub fn from_stderr(stderr: &str) -> Option<LockInfo> {
+		if !stderr.contains("repository is already locked") && !stderr.contains("unable to create lock") {
+			return None;
+		}
+
+		let mut info = LockInfo {
+			marked_stale: stderr.contains("(stale)"),
+			..Default::default()
+		};
+
+		if let Some(rest) = stderr.split("by PID ").nth(1) {
+			let mut words = rest.split_whitespace();
+			info.pid = words.next().and_then(|pid| pid.parse().ok());
+			if words.next() == Some("on") {
+				info.host = words.next().map(|host| host.to_owned());
+			}
+		}
+
+		if let Some(line) = stderr.lines().find(|line| line.starts_with("lock was created at")) {
+			info.age = line
+				.rsplit('(')
+				.next()
+				.and_then(|age| age.strip_suffix(" ago)"))
+				.and_then(parse_go_duration);
+		}
+
+		Some(info)
+	}
+
+	/// Appended to the locked error of read-only commands
+	pub(crate) fn no_lock_hint(&self) -> &'static str {
+		if self.read_only_command {
+			" (this command only reads the repository and would have succeeded with no_lock enabled)"
+		} else {
+			""
+		}
+	}
+
+	/// Whether the lock is stale: reported as such, older than restic's stale age,
+	/// or held by a process on this host that is no longer running
+	pub fn is_stale(&self) -> bool {
+		if self.marked_stale || self.age.map(|age| age > STALE_LOCK_AGE).unwrap_or(false) {
+			return true;
+		}
+
+		match (&self.host, self.pid) {
+			(Some(host), Some(pid)) => {
+				local_hostname().map(|local| &local == host).unwrap_or(false) && !process_is_alive(pid)
+			}
+			_ => false,
+		}
+	}
+}
+
+/// Parse go's time.Duration formatting, e.g. `170h1m2.5s` or `43.38ms`
+fn parse_go_duration(input: &str) -> Option<Duration> {
+	let mut rest = input.trim();
+	if rest == "0s" {
+		return Some(Duration::from_secs(0));
+	}
+
+	let mut total = 0f64;
+	while !rest.is_empty() {
+		let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+		let number: f64 = rest[..number_len].parse().ok()?;
+		rest = &rest[number_len..];
+
+		let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+		let seconds = match &rest[..unit_len] {
+			"h" => 3600.0,
+			"m" => 60.0,
+			"s" => 1.0,
+			"ms" => 1e-3,
+			"us" | "µs" => 1e-6,
+			"ns" => 1e-9,
+			_ => return None,
+		};
+		total += number * seconds;
+		rest = &rest[unit_len..];
+	}
+
+	Some(Duration::from_secs_f64(total))
+}
+
+#[cfg(unix)]
+fn local_hostname() -> Option<String> {
+	let mut buf = [0u8; 256];
+	let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+	if ret != 0 {
+		return None;
+	}
+	let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+	Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn local_hostname() -> Option<String> {
+	std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(unix)]
+fn process_is_alive(pid: u32) -> bool {
+	let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+	ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a portable way to probe the pid, assume it's alive so the lock is never removed
+#[cfg(not(unix))]
+fn process_is_alive(_pid: u32) -> bool {
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LOCKED_STDERR: &str = "repo already locked, waiting up to 0s for the lock
+unable to create lock in backend: repository is already locked by PID 3407 on nas by backup (UID 1000, GID 1000)
+lock was created at 2023-06-29 10:09:04 (170h1m2.5s ago)
+storage ID 7a8c3f4e
+the `unlock` command can be used to remove stale locks
+";
+
+	#[test]
+	fn parses_lock_stderr() {
+		let info = LockInfo::from_stderr(LOCKED_STDERR).unwrap();
+		assert_eq!(info.pid, Some(3407));
+		assert_eq!(info.host.as_deref(), Some("nas"));
+		assert_eq!(info.age, Some(Duration::from_secs_f64(170.0 * 3600.0 + 62.5)));
+		assert!(info.is_stale());
+
+		assert!(LockInfo::from_stderr("Fatal: wrong password or no key found").is_none());
+	}
+
+	#[test]
+	fn fresh_lock_on_other_host_is_not_stale() {
+		let stderr = LOCKED_STDERR
+			.replace("170h1m2.5s", "2m43.38s")
+			.replace("on nas", "on some-other-host-that-is-not-us");
+		let info = LockInfo::from_stderr(&stderr).unwrap();
+		assert!(!info.is_stale());
+	}
+}
@@ -0,0 +1,102 @@
+use crate::errors::*;
+use crate::restic_outputs::{ListJson, LsLine, SnapshotsJson};
+use crate::{ResticConfig, SnapshotId};
+
+/// The snapshots listed by `ls --json` with their nodes. Every snapshot header is followed by
+/// the nodes of that snapshot, so nodes belong to the header before them.
+#[derive(Default)]
+struct LsGroups(Vec<(SnapshotsJson, Vec<ListJson>)>);
+
+impl LsGroups {
+	fn push_line(&mut self, line: &str) -> Result<()> {
+		match LsLine::from_line(line)? {
+			LsLine::Snapshot(header) => self.0.push((header, Vec::new())),
+			LsLine::Node(node) => match self.0.last_mut() {
+				Some((_, nodes)) => nodes.push(node),
+				None => return Err(ErrorKind::Msg(format!("ls printed a node before any snapshot: {}", line)).into()),
+			},
+		}
+		Ok(())
+	}
+}
+
+impl ResticConfig {
+	/// The nodes of each snapshot, grouped under the snapshot they belong to in the order of
+	/// `ids`.
+	///
+	/// Since restic 0.10, `ls` takes one snapshot and treats further arguments as directories,
+	/// so restic runs once per snapshot.
+	pub fn restic_ls_many(&self, ids: &[SnapshotId]) -> Result<Vec<(SnapshotsJson, Vec<ListJson>)>> {
+		let _span = operation_span!(self, "ls");
+		let mut listed = Vec::with_capacity(ids.len());
+		for id in ids {
+			let mut cmd = self.ls_cmd(id)?;
+			let groups = self.stream_collect("ls", &mut cmd, LsGroups::default, LsGroups::push_line)?;
+			if groups.0.is_empty() {
+				return Err(ErrorKind::NoOutputFromRestic.into());
+			}
+			listed.extend(groups.0);
+		}
+		Ok(listed)
+	}
+
+	/// Like `restic_ls_many`, but passes each node to `on_node` with its snapshot as restic
+	/// prints it instead of collecting them. Returns the snapshots, including those without
+	/// nodes.
+	pub fn restic_ls_streaming<F: FnMut(&SnapshotsJson, ListJson)>(
+		&self,
+		ids: &[SnapshotId],
+		mut on_node: F,
+	) -> Result<Vec<SnapshotsJson>> {
+		let _span = operation_span!(self, "ls");
+		let mut headers = Vec::with_capacity(ids.len());
+		for id in ids {
+			let mut cmd = self.ls_cmd(id)?;
+			let first = headers.len();
+			self.stream_lines("ls", &mut cmd, |line| {
+				match LsLine::from_line(line)? {
+					LsLine::Snapshot(header) => headers.push(header),
+					LsLine::Node(node) => match headers[first..].last() {
+						Some(header) => on_node(header, node),
+						None => {
+							return Err(ErrorKind::Msg(format!("ls printed a node before any snapshot: {}", line)).into())
+						}
+					},
+				}
+				Ok(())
+			})?;
+			if headers.len() == first {
+				return Err(ErrorKind::NoOutputFromRestic.into());
+			}
+		}
+		Ok(headers)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn groups_nodes_under_their_snapshot() {
+		let mut groups = LsGroups::default();
+		for line in include_str!("../fixtures/restic_ls_two_snapshots.jsonl").lines() {
+			groups.push_line(line).unwrap();
+		}
+		let summary: Vec<(&str, Vec<&str>)> = groups
+			.0
+			.iter()
+			.map(|(header, nodes)| (header.short_id.as_str(), nodes.iter().map(|node| node.path.as_str()).collect()))
+			.collect();
+		assert_eq!(
+			summary,
+			vec![
+				("4a1d4b0f", vec!["/home", "/home/user", "/home/user/notes.txt"]),
+				("9e0b5d66", vec!["/home", "/home/user"]),
+			]
+		);
+
+		let mut orphan = LsGroups::default();
+		assert!(orphan.push_line(include_str!("../fixtures/restic_ls_nodes.jsonl").lines().nth(1).unwrap()).is_err());
+	}
+}
@@ -0,0 +1,156 @@
+use crate::check::{CheckOptions, CheckReport};
+use crate::errors::*;
+use crate::forget::parse_forget_output;
+use crate::restic_outputs::{parse_size, ForgetGroupJson};
+use crate::{ForgetRate, GroupBy, ResticConfig, StderrLine, TransferStats};
+use std::time::{Duration, Instant};
+
+/// How `apply_retention` runs its phases
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceOptions {
+	/// Tag filter for forget, the inner vec is ANDed and the outer vec is ORed
+	pub tags: Vec<Vec<String>>,
+	/// restic's `--group-by`, the same grouping `ForgetRate::evaluate` previews
+	pub group_by: GroupBy,
+	/// Remove the data of forgotten snapshots
+	pub prune: bool,
+	/// Prune through `forget --prune` instead of a separate prune run, only used with `prune`
+	pub prune_with_forget: bool,
+	/// Structurally check the repository afterwards
+	pub check: bool,
+	/// Only report what would be removed, passed to forget and prune. The check is read only
+	/// and runs as usual.
+	pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PruneStats {
+	pub blobs_removed: Option<u64>,
+	pub bytes_reclaimed: Option<u64>,
+	/// The last state of the `packs repacked` counter, None when nothing was repacked or
+	/// restic didn't print progress
+	pub transfer: Option<TransferStats>,
+}
+
+impl PruneStats {
+	/// Parse the `total prune:` line of restic's prune output, and the repack counter from
+	/// either stream
+	pub(crate) fn from_output(output: &str, stderr: &[StderrLine]) -> PruneStats {
+		let mut stats = PruneStats::default();
+		if let Some(line) = output.lines().find(|line| line.trim_start().starts_with("total prune:")) {
+			let counts = line.split_once(':').map_or("", |(_, counts)| counts);
+			let mut parts = counts.split('/');
+			stats.blobs_removed = parts
+				.next()
+				.and_then(|blobs| blobs.split_whitespace().next())
+				.and_then(|blobs| blobs.parse().ok());
+			stats.bytes_reclaimed = parts.next().and_then(|size| parse_size(size.trim()));
+		}
+		let lines = output.lines().chain(stderr.iter().map(|line| line.text.as_str()));
+		stats.transfer = TransferStats::last_in(lines, "repacked");
+		stats
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceReport {
+	pub forget_groups: Vec<ForgetGroupJson>,
+	pub snapshots_removed: usize,
+	/// None when prune didn't run
+	pub prune: Option<PruneStats>,
+	/// None when the check didn't run
+	pub check: Option<CheckReport>,
+	pub forget_duration: Duration,
+	pub prune_duration: Option<Duration>,
+	pub check_duration: Option<Duration>,
+}
+
+impl ResticConfig {
+	/// Forget snapshots according to the policy, then optionally prune and check.
+	///
+	/// The repository guard is held for all phases, so no other mutating operation from this
+	/// process can slip in between forget and prune.
+	pub fn apply_retention(&self, policy: &ForgetRate, options: &MaintenanceOptions) -> Result<MaintenanceReport> {
+		let _span = operation_span!(self, "apply_retention");
+		let _guard = self.repo_guard(true)?;
+		let mut report = MaintenanceReport::default();
+
+		self.require_forget_rate_supported(policy)?;
+		let start = Instant::now();
+		let mut cmd = self.forget_cmd(policy, &options.tags)?;
+		cmd.arg("--json");
+		cmd.arg("--group-by").arg(options.group_by.as_arg());
+		if options.dry_run {
+			cmd.arg("--dry-run");
+		}
+		let prune_with_forget = options.prune && options.prune_with_forget;
+		if prune_with_forget {
+			cmd.arg("--prune");
+		}
+		let (groups, prune_stats) = self.output_parsing_with_stderr("forget", &mut cmd, |stdout_data, stderr| {
+			let (groups, _) = parse_forget_output(&stdout_data)?;
+			Ok((groups, PruneStats::from_output(&stdout_data, stderr)))
+		})?;
+		report.forget_groups = groups;
+		report.forget_duration = start.elapsed();
+		if prune_with_forget {
+			report.prune = Some(prune_stats);
+		}
+		report.snapshots_removed = report
+			.forget_groups
+			.iter()
+			.map(|group| group.remove.as_ref().map(Vec::len).unwrap_or(0))
+			.sum();
+
+		if options.prune && !prune_with_forget {
+			let start = Instant::now();
+			let mut cmd = self.prune_cmd()?;
+			if options.dry_run {
+				cmd.arg("--dry-run");
+			}
+			report.prune = Some(self.output_parsing_with_stderr("prune", &mut cmd, |stdout_data, stderr| {
+				Ok(PruneStats::from_output(&stdout_data, stderr))
+			})?);
+			report.prune_duration = Some(start.elapsed());
+		}
+
+		if options.check {
+			let start = Instant::now();
+			report.check = Some(self.check(&CheckOptions::default())?);
+			report.check_duration = Some(start.elapsed());
+		}
+
+		Ok(report)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_prune_totals() {
+		let output = "counting files in repo
+to repack:            12 blobs / 1.512 MiB
+this removes:          3 blobs / 200.000 KiB
+to delete:            40 blobs / 10.000 MiB
+total prune:          43 blobs / 10.195 MiB
+remaining:          1000 blobs / 2.000 GiB
+";
+		let stats = PruneStats::from_output(output, &[]);
+		assert_eq!(stats.blobs_removed, Some(43));
+		assert_eq!(stats.bytes_reclaimed, Some((10.195 * 1048576.0f64).round() as u64));
+		assert_eq!(stats.transfer, None);
+		assert_eq!(PruneStats::from_output("nothing to do", &[]), PruneStats::default());
+	}
+
+	/// The stderr fixture is hand-written in restic's format, it isn't captured
+	#[test]
+	fn takes_the_repack_counter_from_stderr() {
+		let stderr = crate::stderr::classify_stderr(include_bytes!("../fixtures/synthetic_prune_stderr.txt"));
+		let stats = PruneStats::from_output("total prune:          43 blobs / 10.195 MiB\n", &stderr);
+		let transfer = stats.transfer.unwrap();
+		assert_eq!((transfer.done, transfer.total, transfer.unit.as_str()), (12, 12, "packs repacked"));
+		assert_eq!(transfer.elapsed, Duration::from_secs(4));
+	}
+}
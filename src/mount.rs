@@ -0,0 +1,336 @@
+use crate::classify;
+use crate::errors::*;
+use crate::running_command::{self, RunningCommand};
+use crate::{CommandPreview, ResticConfig};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long restic gets to exit after the mountpoint was unmounted, before it is interrupted
+const UNMOUNT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// Directory restic creates at the root of the mount, its presence means the mount is usable
+const READY_MARKER: &str = "snapshots";
+
+/// The exit of the restic process, filled in by the reaper thread. Failing to wait is stored
+/// as the message, so nobody blocks on an exit that is never recorded.
+///
+/// restic is only reaped with `exit` locked, so while it is None under the lock the PID still
+/// belongs to restic and can be signalled.
+#[derive(Default)]
+struct Reaped {
+	exit: Mutex<Option<std::result::Result<ExitStatus, String>>>,
+	stderr: Mutex<Vec<u8>>,
+	exited: Condvar,
+}
+
+impl Reaped {
+	fn exit(&self) -> Option<std::result::Result<ExitStatus, String>> {
+		self.exit.lock().unwrap_or_else(|err| err.into_inner()).clone()
+	}
+
+	/// Wait up to `timeout` for restic to exit
+	fn wait_timeout(&self, timeout: Duration) -> Option<Result<ExitStatus>> {
+		let exit = self.exit.lock().unwrap_or_else(|err| err.into_inner());
+		let (exit, _) = self
+			.exited
+			.wait_timeout_while(exit, timeout, |exit| exit.is_none())
+			.unwrap_or_else(|err| err.into_inner());
+		exit.clone().map(|exit| exit.map_err(|err| ErrorKind::Msg(err).into()))
+	}
+
+	fn wait(&self) -> Result<ExitStatus> {
+		let exit = self.exit.lock().unwrap_or_else(|err| err.into_inner());
+		let exit = self
+			.exited
+			.wait_while(exit, |exit| exit.is_none())
+			.unwrap_or_else(|err| err.into_inner());
+		match exit.clone() {
+			Some(Ok(status)) => Ok(status),
+			Some(Err(err)) => Err(ErrorKind::Msg(err).into()),
+			None => unreachable!("wait_while only returns once the exit is recorded"),
+		}
+	}
+}
+
+/// A repository mounted with `restic mount`, unmounted when dropped.
+///
+/// A background thread waits for restic, so its exit status is known as soon as it stops,
+/// e.g. because the mountpoint was unmounted from outside.
+pub struct MountHandle {
+	mountpoint: PathBuf,
+	pid: u32,
+	reaped: Arc<Reaped>,
+	unmounted: bool,
+}
+
+impl MountHandle {
+	pub fn mountpoint(&self) -> &Path {
+		&self.mountpoint
+	}
+
+	pub fn id(&self) -> u32 {
+		self.pid
+	}
+
+	/// The exit status once restic stopped, None while the mount is being served
+	pub fn status(&self) -> Option<ExitStatus> {
+		self.reaped.exit().and_then(|exit| exit.ok())
+	}
+
+	/// Block until restic exits, which happens once the mountpoint is unmounted
+	pub fn wait(mut self) -> Result<ExitStatus> {
+		self.unmounted = true;
+		self.reaped.wait()
+	}
+
+	/// Unmount and wait for restic to exit.
+	///
+	/// The mountpoint is released with `fusermount -u` or `umount` first, restic is only
+	/// interrupted when that fails, since stopping restic while the mount is busy can leave a
+	/// hung mountpoint behind.
+	pub fn unmount(mut self) -> Result<ExitStatus> {
+		self.unmounted = true;
+		self.stop()
+	}
+
+	fn stop(&mut self) -> Result<ExitStatus> {
+		if self.reaped.exit().is_some() {
+			return self.reaped.wait();
+		}
+
+		if release_mountpoint(&self.mountpoint) {
+			if let Some(exit) = self.reaped.wait_timeout(UNMOUNT_GRACE_PERIOD) {
+				return exit;
+			}
+		}
+
+		log::warn!("{} could not be unmounted cleanly, interrupting restic", self.mountpoint.display());
+		self.signal(libc::SIGINT);
+		if let Some(exit) = self.reaped.wait_timeout(UNMOUNT_GRACE_PERIOD) {
+			return exit;
+		}
+		self.signal(libc::SIGKILL);
+		self.reaped.wait()
+	}
+
+	/// Signal restic unless it was reaped already, its PID may belong to another process then
+	fn signal(&self, signal: libc::c_int) {
+		let exit = self.reaped.exit.lock().unwrap_or_else(|err| err.into_inner());
+		if exit.is_none() {
+			unsafe {
+				libc::kill(self.pid as libc::pid_t, signal);
+			}
+		}
+	}
+}
+
+impl Drop for MountHandle {
+	fn drop(&mut self) {
+		if !self.unmounted {
+			if let Err(err) = self.stop() {
+				log::warn!("Failed to unmount {}: {}", self.mountpoint.display(), err);
+			}
+		}
+	}
+}
+
+/// Block until the child `pid` exited, without reaping it
+fn wait_for_exit(pid: u32) {
+	let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+	loop {
+		let result = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT) };
+		if result == 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+			return;
+		}
+	}
+}
+
+/// restic mounts over whatever is in the mountpoint, and a `snapshots` directory left there would
+/// pass for a ready mount. A mountpoint that doesn't exist yet is created by restic.
+fn check_mountpoint_empty(mountpoint: &Path) -> Result<()> {
+	let mut entries = match std::fs::read_dir(mountpoint) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(Error::with_chain(err, format!("Failed to read mountpoint {}", mountpoint.display()))),
+	};
+	if entries.next().is_some() {
+		return Err(ErrorKind::InvalidConfig(format!("Mountpoint {} isn't empty", mountpoint.display())).into());
+	}
+	Ok(())
+}
+
+/// Try the unmount commands of linux and the BSDs/macOS, true if one succeeded
+fn release_mountpoint(mountpoint: &Path) -> bool {
+	let attempts: [(&str, &[&str]); 2] = [("fusermount", &["-u"][..]), ("umount", &[][..])];
+	attempts.iter().any(|(program, args)| {
+		Command::new(program)
+			.args(*args)
+			.arg(mountpoint)
+			.stdin(Stdio::null())
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.status()
+			.map(|status| status.success())
+			.unwrap_or(false)
+	})
+}
+
+impl ResticConfig {
+	fn mount_cmd(&self, mountpoint: &Path) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("mount").arg(mountpoint);
+		Ok(cmd)
+	}
+
+	/// Mount the repository at `mountpoint` and wait until it can be browsed.
+	///
+	/// The mountpoint has to be empty or not exist yet. Returns once restic's `snapshots`
+	/// directory shows up in it. When that takes longer than `ready_timeout` restic is stopped
+	/// and `ErrorKind::Timeout` returned, when restic exits before, its error is classified like
+	/// for every other command.
+	pub fn mount(&self, mountpoint: &Path, ready_timeout: Duration) -> Result<MountHandle> {
+		let _span = operation_span!(self, "mount");
+		check_mountpoint_empty(mountpoint)?;
+		self.prepare()?;
+		let mut cmd = self.mount_cmd(mountpoint)?;
+		cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::piped());
+		let observation = self.observe_start("mount", &cmd);
+		let mut running = match RunningCommand::spawn(&mut cmd) {
+			Ok(running) => running,
+			Err(err) => {
+				let err = self.spawn_error(err);
+				observation.finish(None, 0, 0, Some(&err));
+				return Err(err);
+			}
+		};
+		let stderr_reader = running.take_stderr().map(running_command::read_in_background);
+
+		let reaped = Arc::new(Reaped::default());
+		let mut handle = MountHandle {
+			mountpoint: mountpoint.to_owned(),
+			pid: running.id(),
+			reaped: Arc::clone(&reaped),
+			unmounted: false,
+		};
+		let pid = handle.pid;
+		std::thread::spawn(move || {
+			wait_for_exit(pid);
+			let stderr = running_command::join_reader(stderr_reader).unwrap_or_default();
+			let stderr_len = stderr.len() as u64;
+			*reaped.stderr.lock().unwrap_or_else(|err| err.into_inner()) = stderr;
+
+			let mut exit = reaped.exit.lock().unwrap_or_else(|err| err.into_inner());
+			let status = running.wait().map_err(|err| format!("Failed to wait for restic mount: {}", err));
+			*exit = Some(status.clone());
+			drop(exit);
+			match &status {
+				Ok(status) => observation.finish(status.code(), 0, stderr_len, None),
+				Err(err) => observation.finish(None, 0, stderr_len, Some(&ErrorKind::Msg(err.clone()).into())),
+			}
+			reaped.exited.notify_all();
+		});
+
+		let deadline = Instant::now() + ready_timeout;
+		loop {
+			if handle.reaped.exit().is_some() {
+				handle.unmounted = true;
+				let status = handle.reaped.wait()?;
+				let stderr = std::mem::take(&mut *handle.reaped.stderr.lock().unwrap_or_else(|err| err.into_inner()));
+				let output = Output {
+					status,
+					stdout: Vec::new(),
+					stderr,
+				};
+				return Err(classify::check_output("mount", output)
+					.err()
+					.unwrap_or_else(|| ErrorKind::Msg("restic mount exited before the mount was ready".to_owned()).into()));
+			}
+			if mountpoint.join(READY_MARKER).is_dir() {
+				return Ok(handle);
+			}
+			if Instant::now() >= deadline {
+				// Dropping the handle unmounts whatever was mounted so far
+				return Err(ErrorKind::Timeout(ready_timeout).into());
+			}
+			std::thread::sleep(READY_POLL_INTERVAL);
+		}
+	}
+
+	pub fn preview_mount(&self, mountpoint: &Path) -> Result<CommandPreview> {
+		self.preview(self.mount_cmd(mountpoint))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ResticStorageConfig;
+
+	/// A fake `restic mount` that creates the `snapshots` directory in the mountpoint, its last
+	/// argument, and serves until interrupted
+	const MOUNT_SCRIPT: &str = "#!/bin/sh
+for mountpoint; do :; done
+mkdir -p \"$mountpoint/snapshots\"
+exec sleep 60
+";
+
+	#[test]
+	fn mounts_until_unmounted() {
+		let dir = crate::test_util::test_dir("mount");
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, MOUNT_SCRIPT));
+		let mountpoint = dir.join("mnt");
+
+		let handle = config.mount(&mountpoint, Duration::from_secs(10)).unwrap();
+		assert_eq!(handle.mountpoint(), mountpoint);
+		assert!(handle.status().is_none());
+		// Nothing is mounted, so restic is interrupted
+		assert!(!handle.unmount().unwrap().success());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn refuses_a_mountpoint_that_isnt_empty() {
+		let dir = crate::test_util::test_dir("mount-not-empty");
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		let started = dir.join("started");
+		let script = format!("#!/bin/sh\ntouch '{}'\n", started.display());
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, &script));
+		let mountpoint = dir.join("mnt");
+		std::fs::create_dir_all(mountpoint.join("snapshots")).unwrap();
+
+		let err = config.mount(&mountpoint, Duration::from_secs(10)).err().unwrap();
+		assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)), "{}", err);
+		assert!(!started.exists());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn exited_restic_isnt_signalled() {
+		let dir = crate::test_util::test_dir("mount-exited");
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		let script = "#!/bin/sh
+for mountpoint; do :; done
+mkdir -p \"$mountpoint/snapshots\"
+sleep 1
+";
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, script));
+
+		let handle = config.mount(&dir.join("mnt"), Duration::from_secs(10)).unwrap();
+		let deadline = Instant::now() + Duration::from_secs(10);
+		while handle.status().is_none() && Instant::now() < deadline {
+			std::thread::sleep(READY_POLL_INTERVAL);
+		}
+		assert!(handle.status().unwrap().success());
+		// The PID may belong to another process by now
+		assert!(handle.reaped.exit().is_some());
+		handle.signal(libc::SIGKILL);
+		assert!(handle.unmount().unwrap().success());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,282 @@
+use crate::errors::*;
+use crate::ResticConfig;
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+const RESTIC_PASSWORD_ENV: &str = "RESTIC_PASSWORD";
+const RESTIC_PASSWORD_FILE_ENV: &str = "RESTIC_PASSWORD_FILE";
+
+/// Written in place of a plain password, see `PasswordSource`
+pub const REDACTED_PASSWORD: &str = "<redacted>";
+
+thread_local! {
+	/// Set while `with_secrets` runs, plain passwords are redacted otherwise
+	static WITH_SECRETS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Serialize plain passwords as they are while `f` runs on this thread
+pub(crate) fn with_secrets<T, F: FnOnce() -> T>(f: F) -> T {
+	struct Reset(bool);
+	impl Drop for Reset {
+		fn drop(&mut self) {
+			WITH_SECRETS.with(|flag| flag.set(self.0));
+		}
+	}
+
+	let _reset = Reset(WITH_SECRETS.with(|flag| flag.replace(true)));
+	f()
+}
+
+/// Where the repository password comes from.
+///
+/// Serialized as `"${NAME}"` for `Env` and as `{ "file": "..." }` for `File`, so config files
+/// can point at a secret instead of containing it. A plain password is written as
+/// `REDACTED_PASSWORD` and reads back as `Redacted`, unless it's serialized through
+/// `ResticConfig::serialize_with_secrets` or `save_to_path_with_secrets`. Plain passwords in
+/// files written by earlier versions are still read.
+///
+/// A plain password of the form `${...}` or equal to `REDACTED_PASSWORD` can't be stored, it
+/// reads back as an environment reference or as `Redacted`.
+#[derive(Clone, Eq, PartialEq)]
+pub enum PasswordSource {
+	Plain(String),
+	/// Read from this environment variable of the current process when a command is built
+	Env(String),
+	/// restic reads the password from this file itself
+	File(PathBuf),
+	/// The password was left out when the config was saved. Commands fail with
+	/// `ErrorKind::InvalidConfig` until it is set again, e.g. with `ResticConfig::set_password`.
+	Redacted,
+}
+
+impl PasswordSource {
+	/// Set the password on a restic command, replacing one set before
+	pub(crate) fn apply(&self, cmd: &mut Command) -> Result<()> {
+		cmd.env_remove(RESTIC_PASSWORD_ENV).env_remove(RESTIC_PASSWORD_FILE_ENV);
+		match self {
+			PasswordSource::Plain(password) => {
+				cmd.env(RESTIC_PASSWORD_ENV, password);
+			}
+			PasswordSource::Env(name) => {
+				let password = std::env::var_os(name).ok_or_else(|| {
+					ErrorKind::InvalidConfig(format!("password environment variable {} is not set", name))
+				})?;
+				cmd.env(RESTIC_PASSWORD_ENV, password);
+			}
+			PasswordSource::File(path) => {
+				cmd.env(RESTIC_PASSWORD_FILE_ENV, path);
+			}
+			PasswordSource::Redacted => {
+				return Err(ErrorKind::InvalidConfig(
+					"the password was not saved with the config, set it before running commands".to_owned(),
+				)
+				.into());
+			}
+		}
+		Ok(())
+	}
+
+	fn parse(value: &str) -> PasswordSource {
+		if value == REDACTED_PASSWORD {
+			return PasswordSource::Redacted;
+		}
+		match value.strip_prefix("${").and_then(|rest| rest.strip_suffix('}')) {
+			Some(name) if !name.is_empty() => PasswordSource::Env(name.to_owned()),
+			_ => PasswordSource::Plain(value.to_owned()),
+		}
+	}
+}
+
+impl From<String> for PasswordSource {
+	fn from(password: String) -> Self {
+		PasswordSource::Plain(password)
+	}
+}
+
+impl From<&str> for PasswordSource {
+	fn from(password: &str) -> Self {
+		PasswordSource::Plain(password.to_owned())
+	}
+}
+
+/// Never shows a plain password
+impl fmt::Debug for PasswordSource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PasswordSource::Plain(_) => f.write_str("Plain(<redacted>)"),
+			PasswordSource::Env(name) => f.debug_tuple("Env").field(name).finish(),
+			PasswordSource::File(path) => f.debug_tuple("File").field(path).finish(),
+			PasswordSource::Redacted => f.write_str("Redacted"),
+		}
+	}
+}
+
+impl Serialize for PasswordSource {
+	fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+		where
+			S: Serializer,
+	{
+		match self {
+			PasswordSource::Plain(password) if WITH_SECRETS.with(Cell::get) => serializer.serialize_str(password),
+			PasswordSource::Plain(_) | PasswordSource::Redacted => serializer.serialize_str(REDACTED_PASSWORD),
+			PasswordSource::Env(name) => serializer.serialize_str(&format!("${{{}}}", name)),
+			PasswordSource::File(path) => {
+				let mut map = serializer.serialize_map(Some(1))?;
+				map.serialize_entry("file", path)?;
+				map.end()
+			}
+		}
+	}
+}
+
+struct PasswordSourceVisitor;
+
+impl<'de> Visitor<'de> for PasswordSourceVisitor {
+	type Value = PasswordSource;
+
+	fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str("a password, \"${ENV_VAR}\", or a map with file or env")
+	}
+
+	fn visit_str<E>(self, value: &str) -> std::result::Result<Self::Value, E>
+		where
+			E: de::Error,
+	{
+		Ok(PasswordSource::parse(value))
+	}
+
+	fn visit_map<A>(self, mut map: A) -> std::result::Result<Self::Value, A::Error>
+		where
+			A: MapAccess<'de>,
+	{
+		let source = match map.next_key::<String>()?.as_deref() {
+			Some("file") => PasswordSource::File(map.next_value()?),
+			Some("env") => PasswordSource::Env(map.next_value()?),
+			Some(other) => return Err(de::Error::unknown_field(other, &["file", "env"])),
+			None => return Err(de::Error::invalid_length(0, &self)),
+		};
+		if map.next_key::<String>()?.is_some() {
+			return Err(de::Error::custom("expected only one of file or env"));
+		}
+		Ok(source)
+	}
+}
+
+impl<'de> Deserialize<'de> for PasswordSource {
+	fn deserialize<D>(deserializer: D) -> std::result::Result<PasswordSource, D::Error>
+		where
+			D: Deserializer<'de>,
+	{
+		deserializer.deserialize_any(PasswordSourceVisitor)
+	}
+}
+
+/// The password that opened the repository last, see `ResticConfig::with_fallback_passwords`.
+/// Clones of a config share it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PasswordCache(Arc<Mutex<Option<PasswordSource>>>);
+
+impl ResticConfig {
+	/// Try `passwords` in order when restic rejects `repo_password`, e.g. while the password is
+	/// being rotated.
+	///
+	/// Only `ErrorKind::ResticRepoInvalidPassword` moves on to the next candidate, other failures
+	/// are handled as usual. The candidate that worked is tried first by later commands, so they
+	/// don't pay for the rejected ones again. Commands that aren't retried, like `dump`, only use
+	/// that first candidate.
+	pub fn with_fallback_passwords(mut self, passwords: Vec<PasswordSource>) -> Self {
+		self.fallback_passwords = passwords;
+		self
+	}
+
+	/// The password that worked last if it is still a candidate, `repo_password` otherwise
+	pub(crate) fn active_password(&self) -> PasswordSource {
+		let cached = self.password_cache.0.lock().unwrap_or_else(|err| err.into_inner());
+		match &*cached {
+			Some(password) if *password == self.repo_password || self.fallback_passwords.contains(password) => {
+				password.clone()
+			}
+			_ => self.repo_password.clone(),
+		}
+	}
+
+	/// `active_password` followed by the other candidates in their configured order
+	pub(crate) fn password_candidates(&self) -> Vec<PasswordSource> {
+		let active = self.active_password();
+		let others = std::iter::once(&self.repo_password)
+			.chain(&self.fallback_passwords)
+			.filter(|password| **password != active)
+			.cloned();
+		std::iter::once(active.clone()).chain(others).collect()
+	}
+
+	pub(crate) fn remember_password(&self, password: &PasswordSource) {
+		*self.password_cache.0.lock().unwrap_or_else(|err| err.into_inner()) = Some(password.clone());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn password_source_serde() {
+		let sources = [
+			(PasswordSource::Redacted, r#""<redacted>""#),
+			(PasswordSource::Env("REPO_PW".to_owned()), r#""${REPO_PW}""#),
+			(PasswordSource::File("/run/secrets/repo".into()), r#"{"file":"/run/secrets/repo"}"#),
+		];
+		for (source, json) in &sources {
+			assert_eq!(serde_json::to_string(source).unwrap(), *json);
+			assert_eq!(serde_json::from_str::<PasswordSource>(json).unwrap(), *source);
+		}
+		let env: PasswordSource = serde_json::from_str(r#"{"env":"REPO_PW"}"#).unwrap();
+		assert_eq!(env, PasswordSource::Env("REPO_PW".to_owned()));
+		assert_eq!(format!("{:?}", PasswordSource::from("hunter2")), "Plain(<redacted>)");
+	}
+
+	#[test]
+	fn plain_passwords_need_secrets_context() {
+		let plain = PasswordSource::from("hunter2");
+		assert_eq!(serde_json::to_string(&plain).unwrap(), r#""<redacted>""#);
+		assert_eq!(with_secrets(|| serde_json::to_string(&plain).unwrap()), r#""hunter2""#);
+		// The context ends with the closure, also when it is nested
+		let nested = with_secrets(|| {
+			with_secrets(|| ());
+			serde_json::to_string(&plain).unwrap()
+		});
+		assert_eq!(nested, r#""hunter2""#);
+		assert_eq!(serde_json::to_string(&plain).unwrap(), r#""<redacted>""#);
+
+		// Written by versions that stored the password
+		assert_eq!(serde_json::from_str::<PasswordSource>(r#""hunter2""#).unwrap(), plain);
+
+		let mut cmd = Command::new("restic");
+		assert!(PasswordSource::Redacted.apply(&mut cmd).is_err());
+	}
+
+	#[test]
+	fn remembered_password_is_tried_first() {
+		let config = ResticConfig::new("old", crate::ResticStorageConfig::Local("/srv/repo".into()))
+			.with_fallback_passwords(vec!["new".into(), PasswordSource::Env("REPO_PW".to_owned())]);
+		assert_eq!(config.password_candidates()[0], PasswordSource::from("old"));
+
+		config.remember_password(&"new".into());
+		let clone = config.clone();
+		assert_eq!(
+			clone.password_candidates(),
+			vec!["new".into(), "old".into(), PasswordSource::Env("REPO_PW".to_owned())]
+		);
+
+		// A remembered password that is no longer configured is ignored
+		let mut rotated = clone;
+		rotated.fallback_passwords.clear();
+		assert_eq!(rotated.password_candidates(), vec![PasswordSource::from("old")]);
+	}
+}
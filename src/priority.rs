@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Command;
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+/// What a child failed to set, sent to `failure_pipe` together with the errno
+#[cfg(unix)]
+const FAILED_NICE: u8 = b'n';
+#[cfg(unix)]
+const FAILED_IOPRIO: u8 = b'i';
+#[cfg(windows)]
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+/// Keeps restic from opening a console window when started from a GUI program
+#[cfg(windows)]
+pub(crate) const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// IO scheduling class, mirrors the classes accepted by `ionice`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum IoniceClass {
+	/// Best effort with a priority level from 0 (highest) to 7 (lowest)
+	BestEffort(u8),
+	/// Only get disk time when no other program asks for it
+	Idle,
+}
+
+impl IoniceClass {
+	fn class_number(self) -> u8 {
+		match self {
+			IoniceClass::BestEffort(_) => 2,
+			IoniceClass::Idle => 3,
+		}
+	}
+
+	fn level(self) -> u8 {
+		match self {
+			IoniceClass::BestEffort(level) => level.min(7),
+			IoniceClass::Idle => 0,
+		}
+	}
+}
+
+/// CPU and IO priority restic is started with.
+///
+/// Lowering the priority is best effort, when the platform (or the wrapper programs) can't
+/// do it a warning is logged and restic runs with the normal priority instead.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct ProcessPriority {
+	/// Niceness from -20 (highest priority) to 19 (lowest priority)
+	pub nice: Option<i8>,
+	/// IO scheduling class, only supported on linux
+	pub ionice_class: Option<IoniceClass>,
+	/// Prefix restic with the `nice` and `ionice` programs instead of setting the priority
+	/// directly in the child process
+	#[serde(default)]
+	pub use_wrapper: bool,
+}
+
+impl ProcessPriority {
+	pub(crate) fn command(&self, program: &OsStr) -> Command {
+		if self.use_wrapper {
+			self.wrapped_command(program)
+		} else {
+			let mut cmd = Command::new(program);
+			self.apply_to_child(&mut cmd);
+			cmd
+		}
+	}
+
+	fn wrapped_command(&self, program: &OsStr) -> Command {
+		let mut wrappers: Vec<Vec<String>> = Vec::new();
+
+		if let Some(nice) = self.nice {
+			if find_in_path("nice").is_some() {
+				wrappers.push(vec!["nice".into(), "-n".into(), nice.to_string()]);
+			} else {
+				log::warn!("nice not found in PATH, running restic with normal CPU priority");
+			}
+		}
+
+		if let Some(class) = self.ionice_class {
+			if find_in_path("ionice").is_some() {
+				wrappers.push(vec![
+					"ionice".into(),
+					"-c".into(),
+					class.class_number().to_string(),
+					"-n".into(),
+					class.level().to_string(),
+				]);
+			} else {
+				log::warn!("ionice not found in PATH, running restic with normal IO priority");
+			}
+		}
+
+		let mut args = wrappers.into_iter().flatten();
+		match args.next() {
+			Some(first) => {
+				let mut cmd = Command::new(first);
+				cmd.args(args).arg(program);
+				cmd
+			}
+			None => Command::new(program),
+		}
+	}
+
+	#[cfg(unix)]
+	fn apply_to_child(&self, cmd: &mut Command) {
+		use std::os::unix::process::CommandExt;
+
+		let nice = self.nice;
+		#[cfg(target_os = "linux")]
+		let ioprio = self.ionice_class.map(|class| {
+			(libc::c_int::from(class.class_number()) << IOPRIO_CLASS_SHIFT) | libc::c_int::from(class.level())
+		});
+		#[cfg(not(target_os = "linux"))]
+		{
+			if self.ionice_class.is_some() {
+				log::warn!("IO priority is only supported on linux, running restic with normal IO priority");
+			}
+		}
+
+		if nice.is_none() && self.ionice_class.is_none() {
+			return;
+		}
+
+		#[cfg(not(target_os = "linux"))]
+		let ioprio = None;
+		// A restic at normal priority beats no backup at all, so failures only get logged
+		let report_fd = failure_pipe();
+		unsafe {
+			cmd.pre_exec(move || {
+				set_child_priority(nice, ioprio, report_fd);
+				Ok(())
+			});
+		}
+	}
+
+	#[cfg(windows)]
+	fn apply_to_child(&self, cmd: &mut Command) {
+		use std::os::windows::process::CommandExt;
+
+		// The flags replace each other, so they are all set here
+		let mut flags = CREATE_NO_WINDOW;
+		if self.nice.map(|nice| nice > 0).unwrap_or(false) {
+			flags |= BELOW_NORMAL_PRIORITY_CLASS;
+		}
+		cmd.creation_flags(flags);
+		if self.ionice_class.is_some() {
+			log::warn!("IO priority is not supported on windows, running restic with normal IO priority");
+		}
+	}
+
+	#[cfg(not(any(unix, windows)))]
+	fn apply_to_child(&self, _cmd: &mut Command) {
+		log::warn!("Process priority is not supported on this platform, running restic with normal priority");
+	}
+}
+
+/// Set the priority in the forked child before exec. Only async-signal-safe calls are allowed
+/// there, so failures are written to `report_fd` for the thread of `failure_pipe` to log.
+#[cfg(unix)]
+fn set_child_priority(nice: Option<i8>, ioprio: Option<libc::c_int>, report_fd: libc::c_int) {
+	let report = |what: u8| {
+		if report_fd < 0 {
+			return;
+		}
+		let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+		let mut record = [what, 0, 0, 0, 0];
+		record[1..].copy_from_slice(&errno.to_ne_bytes());
+		unsafe {
+			libc::write(report_fd, record.as_ptr() as *const libc::c_void, record.len());
+		}
+	};
+
+	if let Some(nice) = nice {
+		if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, libc::c_int::from(nice)) } != 0 {
+			report(FAILED_NICE);
+		}
+	}
+	#[cfg(target_os = "linux")]
+	{
+		if let Some(ioprio) = ioprio {
+			if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+				report(FAILED_IOPRIO);
+			}
+		}
+	}
+	#[cfg(not(target_os = "linux"))]
+	let _ = ioprio;
+}
+
+/// Write end of the pipe children report priority failures to, -1 if it couldn't be created.
+/// A thread logs what arrives. Both ends are close-on-exec, and the write end is non-blocking
+/// so a child never waits on it.
+#[cfg(unix)]
+fn failure_pipe() -> libc::c_int {
+	static PIPE: OnceLock<libc::c_int> = OnceLock::new();
+	*PIPE.get_or_init(|| {
+		open_failure_pipe().unwrap_or_else(|err| {
+			log::warn!("Failed to create the pipe for priority errors, they won't be logged: {}", err);
+			-1
+		})
+	})
+}
+
+#[cfg(unix)]
+fn open_failure_pipe() -> std::io::Result<libc::c_int> {
+	use std::os::unix::io::FromRawFd;
+
+	let mut fds = [0; 2];
+	if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	unsafe {
+		libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC);
+		libc::fcntl(fds[1], libc::F_SETFD, libc::FD_CLOEXEC);
+		libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+	}
+	let reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+	let spawned = std::thread::Builder::new()
+		.name("restic-priority-errors".into())
+		.spawn(move || log_failures(reader));
+	if let Err(err) = spawned {
+		unsafe {
+			libc::close(fds[1]);
+		}
+		return Err(err);
+	}
+	Ok(fds[1])
+}
+
+#[cfg(unix)]
+fn log_failures<R: Read>(mut reader: R) {
+	let mut record = [0; 5];
+	while reader.read_exact(&mut record).is_ok() {
+		log::warn!("{}", failure_message(record));
+	}
+}
+
+#[cfg(unix)]
+fn failure_message(record: [u8; 5]) -> String {
+	let errno = i32::from_ne_bytes([record[1], record[2], record[3], record[4]]);
+	let what = if record[0] == FAILED_NICE { "CPU" } else { "IO" };
+	format!(
+		"Failed to set the {} priority of restic, it runs with normal {} priority: {}",
+		what,
+		what,
+		std::io::Error::from_raw_os_error(errno)
+	)
+}
+
+pub(crate) fn find_in_path(program: &str) -> Option<PathBuf> {
+	let path = std::env::var_os("PATH")?;
+	std::env::split_paths(&path)
+		.map(|dir| dir.join(program))
+		.find(|candidate| candidate.is_file())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+	use std::os::unix::io::FromRawFd;
+	use std::os::unix::process::CommandExt;
+
+	#[test]
+	fn runs_with_the_lower_priority() {
+		let priority = ProcessPriority {
+			nice: Some(5),
+			ionice_class: Some(IoniceClass::Idle),
+			use_wrapper: false,
+		};
+		let output = priority.command(OsStr::new("nice")).output().unwrap();
+		assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "5");
+	}
+
+	#[test]
+	fn reports_what_failed_in_the_child() {
+		let mut fds = [0; 2];
+		assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+		let report_fd = fds[1];
+		// There is no IO class 7, the kernel refuses it for root as well
+		let invalid = 7 << IOPRIO_CLASS_SHIFT;
+		let mut cmd = Command::new("true");
+		unsafe {
+			cmd.pre_exec(move || {
+				set_child_priority(Some(5), Some(invalid), report_fd);
+				Ok(())
+			});
+		}
+		assert!(cmd.status().unwrap().success());
+		unsafe {
+			libc::close(report_fd);
+		}
+
+		let mut reported = Vec::new();
+		unsafe { std::fs::File::from_raw_fd(fds[0]) }.read_to_end(&mut reported).unwrap();
+		assert_eq!(reported.len(), 5);
+		assert_eq!(reported[0], FAILED_IOPRIO);
+		let message = failure_message([reported[0], reported[1], reported[2], reported[3], reported[4]]);
+		assert!(message.starts_with("Failed to set the IO priority of restic"), "{}", message);
+	}
+}
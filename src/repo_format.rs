@@ -0,0 +1,95 @@
+use crate::errors::*;
+use crate::ResticConfig;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// Repository format version that introduced compression and variable pack sizes
+pub const COMPRESSION_REPO_VERSION: u32 = 2;
+
+/// restic's `--compression`, needs a version 2 repository
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+	Auto,
+	Off,
+	Max,
+}
+
+impl Compression {
+	pub fn as_arg(self) -> &'static str {
+		match self {
+			Compression::Auto => "auto",
+			Compression::Off => "off",
+			Compression::Max => "max",
+		}
+	}
+}
+
+/// Format version of the repository read by the first command that needed it, together with
+/// the repository it was read from so a changed `repo_path` isn't answered from the cache.
+/// Clones of a config share it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RepoVersionCache(Arc<Mutex<Option<(String, u32)>>>);
+
+impl ResticConfig {
+	/// Format version of the repository, read with `cat config` once and cached afterwards
+	pub fn repo_version(&self) -> Result<u32> {
+		let _span = operation_span!(self, "repo_version");
+		let repo_key = self.repo_key();
+		{
+			let cached = self.repo_version_cache.0.lock().unwrap_or_else(|err| err.into_inner());
+			if let Some((key, version)) = &*cached {
+				if *key == repo_key {
+					return Ok(*version);
+				}
+			}
+		}
+
+		// Without the requirement, so reading the config doesn't check the version again
+		let mut unchecked = self.clone();
+		unchecked.min_repo_version = None;
+		let version = unchecked.cat_config()?.version;
+		*self.repo_version_cache.0.lock().unwrap_or_else(|err| err.into_inner()) = Some((repo_key, version));
+		Ok(version)
+	}
+
+	/// Fail with `RepoVersionTooOld` when the repository format is older than `min`
+	pub fn require_repo_version(&self, min: u32) -> Result<()> {
+		let _span = operation_span!(self, "repo_version");
+		let found = self.repo_version()?;
+		if found < min {
+			return Err(ErrorKind::RepoVersionTooOld { found, needs: min }.into());
+		}
+		Ok(())
+	}
+
+	/// Checks `min_repo_version` before a command runs
+	pub(crate) fn check_min_repo_version(&self) -> Result<()> {
+		match self.min_repo_version {
+			Some(min) => self.require_repo_version(min),
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ResticStorageConfig;
+
+	#[test]
+	fn cached_version_is_per_repository() {
+		let config = ResticConfig::new("1234".to_owned(), ResticStorageConfig::Local("/srv/repo".into()));
+		*config.repo_version_cache.0.lock().unwrap() = Some((config.repo_key(), 1));
+
+		let err = config.require_repo_version(COMPRESSION_REPO_VERSION).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::RepoVersionTooOld { found: 1, needs: 2 }));
+		assert!(config.require_repo_version(1).is_ok());
+
+		// Another repository isn't answered from the cache, reading its config fails here
+		let mut other = config.clone();
+		other.repo_path = ResticStorageConfig::Local("/srv/missing-repo-for-test".into()).into();
+		other.restic_binary = Some("/nonexistent/restic".into());
+		assert!(other.repo_version().is_err());
+	}
+}
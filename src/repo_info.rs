@@ -0,0 +1,92 @@
+use crate::errors::*;
+use crate::restic_outputs::StatsMode;
+use crate::ResticConfig;
+use chrono::{DateTime, FixedOffset};
+
+/// Overview of a repository, for dashboards
+#[derive(Debug)]
+pub struct RepoInfo {
+	pub repo_id: String,
+	pub repo_version: u32,
+	/// Size of the repository on the backend
+	pub size_on_backend: Option<u64>,
+	pub snapshot_count: Option<usize>,
+	pub latest_snapshot_time: Option<DateTime<FixedOffset>>,
+	/// Parts of the summary that couldn't be collected, with the name of the failed step
+	pub errors: Vec<(&'static str, Error)>,
+}
+
+impl ResticConfig {
+	/// Collect repository id and version, size on the backend and snapshot statistics.
+	///
+	/// Only reading the repository config has to succeed, failures of the other steps are
+	/// recorded in `errors` and leave their fields empty.
+	pub fn repo_info(&self) -> Result<RepoInfo> {
+		let _span = operation_span!(self, "repo_info");
+		let config = self.cat_config()?;
+		let mut info = RepoInfo {
+			repo_id: config.id,
+			repo_version: config.version,
+			size_on_backend: None,
+			snapshot_count: None,
+			latest_snapshot_time: None,
+			errors: Vec::new(),
+		};
+
+		match self.stats(StatsMode::RawData) {
+			Ok(stats) => info.size_on_backend = Some(stats.total_size),
+			Err(err) => info.errors.push(("stats", err)),
+		}
+
+		match self.get_restic_snapshots() {
+			Ok(snapshots) => {
+				info.snapshot_count = Some(snapshots.len());
+				info.latest_snapshot_time = snapshots.iter().filter_map(|snapshot| snapshot.timestamp()).max();
+			}
+			Err(err) => info.errors.push(("snapshots", err)),
+		}
+
+		Ok(info)
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use crate::ResticStorageConfig;
+
+	#[test]
+	fn failed_steps_leave_their_fields_empty() {
+		let dir = crate::test_util::test_dir("repo-info");
+		let script = format!(
+			"#!/bin/sh
+case \"$*\" in
+	*'cat config'*) echo '{{\"version\":2,\"id\":\"5f1b3c\",\"chunker_polynomial\":\"3dea92648f6e83\"}}' ;;
+	*stats*) echo 'Fatal: stats failed' >&2; exit 1 ;;
+	*snapshots*) cat '{}/fixtures/restic_snapshots/0.12.1.json' ;;
+esac
+",
+			env!("CARGO_MANIFEST_DIR")
+		);
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, &script));
+
+		let info = config.repo_info().unwrap();
+		assert_eq!(info.repo_id, "5f1b3c");
+		assert_eq!(info.repo_version, 2);
+		assert_eq!(info.size_on_backend, None);
+		assert_eq!(info.snapshot_count, Some(2));
+		assert_eq!(
+			info.latest_snapshot_time,
+			Some(DateTime::parse_from_rfc3339("2021-05-05T21:36:09.114728811+02:00").unwrap())
+		);
+		assert_eq!(info.errors.len(), 1);
+		assert_eq!(info.errors[0].0, "stats");
+
+		// Without the repository config there is nothing to report
+		config.restic_binary = Some(crate::test_util::fake_restic(&dir, "#!/bin/sh\necho 'Fatal: broken' >&2\nexit 1\n"));
+		assert!(config.repo_info().is_err());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
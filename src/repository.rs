@@ -0,0 +1,118 @@
+use crate::errors::*;
+use crate::{CreateRepoPath, ResticStorageConfig, RESTIC_OPTION_FLAG};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+
+const RESTIC_REPO_FLAG: &str = "-r";
+const RESTIC_REPO_FILE_FLAG: &str = "--repository-file";
+
+/// Where the repository location comes from.
+///
+/// Serialized as the `ResticStorageConfig` for `Inline`, so existing config files still load,
+/// and as `{ "repository_file": "..." }` for `File`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "RepositorySourceRepr", into = "RepositorySourceRepr")]
+pub enum RepositorySource {
+	Inline(ResticStorageConfig),
+	/// restic reads the location from this file itself (`--repository-file`). Credentials of
+	/// the backend can't be derived from it, set them in `ResticConfig::extra_env`.
+	File(PathBuf),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RepositorySourceRepr {
+	File { repository_file: PathBuf },
+	// serde_yaml writes enums as tags by default, which `untagged` can't read back
+	Inline(#[cfg_attr(feature = "yaml", serde(with = "serde_yaml::with::singleton_map"))] ResticStorageConfig),
+}
+
+impl From<RepositorySourceRepr> for RepositorySource {
+	fn from(repr: RepositorySourceRepr) -> Self {
+		match repr {
+			RepositorySourceRepr::File { repository_file } => RepositorySource::File(repository_file),
+			RepositorySourceRepr::Inline(storage) => RepositorySource::Inline(storage),
+		}
+	}
+}
+
+impl From<RepositorySource> for RepositorySourceRepr {
+	fn from(source: RepositorySource) -> Self {
+		match source {
+			RepositorySource::File(repository_file) => RepositorySourceRepr::File { repository_file },
+			RepositorySource::Inline(storage) => RepositorySourceRepr::Inline(storage),
+		}
+	}
+}
+
+impl From<ResticStorageConfig> for RepositorySource {
+	fn from(storage: ResticStorageConfig) -> Self {
+		RepositorySource::Inline(storage)
+	}
+}
+
+impl RepositorySource {
+	/// Add the flags selecting the repository
+	pub(crate) fn apply_args(&self, cmd: &mut Command) -> Result<()> {
+		match self {
+			RepositorySource::Inline(storage) => {
+				cmd.arg(RESTIC_REPO_FLAG).arg(&*storage.create_path_string());
+				for option in storage.backend_options()? {
+					cmd.arg(RESTIC_OPTION_FLAG).arg(option.as_arg()?);
+				}
+			}
+			RepositorySource::File(path) => {
+				cmd.arg(RESTIC_REPO_FILE_FLAG).arg(path);
+			}
+		}
+		Ok(())
+	}
+
+	/// The backend's variables, none for `File`
+	pub(crate) fn add_env_vars(&self, cmd: &mut Command) {
+		if let RepositorySource::Inline(storage) = self {
+			storage.add_env_vars(cmd);
+		}
+	}
+
+	/// The location as passed to `-r`, or the path of the file it is read from
+	pub(crate) fn label(&self) -> OsString {
+		match self {
+			RepositorySource::Inline(storage) => (*storage.create_path_string()).as_ref().to_owned(),
+			RepositorySource::File(path) => path.as_os_str().to_owned(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ResticConfig;
+
+	#[test]
+	fn repository_file_replaces_the_repo_flag() {
+		let mut config = ResticConfig::new("1234", RepositorySource::File("/run/secrets/repo".into()));
+		config.extra_env.insert("AWS_ACCESS_KEY_ID".into(), "key-id".into());
+		let preview = config.preview_snapshots().unwrap();
+		assert_eq!(preview.args[..2], ["--repository-file", "/run/secrets/repo"]);
+		assert!(!preview.args.iter().any(|arg| arg == "-r"));
+		assert!(preview.env.iter().any(|(key, _)| key == "AWS_ACCESS_KEY_ID"));
+		assert_eq!(format!("{:?}", config.repo_path), r#"File("/run/secrets/repo")"#);
+	}
+
+	#[test]
+	fn serializes_inline_locations_as_before() {
+		let inline: RepositorySource = ResticStorageConfig::Local("/srv/repo".into()).into();
+		assert_eq!(serde_json::to_string(&inline).unwrap(), r#"{"Local":"/srv/repo"}"#);
+		let file = RepositorySource::File("/run/secrets/repo".into());
+		let json = serde_json::to_string(&file).unwrap();
+		assert_eq!(json, r#"{"repository_file":"/run/secrets/repo"}"#);
+		assert!(matches!(serde_json::from_str(&json).unwrap(), RepositorySource::File(_)));
+		assert!(matches!(
+			serde_json::from_str(r#"{"Local":"/srv/repo"}"#).unwrap(),
+			RepositorySource::Inline(ResticStorageConfig::Local(_))
+		));
+	}
+}
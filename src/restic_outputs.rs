@@ -1,29 +1,471 @@
+use chrono::{DateTime, FixedOffset};
 use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// restic encodes its JSON output with Go, which replaces bytes that aren't valid UTF-8 with
+/// U+FFFD. Paths containing it can't be matched back to the file on disk.
+const REPLACEMENT_CHAR: char = '\u{FFFD}';
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SnapshotsJson {
-    hostname: String,
-    id: String,
-    parent: String,
-    paths: Vec<String>,
-    short_id: String,
-    time: String,
-    tree: String,
-    username: String,
+    pub hostname: String,
+    pub id: String,
+    /// Missing for the first snapshot of a group
+    #[serde(default)]
+    pub parent: Option<String>,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub short_id: String,
+    pub time: String,
+    pub tree: String,
+    pub username: String,
+    /// Version of the restic that made the snapshot, e.g. `restic 0.17.0`. Since restic 0.17.
+    #[serde(default)]
+    pub program_version: Option<String>,
+    /// Statistics of the backup that made the snapshot. Since restic 0.17.
+    #[serde(default)]
+    pub summary: Option<SnapshotSummary>,
+    /// Fields restic printed that this crate doesn't know, e.g. ones added by a newer restic
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The backup statistics restic stores in a snapshot
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnapshotSummary {
+    pub backup_start: DateTime<FixedOffset>,
+    pub backup_end: DateTime<FixedOffset>,
+    #[serde(default)]
+    pub files_new: u64,
+    #[serde(default)]
+    pub files_changed: u64,
+    #[serde(default)]
+    pub files_unmodified: u64,
+    #[serde(default)]
+    pub dirs_new: u64,
+    #[serde(default)]
+    pub dirs_changed: u64,
+    #[serde(default)]
+    pub dirs_unmodified: u64,
+    #[serde(default)]
+    pub data_blobs: u64,
+    #[serde(default)]
+    pub tree_blobs: u64,
+    /// Bytes added to the repository before compression
+    #[serde(default)]
+    pub data_added: u64,
+    /// Bytes added to the repository after compression
+    #[serde(default)]
+    pub data_added_packed: u64,
+    #[serde(default)]
+    pub total_files_processed: u64,
+    #[serde(default)]
+    pub total_bytes_processed: u64,
+}
+
+impl SnapshotsJson {
+    /// The snapshot time, None if restic printed something that isn't RFC 3339
+    pub fn timestamp(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.time).ok()
+    }
+
+    /// Whether one of the paths had bytes restic couldn't print, see `ListJson::is_path_lossy`
+    pub fn has_lossy_paths(&self) -> bool {
+        self.paths.iter().any(|path| path.contains(REPLACEMENT_CHAR))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeType {
+    File,
+    Dir,
+    Symlink,
+    Dev,
+    Chardev,
+    Fifo,
+    Socket,
+    #[serde(other)]
+    Unknown,
 }
 
+/// A node of `ls --json`. Unlike the other outputs it doesn't keep unknown fields, collecting
+/// them would buffer every line of the listing, `restic_ls_raw` has the whole line instead.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListJson {
-    atime: String,
-    ctime: String,
-    gid: i64,
-    uid: i64,
-    mode: i64,
-    mtime: String,
-    name: String,
-    path: String,
-    struct_type: String,
+    pub atime: String,
+    pub ctime: String,
+    pub gid: i64,
+    pub uid: i64,
+    /// Owner name, printed by newer restic versions
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group name, printed by newer restic versions
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Go's `os.FileMode`: permission bits plus type and special bits in the high bits, see
+    /// `permissions_string`
+    pub mode: i64,
+    pub mtime: String,
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub node_type: NodeType,
+    /// Only set for files
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub struct_type: String,
+    /// Where a symlink points, None for every other node type
+    #[serde(default, rename = "linktarget")]
+    pub link_target: Option<String>,
+    /// Device number of device nodes
+    #[serde(default)]
+    pub device: Option<u64>,
+    /// Number of hard links
+    #[serde(default)]
+    pub links: Option<u64>,
 }
+
+/// Special bits of Go's `os.FileMode`, which restic prints as the node mode
+const MODE_SETUID: i64 = 1 << 23;
+const MODE_SETGID: i64 = 1 << 22;
+const MODE_STICKY: i64 = 1 << 20;
+
+impl ListJson {
+    /// `linktarget` belongs to restic's node structure and isn't tied to the node type, it is
+    /// only kept for symlinks
+    pub(crate) fn normalized(mut self) -> ListJson {
+        if self.node_type != NodeType::Symlink {
+            self.link_target = None;
+        }
+        self
+    }
+
+    pub fn mtime_timestamp(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.mtime).ok()
+    }
+
+    pub fn path_buf(&self) -> PathBuf {
+        PathBuf::from(&self.path)
+    }
+
+    /// Whether the path had bytes that aren't valid UTF-8, which restic replaces with U+FFFD in
+    /// its JSON output. `path` is then only good for display, the exact name can be read from
+    /// the tree with `ResticConfig::cat_tree` and `TreeNodeJson::file_name`.
+    pub fn is_path_lossy(&self) -> bool {
+        self.path.contains(REPLACEMENT_CHAR)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.node_type == NodeType::Dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.node_type == NodeType::File
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.node_type == NodeType::Symlink
+    }
+
+    /// The mode as `ls -l` shows it, e.g. `drwxr-xr-x` or `-rwsr-xr-x`
+    pub fn permissions_string(&self) -> String {
+        let mut out = String::with_capacity(10);
+        out.push(match self.node_type {
+            NodeType::File => '-',
+            NodeType::Dir => 'd',
+            NodeType::Symlink => 'l',
+            NodeType::Dev => 'b',
+            NodeType::Chardev => 'c',
+            NodeType::Fifo => 'p',
+            NodeType::Socket => 's',
+            NodeType::Unknown => '?',
+        });
+        let special = [(MODE_SETUID, 's'), (MODE_SETGID, 's'), (MODE_STICKY, 't')];
+        for (class, (special_bit, special_char)) in special.iter().enumerate() {
+            let bits = self.mode >> (6 - 3 * class);
+            out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            let exec = bits & 0o1 != 0;
+            out.push(match (self.mode & special_bit != 0, exec) {
+                (true, true) => *special_char,
+                (true, false) => special_char.to_ascii_uppercase(),
+                (false, true) => 'x',
+                (false, false) => '-',
+            });
+        }
+        out
+    }
+}
+
+/// A snapshot as `cat snapshot` prints it, the stored object without the id
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnapshotDetailJson {
+    pub time: String,
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Id of the root tree, see `cat_tree`
+    pub tree: String,
+    pub paths: Vec<String>,
+    pub hostname: String,
+    pub username: String,
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Id of the snapshot this one was rewritten from
+    #[serde(default)]
+    pub original: Option<String>,
+    #[serde(default)]
+    pub program_version: Option<String>,
+    #[serde(default)]
+    pub summary: Option<SnapshotSummary>,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// One tree blob, the entries of one directory
+#[derive(Debug, Clone, Deserialize)]
+pub struct TreeJson {
+    /// restic prints null for an empty directory
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub nodes: Vec<TreeNodeJson>,
+}
+
+/// An entry of a tree blob
+#[derive(Debug, Clone, Deserialize)]
+pub struct TreeNodeJson {
+    /// The name as restic stores it, quoted like Go's `strconv.Quote` without the quotes: bytes
+    /// that aren't valid UTF-8 are kept as `\xNN`. See `file_name` for the actual name.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub node_type: NodeType,
+    #[serde(default)]
+    pub mode: i64,
+    #[serde(default)]
+    pub mtime: String,
+    #[serde(default)]
+    pub uid: i64,
+    #[serde(default)]
+    pub gid: i64,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub links: Option<u64>,
+    #[serde(default, rename = "linktarget")]
+    pub link_target: Option<String>,
+    /// Ids of the data blobs holding a file's contents, in order
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: Vec<String>,
+    /// Id of the tree of a directory, pass it to `cat_tree` to descend
+    #[serde(default)]
+    pub subtree: Option<String>,
+}
+
+impl TreeNodeJson {
+    /// The bytes of the name, with restic's quoting undone
+    pub fn name_bytes(&self) -> Vec<u8> {
+        unquote_go(&self.name)
+    }
+
+    /// The exact name on unix, elsewhere bytes that aren't valid UTF-8 become U+FFFD
+    pub fn file_name(&self) -> OsString {
+        os_string_from_bytes(self.name_bytes())
+    }
+
+    /// The name for display, bytes that aren't valid UTF-8 become U+FFFD
+    pub fn display_name(&self) -> String {
+        String::from_utf8_lossy(&self.name_bytes()).into_owned()
+    }
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// Undo Go's `strconv.Quote` for a string without the surrounding quotes. A malformed escape
+/// is kept as it is.
+fn unquote_go(quoted: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(quoted.len());
+    let mut rest = quoted;
+    while let Some(pos) = rest.find('\\') {
+        out.extend_from_slice(&rest.as_bytes()[..pos]);
+        let escape = &rest[pos + 1..];
+        let simple = match escape.chars().next() {
+            Some('a') => Some(b'\x07'),
+            Some('b') => Some(b'\x08'),
+            Some('f') => Some(b'\x0c'),
+            Some('n') => Some(b'\n'),
+            Some('r') => Some(b'\r'),
+            Some('t') => Some(b'\t'),
+            Some('v') => Some(b'\x0b'),
+            Some('\\') => Some(b'\\'),
+            Some('"') => Some(b'"'),
+            Some('\'') => Some(b'\''),
+            _ => None,
+        };
+        if let Some(byte) = simple {
+            out.push(byte);
+            rest = &escape[1..];
+            continue;
+        }
+
+        let hex = |len: usize| escape.get(1..=len).and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        let decoded = match escape.chars().next() {
+            Some('x') => hex(2).map(|byte| (vec![byte as u8], 3)),
+            Some('u') => hex(4).and_then(std::char::from_u32).map(|c| (c.to_string().into_bytes(), 5)),
+            Some('U') => hex(8).and_then(std::char::from_u32).map(|c| (c.to_string().into_bytes(), 9)),
+            _ => None,
+        };
+        match decoded {
+            Some((bytes, len)) => {
+                out.extend_from_slice(&bytes);
+                rest = &escape[len..];
+            }
+            None => {
+                out.push(b'\\');
+                rest = escape;
+            }
+        }
+    }
+    out.extend_from_slice(rest.as_bytes());
+    out
+}
+
+fn null_as_empty<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::<Vec<T>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// One group of snapshots from `forget --json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForgetGroupJson {
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub paths: Option<Vec<String>>,
+    #[serde(default)]
+    pub keep: Vec<SnapshotsJson>,
+    /// restic prints null instead of an empty list when nothing is removed
+    #[serde(default)]
+    pub remove: Option<Vec<SnapshotsJson>>,
+    /// Why each kept snapshot was kept, in the order of `keep`
+    #[serde(default)]
+    pub reasons: Option<Vec<KeepReasonJson>>,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The policy rules that kept a snapshot, from `forget --json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeepReasonJson {
+    pub snapshot: SnapshotsJson,
+    /// Descriptions of the matching rules, e.g. `daily snapshot` or `within 7d`
+    #[serde(default)]
+    pub matches: Vec<String>,
+    /// Per rule (`last`, `hourly`, `daily`, `weekly`, `monthly`, `yearly`), how many more
+    /// snapshots it could keep after this one. -1 for unlimited rules.
+    #[serde(default)]
+    pub counters: BTreeMap<String, i64>,
+}
+
+/// The repository config, from `cat config`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigJson {
+    pub version: u32,
+    pub id: String,
+    pub chunker_polynomial: String,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// What `stats` counts, see restic's documentation of `--mode`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StatsMode {
+    RestoreSize,
+    FilesByContents,
+    RawData,
+    BlobsPerFile,
+}
+
+impl StatsMode {
+    pub fn as_arg(self) -> &'static str {
+        match self {
+            StatsMode::RestoreSize => "restore-size",
+            StatsMode::FilesByContents => "files-by-contents",
+            StatsMode::RawData => "raw-data",
+            StatsMode::BlobsPerFile => "blobs-per-file",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsJson {
+    pub total_size: u64,
+    #[serde(default)]
+    pub total_file_count: Option<u64>,
+    #[serde(default)]
+    pub total_blob_count: Option<u64>,
+    #[serde(default)]
+    pub snapshots_count: Option<u64>,
+    /// Only reported for raw-data by restic versions supporting compression
+    #[serde(default)]
+    pub total_uncompressed_size: Option<u64>,
+    #[serde(default)]
+    pub compression_ratio: Option<f64>,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A line of `ls --json`, the snapshot header or one of its nodes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "struct_type")]
+pub enum LsLine {
+    #[serde(rename = "snapshot")]
+    Snapshot(SnapshotsJson),
+    #[serde(rename = "node")]
+    Node(ListJson),
+}
+
+impl LsLine {
+    /// Parse a line of `ls --json`, nodes `normalized`
+    pub(crate) fn from_line(line: &str) -> crate::Result<LsLine> {
+        Ok(match crate::json::parse_json("ls", line)? {
+            LsLine::Node(node) => LsLine::Node(node.normalized()),
+            header => header,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "message_type")]
 pub enum BackupJson {
@@ -38,15 +480,447 @@ pub enum BackupJson {
         data_blobs: u64,
         tree_blobs: u64,
         data_added: u64,
+        /// Bytes added after compression. Since restic 0.14.
+        #[serde(default)]
+        data_added_packed: Option<u64>,
         total_files_processed: u64,
         total_bytes_processed: u64,
         total_duration: f64,
+        /// Since restic 0.17
+        #[serde(default)]
+        backup_start: Option<DateTime<FixedOffset>>,
+        /// Since restic 0.17
+        #[serde(default)]
+        backup_end: Option<DateTime<FixedOffset>>,
+        /// Empty for `--dry-run` backups, which don't create a snapshot
+        #[serde(default)]
         snapshot_id: String,
+        /// Unknown fields
+        #[serde(flatten)]
+        extra: Map<String, Value>,
     },
     #[serde(rename = "status")]
     Status {
         percent_done: f64,
         total_files: u64,
         total_bytes: u64,
+        /// Unknown fields
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    /// One per processed file or directory, only printed with `--verbose`
+    #[serde(rename = "verbose_status")]
+    VerboseStatus(VerboseStatusJson),
+}
+
+/// What a verbose backup did with an item
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerboseAction {
+    New,
+    Unchanged,
+    Modified,
+    /// Printed once when restic finished scanning, without an item
+    ScanFinished,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A `verbose_status` line of `backup --json --verbose`
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct VerboseStatusJson {
+    pub action: VerboseAction,
+    #[serde(default)]
+    pub item: String,
+    /// Seconds spent on the item
+    #[serde(default)]
+    pub duration: f64,
+    #[serde(default)]
+    pub data_size: u64,
+    /// Bytes added to the repository for the item. Since restic 0.17.
+    #[serde(default)]
+    pub data_size_in_repo: Option<u64>,
+    #[serde(default)]
+    pub metadata_size: u64,
+    #[serde(default)]
+    pub total_files: u64,
+}
+
+/// Only the `message_type` of a JSON line, to pick the type to parse it as
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct MessageTypeJson {
+    pub message_type: String,
+}
+
+/// A status line of `backup --json`, restic leaves out the counters that are still zero
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct BackupStatusJson {
+    #[serde(default)]
+    pub seconds_elapsed: u64,
+    #[serde(default)]
+    pub seconds_remaining: Option<u64>,
+    #[serde(default)]
+    pub percent_done: f64,
+    #[serde(default)]
+    pub total_files: u64,
+    #[serde(default)]
+    pub files_done: u64,
+    #[serde(default)]
+    pub total_bytes: u64,
+    #[serde(default)]
+    pub bytes_done: u64,
+    #[serde(default)]
+    pub error_count: u64,
+    #[serde(default)]
+    pub current_files: Vec<String>,
+}
+
+/// A file restic couldn't back up, `backup --json` prints these to stderr
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+pub struct BackupErrorJson {
+    pub error: BackupErrorMessageJson,
+    #[serde(default)]
+    pub during: String,
+    #[serde(default)]
+    pub item: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+pub struct BackupErrorMessageJson {
+    pub message: String,
+}
+
+/// The summary line of `restore --json`, printed by restic 0.17 and newer
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+pub struct RestoreSummaryJson {
+    #[serde(default)]
+    pub total_files: Option<u64>,
+    #[serde(default)]
+    pub files_restored: Option<u64>,
+    /// Files left alone because of the overwrite policy or because they were unchanged
+    #[serde(default)]
+    pub files_skipped: Option<u64>,
+    #[serde(default)]
+    pub total_bytes: Option<u64>,
+    #[serde(default)]
+    pub bytes_restored: Option<u64>,
+    #[serde(default)]
+    pub bytes_skipped: Option<u64>,
+    /// Only with `--delete`
+    #[serde(default)]
+    pub files_deleted: Option<u64>,
+}
+
+/// How a path differs between the two snapshots of `diff`
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(from = "String")]
+pub enum DiffModifier {
+    Added,
+    Removed,
+    Modified,
+    /// E.g. a file that became a directory, restic may report the content change along with it
+    TypeChanged,
+    /// Only the metadata (mode, owner, times) changed
+    MetadataChanged,
+    Unknown,
+}
+
+impl From<String> for DiffModifier {
+    /// restic combines the letters, e.g. `TM`, the most significant one wins
+    fn from(modifier: String) -> Self {
+        if modifier.contains('+') {
+            DiffModifier::Added
+        } else if modifier.contains('-') {
+            DiffModifier::Removed
+        } else if modifier.contains('T') {
+            DiffModifier::TypeChanged
+        } else if modifier.contains('M') {
+            DiffModifier::Modified
+        } else if modifier.contains('U') {
+            DiffModifier::MetadataChanged
+        } else {
+            DiffModifier::Unknown
+        }
+    }
+}
+
+/// Counts of one side of the `diff --json` statistics
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+pub struct DiffStatsSideJson {
+    #[serde(default)]
+    pub files: u64,
+    #[serde(default)]
+    pub dirs: u64,
+    #[serde(default)]
+    pub others: u64,
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+/// A line of `diff --json`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "message_type")]
+pub enum DiffLine {
+    /// One per changed path, without unknown fields like `ListJson`
+    #[serde(rename = "change")]
+    Change { path: String, modifier: DiffModifier },
+    #[serde(rename = "statistics")]
+    Statistics {
+        #[serde(default)]
+        changed_files: u64,
+        #[serde(default)]
+        added: DiffStatsSideJson,
+        #[serde(default)]
+        removed: DiffStatsSideJson,
+        /// Unknown fields
+        #[serde(flatten)]
+        extra: Map<String, Value>,
     },
 }
+
+/// The matches of `find --json` in one snapshot
+#[derive(Debug, Clone, Deserialize)]
+pub struct FindJson {
+    pub snapshot: String,
+    #[serde(default)]
+    pub matches: Vec<FindNodeJson>,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FindNodeJson {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub node_type: NodeType,
+    /// Only set for files
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub mtime: Option<String>,
+}
+
+/// A use of an object found by `find --json` with `--blob`, `--tree` or `--pack`
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+pub struct FindObjectJson {
+    /// `blob` or `tree`, packs are reported through the blobs they contain
+    pub object_type: String,
+    pub id: String,
+    pub path: String,
+    #[serde(default)]
+    pub parent_tree: Option<String>,
+    pub snapshot: String,
+    #[serde(default)]
+    pub time: Option<String>,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A key of `key list --json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyJson {
+    /// The key the password opened
+    pub current: bool,
+    pub id: String,
+    #[serde(rename = "userName")]
+    pub user_name: String,
+    #[serde(rename = "hostName")]
+    pub host_name: String,
+    pub created: String,
+    /// Unknown fields
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Parse sizes as restic prints them, e.g. `1.234 MiB` or `0 B`
+pub(crate) fn parse_size(input: &str) -> Option<u64> {
+    let mut parts = input.split_whitespace();
+    let number: f64 = parts.next()?.parse().ok()?;
+    let multiplier = match parts.next()? {
+        "B" => 1u64,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * multiplier as f64).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_nodes() -> Vec<ListJson> {
+        include_str!("../fixtures/restic_ls_nodes.jsonl")
+            .lines()
+            .map(|line| match LsLine::from_line(line).unwrap() {
+                LsLine::Node(node) => node,
+                LsLine::Snapshot(_) => panic!("not a node: {}", line),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn link_target_only_for_symlinks() {
+        let nodes = fixture_nodes();
+        let link = nodes.iter().find(|node| node.node_type == NodeType::Symlink).unwrap();
+        assert_eq!(link.link_target.as_deref(), Some("../shared/current"));
+        assert_eq!(link.links, Some(1));
+
+        let file = nodes.iter().find(|node| node.name == "notes.txt").unwrap();
+        assert_eq!(file.link_target, None);
+        assert_eq!(file.links, Some(2));
+
+        let dev = nodes.iter().find(|node| node.node_type == NodeType::Chardev).unwrap();
+        assert_eq!(dev.device, Some(259));
+    }
+
+    #[test]
+    fn snapshots_from_old_and_new_restic() {
+        let old: Vec<SnapshotsJson> = serde_json::from_str(include_str!("../fixtures/restic_snapshots/0.12.1.json")).unwrap();
+        assert_eq!(old.len(), 2);
+        assert!(old.iter().all(|snapshot| snapshot.program_version.is_none() && snapshot.summary.is_none()));
+
+        let new: Vec<SnapshotsJson> = serde_json::from_str(include_str!("../fixtures/restic_snapshots/0.17.0.json")).unwrap();
+        assert_eq!(new[0].program_version.as_deref(), Some("restic 0.17.0"));
+        let summary = new[0].summary.as_ref().unwrap();
+        assert_eq!(summary.files_new, 3);
+        assert_eq!(summary.data_added, 12719);
+        assert_eq!(summary.data_added_packed, 5317);
+        assert_eq!((summary.backup_end - summary.backup_start).num_seconds(), 2);
+    }
+
+    /// The summaries are hand-written in the format of each version, they aren't captured
+    #[test]
+    fn backup_summaries_across_versions() {
+        let summaries = [
+            ("0.9.6", include_str!("../fixtures/synthetic_backup_summary/0.9.6.json")),
+            ("0.14.0", include_str!("../fixtures/synthetic_backup_summary/0.14.0.json")),
+            ("0.17.3", include_str!("../fixtures/synthetic_backup_summary/0.17.3.json")),
+        ];
+        for (version, line) in summaries.iter() {
+            let summary: BackupJson = serde_json::from_str(line.trim())
+                .unwrap_or_else(|err| panic!("summary of restic {} failed to parse: {}", version, err));
+            match summary {
+                BackupJson::Summary {
+                    files_new,
+                    data_added_packed,
+                    backup_start,
+                    backup_end,
+                    snapshot_id,
+                    ..
+                } => {
+                    assert_eq!(files_new, 3, "restic {}", version);
+                    assert!(!snapshot_id.is_empty(), "restic {}", version);
+                    assert_eq!(data_added_packed.is_some(), *version != "0.9.6", "restic {}", version);
+                    assert_eq!(backup_start.is_some(), *version == "0.17.3", "restic {}", version);
+                    if let (Some(start), Some(end)) = (backup_start, backup_end) {
+                        assert!(start < end);
+                    }
+                }
+                other => panic!("restic {} summary parsed as {:?}", version, other),
+            }
+        }
+    }
+
+    /// Runs restic itself, `cargo test -- --ignored` with restic in PATH
+    #[cfg(unix)]
+    #[test]
+    #[ignore]
+    fn parses_the_summary_of_restic_backup() {
+        use crate::{BackupTarget, ResticConfig, ResticStorageConfig, ResticVersion};
+
+        let dir = crate::test_util::test_dir("backup-summary");
+        let data = dir.join("data");
+        std::fs::create_dir_all(data.join("sub")).unwrap();
+        for name in ["a.txt", "b.txt", "sub/c.txt"].iter() {
+            std::fs::write(data.join(name), name.as_bytes()).unwrap();
+        }
+
+        let config = ResticConfig::new("1234".to_owned(), ResticStorageConfig::Local(dir.join("repo")));
+        config.create_restic_repo().unwrap();
+        let version = config.restic_version().unwrap();
+        let target = BackupTarget::new(&[&data], Vec::new(), Vec::new()).unwrap();
+        match config.restic_backup(&target, None).unwrap().summary {
+            BackupJson::Summary {
+                files_new,
+                data_added_packed,
+                backup_start,
+                backup_end,
+                snapshot_id,
+                ..
+            } => {
+                assert_eq!(files_new, 3);
+                assert!(!snapshot_id.is_empty());
+                assert_eq!(backup_start.is_some(), version >= ResticVersion::new(0, 17, 0), "restic {}", version);
+                if version >= ResticVersion::new(0, 17, 0) {
+                    assert!(data_added_packed.is_some());
+                }
+                if let (Some(start), Some(end)) = (backup_start, backup_end) {
+                    assert!(start <= end);
+                }
+            }
+            other => panic!("restic {} summary parsed as {:?}", version, other),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undoes_go_quoting_of_names() {
+        assert_eq!(unquote_go("plain.txt"), b"plain.txt");
+        // Latin-1 "café", which isn't valid UTF-8
+        assert_eq!(unquote_go(r"caf\xe9.txt"), b"caf\xe9.txt");
+        assert_eq!(unquote_go(r#"tab\there \\ quote\" \u00e9"#), "tab\there \\ quote\" é".as_bytes());
+        assert_eq!(unquote_go(r"bad\xZZ"), br"bad\xZZ");
+        assert_eq!(unquote_go("trailing\\"), b"trailing\\");
+
+        let tree: TreeJson =
+            serde_json::from_str(r#"{"nodes":[{"name":"caf\\xe9.txt","type":"file","content":[]}]}"#).unwrap();
+        assert_eq!(tree.nodes[0].name, r"caf\xe9.txt");
+        assert_eq!(tree.nodes[0].display_name(), "caf\u{FFFD}.txt");
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            assert_eq!(tree.nodes[0].file_name().as_bytes(), b"caf\xe9.txt");
+        }
+    }
+
+    #[test]
+    fn lossy_paths_are_detected() {
+        let mut node = fixture_nodes().remove(1);
+        assert!(!node.is_path_lossy());
+        node.path = "/home/user/caf\u{FFFD}.txt".to_owned();
+        assert!(node.is_path_lossy());
+    }
+
+    fn node(node_type: NodeType, mode: i64) -> ListJson {
+        let mut node = fixture_nodes().remove(1);
+        node.node_type = node_type;
+        node.mode = mode;
+        node
+    }
+
+    #[test]
+    fn permissions_strings() {
+        assert_eq!(node(NodeType::File, 0o644).permissions_string(), "-rw-r--r--");
+        // Go's ModeDir bit is set for directories
+        let dir = node(NodeType::Dir, (1 << 31) | 0o755);
+        assert!(dir.is_dir());
+        assert_eq!(dir.permissions_string(), "drwxr-xr-x");
+        assert_eq!(node(NodeType::File, MODE_SETUID | 0o755).permissions_string(), "-rwsr-xr-x");
+        assert_eq!(node(NodeType::File, MODE_SETUID | 0o644).permissions_string(), "-rwSr--r--");
+        assert_eq!(node(NodeType::Dir, (1 << 31) | MODE_STICKY | 0o777).permissions_string(), "drwxrwxrwt");
+        assert_eq!(node(NodeType::Dir, (1 << 31) | MODE_STICKY | 0o770).permissions_string(), "drwxrwx--T");
+        assert_eq!(node(NodeType::File, MODE_SETGID | 0o755).permissions_string(), "-rwxr-sr-x");
+
+        let link = &fixture_nodes()[0];
+        assert!(link.is_symlink());
+        assert_eq!(link.permissions_string(), "lrwxrwxrwx");
+        assert_eq!(link.user.as_deref(), Some("user"));
+        assert_eq!(link.group.as_deref(), Some("users"));
+    }
+}
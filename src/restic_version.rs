@@ -0,0 +1,147 @@
+use crate::errors::*;
+use crate::ResticConfig;
+use std::ffi::OsString;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Version of the restic binary, for features that need a minimum version
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ResticVersion {
+	pub major: u32,
+	pub minor: u32,
+	pub patch: u32,
+}
+
+impl ResticVersion {
+	pub const fn new(major: u32, minor: u32, patch: u32) -> ResticVersion {
+		ResticVersion { major, minor, patch }
+	}
+
+	/// Parse the output of `restic version`, e.g.
+	/// `restic 0.16.4 compiled with go1.21.6 on linux/amd64`
+	pub fn from_version_output(output: &str) -> Option<ResticVersion> {
+		let mut words = output.split_whitespace();
+		words.find(|word| *word == "restic")?;
+		words.next()?.parse().ok()
+	}
+
+	/// Fail with `UnsupportedByResticVersion` when this is older than `required`
+	pub(crate) fn require(self, feature: &str, required: ResticVersion) -> Result<()> {
+		if self < required {
+			return Err(ErrorKind::UnsupportedByResticVersion {
+				feature: feature.to_owned(),
+				required,
+				found: self,
+			}
+			.into());
+		}
+		Ok(())
+	}
+}
+
+impl FromStr for ResticVersion {
+	type Err = String;
+
+	/// Accepts `0.16.4`, `v0.16.4` and development versions like `0.16.4-dev`
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		let invalid = || format!("{:?} is not a restic version", s);
+		let release = s.trim_start_matches('v').split(['-', '+']).next().unwrap_or("");
+		let mut parts = release.split('.').map(|part| part.parse::<u32>().map_err(|_| invalid()));
+		let major = parts.next().ok_or_else(invalid)??;
+		let minor = parts.next().unwrap_or(Ok(0))?;
+		let patch = parts.next().unwrap_or(Ok(0))?;
+		if parts.next().is_some() {
+			return Err(invalid());
+		}
+		Ok(ResticVersion { major, minor, patch })
+	}
+}
+
+impl fmt::Display for ResticVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+	}
+}
+
+/// Version of the restic binary read by the first command that needed it, together with the
+/// binary it was read from so a changed `restic_binary` isn't answered from the cache. Clones of
+/// a config share it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ResticVersionCache(Arc<Mutex<Option<(OsString, ResticVersion)>>>);
+
+impl ResticConfig {
+	/// Version of the configured restic binary, doesn't need access to the repository. Read with
+	/// `restic version` once and cached afterwards.
+	pub fn restic_version(&self) -> Result<ResticVersion> {
+		let _span = operation_span!(self, "version");
+		let program = self.restic_program().to_owned();
+		{
+			let cached = self.restic_version_cache.0.lock().unwrap_or_else(|err| err.into_inner());
+			if let Some((cached_for, version)) = &*cached {
+				if *cached_for == program {
+					return Ok(*version);
+				}
+			}
+		}
+
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("version");
+		let version = self.output_parsing("version", &mut cmd, |stdout_data| {
+			ResticVersion::from_version_output(&stdout_data)
+				.ok_or_else(|| ErrorKind::Msg(format!("Failed to parse restic version from {:?}", stdout_data.trim())).into())
+		})?;
+		*self.restic_version_cache.0.lock().unwrap_or_else(|err| err.into_inner()) = Some((program, version));
+		Ok(version)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_version_output() {
+		assert_eq!(
+			ResticVersion::from_version_output("restic 0.16.4 compiled with go1.21.6 on linux/amd64\n"),
+			Some(ResticVersion::new(0, 16, 4))
+		);
+		assert_eq!(
+			ResticVersion::from_version_output("restic 0.17.0-dev (compiled manually) compiled with go1.22.1"),
+			Some(ResticVersion::new(0, 17, 0))
+		);
+		assert_eq!(ResticVersion::from_version_output("something else"), None);
+		assert!(ResticVersion::new(0, 9, 6) < ResticVersion::new(0, 17, 0));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn version_is_read_once_per_binary() {
+		let dir = crate::test_util::test_dir("restic-version");
+		let calls = dir.join("calls");
+		let script = |version: &str| {
+			format!(
+				"#!/bin/sh\necho version >> '{}'\necho 'restic {} compiled with go1.22.1 on linux/amd64'\n",
+				calls.display(),
+				version
+			)
+		};
+		let old = dir.join("old");
+		let new = dir.join("new");
+		std::fs::create_dir_all(&old).unwrap();
+		std::fs::create_dir_all(&new).unwrap();
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local(dir.join("repo")));
+		config.restic_binary = Some(crate::test_util::fake_restic(&old, &script("0.16.4")));
+
+		assert_eq!(config.restic_version().unwrap(), ResticVersion::new(0, 16, 4));
+		assert_eq!(config.clone().restic_version().unwrap(), ResticVersion::new(0, 16, 4));
+		assert_eq!(std::fs::read_to_string(&calls).unwrap().lines().count(), 1);
+
+		// Another binary isn't answered from the cache
+		config.restic_binary = Some(crate::test_util::fake_restic(&new, &script("0.17.3")));
+		assert_eq!(config.restic_version().unwrap(), ResticVersion::new(0, 17, 3));
+		assert_eq!(std::fs::read_to_string(&calls).unwrap().lines().count(), 2);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
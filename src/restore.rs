@@ -0,0 +1,258 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::RestoreSummaryJson;
+use crate::restic_version::ResticVersion;
+use crate::{CommandPreview, ResticConfig, SnapshotId};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// `--overwrite` and the JSON summary of restore were added in restic 0.17.0
+const OVERWRITE_VERSION: ResticVersion = ResticVersion::new(0, 17, 0);
+const SPARSE_VERSION: ResticVersion = ResticVersion::new(0, 15, 0);
+const DELETE_VERSION: ResticVersion = ResticVersion::new(0, 17, 0);
+
+/// What restore does with files that already exist in the target directory
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverwritePolicy {
+	/// Replace every existing file, what restic always did before 0.17
+	Always,
+	/// Replace files whose contents differ from the snapshot
+	IfChanged,
+	/// Only replace files that are older than the ones in the snapshot
+	IfNewer,
+	/// Keep every existing file
+	Never,
+}
+
+impl OverwritePolicy {
+	pub fn as_arg(self) -> &'static str {
+		match self {
+			OverwritePolicy::Always => "always",
+			OverwritePolicy::IfChanged => "if-changed",
+			OverwritePolicy::IfNewer => "if-newer",
+			OverwritePolicy::Never => "never",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RestoreOptions {
+	/// Directory the snapshot is restored into
+	pub target: PathBuf,
+	/// Only restore paths matching these patterns, restic's `--include`
+	pub include: Vec<String>,
+	/// Skip paths matching these patterns, restic's `--exclude`
+	pub exclude: Vec<String>,
+	/// restic's default when None, which is `IfChanged` since 0.17 and `Always` before
+	pub overwrite: Option<OverwritePolicy>,
+	/// Read the restored files back and compare them with the snapshot
+	pub verify: bool,
+	/// Restore runs of zeros as holes, so e.g. VM images don't take their full size on disk
+	pub sparse: bool,
+	/// Only settable through `delete_extraneous`, so deleting files is never a stray `true`
+	delete_extraneous: bool,
+}
+
+impl RestoreOptions {
+	pub fn new<P: Into<PathBuf>>(target: P) -> RestoreOptions {
+		RestoreOptions {
+			target: target.into(),
+			..RestoreOptions::default()
+		}
+	}
+
+	/// Delete files in the target that are not in the snapshot, so the target ends up an exact
+	/// copy of it. Files excluded by `include`/`exclude` are deleted as well.
+	pub fn delete_extraneous(mut self, delete: bool) -> RestoreOptions {
+		self.delete_extraneous = delete;
+		self
+	}
+
+	pub fn deletes_extraneous(&self) -> bool {
+		self.delete_extraneous
+	}
+}
+
+/// Counts reported by restic 0.17 and newer, older versions print no summary and leave it empty
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RestoreSummary {
+	pub total_files: Option<u64>,
+	pub files_restored: Option<u64>,
+	/// Files left in place, because of the overwrite policy or because they were unchanged
+	pub files_skipped: Option<u64>,
+	pub bytes_restored: Option<u64>,
+	pub bytes_skipped: Option<u64>,
+	/// Files removed from the target by `delete_extraneous`
+	pub files_deleted: Option<u64>,
+}
+
+impl From<RestoreSummaryJson> for RestoreSummary {
+	fn from(json: RestoreSummaryJson) -> Self {
+		RestoreSummary {
+			total_files: json.total_files,
+			files_restored: json.files_restored,
+			files_skipped: json.files_skipped,
+			bytes_restored: json.bytes_restored,
+			bytes_skipped: json.bytes_skipped,
+			files_deleted: json.files_deleted,
+		}
+	}
+}
+
+impl ResticConfig {
+	fn restore_cmd(&self, id: &SnapshotId, options: &RestoreOptions) -> Result<Command> {
+		let mut cmd = self.cmd_setup()?;
+		cmd.arg("--json");
+		cmd.arg("restore").arg(id);
+		cmd.arg("--target").arg(&options.target);
+		for include in &options.include {
+			cmd.arg("--include").arg(include);
+		}
+		for exclude in &options.exclude {
+			cmd.arg("--exclude").arg(exclude);
+		}
+		if let Some(overwrite) = options.overwrite {
+			cmd.arg("--overwrite").arg(overwrite.as_arg());
+		}
+		if options.verify {
+			cmd.arg("--verify");
+		}
+		if options.sparse {
+			cmd.arg("--sparse");
+		}
+		if options.delete_extraneous {
+			cmd.arg("--delete");
+		}
+		Ok(cmd)
+	}
+
+	/// Restore a snapshot into `options.target`.
+	///
+	/// Options the installed restic doesn't support fail with `UnsupportedByResticVersion`
+	/// before anything is restored.
+	pub fn restore(&self, id: &SnapshotId, options: &RestoreOptions) -> Result<RestoreSummary> {
+		let _span = operation_span!(self, "restore");
+		let options = supported_options(self.restic_version()?, options)?;
+		let mut cmd = self.restore_cmd(id, &options)?;
+		let summary = self.stream_collect("restore", &mut cmd, RestoreSummary::default, |summary, line| {
+			if line.contains("\"summary\"") {
+				let json: RestoreSummaryJson = parse_json("restore", line)?;
+				*summary = json.into();
+			}
+			Ok(())
+		})?;
+		Ok(summary)
+	}
+
+	pub fn preview_restore(&self, id: &SnapshotId, options: &RestoreOptions) -> Result<CommandPreview> {
+		self.preview(self.restore_cmd(id, options))
+	}
+}
+
+/// `options` as restic `version` accepts them, or the error for the first one it doesn't support
+fn supported_options(version: ResticVersion, options: &RestoreOptions) -> Result<RestoreOptions> {
+	let mut options = options.clone();
+	match options.overwrite {
+		// Older versions always overwrite, there is just no flag for it
+		Some(OverwritePolicy::Always) if version < OVERWRITE_VERSION => options.overwrite = None,
+		Some(policy) => version.require(&format!("--overwrite {}", policy.as_arg()), OVERWRITE_VERSION)?,
+		None => (),
+	}
+	if options.sparse {
+		version.require("--sparse", SPARSE_VERSION)?;
+	}
+	if options.delete_extraneous {
+		version.require("--delete", DELETE_VERSION)?;
+	}
+	Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn gates_overwrite_by_version() {
+		let old = ResticVersion::new(0, 16, 4);
+		let mut options = RestoreOptions::new("/restore");
+
+		options.overwrite = Some(OverwritePolicy::Always);
+		assert_eq!(supported_options(old, &options).unwrap().overwrite, None);
+		assert_eq!(
+			supported_options(OVERWRITE_VERSION, &options).unwrap().overwrite,
+			Some(OverwritePolicy::Always)
+		);
+
+		options.overwrite = Some(OverwritePolicy::IfNewer);
+		let err = supported_options(old, &options).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::UnsupportedByResticVersion { .. }));
+		assert!(supported_options(OVERWRITE_VERSION, &options).is_ok());
+	}
+
+	#[test]
+	fn gates_sparse_by_version() {
+		let mut options = RestoreOptions::new("/restore");
+		options.sparse = true;
+		assert!(supported_options(ResticVersion::new(0, 14, 0), &options).is_err());
+		assert!(supported_options(SPARSE_VERSION, &options).unwrap().sparse);
+	}
+
+	#[test]
+	fn gates_delete_by_version() {
+		let options = RestoreOptions::new("/restore").delete_extraneous(true);
+		assert!(supported_options(ResticVersion::new(0, 16, 4), &options).is_err());
+		assert!(supported_options(DELETE_VERSION, &options).unwrap().deletes_extraneous());
+	}
+
+	/// Runs restic itself, `cargo test -- --ignored` with restic in PATH
+	#[cfg(unix)]
+	#[test]
+	#[ignore]
+	fn sparse_restore_allocates_less() {
+		use crate::{BackupTarget, ResticStorageConfig};
+		use std::io::{Seek, SeekFrom, Write};
+		use std::os::unix::fs::MetadataExt;
+
+		let dir = crate::test_util::test_dir("sparse");
+		let data = dir.join("data");
+		std::fs::create_dir_all(&data).unwrap();
+
+		// 64 MiB of zeros between two bytes of data
+		let image = data.join("disk.img");
+		let mut file = std::fs::File::create(&image).unwrap();
+		file.write_all(b"x").unwrap();
+		file.seek(SeekFrom::Start(64 << 20)).unwrap();
+		file.write_all(b"x").unwrap();
+		drop(file);
+
+		let config = ResticConfig::new("1234".to_owned(), ResticStorageConfig::Local(dir.join("repo")));
+		config.create_restic_repo().unwrap();
+		let target = BackupTarget::new(&[&data], Vec::new(), Vec::new()).unwrap();
+		config.restic_backup(&target, None).unwrap();
+
+		let mut options = RestoreOptions::new(dir.join("restore"));
+		options.sparse = true;
+		config.restore(&SnapshotId::latest(), &options).unwrap();
+
+		// The folder is canonicalized by the target, restic restores below that path
+		let folder = target.folders[0].strip_prefix("/").unwrap();
+		let restored = dir.join("restore").join(folder).join("disk.img");
+		let meta = std::fs::metadata(&restored).unwrap();
+		assert_eq!(meta.len(), (64 << 20) + 1);
+		// st_blocks counts 512 byte units
+		assert!(meta.blocks() * 512 < meta.len());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn parses_summary() {
+		let line = r#"{"message_type":"summary","seconds_elapsed":1,"total_files":10,"files_restored":7,"files_skipped":3,"total_bytes":100,"files_deleted":2,"bytes_restored":70,"bytes_skipped":30}"#;
+		let summary: RestoreSummary = serde_json::from_str::<RestoreSummaryJson>(line).unwrap().into();
+		assert_eq!(summary.files_skipped, Some(3));
+		assert_eq!(summary.bytes_restored, Some(70));
+		assert_eq!(summary.files_deleted, Some(2));
+	}
+}
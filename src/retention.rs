@@ -0,0 +1,378 @@
+use crate::forget::{ForgetGroup, KeepReason, KeptSnapshot};
+use crate::restic_outputs::SnapshotsJson;
+use crate::{ForgetRate, KeepCount};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike};
+use std::collections::BTreeMap;
+
+/// Host, paths and tags of a group, empty for the ones not grouped by
+type GroupKey = (String, Vec<String>, Vec<String>);
+
+/// What `forget` groups snapshots by before applying the policy to each group, restic's
+/// `--group-by`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GroupBy {
+	pub host: bool,
+	pub paths: bool,
+	pub tags: bool,
+}
+
+impl Default for GroupBy {
+	/// `host,paths`, restic's default
+	fn default() -> Self {
+		GroupBy {
+			host: true,
+			paths: true,
+			tags: false,
+		}
+	}
+}
+
+impl GroupBy {
+	/// The value of `--group-by`, empty to put all snapshots into one group
+	pub fn as_arg(&self) -> String {
+		let mut parts = Vec::new();
+		if self.host {
+			parts.push("host");
+		}
+		if self.paths {
+			parts.push("paths");
+		}
+		if self.tags {
+			parts.push("tags");
+		}
+		parts.join(",")
+	}
+}
+
+/// What `forget` would do with a list of snapshots, see `ForgetRate::evaluate`
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPreview {
+	/// Sorted by host, paths and tags. Kept and removed snapshots are newest first.
+	pub groups: Vec<ForgetGroup>,
+	/// Snapshots whose time couldn't be read, the policy can't place them
+	pub unreadable: Vec<SnapshotsJson>,
+}
+
+impl RetentionPreview {
+	pub fn kept(&self) -> impl Iterator<Item = &KeptSnapshot> {
+		self.groups.iter().flat_map(|group| group.kept.iter())
+	}
+
+	pub fn removed(&self) -> impl Iterator<Item = &SnapshotsJson> {
+		self.groups.iter().flat_map(|group| group.removed.iter())
+	}
+}
+
+/// A `--keep-*` count rule while the snapshots of a group are walked
+struct Bucket {
+	name: &'static str,
+	reason: &'static str,
+	/// Remaining count, -1 for unlimited
+	count: i64,
+	bucket_of: fn(&DateTime<FixedOffset>, usize) -> i64,
+	last: i64,
+}
+
+impl Bucket {
+	fn new(
+		name: &'static str,
+		reason: &'static str,
+		keep: Option<KeepCount>,
+		bucket_of: fn(&DateTime<FixedOffset>, usize) -> i64,
+	) -> Bucket {
+		let count = match keep {
+			Some(KeepCount::Limited(count)) => i64::from(count),
+			Some(KeepCount::Unlimited) => -1,
+			None => 0,
+		};
+		Bucket {
+			name,
+			reason,
+			count,
+			bucket_of,
+			last: -1,
+		}
+	}
+}
+
+impl ForgetRate {
+	/// Apply the policy to `snapshots` like `forget` does, without running restic.
+	///
+	/// Follows restic 0.16 and newer: in every group the snapshots are walked newest first, a
+	/// rule keeps a snapshot when it is the first one seen in its hour, day, ISO week, month or
+	/// year while the rule's count lasts. When a time based rule has count left at the oldest
+	/// snapshot it keeps that one as well (`oldest daily snapshot`). `keep_within` is counted
+	/// back from the newest snapshot of the group. The reasons and counters read like the ones
+	/// of `forget --json`.
+	pub fn evaluate(&self, snapshots: &[SnapshotsJson], group_by: GroupBy) -> RetentionPreview {
+		let mut preview = RetentionPreview::default();
+		let mut groups: BTreeMap<GroupKey, Vec<(DateTime<FixedOffset>, &SnapshotsJson)>> = BTreeMap::new();
+		for snapshot in snapshots {
+			let time = match snapshot.timestamp() {
+				Some(time) => time,
+				None => {
+					preview.unreadable.push(snapshot.clone());
+					continue;
+				}
+			};
+			groups.entry(group_key(snapshot, group_by)).or_default().push((time, snapshot));
+		}
+
+		for ((host, paths, tags), mut members) in groups {
+			members.sort_by(|(a_time, a), (b_time, b)| b_time.cmp(a_time).then_with(|| a.id.cmp(&b.id)));
+			let mut group = ForgetGroup {
+				host,
+				tags,
+				paths,
+				..ForgetGroup::default()
+			};
+			self.apply_to_group(&members, &mut group);
+			preview.groups.push(group);
+		}
+		preview
+	}
+
+	fn apply_to_group(&self, members: &[(DateTime<FixedOffset>, &SnapshotsJson)], group: &mut ForgetGroup) {
+		let within = self.keep_within.map(|within| within.as_secs().div_ceil(3600)).filter(|hours| *hours > 0);
+		let keep_tags: Vec<Vec<&str>> = self.keep_tags.iter().map(|tags| tags.split(',').collect()).collect();
+		let mut buckets = self.buckets();
+		let empty = keep_tags.is_empty() && within.is_none() && buckets.iter().all(|bucket| bucket.count == 0);
+		let newest = match members.first() {
+			Some((time, _)) => *time,
+			None => return,
+		};
+
+		for (nr, (time, snapshot)) in members.iter().enumerate() {
+			let mut matches = Vec::new();
+			if empty {
+				matches.push("policy is empty".to_owned());
+			}
+			for tags in &keep_tags {
+				if tags.iter().all(|tag| snapshot.tags.iter().any(|own| own == tag)) {
+					matches.push(format!("has tags [{}]", tags.join(", ")));
+				}
+			}
+			if let Some(hours) = within {
+				if *time > newest - chrono::Duration::hours(hours as i64) {
+					matches.push(format!("within {}", format_within(hours)));
+				}
+			}
+			let oldest = nr == members.len() - 1;
+			for bucket in buckets.iter_mut().filter(|bucket| bucket.count != 0) {
+				let value = (bucket.bucket_of)(time, nr);
+				if value == bucket.last && !oldest {
+					continue;
+				}
+				matches.push(match value == bucket.last {
+					true => format!("oldest {}", bucket.reason),
+					false => bucket.reason.to_owned(),
+				});
+				bucket.last = value;
+				if bucket.count > 0 {
+					bucket.count -= 1;
+				}
+			}
+
+			if matches.is_empty() {
+				group.removed.push((*snapshot).clone());
+				continue;
+			}
+			let counters = buckets
+				.iter()
+				.filter(|bucket| bucket.count != 0)
+				.map(|bucket| (bucket.name.to_owned(), bucket.count))
+				.collect();
+			group.kept.push(KeptSnapshot {
+				snapshot: (*snapshot).clone(),
+				reason: Some(KeepReason::new(matches, counters)),
+			});
+		}
+	}
+
+	fn buckets(&self) -> Vec<Bucket> {
+		let bucket = Bucket::new;
+		vec![
+			bucket("last", "last snapshot", self.keep_last, |_, nr| nr as i64),
+			bucket("hourly", "hourly snapshot", self.keep_hourly, |time, _| {
+				i64::from(time.year()) * 1_000_000 + i64::from(time.month() * 10_000 + time.day() * 100 + time.hour())
+			}),
+			bucket("daily", "daily snapshot", self.keep_daily, |time, _| {
+				i64::from(time.year()) * 10_000 + i64::from(time.month() * 100 + time.day())
+			}),
+			bucket("weekly", "weekly snapshot", self.keep_weekly, |time, _| {
+				let week = time.iso_week();
+				i64::from(week.year()) * 100 + i64::from(week.week())
+			}),
+			bucket("monthly", "monthly snapshot", self.keep_monthly, |time, _| {
+				i64::from(time.year()) * 100 + i64::from(time.month())
+			}),
+			bucket("yearly", "yearly snapshot", self.keep_yearly, |time, _| i64::from(time.year())),
+		]
+	}
+}
+
+/// How restic prints a `--keep-within` duration, e.g. `7d` or `1d12h`
+fn format_within(hours: u64) -> String {
+	let mut out = String::new();
+	if hours >= 24 {
+		out.push_str(&format!("{}d", hours / 24));
+	}
+	if !hours.is_multiple_of(24) {
+		out.push_str(&format!("{}h", hours % 24));
+	}
+	out
+}
+
+fn group_key(snapshot: &SnapshotsJson, group_by: GroupBy) -> GroupKey {
+	let sorted = |list: &[String]| {
+		let mut list = list.to_vec();
+		list.sort();
+		list
+	};
+	(
+		if group_by.host { snapshot.hostname.clone() } else { String::new() },
+		if group_by.paths { sorted(&snapshot.paths) } else { Vec::new() },
+		if group_by.tags { sorted(&snapshot.tags) } else { Vec::new() },
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::forget::parse_forget_output;
+
+	/// Short id, matched rules and counters of a kept snapshot
+	type KeptSummary = (String, Vec<String>, Vec<(String, i64)>);
+
+	/// Ids and reasons of a group, to compare with restic's
+	fn summary(group: &ForgetGroup) -> (String, Vec<KeptSummary>, Vec<String>) {
+		let kept = group
+			.kept
+			.iter()
+			.map(|kept| {
+				let reason = kept.reason.clone().unwrap_or_default();
+				(kept.snapshot.short_id.clone(), reason.matches, reason.counters.into_iter().collect())
+			})
+			.collect();
+		let removed = group.removed.iter().map(|snapshot| snapshot.short_id.clone()).collect();
+		(group.host.clone(), kept, removed)
+	}
+
+	/// The fixture is hand-written in the format of `forget --dry-run --json --keep-last 2
+	/// --keep-daily 3 --keep-weekly 2 --keep-tag important` of restic 0.16, it isn't captured.
+	/// `matches_restic_forget` compares with restic itself.
+	#[test]
+	fn matches_synthetic_forget_dry_run() {
+		let (restic, _) = parse_forget_output(include_str!("../fixtures/synthetic_forget_dry_run.json")).unwrap();
+		let restic: Vec<ForgetGroup> = restic.into_iter().map(ForgetGroup::from).collect();
+		let mut snapshots: Vec<SnapshotsJson> = restic
+			.iter()
+			.flat_map(|group| group.kept.iter().map(|kept| kept.snapshot.clone()).chain(group.removed.clone()))
+			.collect();
+		snapshots.reverse();
+
+		let rate = ForgetRate {
+			keep_last: Some(KeepCount::Limited(2)),
+			keep_daily: Some(KeepCount::Limited(3)),
+			keep_weekly: Some(KeepCount::Limited(2)),
+			keep_tags: vec!["important".to_owned()],
+			..ForgetRate::default()
+		};
+		let preview = rate.evaluate(&snapshots, GroupBy::default());
+		assert!(preview.unreadable.is_empty());
+		assert_eq!(
+			preview.groups.iter().map(summary).collect::<Vec<_>>(),
+			restic.iter().map(summary).collect::<Vec<_>>()
+		);
+		assert_eq!(preview.kept().count(), 7);
+		assert_eq!(preview.removed().count(), 2);
+	}
+
+	#[test]
+	fn keeps_within_and_everything_for_an_empty_policy() {
+		let (groups, _) = parse_forget_output(include_str!("../fixtures/synthetic_forget_dry_run.json")).unwrap();
+		let snapshots: Vec<SnapshotsJson> = groups.into_iter().flat_map(|group| group.keep).collect();
+
+		let rate = ForgetRate {
+			keep_within: Some(std::time::Duration::from_secs(36 * 3600)),
+			..ForgetRate::default()
+		};
+		let preview = rate.evaluate(&snapshots, GroupBy::default());
+		let desktop = &preview.groups[0];
+		assert_eq!(desktop.kept.len(), 3);
+		assert_eq!(desktop.kept[1].reason.as_ref().unwrap().matches, ["within 1d12h"]);
+
+		let preview = ForgetRate::default().evaluate(&snapshots, GroupBy::default());
+		assert_eq!(preview.removed().count(), 0);
+		assert_eq!(preview.kept().next().unwrap().reason.as_ref().unwrap().matches, ["policy is empty"]);
+
+		let ungrouped = GroupBy {
+			host: false,
+			paths: false,
+			tags: false,
+		};
+		assert_eq!(ungrouped.as_arg(), "");
+		assert_eq!(ForgetRate::default().evaluate(&snapshots, ungrouped).groups.len(), 1);
+	}
+
+	/// Runs restic itself, `cargo test -- --ignored` with restic in PATH
+	#[cfg(unix)]
+	#[test]
+	#[ignore]
+	fn matches_restic_forget() {
+		use crate::{MaintenanceOptions, ResticConfig, ResticStorageConfig};
+		use std::ffi::OsStr;
+
+		let dir = crate::test_util::test_dir("forget-evaluate");
+		let data = dir.join("data");
+		std::fs::create_dir_all(&data).unwrap();
+		std::fs::write(data.join("file"), "contents").unwrap();
+		let config = ResticConfig::new("1234".to_owned(), ResticStorageConfig::Local(dir.join("repo")));
+		config.create_restic_repo().unwrap();
+
+		// Two hosts with two snapshots a day for five weeks, every seventh day tagged
+		for host in &["desktop", "laptop"] {
+			for day in 0..35 {
+				for hour in &[9, 21] {
+					let date = chrono::NaiveDate::from_ymd_opt(2024, 5, 20).unwrap() + chrono::Duration::days(day);
+					let time = format!("{} {:02}:00:00", date, hour);
+					let mut args = vec!["backup", "--host", *host, "--time", time.as_str()];
+					if day % 7 == 0 {
+						args.extend(&["--tag", "important"]);
+					}
+					args.push(data.to_str().unwrap());
+					let args: Vec<&OsStr> = args.into_iter().map(OsStr::new).collect();
+					config.run_raw(&args).unwrap();
+				}
+			}
+		}
+
+		let rate = ForgetRate {
+			keep_last: Some(KeepCount::Limited(2)),
+			keep_daily: Some(KeepCount::Limited(5)),
+			keep_weekly: Some(KeepCount::Limited(3)),
+			keep_monthly: Some(KeepCount::Unlimited),
+			keep_within: Some(std::time::Duration::from_secs(36 * 3600)),
+			keep_tags: vec!["important".to_owned()],
+			..ForgetRate::default()
+		};
+		let options = MaintenanceOptions {
+			dry_run: true,
+			..MaintenanceOptions::default()
+		};
+		let restic: Vec<ForgetGroup> = config
+			.apply_retention(&rate, &options)
+			.unwrap()
+			.forget_groups
+			.into_iter()
+			.map(ForgetGroup::from)
+			.collect();
+		let preview = rate.evaluate(&config.get_restic_snapshots().unwrap(), options.group_by);
+		assert_eq!(
+			preview.groups.iter().map(summary).collect::<Vec<_>>(),
+			restic.iter().map(summary).collect::<Vec<_>>()
+		);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
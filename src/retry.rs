@@ -0,0 +1,141 @@
+use crate::errors::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Lowercased stderr fragments restic prints for backend failures that are worth retrying
+const TRANSIENT_ERROR_PATTERNS: &[&str] = &[
+	"connection reset",
+	"connection refused",
+	"broken pipe",
+	"timeout",
+	"timed out",
+	"500 internal server error",
+	"502 bad gateway",
+	"503 service unavailable",
+	"504 gateway timeout",
+	"service unavailable",
+	"too many requests",
+	"unexpected eof",
+];
+
+/// Retry commands that failed with a transient error (a flaky backend, not a wrong password).
+///
+/// The delay doubles after every failed attempt, starting from `base_delay`.
+///
+/// Only `max_attempts` and `base_delay` are serialized, a loaded policy classifies with
+/// `is_transient_error`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "RetryPolicyRepr", into = "RetryPolicyRepr")]
+pub struct RetryPolicy {
+	/// Total number of attempts, including the first one
+	pub max_attempts: u32,
+	pub base_delay: Duration,
+	/// Decides whether an error is transient and the command should be run again
+	pub classify: fn(&Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		RetryPolicy {
+			max_attempts: 3,
+			base_delay: Duration::from_secs(5),
+			classify: is_transient_error,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct RetryPolicyRepr {
+	max_attempts: u32,
+	base_delay: Duration,
+}
+
+impl From<RetryPolicyRepr> for RetryPolicy {
+	fn from(repr: RetryPolicyRepr) -> Self {
+		RetryPolicy::new(repr.max_attempts, repr.base_delay)
+	}
+}
+
+impl From<RetryPolicy> for RetryPolicyRepr {
+	fn from(policy: RetryPolicy) -> Self {
+		RetryPolicyRepr {
+			max_attempts: policy.max_attempts,
+			base_delay: policy.base_delay,
+		}
+	}
+}
+
+impl RetryPolicy {
+	pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+		RetryPolicy {
+			max_attempts,
+			base_delay,
+			..Default::default()
+		}
+	}
+
+	/// Delay before the attempt following `attempt` (1 based)
+	pub fn delay_for(&self, attempt: u32) -> Duration {
+		self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1))
+	}
+
+	pub(crate) fn should_retry(&self, attempt: u32, err: &Error) -> bool {
+		attempt < self.max_attempts && (self.classify)(err)
+	}
+}
+
+/// Default classification, unreachable backends and stderr matching known network and 5xx failures
+pub fn is_transient_error(err: &Error) -> bool {
+	match err.kind() {
+		ErrorKind::BackendUnreachable => true,
+		ErrorKind::ResticCommandFailed(failure) => {
+			let stderr = failure.stderr.to_lowercase();
+			TRANSIENT_ERROR_PATTERNS.iter().any(|pattern| stderr.contains(pattern))
+		}
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::snapshot_id::IdError;
+
+	fn failed_with(stderr: &str) -> Error {
+		ErrorKind::ResticCommandFailed(CommandFailure {
+			subcommand: "backup".into(),
+			exit_code: Some(1),
+			stderr: stderr.into(),
+			stdout_excerpt: String::new(),
+		})
+		.into()
+	}
+
+	#[test]
+	fn classifies_transient_errors() {
+		assert!(is_transient_error(&failed_with("Fatal: Load(<lock/1234>): net/http: TLS handshake timeout")));
+		assert!(is_transient_error(&failed_with("b2_download_file_by_name: 503: Service Unavailable")));
+		assert!(!is_transient_error(&failed_with("Fatal: unable to open config file")));
+
+		assert!(!is_transient_error(&ErrorKind::ResticRepoInvalidPassword.into()));
+		assert!(!is_transient_error(&ErrorKind::InvalidId(IdError::Empty).into()));
+	}
+
+	#[test]
+	fn delay_doubles() {
+		let policy = RetryPolicy::new(4, Duration::from_secs(2));
+		assert_eq!(policy.delay_for(1), Duration::from_secs(2));
+		assert_eq!(policy.delay_for(3), Duration::from_secs(8));
+	}
+
+	#[test]
+	fn round_trips_without_the_classifier() {
+		let policy = RetryPolicy {
+			classify: |_| true,
+			..RetryPolicy::new(4, Duration::from_millis(1500))
+		};
+		let loaded: RetryPolicy = serde_json::from_str(&serde_json::to_string(&policy).unwrap()).unwrap();
+		assert_eq!((loaded.max_attempts, loaded.base_delay), (4, Duration::from_millis(1500)));
+		assert!(!(loaded.classify)(&failed_with("Fatal: unable to open config file")));
+	}
+}
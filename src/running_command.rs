@@ -0,0 +1,242 @@
+use std::io::{self, BufRead, Read};
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long restic gets to remove its lock after SIGINT before it is killed
+const ABORT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const ABORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Lines or chunks read ahead of the consumer, bounds memory when the consumer is slower than restic
+const PIECE_BUFFER: usize = 1024;
+
+/// A spawned restic process that is stopped when dropped before it finished.
+///
+/// On unix restic first gets a SIGINT so it can remove its repository lock, and is
+/// killed if it's still running after a grace period.
+pub struct RunningCommand {
+	child: Child,
+	finished: bool,
+	/// Dropped after the child was stopped, see `hold`
+	held: Vec<Box<dyn Send>>,
+}
+
+impl RunningCommand {
+	pub(crate) fn spawn(cmd: &mut Command) -> io::Result<RunningCommand> {
+		Ok(RunningCommand {
+			child: cmd.spawn()?,
+			finished: false,
+			held: Vec::new(),
+		})
+	}
+
+	/// Keep `value` until restic exited, e.g. the repository guard of the operation
+	pub(crate) fn hold<T: Send + 'static>(&mut self, value: T) {
+		self.held.push(Box::new(value));
+	}
+
+	/// Spawn with stdout and stderr captured, for `wait_with_output_timeout`
+	pub(crate) fn spawn_captured(cmd: &mut Command) -> io::Result<RunningCommand> {
+		cmd.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped());
+		Self::spawn(cmd)
+	}
+
+	pub fn id(&self) -> u32 {
+		self.child.id()
+	}
+
+	/// Take the stdout pipe, only available if the command was spawned with a piped stdout
+	pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+		self.child.stdout.take()
+	}
+
+	/// Take the stderr pipe, only available if the command was spawned with a piped stderr.
+	/// It has to be read along with stdout, restic stops once a full pipe isn't drained.
+	pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+		self.child.stderr.take()
+	}
+
+	/// Wait for restic to exit on its own
+	pub fn wait(mut self) -> io::Result<ExitStatus> {
+		let status = self.child.wait()?;
+		self.finished = true;
+		Ok(status)
+	}
+
+	/// Stop restic, waiting for it to exit
+	pub fn abort(mut self) -> io::Result<ExitStatus> {
+		let status = self.terminate()?;
+		self.finished = true;
+		Ok(status)
+	}
+
+	/// Collect the output, stopping restic with a `TimedOut` error once `timeout` elapsed
+	pub(crate) fn wait_with_output_timeout(mut self, timeout: Option<Duration>) -> io::Result<Output> {
+		let stdout_reader = self.child.stdout.take().map(read_in_background);
+		let stderr_reader = self.child.stderr.take().map(read_in_background);
+
+		let status = match timeout {
+			Some(timeout) => {
+				let deadline = Instant::now() + timeout;
+				loop {
+					if let Some(status) = self.child.try_wait()? {
+						break status;
+					}
+					if Instant::now() >= deadline {
+						self.terminate()?;
+						self.finished = true;
+						return Err(io::Error::new(io::ErrorKind::TimedOut, "restic did not finish in time"));
+					}
+					std::thread::sleep(TIMEOUT_POLL_INTERVAL);
+				}
+			}
+			None => self.child.wait()?,
+		};
+		self.finished = true;
+
+		Ok(Output {
+			status,
+			stdout: join_reader(stdout_reader)?,
+			stderr: join_reader(stderr_reader)?,
+		})
+	}
+
+	#[cfg(unix)]
+	fn terminate(&mut self) -> io::Result<ExitStatus> {
+		if let Some(status) = self.child.try_wait()? {
+			return Ok(status);
+		}
+
+		unsafe {
+			libc::kill(self.child.id() as libc::pid_t, libc::SIGINT);
+		}
+
+		let deadline = Instant::now() + ABORT_GRACE_PERIOD;
+		while Instant::now() < deadline {
+			if let Some(status) = self.child.try_wait()? {
+				return Ok(status);
+			}
+			std::thread::sleep(ABORT_POLL_INTERVAL);
+		}
+
+		self.kill()
+	}
+
+	#[cfg(not(unix))]
+	fn terminate(&mut self) -> io::Result<ExitStatus> {
+		self.kill()
+	}
+
+	fn kill(&mut self) -> io::Result<ExitStatus> {
+		// Fails if the process already exited, which wait below reports either way
+		let _ = self.child.kill();
+		self.child.wait()
+	}
+}
+
+pub(crate) fn read_in_background<R: Read + Send + 'static>(mut pipe: R) -> JoinHandle<io::Result<Vec<u8>>> {
+	std::thread::spawn(move || {
+		let mut buf = Vec::new();
+		pipe.read_to_end(&mut buf).map(|_| buf)
+	})
+}
+
+/// Read lines (including the `\n`) on a background thread, the channel closes at the end of
+/// the output or after the first read error
+pub(crate) fn lines_in_background<R: Read + Send + 'static>(pipe: R) -> Receiver<io::Result<Vec<u8>>> {
+	pieces_in_background(pipe, |pipe| {
+		let mut line = Vec::new();
+		pipe.read_until(b'\n', &mut line)?;
+		Ok(line)
+	})
+}
+
+/// Like `lines_in_background`, for binary output that is passed on in whatever pieces arrive
+pub(crate) fn chunks_in_background<R: Read + Send + 'static>(pipe: R) -> Receiver<io::Result<Vec<u8>>> {
+	pieces_in_background(pipe, |pipe| {
+		let chunk = pipe.fill_buf()?.to_vec();
+		pipe.consume(chunk.len());
+		Ok(chunk)
+	})
+}
+
+/// `read_piece` returns an empty piece at the end of the output
+fn pieces_in_background<R, F>(pipe: R, mut read_piece: F) -> Receiver<io::Result<Vec<u8>>>
+where
+	R: Read + Send + 'static,
+	F: FnMut(&mut io::BufReader<R>) -> io::Result<Vec<u8>> + Send + 'static,
+{
+	let (sender, receiver) = std::sync::mpsc::sync_channel(PIECE_BUFFER);
+	std::thread::spawn(move || {
+		let mut pipe = io::BufReader::new(pipe);
+		loop {
+			match read_piece(&mut pipe) {
+				Ok(piece) if piece.is_empty() => return,
+				Ok(piece) => {
+					if sender.send(Ok(piece)).is_err() {
+						return;
+					}
+				}
+				Err(err) => {
+					let _ = sender.send(Err(err));
+					return;
+				}
+			}
+		}
+	});
+	receiver
+}
+
+pub(crate) fn join_reader(reader: Option<JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+	match reader {
+		Some(reader) => reader
+			.join()
+			.map_err(|_| io::Error::other("output reader panicked"))?,
+		None => Ok(Vec::new()),
+	}
+}
+
+impl Drop for RunningCommand {
+	fn drop(&mut self) {
+		if !self.finished {
+			let _ = self.terminate();
+		}
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn drop_kills_unfinished_child() {
+		let running = RunningCommand::spawn(Command::new("sleep").arg("60")).unwrap();
+		let pid = running.id();
+		drop(running);
+
+		let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+		assert!(!alive);
+	}
+
+	#[test]
+	fn captures_output() {
+		let output = RunningCommand::spawn_captured(Command::new("echo").arg("hello"))
+			.unwrap()
+			.wait_with_output_timeout(None)
+			.unwrap();
+		assert!(output.status.success());
+		assert_eq!(output.stdout, b"hello\n");
+	}
+
+	#[test]
+	fn timeout_stops_child() {
+		let err = RunningCommand::spawn_captured(Command::new("sleep").arg("60"))
+			.unwrap()
+			.wait_with_output_timeout(Some(Duration::from_millis(200)))
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+	}
+}
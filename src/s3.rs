@@ -0,0 +1,91 @@
+use crate::errors::*;
+use crate::{BackendOption, CreateRepoPath};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::process::Command;
+
+/// A repository in an S3 bucket, `s3:<endpoint>/<bucket>/<path>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Config {
+	/// e.g. `s3.amazonaws.com` or the host of another S3 compatible service
+	pub endpoint: String,
+	pub bucket_name: String,
+	pub repo_path: String,
+	pub access_key_id: String,
+	pub secret_access_key: String,
+	/// Storage class of the objects restic writes, e.g. `STANDARD_IA`, passed as
+	/// `-o s3.storage-class`. Archive classes (`GLACIER`, `DEEP_ARCHIVE`) are rejected with
+	/// `InvalidConfig`, see `BackendOption::S3StorageClass`.
+	#[serde(default)]
+	pub storage_class: Option<String>,
+	/// Concurrent connections to the endpoint, passed as `-o s3.connections`
+	#[serde(default)]
+	pub connections: Option<u32>,
+}
+
+impl CreateRepoPath for S3Config {
+	fn create_path_string(&self) -> Box<dyn AsRef<OsStr>> {
+		Box::new(format!("s3:{}/{}/{}", self.endpoint, self.bucket_name, self.repo_path))
+	}
+
+	fn add_env_vars(&self, cmd: &mut Command) {
+		cmd.env("AWS_ACCESS_KEY_ID", &self.access_key_id)
+			.env("AWS_SECRET_ACCESS_KEY", &self.secret_access_key);
+	}
+
+	fn backend_options(&self) -> Result<Vec<BackendOption>> {
+		let mut options: Vec<BackendOption> = self.connections.map(BackendOption::S3Connections).into_iter().collect();
+		if let Some(storage_class) = &self.storage_class {
+			options.push(BackendOption::S3StorageClass(storage_class.clone()));
+		}
+		Ok(options)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{ResticConfig, ResticStorageConfig};
+
+	fn s3_config(storage_class: Option<&str>) -> ResticConfig {
+		ResticConfig::new(
+			"1234",
+			ResticStorageConfig::S3(S3Config {
+				endpoint: "s3.amazonaws.com".into(),
+				bucket_name: "bucket".into(),
+				repo_path: "repo".into(),
+				access_key_id: "key-id".into(),
+				secret_access_key: "secret".into(),
+				storage_class: storage_class.map(str::to_owned),
+				connections: None,
+			}),
+		)
+	}
+
+	fn args(config: &ResticConfig) -> Vec<String> {
+		let preview = config.preview_snapshots().unwrap();
+		preview.args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+	}
+
+	#[test]
+	fn passes_the_storage_class_as_an_option() {
+		let standard_ia = args(&s3_config(Some("STANDARD_IA")));
+		assert_eq!(standard_ia[..4], ["-r", "s3:s3.amazonaws.com/bucket/repo", "-o", "s3.storage-class=STANDARD_IA"]);
+
+		assert!(!args(&s3_config(None)).contains(&"-o".to_owned()));
+
+		let mut config = s3_config(Some("STANDARD_IA"));
+		if let crate::RepositorySource::Inline(ResticStorageConfig::S3(s3)) = &mut config.repo_path {
+			s3.connections = Some(10);
+		}
+		assert_eq!(args(&config)[2..6], ["-o", "s3.connections=10", "-o", "s3.storage-class=STANDARD_IA"]);
+	}
+
+	#[test]
+	fn rejects_archive_storage_classes() {
+		for storage_class in &["GLACIER", "deep_archive"] {
+			let err = s3_config(Some(storage_class)).preview_snapshots().unwrap_err();
+			assert!(matches!(err.kind(), ErrorKind::InvalidConfig(_)));
+		}
+	}
+}
@@ -0,0 +1,165 @@
+use crate::errors::*;
+use std::ffi::OsStr;
+use std::fmt;
+use std::str::FromStr;
+
+/// restic prints 8 character short ids, full ids are 64 characters
+pub const MIN_ID_LEN: usize = 8;
+pub const MAX_ID_LEN: usize = 64;
+const LATEST: &str = "latest";
+
+/// Why a string was rejected as a restic id
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IdError {
+	Empty,
+	TooShort(usize),
+	TooLong(usize),
+	NotHex(char),
+	/// A shortened id where restic needs the full one
+	NotFull(usize),
+}
+
+impl fmt::Display for IdError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			IdError::Empty => write!(f, "id is empty"),
+			IdError::TooShort(len) => write!(f, "id is {} characters long, at least {} are needed", len, MIN_ID_LEN),
+			IdError::TooLong(len) => write!(f, "id is {} characters long, at most {} are allowed", len, MAX_ID_LEN),
+			IdError::NotHex(c) => write!(f, "id contains the non hex character {:?}", c),
+			IdError::NotFull(len) => write!(f, "id is {} characters long, the full {} are needed", len, MAX_ID_LEN),
+		}
+	}
+}
+
+/// Check that `id` looks like a (possibly shortened) restic object id
+pub fn validate_id(id: &str) -> std::result::Result<(), IdError> {
+	if id.is_empty() {
+		return Err(IdError::Empty);
+	}
+	if let Some(c) = id.chars().find(|c| !c.is_ascii_hexdigit()) {
+		return Err(IdError::NotHex(c));
+	}
+	// All characters are ascii at this point, so the byte length is the character count
+	match id.len() {
+		len if len < MIN_ID_LEN => Err(IdError::TooShort(len)),
+		len if len > MAX_ID_LEN => Err(IdError::TooLong(len)),
+		_ => Ok(()),
+	}
+}
+
+/// Like `validate_id`, but only accepts full length ids. restic resolves shortened ids for
+/// snapshots, but not for blobs.
+pub fn validate_full_id(id: &str) -> std::result::Result<(), IdError> {
+	validate_id(id)?;
+	if id.len() != MAX_ID_LEN {
+		return Err(IdError::NotFull(id.len()));
+	}
+	Ok(())
+}
+
+/// A validated snapshot id, either a hex id (full or shortened) or `latest`
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SnapshotId(String);
+
+impl SnapshotId {
+	/// Hex id, case insensitive and stored lowercase
+	pub fn new(id: &str) -> Result<SnapshotId> {
+		validate_id(id).map_err(ErrorKind::InvalidId)?;
+		Ok(SnapshotId(id.to_ascii_lowercase()))
+	}
+
+	/// Like `new`, but also accepts `latest`
+	pub fn new_or_latest(id: &str) -> Result<SnapshotId> {
+		if id == LATEST {
+			Ok(Self::latest())
+		} else {
+			Self::new(id)
+		}
+	}
+
+	pub fn latest() -> SnapshotId {
+		SnapshotId(LATEST.to_owned())
+	}
+
+	pub fn is_latest(&self) -> bool {
+		self.0 == LATEST
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl FromStr for SnapshotId {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<SnapshotId> {
+		SnapshotId::new_or_latest(s)
+	}
+}
+
+impl fmt::Display for SnapshotId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl AsRef<OsStr> for SnapshotId {
+	fn as_ref(&self) -> &OsStr {
+		OsStr::new(&self.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn accepts_short_and_full_ids() {
+		assert_eq!(validate_id("0d9613ea"), Ok(()));
+		assert_eq!(validate_id(&"a".repeat(MAX_ID_LEN)), Ok(()));
+		assert_eq!(validate_id("0123456789abcdef"), Ok(()));
+	}
+
+	#[test]
+	fn accepts_uppercase_and_normalizes() {
+		assert_eq!(validate_id("0D9613EA"), Ok(()));
+		assert_eq!(SnapshotId::new("0D9613EA").unwrap().as_str(), "0d9613ea");
+	}
+
+	#[test]
+	fn rejects_bad_lengths() {
+		assert_eq!(validate_id(""), Err(IdError::Empty));
+		assert_eq!(validate_id("0d96"), Err(IdError::TooShort(4)));
+		assert_eq!(validate_id(&"a".repeat(MAX_ID_LEN + 1)), Err(IdError::TooLong(MAX_ID_LEN + 1)));
+	}
+
+	#[test]
+	fn full_ids_only() {
+		assert_eq!(validate_full_id(&"a".repeat(MAX_ID_LEN)), Ok(()));
+		assert_eq!(validate_full_id("0d9613ea"), Err(IdError::NotFull(8)));
+		assert_eq!(validate_full_id("0d96"), Err(IdError::TooShort(4)));
+	}
+
+	#[test]
+	fn rejects_non_hex() {
+		assert_eq!(validate_id("0d9613eg"), Err(IdError::NotHex('g')));
+		assert_eq!(validate_id(" 0d9613ea"), Err(IdError::NotHex(' ')));
+		assert_eq!(validate_id("0d9613e-"), Err(IdError::NotHex('-')));
+		// Unicode digits and fullwidth letters are not hex to restic
+		assert_eq!(validate_id("0d9613e\u{0663}"), Err(IdError::NotHex('\u{0663}')));
+		assert_eq!(validate_id("0d9613e\u{FF41}"), Err(IdError::NotHex('\u{FF41}')));
+		assert_eq!(validate_id("0d9613e\u{00B2}"), Err(IdError::NotHex('\u{00B2}')));
+	}
+
+	#[test]
+	fn latest_only_where_allowed() {
+		assert!(SnapshotId::new("latest").is_err());
+		assert!(SnapshotId::new_or_latest("latest").unwrap().is_latest());
+		assert!("latest".parse::<SnapshotId>().unwrap().is_latest());
+		assert!(matches!(
+			SnapshotId::new("xyz").unwrap_err().kind(),
+			ErrorKind::InvalidId(IdError::NotHex('x'))
+		));
+	}
+}
@@ -0,0 +1,312 @@
+use crate::errors::*;
+use crate::json::parse_json;
+use crate::restic_outputs::SnapshotsJson;
+use crate::tag::validate_tags;
+use crate::{ResticConfig, SnapshotId};
+use serde::de::IgnoredAny;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Which snapshots restic lists, all conditions have to match
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SnapshotFilter {
+	/// Only snapshots made on this host, restic's `--host`
+	pub host: Option<String>,
+	/// Only snapshots carrying all of these tags
+	pub tags: Vec<String>,
+	/// Only snapshots of exactly these paths, restic's `--path`
+	pub paths: Vec<PathBuf>,
+}
+
+/// Order of listed snapshots. Ties are always broken by time, newest first, and then by id, so
+/// the same snapshots come out in the same order whatever order restic printed them in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SnapshotSort {
+	/// The default
+	NewestFirst,
+	OldestFirst,
+	/// By host name
+	Host,
+	/// By the backed up paths, compared as restic lists them
+	Paths,
+}
+
+impl SnapshotFilter {
+	/// Add the filter flags shared by snapshots, copy and other commands selecting snapshots
+	pub(crate) fn apply(&self, cmd: &mut Command) -> Result<()> {
+		validate_tags(&self.tags).map_err(ErrorKind::InvalidTag)?;
+		if let Some(host) = &self.host {
+			cmd.arg("--host").arg(host);
+		}
+		if !self.tags.is_empty() {
+			cmd.arg("--tag").arg(self.tags.join(","));
+		}
+		for path in &self.paths {
+			cmd.arg("--path").arg(path);
+		}
+		Ok(())
+	}
+}
+
+impl Default for SnapshotSort {
+	/// `NewestFirst`
+	fn default() -> Self {
+		SnapshotSort::NewestFirst
+	}
+}
+
+/// Options of `query_snapshots`
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SnapshotQuery {
+	/// Only the newest n snapshots of all that match the filter
+	pub latest: Option<usize>,
+	pub filter: SnapshotFilter,
+	pub sort: SnapshotSort,
+}
+
+impl SnapshotQuery {
+	/// The newest `n` snapshots
+	pub fn latest(n: usize) -> SnapshotQuery {
+		SnapshotQuery {
+			latest: Some(n),
+			..SnapshotQuery::default()
+		}
+	}
+}
+
+impl ResticConfig {
+	fn snapshots_query_cmd(&self, query: &SnapshotQuery) -> Result<Command> {
+		let mut cmd = self.snapshots_cmd()?;
+		// restic keeps the newest n of every host and path group, which is a superset of the
+		// newest n overall, the rest is cut after sorting
+		if let Some(latest) = query.latest {
+			cmd.arg("--latest").arg(latest.to_string());
+		}
+		query.filter.apply(&mut cmd)?;
+		Ok(cmd)
+	}
+
+	/// List snapshots matching `query`, in the order it asks for.
+	///
+	/// The order doesn't depend on restic's output: snapshots are sorted by their parsed time,
+	/// ties by id, and the ones with an unreadable time count as oldest. restic prints the
+	/// snapshots as one JSON array, so they can't be handed out before all were read.
+	pub fn query_snapshots(&self, query: &SnapshotQuery) -> Result<Vec<SnapshotsJson>> {
+		let _span = operation_span!(self, "snapshots");
+		let mut snapshots = self.parse_snapshots(&mut self.snapshots_query_cmd(query)?)?;
+		order_snapshots(&mut snapshots, query.latest, query.sort);
+		Ok(snapshots)
+	}
+
+	/// Whether the repository has any snapshot matching `filter`, or any at all without one.
+	///
+	/// Only asks restic for the newest snapshot and doesn't parse it. A repository that can't be
+	/// opened is an error, e.g. `ResticRepoNotFound` or `ResticRepoInvalidPassword`, and never
+	/// reported as having no snapshots.
+	pub fn has_snapshots(&self, filter: Option<&SnapshotFilter>) -> Result<bool> {
+		let _span = operation_span!(self, "snapshots");
+		let mut cmd = self.snapshots_cmd()?;
+		cmd.arg("--latest").arg("1");
+		if let Some(filter) = filter {
+			filter.apply(&mut cmd)?;
+		}
+		self.output_parsing("snapshots", &mut cmd, |stdout_data| {
+			// Flat or grouped by `--group-by`, any element means there is a snapshot
+			let snapshots: Vec<IgnoredAny> = parse_json("snapshots", &stdout_data)?;
+			Ok(!snapshots.is_empty())
+		})
+	}
+
+	/// Metadata of just the given snapshots, in restic's order.
+	///
+	/// Ids restic doesn't know are reported together in `SnapshotsNotFound`. Depending on the
+	/// version restic either fails naming them on stderr or skips them with a warning, the
+	/// second case is detected by checking every id against the returned snapshots.
+	pub fn get_snapshots_by_id(&self, ids: &[SnapshotId]) -> Result<Vec<SnapshotsJson>> {
+		let _span = operation_span!(self, "snapshots");
+		// Without ids restic would list every snapshot
+		if ids.is_empty() {
+			return Ok(Vec::new());
+		}
+
+		let mut cmd = self.snapshots_cmd()?;
+		cmd.args(ids);
+		let snapshots = match self.parse_snapshots(&mut cmd) {
+			Ok(snapshots) => snapshots,
+			Err(err) => {
+				let unknown = err
+					.command_failure()
+					.map(|failure| ids_named_in(&failure.stderr, ids))
+					.unwrap_or_default();
+				if unknown.is_empty() {
+					return Err(err);
+				}
+				return Err(Error::with_chain(err, ErrorKind::SnapshotsNotFound(unknown)));
+			}
+		};
+
+		let unknown: Vec<SnapshotId> = ids
+			.iter()
+			.filter(|id| !id.is_latest() && !snapshots.iter().any(|snapshot| snapshot.id.starts_with(id.as_str())))
+			.cloned()
+			.collect();
+		if !unknown.is_empty() {
+			return Err(ErrorKind::SnapshotsNotFound(unknown).into());
+		}
+		Ok(snapshots)
+	}
+}
+
+/// The ids restic complained about, it quotes them in its messages, e.g.
+/// `Ignoring "1234abcd": no matching ID found for prefix "1234abcd"`
+fn ids_named_in(stderr: &str, ids: &[SnapshotId]) -> Vec<SnapshotId> {
+	let stderr = stderr.to_ascii_lowercase();
+	ids.iter()
+		.filter(|id| stderr.contains(&format!("\"{}\"", id.as_str())))
+		.cloned()
+		.collect()
+}
+
+fn newest_first(a: &SnapshotsJson, b: &SnapshotsJson) -> Ordering {
+	b.timestamp()
+		.cmp(&a.timestamp())
+		.then_with(|| a.id.cmp(&b.id))
+}
+
+/// Sort by `sort` after keeping the newest `latest`, also for snapshots wrapped in `WithRaw`
+pub(crate) fn order_snapshots<S: Borrow<SnapshotsJson>>(snapshots: &mut Vec<S>, latest: Option<usize>, sort: SnapshotSort) {
+	snapshots.sort_by(|a, b| newest_first(a.borrow(), b.borrow()));
+	if let Some(latest) = latest {
+		snapshots.truncate(latest);
+	}
+	// The sorts are stable, equal keys stay newest first
+	match sort {
+		SnapshotSort::NewestFirst => (),
+		SnapshotSort::OldestFirst => snapshots.reverse(),
+		SnapshotSort::Host => snapshots.sort_by(|a, b| a.borrow().hostname.cmp(&b.borrow().hostname)),
+		SnapshotSort::Paths => snapshots.sort_by(|a, b| a.borrow().paths.cmp(&b.borrow().paths)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn snapshot(id: &str, time: &str) -> SnapshotsJson {
+		SnapshotsJson {
+			hostname: "host".to_owned(),
+			id: id.to_owned(),
+			parent: None,
+			paths: vec!["/data".to_owned()],
+			tags: Vec::new(),
+			short_id: id.to_owned(),
+			time: time.to_owned(),
+			tree: String::new(),
+			username: "user".to_owned(),
+			program_version: None,
+			summary: None,
+			extra: Default::default(),
+		}
+	}
+
+	fn ids(snapshots: &[SnapshotsJson]) -> Vec<&str> {
+		snapshots.iter().map(|snapshot| snapshot.id.as_str()).collect()
+	}
+
+	#[test]
+	fn orders_by_parsed_time() {
+		let unordered = vec![
+			// Earlier than b, but later when compared as strings
+			snapshot("a", "2021-01-01T10:00:00+02:00"),
+			snapshot("b", "2021-01-01T09:30:00+00:00"),
+			snapshot("c", "not a time"),
+			snapshot("d", "2021-01-02T00:00:00Z"),
+		];
+
+		let mut snapshots = unordered.clone();
+		order_snapshots(&mut snapshots, None, SnapshotSort::NewestFirst);
+		assert_eq!(ids(&snapshots), vec!["d", "b", "a", "c"]);
+
+		let mut snapshots = unordered.clone();
+		order_snapshots(&mut snapshots, Some(2), SnapshotSort::NewestFirst);
+		assert_eq!(ids(&snapshots), vec!["d", "b"]);
+
+		let mut snapshots = unordered;
+		order_snapshots(&mut snapshots, Some(2), SnapshotSort::OldestFirst);
+		assert_eq!(ids(&snapshots), vec!["b", "d"]);
+	}
+
+	#[test]
+	fn breaks_ties_by_time_and_id() {
+		let on = |id: &str, host: &str, path: &str, time: &str| SnapshotsJson {
+			hostname: host.to_owned(),
+			paths: vec![path.to_owned()],
+			..snapshot(id, time)
+		};
+		let unordered = vec![
+			on("e", "web", "/srv", "2021-01-01T00:00:00Z"),
+			on("b", "db", "/srv", "2021-01-01T00:00:00Z"),
+			on("a", "web", "/data", "2021-01-03T00:00:00Z"),
+			on("d", "db", "/data", "2021-01-02T00:00:00Z"),
+			on("c", "db", "/srv", "2021-01-01T00:00:00Z"),
+		];
+
+		for shuffled in &[unordered.clone(), unordered.iter().rev().cloned().collect()] {
+			let mut snapshots = shuffled.clone();
+			order_snapshots(&mut snapshots, None, SnapshotSort::Host);
+			assert_eq!(ids(&snapshots), vec!["d", "b", "c", "a", "e"]);
+
+			let mut snapshots = shuffled.clone();
+			order_snapshots(&mut snapshots, None, SnapshotSort::Paths);
+			assert_eq!(ids(&snapshots), vec!["a", "d", "b", "c", "e"]);
+
+			let mut snapshots = shuffled.clone();
+			order_snapshots(&mut snapshots, Some(3), SnapshotSort::Host);
+			assert_eq!(ids(&snapshots), vec!["d", "b", "a"]);
+		}
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn tells_no_snapshots_from_a_failure() {
+		use crate::ResticStorageConfig;
+
+		let dir = crate::test_util::test_dir("has-snapshots");
+		let script = "#!/bin/sh
+[ \"$RESTIC_PASSWORD\" = 1234 ] || { echo 'Fatal: wrong password or no key found' >&2; exit 12; }
+case \"$*\" in
+	*'--host empty'*) echo '[]' ;;
+	*) echo '[{\"id\": \"4ed2b1c3\"}]' ;;
+esac
+";
+		let restic = crate::test_util::fake_restic(&dir, script);
+
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(restic);
+		assert!(config.has_snapshots(None).unwrap());
+		let filter = SnapshotFilter {
+			host: Some("empty".to_owned()),
+			..SnapshotFilter::default()
+		};
+		assert!(!config.has_snapshots(Some(&filter)).unwrap());
+
+		config.set_password("wrong");
+		let err = config.has_snapshots(None).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::ResticRepoInvalidPassword));
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn finds_unknown_ids_in_stderr() {
+		let requested = vec![
+			SnapshotId::new("1234abcd").unwrap(),
+			SnapshotId::new("deadbeef").unwrap(),
+		];
+		let stderr = "Ignoring \"DEADBEEF\": failed to find snapshot: no matching ID found for prefix \"deadbeef\"\n";
+		assert_eq!(ids_named_in(stderr, &requested), vec![requested[1].clone()]);
+		assert!(ids_named_in("Fatal: wrong password", &requested).is_empty());
+	}
+}
@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+/// What a line restic printed to stderr is about
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StderrKind {
+	/// A progress counter, e.g. `[0:03] 100.00%  14 / 14 packs copied`
+	Progress,
+	/// A problem restic carried on after, e.g. a file it couldn't read
+	Warning,
+	/// The error restic exited with
+	Fatal,
+	Other,
+}
+
+/// A classified line of restic's stderr
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StderrLine {
+	pub kind: StderrKind,
+	pub text: String,
+}
+
+/// Line prefixes and what they mean, checked in order. Progress lines are recognized by their
+/// shape instead, see `TransferStats::parse`.
+const STDERR_PREFIXES: &[(&str, StderrKind)] = &[
+	("Fatal:", StderrKind::Fatal),
+	("Warning:", StderrKind::Warning),
+	("warning:", StderrKind::Warning),
+	("error:", StderrKind::Warning),
+	("Ignoring ", StderrKind::Warning),
+];
+
+pub fn classify_stderr_line(line: &str) -> StderrKind {
+	let line = line.trim();
+	if let Some((_, kind)) = STDERR_PREFIXES.iter().find(|(prefix, _)| line.starts_with(prefix)) {
+		return *kind;
+	}
+	if TransferStats::parse(line).is_some() {
+		return StderrKind::Progress;
+	}
+	StderrKind::Other
+}
+
+/// Split stderr into classified lines. Progress counters redrawn with `\r` count as separate
+/// lines, blank lines are dropped.
+pub(crate) fn classify_stderr(stderr: &[u8]) -> Vec<StderrLine> {
+	String::from_utf8_lossy(stderr)
+		.split(['\n', '\r'])
+		.map(str::trim_end)
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| StderrLine {
+			kind: classify_stderr_line(line),
+			text: line.to_owned(),
+		})
+		.collect()
+}
+
+/// The last state of one of restic's progress counters, e.g. the packs copied or repacked
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TransferStats {
+	pub done: u64,
+	pub total: u64,
+	/// What was counted, e.g. `packs copied`
+	pub unit: String,
+	pub elapsed: Duration,
+}
+
+impl TransferStats {
+	/// Parse a counter line, `[<elapsed>] <percent>%  <done> / <total> <unit>`. The elapsed time
+	/// is `m:ss`, `h:mm:ss` or `<days>d h:mm:ss`.
+	pub fn parse(line: &str) -> Option<TransferStats> {
+		let rest = line.trim().strip_prefix('[')?;
+		let (elapsed, rest) = rest.split_once(']')?;
+		let elapsed = parse_elapsed(elapsed)?;
+
+		let mut words = rest.split_whitespace().peekable();
+		if words.peek().is_some_and(|word| word.ends_with('%')) {
+			words.next();
+		}
+		let done = words.next()?.parse().ok()?;
+		if words.next()? != "/" {
+			return None;
+		}
+		let total = words.next()?.parse().ok()?;
+		let unit = words.collect::<Vec<_>>().join(" ");
+		if unit.is_empty() {
+			return None;
+		}
+		Some(TransferStats {
+			done,
+			total,
+			unit,
+			elapsed,
+		})
+	}
+
+	/// The last counter among `lines` whose unit contains `unit`, restic redraws a counter
+	/// until it is done
+	pub(crate) fn last_in<'a, I: IntoIterator<Item = &'a str>>(lines: I, unit: &str) -> Option<TransferStats> {
+		lines
+			.into_iter()
+			.flat_map(|line| line.split('\r'))
+			.filter_map(TransferStats::parse)
+			.filter(|stats| stats.unit.contains(unit))
+			.last()
+	}
+}
+
+fn parse_elapsed(elapsed: &str) -> Option<Duration> {
+	let (days, clock) = match elapsed.split_once("d ") {
+		Some((days, clock)) => (days.trim().parse::<u64>().ok()?, clock),
+		None => (0, elapsed),
+	};
+	let parts: Vec<&str> = clock.trim().split(':').collect();
+	if !(2..=3).contains(&parts.len()) {
+		return None;
+	}
+	let mut secs = 0;
+	for part in parts {
+		secs = secs * 60 + part.parse::<u64>().ok()?;
+	}
+	Some(Duration::from_secs(days * 24 * 3600 + secs))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The fixture lines are hand-written in restic's formats, they aren't captured
+	#[test]
+	fn classifies_stderr_like_restic_prints_it() {
+		let fixtures = include_str!("../fixtures/synthetic_stderr.tsv");
+		for line in fixtures.lines().filter(|line| !line.starts_with('#')) {
+			let (kind, text) = line.split_once('\t').unwrap();
+			let expected = match kind {
+				"progress" => StderrKind::Progress,
+				"warning" => StderrKind::Warning,
+				"fatal" => StderrKind::Fatal,
+				_ => StderrKind::Other,
+			};
+			assert_eq!(classify_stderr_line(text), expected, "{:?}", text);
+		}
+	}
+
+	#[test]
+	fn parses_progress_counters() {
+		let stats = TransferStats::parse("[1:02:07] 45.12%  1200 / 2659 packs repacked").unwrap();
+		assert_eq!((stats.done, stats.total, stats.unit.as_str()), (1200, 2659, "packs repacked"));
+		assert_eq!(stats.elapsed, Duration::from_secs(3727));
+		assert_eq!(TransferStats::parse("[1d 2:00:00] 1 / 2 packs copied").unwrap().elapsed, Duration::from_secs(93600));
+		assert_eq!(TransferStats::parse("[0:01] loading index"), None);
+
+		let stderr = b"[0:00] 0.00%  0 / 14 packs copied\r[0:03] 100.00%  14 / 14 packs copied\nWarning: x\n";
+		let lines = classify_stderr(stderr);
+		assert_eq!(lines.len(), 3);
+		let last = TransferStats::last_in(lines.iter().map(|line| line.text.as_str()), "copied").unwrap();
+		assert_eq!((last.done, last.total), (14, 14));
+	}
+
+	/// Runs restic itself, `cargo test -- --ignored` with restic in PATH. Checks the
+	/// classification against what `copy` and `prune` really print, the fixtures are
+	/// hand-written.
+	#[cfg(unix)]
+	#[test]
+	#[ignore]
+	fn classifies_what_restic_copy_and_prune_print() {
+		use crate::{BackupTarget, ForgetRate, KeepCount, ResticConfig, ResticStorageConfig, SnapshotFilter};
+
+		let dir = crate::test_util::test_dir("stderr");
+		let data = dir.join("data");
+		std::fs::create_dir_all(&data).unwrap();
+		let repo = |name: &str| {
+			let mut config = ResticConfig::new("1234", ResticStorageConfig::Local(dir.join(name)));
+			// Without a terminal restic only prints its counters when asked to
+			config.extra_env.insert("RESTIC_PROGRESS_FPS".into(), "10".into());
+			config
+		};
+		let (source, dest) = (repo("source"), repo("dest"));
+		source.create_restic_repo().unwrap();
+		let version = source.restic_version().unwrap();
+		let target = BackupTarget::new(&[&data], Vec::new(), Vec::new()).unwrap();
+		for round in 0..2u8 {
+			let _ = std::fs::remove_file(data.join(format!("file{}", round.wrapping_sub(1))));
+			std::fs::write(data.join(format!("file{}", round)), vec![round; 1 << 20]).unwrap();
+			source.restic_backup(&target, None).unwrap();
+		}
+
+		dest.init_copy_destination(&source).unwrap();
+		let mut copy = dest.copy_cmd(&source, &SnapshotFilter::default()).unwrap();
+		let copied = dest.output_parsing_with_stderr("copy", &mut copy, |_, stderr| Ok(stderr.to_vec())).unwrap();
+
+		let forget_rate = ForgetRate {
+			keep_last: Some(KeepCount::Limited(1)),
+			..Default::default()
+		};
+		source.forget_detailed(&forget_rate, &[]).unwrap();
+		let mut prune = source.prune_cmd().unwrap();
+		let pruned = source.output_parsing_with_stderr("prune", &mut prune, |_, stderr| Ok(stderr.to_vec())).unwrap();
+
+		for (subcommand, lines) in &[("copy", &copied), ("prune", &pruned)] {
+			assert!(
+				lines.iter().any(|line| line.kind == StderrKind::Progress),
+				"restic {} {} printed no counter: {:?}",
+				version,
+				subcommand,
+				lines
+			);
+			for line in lines.iter() {
+				let lower = line.text.to_lowercase();
+				if line.text.starts_with('[') && line.text.contains('%') {
+					assert_eq!(line.kind, StderrKind::Progress, "restic {} {}: {:?}", version, subcommand, line.text);
+				} else if lower.contains("warning") || lower.contains("error") {
+					assert_ne!(line.kind, StderrKind::Other, "restic {} {}: {:?}", version, subcommand, line.text);
+				}
+			}
+		}
+		let copied = TransferStats::last_in(copied.iter().map(|line| line.text.as_str()), "copied").unwrap();
+		assert_eq!(copied.done, copied.total);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
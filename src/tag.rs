@@ -0,0 +1,67 @@
+use std::fmt;
+
+/// Longer tags work with restic, but are most likely a mistake
+pub const MAX_TAG_LEN: usize = 255;
+
+/// Why a string was rejected as a restic tag
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TagError {
+	Empty,
+	/// restic splits tags at commas
+	ContainsComma(String),
+	SurroundingWhitespace(String),
+	TooLong(usize),
+}
+
+impl fmt::Display for TagError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TagError::Empty => write!(f, "tag is empty"),
+			TagError::ContainsComma(tag) => write!(f, "tag {:?} contains a comma", tag),
+			TagError::SurroundingWhitespace(tag) => write!(f, "tag {:?} starts or ends with whitespace", tag),
+			TagError::TooLong(len) => write!(f, "tag is {} bytes long, at most {} are allowed", len, MAX_TAG_LEN),
+		}
+	}
+}
+
+impl std::error::Error for TagError {}
+
+/// Check that `tag` survives being passed to restic unchanged.
+///
+/// Used for backup target tags as well as for the tags of forget policies and filters.
+pub fn validate_tag(tag: &str) -> std::result::Result<(), TagError> {
+	if tag.is_empty() {
+		return Err(TagError::Empty);
+	}
+	if tag.contains(',') {
+		return Err(TagError::ContainsComma(tag.to_owned()));
+	}
+	if tag.trim() != tag {
+		return Err(TagError::SurroundingWhitespace(tag.to_owned()));
+	}
+	if tag.len() > MAX_TAG_LEN {
+		return Err(TagError::TooLong(tag.len()));
+	}
+	Ok(())
+}
+
+/// Validate all tags, stopping at the first invalid one
+pub fn validate_tags<'a, I: IntoIterator<Item = &'a String>>(tags: I) -> std::result::Result<(), TagError> {
+	tags.into_iter().try_for_each(|tag| validate_tag(tag))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tag_rules() {
+		assert_eq!(validate_tag("nightly"), Ok(()));
+		assert_eq!(validate_tag("with space"), Ok(()));
+		assert_eq!(validate_tag(""), Err(TagError::Empty));
+		assert_eq!(validate_tag("a,b"), Err(TagError::ContainsComma("a,b".to_owned())));
+		assert_eq!(validate_tag(" a"), Err(TagError::SurroundingWhitespace(" a".to_owned())));
+		assert_eq!(validate_tag(&"x".repeat(MAX_TAG_LEN + 1)), Err(TagError::TooLong(MAX_TAG_LEN + 1)));
+		assert!(validate_tags(&["ok".to_owned(), "".to_owned()]).is_err());
+	}
+}
@@ -0,0 +1,30 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_TEST_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory below the system temp dir, unique per process and call so tests running
+/// at the same time, in one or several processes, never share one
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!(
+		"restic-interfacer-{}-test-{}-{}",
+		name,
+		std::process::id(),
+		NEXT_TEST_DIR.fetch_add(1, Ordering::Relaxed)
+	));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+/// Write `script` as an executable `restic` into `dir`, for tests that point
+/// `ResticConfig::restic_binary` at it
+#[cfg(unix)]
+pub(crate) fn fake_restic(dir: &Path, script: &str) -> PathBuf {
+	use std::os::unix::fs::PermissionsExt;
+
+	let restic = dir.join("restic");
+	std::fs::write(&restic, script).unwrap();
+	std::fs::set_permissions(&restic, std::fs::Permissions::from_mode(0o755)).unwrap();
+	restic
+}
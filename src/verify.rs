@@ -0,0 +1,304 @@
+use crate::errors::*;
+use crate::restic_outputs::ListJson;
+use crate::{ResticConfig, SnapshotId};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files larger than this aren't sampled by default, they are dumped into memory
+pub const DEFAULT_VERIFY_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Options of `verify_samples_with`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VerifyOptions {
+	/// How many files to restore, fewer when the snapshot has fewer eligible files
+	pub sample_count: usize,
+	/// Larger files are never picked
+	pub max_file_size: u64,
+	/// Makes the picked files reproducible, a random seed is used without one
+	pub seed: Option<u64>,
+}
+
+impl Default for VerifyOptions {
+	fn default() -> Self {
+		VerifyOptions {
+			sample_count: 10,
+			max_file_size: DEFAULT_VERIFY_MAX_FILE_SIZE,
+			seed: None,
+		}
+	}
+}
+
+/// What a sample that passed was checked against
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SampleCheck {
+	/// The restored contents equal the file on disk, which wasn't modified since the snapshot
+	MatchesLive,
+	/// The file on disk is gone or was modified since the snapshot, only the restored size was
+	/// compared to the snapshot's
+	SizeOnly,
+}
+
+/// One restored file of a `VerifyReport`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SampleResult {
+	pub path: PathBuf,
+	/// The size recorded in the snapshot
+	pub size: u64,
+	/// Why the file failed otherwise
+	pub result: std::result::Result<SampleCheck, String>,
+}
+
+impl SampleResult {
+	pub fn passed(&self) -> bool {
+		self.result.is_ok()
+	}
+}
+
+/// Outcome of `verify_samples`, files that couldn't be restored intact are reported here
+/// instead of as errors
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+	/// Files of the snapshot that could have been picked
+	pub eligible_files: u64,
+	pub samples: Vec<SampleResult>,
+}
+
+impl VerifyReport {
+	pub fn all_passed(&self) -> bool {
+		self.samples.iter().all(SampleResult::passed)
+	}
+
+	pub fn failed(&self) -> impl Iterator<Item = &SampleResult> {
+		self.samples.iter().filter(|sample| !sample.passed())
+	}
+}
+
+/// xorshift64*, good enough to pick samples without another dependency
+struct SampleRng(u64);
+
+impl SampleRng {
+	fn new(seed: Option<u64>) -> SampleRng {
+		let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+		// The state must never be 0
+		SampleRng(seed | 1)
+	}
+
+	/// A number below `bound`, which must not be 0
+	fn below(&mut self, bound: u64) -> u64 {
+		self.0 ^= self.0 >> 12;
+		self.0 ^= self.0 << 25;
+		self.0 ^= self.0 >> 27;
+		self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound
+	}
+}
+
+/// Collects restored bytes, failing once there are more than expected
+struct BoundedSink {
+	data: Vec<u8>,
+	limit: u64,
+}
+
+impl Write for BoundedSink {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if (self.data.len() + buf.len()) as u64 > self.limit {
+			return Err(io::Error::other("restored file is larger than the limit"));
+		}
+		self.data.extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl ResticConfig {
+	/// A restore drill: restore `sample_count` random files of the snapshot into memory and
+	/// compare them with the files on disk, see `verify_samples_with`
+	pub fn verify_samples(&self, snapshot: &SnapshotId, sample_count: usize) -> Result<VerifyReport> {
+		self.verify_samples_with(
+			snapshot,
+			&VerifyOptions {
+				sample_count,
+				..VerifyOptions::default()
+			},
+		)
+	}
+
+	/// Restore random files of the snapshot with `dump` and check them.
+	///
+	/// Unlike `check --read-data` this goes through the whole restore path. A file still on
+	/// disk with the mtime and size recorded in the snapshot has to have the same contents,
+	/// otherwise only the restored size is checked. Files whose names restic can't print
+	/// exactly are never picked. Only failing to list the snapshot is an error.
+	pub fn verify_samples_with(&self, snapshot: &SnapshotId, options: &VerifyOptions) -> Result<VerifyReport> {
+		let _span = operation_span!(self, "verify");
+		let mut rng = SampleRng::new(options.seed);
+		let mut report = VerifyReport::default();
+		// Reservoir sampling, the listing of a large snapshot isn't kept
+		let mut picked: Vec<ListJson> = Vec::with_capacity(options.sample_count);
+		self.restic_ls_streaming(std::slice::from_ref(snapshot), |_, node| {
+			let eligible = node.is_file()
+				&& node.size.is_some_and(|size| size <= options.max_file_size)
+				&& !node.is_path_lossy();
+			if !eligible {
+				return;
+			}
+			report.eligible_files += 1;
+			if picked.len() < options.sample_count {
+				picked.push(node);
+			} else if options.sample_count > 0 {
+				let slot = rng.below(report.eligible_files) as usize;
+				if slot < options.sample_count {
+					picked[slot] = node;
+				}
+			}
+		})?;
+
+		picked.sort_by(|a, b| a.path.cmp(&b.path));
+		report.samples = picked.iter().map(|node| self.verify_sample(snapshot, node)).collect();
+		Ok(report)
+	}
+
+	fn verify_sample(&self, snapshot: &SnapshotId, node: &ListJson) -> SampleResult {
+		let path = node.path_buf();
+		let size = node.size.unwrap_or(0);
+		let mut sink = BoundedSink {
+			data: Vec::with_capacity(size as usize),
+			limit: size,
+		};
+		let result = match self.dump(snapshot, &path, &mut sink) {
+			Err(err) => Err(format!("restoring failed: {}", err)),
+			Ok(restored) if restored != size => {
+				Err(format!("restored {} bytes, the snapshot recorded {}", restored, size))
+			}
+			Ok(_) if unchanged_on_disk(&path, node) => match std::fs::read(&path) {
+				Ok(live) if live == sink.data => Ok(SampleCheck::MatchesLive),
+				Ok(_) => Err("differs from the file on disk, which wasn't modified since the snapshot".to_owned()),
+				Err(err) => Err(format!("failed to read the file on disk: {}", err)),
+			},
+			Ok(_) => Ok(SampleCheck::SizeOnly),
+		};
+		SampleResult { path, size, result }
+	}
+}
+
+/// Whether the file at `path` still has the size and mtime of the snapshot's node
+fn unchanged_on_disk(path: &Path, node: &ListJson) -> bool {
+	let meta = match std::fs::metadata(path) {
+		Ok(meta) if meta.is_file() => meta,
+		_ => return false,
+	};
+	let recorded = match node.mtime_timestamp() {
+		Some(mtime) => SystemTime::from(mtime),
+		None => return false,
+	};
+	Some(meta.len()) == node.size && meta.modified().ok() == Some(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn samples_are_uniform_picks() {
+		let mut rng = SampleRng::new(Some(7));
+		let mut counts = [0; 4];
+		for _ in 0..4000 {
+			counts[rng.below(4) as usize] += 1;
+		}
+		assert!(counts.iter().all(|&count| count > 800), "{:?}", counts);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn checks_restored_files() {
+		use crate::ResticStorageConfig;
+		use chrono::{DateTime, SecondsFormat, Utc};
+
+		let dir = crate::test_util::test_dir("verify");
+		let (live, stored) = (dir.join("live"), dir.join("stored"));
+		std::fs::create_dir_all(&live).unwrap();
+		std::fs::create_dir_all(&stored).unwrap();
+
+		let mut ls = vec![format!(
+			r#"{{"time":"2024-05-01T01:00:00Z","tree":"ab12cd34","paths":["{}"],"hostname":"host","username":"me","id":"1234abcd","short_id":"1234abcd","struct_type":"snapshot"}}"#,
+			live.display()
+		)];
+		let node = |name: &str, kind: &str, size: u64, mtime: SystemTime| {
+			format!(
+				r#"{{"name":"{}","type":"{}","path":"{}","uid":0,"gid":0,"mode":420,"size":{},"mtime":"{}","atime":"2024-05-01T00:00:00Z","ctime":"2024-05-01T00:00:00Z","struct_type":"node"}}"#,
+				name,
+				kind,
+				live.join(name).display(),
+				size,
+				DateTime::<Utc>::from(mtime).to_rfc3339_opts(SecondsFormat::Nanos, true)
+			)
+		};
+		// Name, contents on disk (None once deleted), contents in the repository, recorded size
+		let files = [
+			("same", Some("alpha"), "alpha", 5),
+			("corrupt", Some("bravo"), "BRAVO", 5),
+			("deleted", None, "charlie", 7),
+			("truncated", Some("delta"), "del", 5),
+			("large", Some("echo"), "echo", 5000),
+		];
+		for (name, on_disk, in_repo, size) in &files {
+			let path = live.join(name);
+			std::fs::write(&path, on_disk.unwrap_or(in_repo)).unwrap();
+			std::fs::write(stored.join(name), in_repo).unwrap();
+			ls.push(node(name, "file", *size, std::fs::metadata(&path).unwrap().modified().unwrap()));
+			if on_disk.is_none() {
+				std::fs::remove_file(&path).unwrap();
+			}
+		}
+		ls.push(node("sub", "dir", 0, SystemTime::now()));
+		std::fs::write(dir.join("ls.jsonl"), ls.join("\n")).unwrap();
+
+		let script = format!(
+			"#!/bin/sh
+case \"$*\" in
+	*' ls '*) cat '{}' ;;
+	*' dump '*) for last; do :; done; cat \"{}/$(basename \"$last\")\" ;;
+esac
+",
+			dir.join("ls.jsonl").display(),
+			stored.display()
+		);
+		let restic = crate::test_util::fake_restic(&dir, &script);
+
+		let mut config = ResticConfig::new("1234", ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(restic);
+		let options = VerifyOptions {
+			sample_count: 10,
+			max_file_size: 1000,
+			seed: Some(1),
+		};
+		let report = config.verify_samples_with(&SnapshotId::new("1234abcd").unwrap(), &options).unwrap();
+		assert_eq!(report.eligible_files, 4);
+		let results: Vec<_> = report
+			.samples
+			.iter()
+			.map(|sample| (sample.path.file_name().unwrap().to_str().unwrap(), sample.result.clone()))
+			.collect();
+		assert_eq!(results[0].0, "corrupt");
+		assert!(results[0].1.as_ref().unwrap_err().contains("differs"));
+		assert_eq!(results[1], ("deleted", Ok(SampleCheck::SizeOnly)));
+		assert_eq!(results[2], ("same", Ok(SampleCheck::MatchesLive)));
+		assert_eq!(results[3].0, "truncated");
+		assert!(results[3].1.as_ref().unwrap_err().contains("restored 3 bytes"));
+		assert!(!report.all_passed());
+		assert_eq!(report.failed().count(), 2);
+
+		let two = VerifyOptions {
+			sample_count: 2,
+			..options
+		};
+		assert_eq!(config.verify_samples_with(&SnapshotId::new("1234abcd").unwrap(), &two).unwrap().samples.len(), 2);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,255 @@
+use crate::{BackupTarget, FileMeta, WalkError, WalkReport};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// What a directory looked like when it was walked. A new or deleted child changes the
+/// directory's mtime on most filesystems, the child count catches it on the others as long as
+/// the number of children changed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+struct DirStamp {
+	mtime: SystemTime,
+	children: usize,
+}
+
+impl DirStamp {
+	fn read(dir: &Path) -> Option<DirStamp> {
+		let mtime = std::fs::symlink_metadata(dir).ok()?.modified().ok()?;
+		let children = std::fs::read_dir(dir).ok()?.count();
+		Some(DirStamp { mtime, children })
+	}
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct CachedEntry {
+	meta: Option<FileMeta>,
+	/// Only for directories, None when it couldn't be read so the directory is always walked
+	stamp: Option<DirStamp>,
+}
+
+/// The result of a walk kept for the next one, from `BackupTarget::generate_files_cached`.
+///
+/// Serializable so it can be stored between runs. It only applies to the target it was made
+/// for, with another target the next walk is a full one.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct WalkSnapshot {
+	/// The serialized target the snapshot was made for
+	target: String,
+	/// When the last walk that didn't skip any directory ran
+	full_walk_at: Option<SystemTime>,
+	entries: BTreeMap<PathBuf, CachedEntry>,
+}
+
+impl WalkSnapshot {
+	/// Walk `path` and the directories above it again next time, e.g. after changing a file
+	/// in it. Files changed in place don't touch their directory's mtime, so the cache can't
+	/// see them on its own.
+	pub fn invalidate(&mut self, path: &Path) {
+		for ancestor in path.ancestors() {
+			if let Some(entry) = self.entries.get_mut(ancestor) {
+				entry.stamp = None;
+			}
+		}
+	}
+
+	/// Make the next walk a full one
+	pub fn invalidate_all(&mut self) {
+		self.full_walk_at = None;
+	}
+
+	/// When the last full walk ran, None if the next walk will be one
+	pub fn full_walk_at(&self) -> Option<SystemTime> {
+		self.full_walk_at
+	}
+
+	fn usable_for(&self, target: &str, options: &WalkCacheOptions) -> bool {
+		let age = self
+			.full_walk_at
+			.and_then(|full_walk_at| SystemTime::now().duration_since(full_walk_at).ok());
+		self.target == target && age.is_some_and(|age| age < options.full_walk_interval)
+	}
+
+	/// The cached directories that changed or can't be compared, and all directories above
+	/// them. A directory's stamp only covers its own children, so one can only be skipped when
+	/// nothing below it changed either.
+	fn dirty_dirs(&self) -> HashSet<&Path> {
+		let mut dirty = HashSet::new();
+		let dirs = self.entries.iter().filter(|(_, cached)| cached.meta.is_none_or(|meta| meta.is_dir));
+		for (dir, cached) in dirs {
+			if cached.stamp.is_none() || cached.stamp != DirStamp::read(dir) {
+				for ancestor in dir.ancestors() {
+					if !dirty.insert(ancestor) {
+						break;
+					}
+				}
+			}
+		}
+		dirty
+	}
+
+	/// The cached entries below `dir`, not including `dir` itself
+	fn descendants<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = (&'a PathBuf, &'a CachedEntry)> + 'a {
+		self.entries
+			.range::<Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+			.take_while(move |(path, _)| path.starts_with(dir))
+	}
+}
+
+/// How `generate_files_cached` uses a previous `WalkSnapshot`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WalkCacheOptions {
+	/// Ignore the snapshot and walk everything once the last full walk is this old. Catches what
+	/// the directory stamps miss: changed files, and renames on filesystems that don't update
+	/// directory mtimes, like FAT.
+	pub full_walk_interval: Duration,
+}
+
+impl Default for WalkCacheOptions {
+	/// A full walk once a day
+	fn default() -> Self {
+		WalkCacheOptions {
+			full_walk_interval: Duration::from_secs(24 * 60 * 60),
+		}
+	}
+}
+
+impl BackupTarget {
+	/// Like `generate_files`, but directories where neither they nor any directory below them
+	/// changed mtime or number of children since `previous` aren't walked again, their entries
+	/// are taken from the snapshot instead. Every cached directory is still stat'ed, but files
+	/// in unchanged directories aren't. Returns the tree and the snapshot to pass next time.
+	///
+	/// Sizes and mtimes of files in skipped directories are the cached ones until the next full
+	/// walk, see `WalkCacheOptions::full_walk_interval` and `WalkSnapshot::invalidate`.
+	pub fn generate_files_cached(
+		&self,
+		previous: Option<&WalkSnapshot>,
+		options: &WalkCacheOptions,
+	) -> (WalkReport, WalkSnapshot) {
+		let target = serde_json::to_string(self).unwrap_or_default();
+		let previous = previous.filter(|previous| previous.usable_for(&target, options));
+		let mut snapshot = WalkSnapshot {
+			target,
+			full_walk_at: match previous {
+				Some(previous) => previous.full_walk_at,
+				None => Some(SystemTime::now()),
+			},
+			entries: BTreeMap::new(),
+		};
+		let dirty = previous.map(WalkSnapshot::dirty_dirs).unwrap_or_default();
+		let mut report = WalkReport {
+			store: filepath_tree::PathStore::new(None),
+			errors: Vec::new(),
+		};
+
+		let mut walk = self.walk();
+		while let Some(entry) = walk.next() {
+			let entry = match entry {
+				Ok(c) => c,
+				Err(err) => {
+					report.errors.push(WalkError::from(err));
+					continue;
+				}
+			};
+
+			let meta = FileMeta::from_entry(&entry).ok();
+			let is_dir = entry.file_type().is_dir();
+			let path = entry.into_path();
+			report.store.add_path(&path, meta).expect("Failed to add to store");
+			if !is_dir {
+				snapshot.entries.insert(path, CachedEntry { meta, stamp: None });
+				continue;
+			}
+
+			let cached = previous
+				.filter(|_| !dirty.contains(path.as_path()))
+				.and_then(|previous| previous.entries.get(&path).map(|cached| (previous, cached)));
+			match cached {
+				Some((previous, cached)) => {
+					walk.skip_current_dir();
+					for (cached_path, cached_entry) in previous.descendants(&path) {
+						report
+							.store
+							.add_path(cached_path, cached_entry.meta)
+							.expect("Failed to add to store");
+						snapshot.entries.insert(cached_path.clone(), cached_entry.clone());
+					}
+					snapshot.entries.insert(path, cached.clone());
+				}
+				None => {
+					let stamp = DirStamp::read(&path);
+					snapshot.entries.insert(path, CachedEntry { meta, stamp });
+				}
+			}
+		}
+
+		(report, snapshot)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cached_paths(snapshot: &WalkSnapshot, root: &Path) -> Vec<PathBuf> {
+		snapshot
+			.entries
+			.keys()
+			.map(|path| path.strip_prefix(root).unwrap().to_owned())
+			.collect()
+	}
+
+	#[test]
+	fn unchanged_directories_come_from_the_snapshot() {
+		let root = crate::test_util::test_dir("walk-cache");
+		std::fs::create_dir_all(root.join("sub")).unwrap();
+		std::fs::write(root.join("sub/a"), b"a").unwrap();
+		let target = BackupTarget::new_from_string(&[root.to_str().unwrap()], vec![], vec![]).unwrap();
+		let options = WalkCacheOptions::default();
+
+		let (_, first) = target.generate_files_cached(None, &options);
+		assert_eq!(cached_paths(&first, &root), ["", "sub", "sub/a"].iter().map(PathBuf::from).collect::<Vec<_>>());
+
+		// A new file changes the child count even where the mtime stays the same
+		std::fs::write(root.join("sub/b"), b"b").unwrap();
+		let mut stale = first.clone();
+		let sub = stale.entries.get_mut(&root.join("sub")).unwrap();
+		sub.stamp.as_mut().unwrap().mtime = std::fs::metadata(root.join("sub")).unwrap().modified().unwrap();
+		let (_, second) = target.generate_files_cached(Some(&stale), &options);
+		assert!(second.entries.contains_key(&root.join("sub/b")));
+		assert_eq!(second.full_walk_at(), first.full_walk_at());
+
+		// A rename on a filesystem that keeps the mtime, like FAT, is only seen by a full walk
+		std::fs::rename(root.join("sub/b"), root.join("sub/c")).unwrap();
+		let mut stale = second.clone();
+		let sub = stale.entries.get_mut(&root.join("sub")).unwrap();
+		sub.stamp.as_mut().unwrap().mtime = std::fs::metadata(root.join("sub")).unwrap().modified().unwrap();
+		let (_, missed) = target.generate_files_cached(Some(&stale), &options);
+		assert!(missed.entries.contains_key(&root.join("sub/b")));
+		assert!(!missed.entries.contains_key(&root.join("sub/c")));
+
+		let mut invalidated = stale.clone();
+		invalidated.invalidate(&root.join("sub/b"));
+		let (_, caught) = target.generate_files_cached(Some(&invalidated), &options);
+		assert!(caught.entries.contains_key(&root.join("sub/c")));
+
+		let every_time = WalkCacheOptions {
+			full_walk_interval: Duration::from_secs(0),
+		};
+		let (_, full) = target.generate_files_cached(Some(&stale), &every_time);
+		assert_eq!(cached_paths(&full, &root), ["", "sub", "sub/a", "sub/c"].iter().map(PathBuf::from).collect::<Vec<_>>());
+		assert!(full.full_walk_at() > stale.full_walk_at());
+
+		let mut reset = stale.clone();
+		reset.invalidate_all();
+		let (_, full) = target.generate_files_cached(Some(&reset), &options);
+		assert!(full.entries.contains_key(&root.join("sub/c")));
+
+		let json = serde_json::to_string(&full).unwrap();
+		assert_eq!(serde_json::from_str::<WalkSnapshot>(&json).unwrap(), full);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+}
@@ -0,0 +1,136 @@
+use crate::errors::*;
+use crate::json::{parse_json_list_with_raw, parse_json_with_raw};
+use crate::restic_outputs::{BackupJson, ListJson, LsLine, SnapshotsJson, StatsJson, StatsMode};
+use crate::snapshot_query::order_snapshots;
+use crate::{BackupTarget, ResticConfig, SnapshotId, SnapshotSort};
+use serde_json::Value;
+use std::borrow::Borrow;
+
+/// A parsed result of restic together with the JSON it was read from, for fields this crate
+/// doesn't have in its types yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithRaw<T> {
+	pub value: T,
+	pub raw: Value,
+}
+
+impl<T> WithRaw<T> {
+	pub fn into_value(self) -> T {
+		self.value
+	}
+}
+
+impl<T> Borrow<T> for WithRaw<T> {
+	fn borrow(&self) -> &T {
+		&self.value
+	}
+}
+
+impl ResticConfig {
+	/// Like `get_restic_snapshots`, every snapshot with the JSON object restic printed for it,
+	/// newest first
+	pub fn get_restic_snapshots_raw(&self) -> Result<Vec<WithRaw<SnapshotsJson>>> {
+		let _span = operation_span!(self, "snapshots");
+		let mut cmd = self.snapshots_cmd()?;
+		let mut snapshots = self.output_parsing("snapshots", &mut cmd, |stdout_data| {
+			parse_json_list_with_raw("snapshots", &stdout_data)
+		})?;
+		order_snapshots(&mut snapshots, None, SnapshotSort::default());
+		Ok(snapshots)
+	}
+
+	/// Like `restic_ls`, every node with its line of `ls --json`
+	pub fn restic_ls_raw(&self, id: &str) -> Result<Vec<WithRaw<ListJson>>> {
+		let _span = operation_span!(self, "ls");
+		let id = SnapshotId::new_or_latest(id.trim())?;
+		let mut cmd = self.ls_cmd(&id)?;
+		self.stream_collect("ls", &mut cmd, Vec::new, |nodes, line| {
+			let WithRaw { value, raw } = parse_json_with_raw::<LsLine>("ls", line)?;
+			if let LsLine::Node(node) = value {
+				nodes.push(WithRaw {
+					value: node.normalized(),
+					raw,
+				});
+			}
+			Ok(())
+		})
+	}
+
+	/// Like `restic_backup`, the summary with its JSON line. The warnings about skipped folders
+	/// and exclude files are only logged, and no `BackupJournal` is written.
+	pub fn restic_backup_raw(&self, backup_targets: &BackupTarget) -> Result<WithRaw<BackupJson>> {
+		let _span = operation_span!(self, "backup");
+		let _guard = self.repo_guard(true)?;
+		self.run_backup_raw(backup_targets, &mut Vec::new())
+	}
+
+	/// Like `stats`, the statistics with the JSON restic printed
+	pub fn stats_raw(&self, mode: StatsMode) -> Result<WithRaw<StatsJson>> {
+		let _span = operation_span!(self, "stats");
+		let mut cmd = self.stats_cmd(mode, None)?;
+		self.output_parsing("stats", &mut cmd, |stdout_data| parse_json_with_raw("stats", &stdout_data))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn orders_raw_snapshots_like_parsed_ones() {
+		let snapshot = |id: &str, time: &str| {
+			format!(
+				r#"{{"id":"{0}","short_id":"{0}","time":"{1}","tree":"","paths":["/data"],"hostname":"host","username":"user"}}"#,
+				id, time
+			)
+		};
+		let json = format!(
+			"[{},{}]",
+			snapshot("older", "2021-01-01T00:00:00Z"),
+			snapshot("newer", "2021-01-02T00:00:00Z")
+		);
+		let mut snapshots: Vec<WithRaw<SnapshotsJson>> = parse_json_list_with_raw("snapshots", &json).unwrap();
+		order_snapshots(&mut snapshots, None, SnapshotSort::default());
+		let ids: Vec<_> = snapshots.iter().map(|snapshot| snapshot.raw["id"].as_str().unwrap()).collect();
+		assert_eq!(ids, ["newer", "older"]);
+		assert_eq!(snapshots[0].value.id, "newer");
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn keeps_the_json_of_each_result() {
+		let dir = crate::test_util::test_dir("with-raw");
+		let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+		let script = format!(
+			"#!/bin/sh
+case \"$*\" in
+	*' snapshots'*) cat '{}' ;;
+	*' ls '*) cat '{}' ;;
+	*' stats '*) echo '{{\"total_size\":2048,\"total_file_count\":3,\"snapshots_count\":1,\"total_ghost_size\":7}}' ;;
+esac
+",
+			fixtures.join("restic_snapshots/0.17.0.json").display(),
+			fixtures.join("restic_ls_nodes.jsonl").display()
+		);
+		let restic = crate::test_util::fake_restic(&dir, &script);
+
+		let mut config = ResticConfig::new("1234", crate::ResticStorageConfig::Local("./sample_repo".into()));
+		config.restic_binary = Some(restic);
+
+		let snapshots = config.get_restic_snapshots_raw().unwrap();
+		assert_eq!(snapshots.len(), 1);
+		assert_eq!(snapshots[0].raw["id"], snapshots[0].value.id.as_str());
+		assert_eq!(snapshots[0].raw["uid"], 1000);
+
+		let nodes = config.restic_ls_raw("latest").unwrap();
+		assert_eq!(nodes.len(), 3);
+		assert_eq!(nodes[0].raw["inode"], 1576843);
+		assert_eq!(nodes[0].value.link_target.as_deref(), Some("../shared/current"));
+
+		let stats = config.stats_raw(StatsMode::RestoreSize).unwrap();
+		assert_eq!(stats.value.total_size, 2048);
+		assert_eq!(stats.raw["total_ghost_size"], 7);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}